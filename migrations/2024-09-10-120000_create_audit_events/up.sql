@@ -0,0 +1,15 @@
+-- No foreign keys to users, the events outlive the deleted accounts
+CREATE TABLE audit_events (
+    id SERIAL PRIMARY KEY,
+    actor_id INT,
+    target_id INT,
+    event_type VARCHAR(64) NOT NULL,
+    ip VARCHAR(45),
+    user_agent VARCHAR(256),
+    payload JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP DEFAULT NOW() NOT NULL
+);
+
+CREATE INDEX audit_events_target_id_idx ON audit_events (target_id, created_at);
+CREATE INDEX audit_events_actor_id_idx ON audit_events (actor_id, created_at);
+CREATE INDEX audit_events_event_type_idx ON audit_events (event_type, created_at);
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN confirmation_sent_at;
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN confirmation_sent_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
+UPDATE users SET confirmation_sent_at = created_at;
@@ -1,22 +1,60 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use argon2::password_hash::{Error, SaltString};
 use argon2::{PasswordHash, PasswordHasher, PasswordVerifier};
 use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TotpUrlError, TOTP};
 
 use crate::dto::CredentialsDto;
 use crate::models::NewUser;
 use crate::{errors::AuthError, models::User};
 
-pub const SESSION_LIFE_TIME: usize = 60 * 60 * 24;
 pub const SESSION_ID_LENGTH: usize = 128;
-pub const RESET_TOKEN_LIFE_TIME: usize = 60 * 60;
 pub const SESSIONS_KEY_PREFIX: &str = "sessions";
+pub const REFRESH_TOKENS_KEY_PREFIX: &str = "refresh_tokens";
+pub const TOKEN_FAMILIES_KEY_PREFIX: &str = "token_families";
+pub const TOKEN_FAMILY_ID_LENGTH: usize = 32;
+pub const SESSION_FAMILIES_KEY_PREFIX: &str = "session_families";
+pub const USER_SESSIONS_KEY_PREFIX: &str = "user_sessions";
+pub const JWT_DENYLIST_KEY_PREFIX: &str = "jwt_denylist";
 pub const RESET_TOKEN_KEY_PREFIX: &str = "reset_token";
 pub const RESET_PASSWORD_PATH: &str = "reset_password";
-pub const CONFIRM_TOKEN_LIFE_TIME: usize = 60 * 60 * 24;
+pub const MAGIC_LINK_TOKEN_KEY_PREFIX: &str = "magic_link_token";
+pub const USER_MAGIC_LINK_KEY_PREFIX: &str = "user_magic_link";
+pub const MAGIC_LINK_PATH: &str = "magic_login";
 pub const CONFIRM_TOKEN_KEY_PREFIX: &str = "confirm_token";
 pub const CONFIRM_EMAIL_PATH: &str = "confirm";
+pub const USER_CONFIRM_TOKEN_KEY_PREFIX: &str = "user_confirm_token";
+pub const EMAIL_CHANGE_TOKEN_KEY_PREFIX: &str = "email_change_token";
+pub const EMAIL_REVERT_TOKEN_KEY_PREFIX: &str = "email_revert_token";
+pub const PENDING_EMAIL_KEY_PREFIX: &str = "pending_email";
+pub const CHANGE_EMAIL_PATH: &str = "change_email";
+pub const REVERT_EMAIL_PATH: &str = "revert_email";
+pub const OIDC_STATE_KEY_PREFIX: &str = "oidc_state";
+pub const ACCOUNT_LINK_TOKEN_KEY_PREFIX: &str = "account_link_token";
+pub const PASSKEY_REGISTRATION_KEY_PREFIX: &str = "passkey_registration";
+pub const PASSKEY_LOGIN_KEY_PREFIX: &str = "passkey_login";
+pub const MAX_PASSKEY_NAME_LENGTH: usize = 64;
+pub const MAX_API_KEY_NAME_LENGTH: usize = 64;
+pub const INVITATION_TOKEN_KEY_PREFIX: &str = "invitation_token";
+pub const INVITATION_PATH: &str = "invitation";
+pub const TWO_FACTOR_CHALLENGE_KEY_PREFIX: &str = "two_factor_challenge";
+pub const TWO_FACTOR_ATTEMPTS_KEY_PREFIX: &str = "two_factor_attempts";
+pub const MAX_TWO_FACTOR_ATTEMPTS: i64 = 5;
+pub const RECOVERY_CODES_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const TOTP_ISSUER: &str = "Template App";
+const TOTP_DIGITS: usize = 6;
+const TOTP_SKEW: u8 = 1;
+const TOTP_STEP: u64 = 30;
+const API_KEY_PREFIX: &str = "tmplt";
+const API_KEY_ID_LENGTH: usize = 12;
+const API_KEY_SECRET_LENGTH: usize = 40;
 const MIN_PASSWORD_LENGTH: usize = 6;
 const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_DERIVED_USERNAME_LENGTH: usize = 32;
 
 pub fn hash_password(password: String) -> Result<String, Error> {
     let salt = SaltString::generate(OsRng);
@@ -26,14 +64,18 @@ pub fn hash_password(password: String) -> Result<String, Error> {
 }
 
 pub fn authorize_user(user: &User, credentials: &CredentialsDto) -> Result<String, Error> {
-    let db_hash = PasswordHash::new(&user.password)?;
-    let argon = argon2::Argon2::default();
-    argon.verify_password(credentials.password.as_bytes(), &db_hash)?;
+    verify_password(user, &credentials.password)?;
 
     let session_id = generate_token(SESSION_ID_LENGTH);
     Ok(session_id)
 }
 
+pub fn verify_password(user: &User, password: &str) -> Result<(), Error> {
+    let db_hash = PasswordHash::new(&user.password)?;
+    let argon = argon2::Argon2::default();
+    argon.verify_password(password.as_bytes(), &db_hash)
+}
+
 pub fn generate_token(length: usize) -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -42,6 +84,84 @@ pub fn generate_token(length: usize) -> String {
         .collect()
 }
 
+pub fn generate_totp_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+/// Build an RFC 6238 generator (SHA-1, 6 digits, 30 seconds) for the base32 encoded secret
+pub fn build_totp(secret: &str, account_name: &str) -> Result<TOTP, TotpUrlError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| TotpUrlError::Secret(secret.to_string()))?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        TOTP_SKEW,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        account_name.to_string(),
+    )
+}
+
+/// Time step the code was generated for, the current one or one within the allowed skew
+pub fn find_totp_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    let current = now.as_secs() / TOTP_STEP;
+    let skew = TOTP_SKEW as u64;
+
+    (current.saturating_sub(skew)..=current + skew)
+        .find(|step| {
+            totp.generate(step * TOTP_STEP)
+                .as_bytes()
+                .ct_eq(code.as_bytes())
+                .into()
+        })
+        .map(|step| step as i64)
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES_COUNT)
+        .map(|_| generate_token(RECOVERY_CODE_LENGTH).to_lowercase())
+        .collect()
+}
+
+/// Recovery codes are random enough to be stored as plain SHA-256 digests
+pub fn hash_recovery_code(code: &str) -> String {
+    format!(
+        "{:x}",
+        Sha256::digest(code.trim().to_lowercase().as_bytes())
+    )
+}
+
+/// A new API key `tmplt_{id}_{secret}` with its public prefix `tmplt_{id}`
+pub fn generate_api_key() -> (String, String) {
+    let prefix = format!("{}_{}", API_KEY_PREFIX, generate_token(API_KEY_ID_LENGTH));
+    let key = format!("{}_{}", prefix, generate_token(API_KEY_SECRET_LENGTH));
+    (prefix, key)
+}
+
+/// The public prefix of the token if it is formatted as an API key
+pub fn api_key_prefix(token: &str) -> Option<&str> {
+    let (prefix, secret) = token.rsplit_once('_')?;
+    let (name, id) = prefix.split_once('_')?;
+    (name == API_KEY_PREFIX
+        && id.len() == API_KEY_ID_LENGTH
+        && secret.len() == API_KEY_SECRET_LENGTH)
+        .then_some(prefix)
+}
+
+/// Whether the Bearer token is a JWT rather than a session id, which never contains dots
+pub fn is_jwt(token: &str) -> bool {
+    token.contains('.')
+}
+
+/// API keys are random enough to be stored as plain SHA-256 digests
+pub fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
 pub fn validate_signup_credentials(credentials: &NewUser) -> Result<(), AuthError> {
     if !is_username_valid(&credentials.username) {
         return Err(AuthError::InvalidUsername);
@@ -59,6 +179,31 @@ pub fn is_username_valid(username: &str) -> bool {
     username.len() >= MIN_USERNAME_LENGTH && username.chars().all(|c| c.is_ascii_alphanumeric())
 }
 
+/// Username of a user signed up with a provider, made of the ascii alphanumeric characters of
+/// the preferred username or of the email; later attempts add a random suffix
+pub fn derive_username(preferred_username: Option<&str>, email: &str, attempt: usize) -> String {
+    let alphanumeric = |value: &str| -> String {
+        value
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .take(MAX_DERIVED_USERNAME_LENGTH)
+            .collect()
+    };
+
+    let local_part = alphanumeric(email.split('@').next().unwrap_or_default());
+    let username = preferred_username
+        .map(alphanumeric)
+        .filter(|username| is_username_valid(username))
+        .or_else(|| Some(local_part.clone()).filter(|username| is_username_valid(username)))
+        .unwrap_or_else(|| format!("user{}", local_part));
+
+    if attempt == 0 {
+        username
+    } else {
+        format!("{}{}", username, rand::thread_rng().gen_range(1000..10000))
+    }
+}
+
 pub fn is_password_valid(password: &str) -> bool {
     password.len() >= MIN_PASSWORD_LENGTH
         && password.is_ascii()
@@ -1,4 +1,5 @@
 use clap::{Arg, Command};
+use rust_template::config::AppConfig;
 
 extern crate rust_template;
 
@@ -11,6 +12,9 @@ const CMD_ADD: &str = "add";
 const CMD_SET_TYPE: &str = "set_type";
 const CMD_ADD_ROLES: &str = "add_roles";
 const CMD_REMOVE_ROLES: &str = "remove_roles";
+const CMD_EMAILS: &str = "emails";
+const CMD_REPLAY: &str = "replay";
+const CMD_PURGE: &str = "purge";
 const ARG_USERNAME: &str = "username";
 const ARG_EMAIL: &str = "email";
 const ARG_PASSWORD: &str = "password";
@@ -93,6 +97,10 @@ Multiple roles can be separated by comma."
                             .value_parser(clap::value_parser!(i32)),
                     ),
                 )
+                .subcommand(
+                    Command::new(CMD_PURGE)
+                        .about("Delete users who have not confirmed the registration before the confirmation token expired"),
+                )
                 .subcommand(
                     Command::new(CMD_SET_TYPE)
                         .about("Set user type")
@@ -200,12 +208,38 @@ Multiple roles can be separated by comma. Existing roles will be replaced.",
                         ),
                 ),
         )
+        .subcommand(
+            Command::new(CMD_EMAILS)
+                .about("Rust Template outbound email queue CLI")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new(CMD_LIST)
+                        .about("Show the size of the email queue and list the emails waiting for a retry or failed to be sent"),
+                )
+                .subcommand(
+                    Command::new(CMD_REPLAY)
+                        .about("Queue failed emails to be sent again")
+                        .arg(
+                            Arg::new(ARG_ID)
+                                .help("ID of the failed email to queue again, all failed emails are queued if omitted"),
+                        ),
+                ),
+        )
         .get_matches();
 
+    let config = match AppConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid app config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     #[allow(clippy::single_match)]
     match matches.subcommand() {
         Some((CMD_USERS, sub_matches)) => match sub_matches.subcommand() {
             Some((CMD_CREATE, sub_matches)) => rust_template::commands::create_user(
+                &config,
                 sub_matches
                     .get_one::<String>(ARG_USERNAME)
                     .unwrap()
@@ -230,11 +264,14 @@ Multiple roles can be separated by comma. Existing roles will be replaced.",
                     .map(|v| v.to_string())
                     .collect(),
             ),
-            Some((CMD_LIST, _)) => rust_template::commands::list_users(),
+            Some((CMD_LIST, _)) => rust_template::commands::list_users(&config),
             Some((CMD_DELETE, sub_matches)) => rust_template::commands::delete_user(
+                &config,
                 sub_matches.get_one::<i32>(ARG_ID).unwrap().to_owned(),
             ),
+            Some((CMD_PURGE, _)) => rust_template::commands::purge_unconfirmed_users(&config),
             Some((CMD_SET_TYPE, sub_matches)) => rust_template::commands::set_user_type(
+                &config,
                 sub_matches.get_one::<i32>(ARG_ID).unwrap().to_owned(),
                 sub_matches
                     .get_one::<String>(ARG_TYPE)
@@ -242,6 +279,7 @@ Multiple roles can be separated by comma. Existing roles will be replaced.",
                     .unwrap(),
             ),
             Some((CMD_ADD_ROLES, sub_matches)) => rust_template::commands::set_roles(
+                &config,
                 sub_matches.get_one::<i32>(ARG_ID).unwrap().to_owned(),
                 sub_matches
                     .get_many::<String>(ARG_ROLES)
@@ -251,6 +289,7 @@ Multiple roles can be separated by comma. Existing roles will be replaced.",
                 true,
             ),
             Some((CMD_REMOVE_ROLES, sub_matches)) => rust_template::commands::set_roles(
+                &config,
                 sub_matches.get_one::<i32>(ARG_ID).unwrap().to_owned(),
                 sub_matches
                     .get_many::<String>(ARG_ROLES)
@@ -263,6 +302,7 @@ Multiple roles can be separated by comma. Existing roles will be replaced.",
         },
         Some((CMD_COMPANIES, sub_matches)) => match sub_matches.subcommand() {
             Some((CMD_CREATE, sub_matches)) => rust_template::commands::create_company(
+                &config,
                 sub_matches.get_one::<String>(ARG_NAME).unwrap().to_owned(),
                 sub_matches
                     .get_one::<String>(ARG_EMAIL)
@@ -274,11 +314,13 @@ Multiple roles can be separated by comma. Existing roles will be replaced.",
                     .get_one::<String>(ARG_ADDRESS)
                     .map(|v| v.to_string()),
             ),
-            Some((CMD_LIST, _)) => rust_template::commands::list_companies(),
+            Some((CMD_LIST, _)) => rust_template::commands::list_companies(&config),
             Some((CMD_DELETE, sub_matches)) => rust_template::commands::delete_company(
+                &config,
                 sub_matches.get_one::<i32>(ARG_ID).unwrap().to_owned(),
             ),
             Some((CMD_ADD, sub_matches)) => rust_template::commands::add_user_to_company(
+                &config,
                 sub_matches.get_one::<String>(ARG_NAME).unwrap().to_owned(),
                 sub_matches.get_one::<String>(ARG_EMAIL).unwrap().to_owned(),
                 sub_matches
@@ -289,6 +331,14 @@ Multiple roles can be separated by comma. Existing roles will be replaced.",
             ),
             _ => {}
         },
+        Some((CMD_EMAILS, sub_matches)) => match sub_matches.subcommand() {
+            Some((CMD_LIST, _)) => rust_template::commands::list_emails(&config),
+            Some((CMD_REPLAY, sub_matches)) => rust_template::commands::replay_emails(
+                &config,
+                sub_matches.get_one::<String>(ARG_ID).map(|v| v.to_string()),
+            ),
+            _ => {}
+        },
         _ => {}
     }
 }
@@ -1,11 +1,29 @@
-use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use diesel_migrations::MigrationHarness;
 use rocket::fairing::AdHoc;
 
+use rocket::config::LogLevel;
 use rocket::{Build, Rocket};
 use rocket_db_pools::Database;
 use rocket_dyn_templates::Template;
-use rust_template::rocket_routes::{authorization, profile, Cors};
-use rust_template::rocket_routes::{CacheConnection, DbConnection};
+use rust_template::config::AppConfig;
+use rust_template::geoip::GeoIpFairing;
+use rust_template::jobs::PurgeFairing;
+use rust_template::jwt::JwtFairing;
+use rust_template::logging;
+use rust_template::mail::MailerFairing;
+use rust_template::metrics::MetricsFairing;
+use rust_template::oidc::OidcFairing;
+use rust_template::rocket_routes::cors::Cors;
+use rust_template::rocket_routes::rate_limit::RateLimiter;
+use rust_template::rocket_routes::request_id::RequestLogger;
+use rust_template::rocket_routes::{
+    admin, api_keys, authorization, companies, cors, health, magic_link, metrics, oidc, passkeys,
+    profile,
+};
+use rust_template::rocket_routes::{
+    default_catcher, unprocessable_entity, CacheConnection, DbConnection, MIGRATIONS,
+};
+use rust_template::webauthn::WebauthnFairing;
 use rust_template::{dto, errors};
 use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
 use utoipa::openapi::{ContactBuilder, InfoBuilder, LicenseBuilder, OpenApiBuilder, ServerBuilder};
@@ -15,40 +33,148 @@ use utoipa_swagger_ui::SwaggerUi;
 extern crate rust_template;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
-const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 #[rocket::main]
 async fn main() {
+    let figment = AppConfig::figment();
+    let config = match AppConfig::from_figment(&figment) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid app config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let log_level = figment
+        .extract_inner::<LogLevel>("log_level")
+        .unwrap_or(LogLevel::Normal);
+    logging::init(config.log_format, log_level.into());
+
     #[derive(OpenApi)]
     #[openapi(
         paths(
             authorization::login,
+            authorization::login_two_factor,
+            magic_link::request_magic_link,
+            magic_link::magic_login,
+            passkeys::begin_login,
+            passkeys::finish_login,
+            authorization::refresh_token,
+            authorization::jwks,
+            authorization::logout,
+            authorization::logout_all,
             authorization::signup,
             authorization::reset_password,
             authorization::change_password,
             authorization::confirm_signup,
+            authorization::resend_confirmation,
+            oidc::authorize,
+            oidc::login,
+            oidc::link,
             profile::me,
             profile::update_password,
+            profile::change_email,
+            profile::confirm_email,
+            profile::revert_email,
             profile::update_user,
             profile::delete_user,
+            profile::sessions,
+            profile::revoke_session,
+            profile::enroll_two_factor,
+            profile::verify_two_factor,
+            profile::disable_two_factor,
+            profile::activity,
+            passkeys::begin_registration,
+            passkeys::finish_registration,
+            passkeys::passkeys,
+            passkeys::rename_passkey,
+            passkeys::delete_passkey,
+            api_keys::create_api_key,
+            api_keys::api_keys,
+            api_keys::rename_api_key,
+            api_keys::delete_api_key,
+            admin::list_users,
+            admin::get_user,
+            admin::create_user,
+            admin::delete_user,
+            admin::set_user_type,
+            admin::add_roles,
+            admin::remove_roles,
+            admin::list_audit_events,
+            companies::list_companies,
+            companies::create_company,
+            companies::get_company,
+            companies::update_company,
+            companies::delete_company,
+            companies::list_members,
+            companies::add_member,
+            companies::update_member,
+            companies::remove_member,
+            companies::invite_member,
+            companies::get_invitation,
+            companies::accept_invitation,
+            companies::decline_invitation,
+            health::live,
+            health::ready,
         ),
         components(schemas(
             dto::UserProfileDto,
             dto::CredentialsDto,
             dto::AuthTokenDto,
+            dto::RefreshTokenDto,
             dto::NewPasswordDto,
+            dto::NewEmailDto,
             dto::NewUserDto,
             dto::NewUserResponseDto,
             dto::ResetPasswordEmailDto,
+            dto::MagicLinkRequestDto,
+            dto::ResendConfirmationDto,
+            dto::OidcAuthorizeDto,
+            dto::OidcAuthorizationDto,
+            dto::OidcLoginDto,
+            dto::AccountLinkChallengeDto,
+            dto::AccountLinkDto,
             dto::UpdateUserDto,
-            errors::AuthError,
-            errors::ProfileError,
+            dto::SessionDto,
+            dto::AdminUserDto,
+            dto::UsersPageDto,
+            dto::AdminNewUserDto,
+            dto::UserTypeDto,
+            dto::RolesDto,
+            dto::AuditEventDto,
+            dto::AuditEventsPageDto,
+            dto::CompanyDto,
+            dto::CompanyInfoDto,
+            dto::CompanyMemberDto,
+            dto::NewMemberDto,
+            dto::NewInvitationDto,
+            dto::InvitationDto,
+            dto::TwoFactorEnrollmentDto,
+            dto::TotpCodeDto,
+            dto::RecoveryCodesDto,
+            dto::TwoFactorChallengeDto,
+            dto::TwoFactorLoginDto,
+            dto::PasskeyCreationDto,
+            dto::PasskeyRegistrationDto,
+            dto::PasskeyDto,
+            dto::PasskeyNameDto,
+            dto::PasskeyLoginStartDto,
+            dto::PasskeyChallengeDto,
+            dto::PasskeyLoginDto,
+            dto::NewApiKeyDto,
+            dto::ApiKeyDto,
+            dto::NewApiKeyResponseDto,
+            dto::ApiKeyNameDto,
+            dto::HealthStatus,
+            dto::HealthCheckDto,
+            dto::HealthDto,
+            errors::ApiError,
+            errors::Problem,
         )),
         modifiers(&SecurityAddon),
     )]
     struct ApiDoc;
 
-    let openapi = set_openapi_doc_parameters(ApiDoc::openapi().into()).build();
+    let openapi = set_openapi_doc_parameters(ApiDoc::openapi().into(), &config.base_url).build();
 
     struct SecurityAddon;
 
@@ -59,32 +185,114 @@ async fn main() {
                 "token",
                 SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
             );
+            for role in ["admin", "editor", "viewer"] {
+                components.add_security_scheme(
+                    format!("{role}_token"),
+                    SecurityScheme::Http(
+                        HttpBuilder::new()
+                            .scheme(HttpAuthScheme::Bearer)
+                            .description(Some(format!(
+                                "Token of a regular user with the {role} role or a higher one"
+                            )))
+                            .build(),
+                    ),
+                );
+            }
         }
     }
 
-    let _ = rocket::build()
+    let _ = rocket::custom(figment)
+        .manage(config)
         .mount(
             "/",
             rocket::routes![
-                rust_template::rocket_routes::options,
+                cors::options,
                 authorization::login,
+                authorization::login_two_factor,
+                magic_link::request_magic_link,
+                magic_link::magic_login,
+                passkeys::begin_login,
+                passkeys::finish_login,
+                authorization::refresh_token,
+                authorization::jwks,
+                authorization::logout,
+                authorization::logout_all,
                 authorization::signup,
                 authorization::reset_password,
                 authorization::change_password,
                 authorization::confirm_signup,
+                authorization::resend_confirmation,
+                oidc::authorize,
+                oidc::login,
+                oidc::link,
                 profile::me,
                 profile::update_password,
+                profile::change_email,
+                profile::confirm_email,
+                profile::revert_email,
                 profile::update_user,
                 profile::delete_user,
+                profile::sessions,
+                profile::revoke_session,
+                profile::enroll_two_factor,
+                profile::verify_two_factor,
+                profile::disable_two_factor,
+                profile::activity,
+                passkeys::begin_registration,
+                passkeys::finish_registration,
+                passkeys::passkeys,
+                passkeys::rename_passkey,
+                passkeys::delete_passkey,
+                api_keys::create_api_key,
+                api_keys::api_keys,
+                api_keys::rename_api_key,
+                api_keys::delete_api_key,
+                admin::list_users,
+                admin::get_user,
+                admin::create_user,
+                admin::delete_user,
+                admin::set_user_type,
+                admin::add_roles,
+                admin::remove_roles,
+                admin::list_audit_events,
+                companies::list_companies,
+                companies::create_company,
+                companies::get_company,
+                companies::update_company,
+                companies::delete_company,
+                companies::list_members,
+                companies::add_member,
+                companies::update_member,
+                companies::remove_member,
+                companies::invite_member,
+                companies::get_invitation,
+                companies::accept_invitation,
+                companies::decline_invitation,
+                metrics::metrics,
+                health::live,
+                health::ready,
             ],
         )
+        .register(
+            "/",
+            rocket::catchers![unprocessable_entity, default_catcher,],
+        )
         .mount(
             "/",
             SwaggerUi::new("/swagger-ui/<_..>").url("/api-docs/openapi.json", openapi),
         )
+        .attach(RequestLogger)
+        .attach(MetricsFairing)
         .attach(Cors)
+        .attach(RateLimiter)
         .attach(DbConnection::fairing())
         .attach(CacheConnection::init())
+        .attach(MailerFairing)
+        .attach(PurgeFairing)
+        .attach(GeoIpFairing)
+        .attach(OidcFairing)
+        .attach(WebauthnFairing)
+        .attach(JwtFairing)
         .attach(Template::fairing())
         .attach(AdHoc::on_ignite(
             "Run database migrations",
@@ -94,7 +302,7 @@ async fn main() {
         .await;
 }
 
-fn set_openapi_doc_parameters(builder: OpenApiBuilder) -> OpenApiBuilder {
+fn set_openapi_doc_parameters(builder: OpenApiBuilder, base_url: &str) -> OpenApiBuilder {
     let info = InfoBuilder::new()
         .title("Template API")
         .version(VERSION)
@@ -114,8 +322,6 @@ fn set_openapi_doc_parameters(builder: OpenApiBuilder) -> OpenApiBuilder {
         ))
         .build();
 
-    let base_url = std::env::var("BASE_URL").expect("Unable to read base URL from env");
-
     let server = ServerBuilder::new()
         .url(base_url)
         .description(Some("The URL of the server in the Dev environment"))
@@ -1,19 +1,73 @@
 use std::str::FromStr;
 
+use chrono::DateTime;
 use diesel::{Connection, PgConnection};
+use rocket::serde::json::{serde_json::json, Value};
+use rocket::tokio::runtime::Runtime;
+use rocket_db_pools::deadpool_redis;
 
 use crate::{
     auth,
-    models::{NewCompany, NewUser, RoleCode, User, UserType},
-    repositories::{CompanyRepository, RoleRepository, UserRepository},
+    config::AppConfig,
+    jobs,
+    models::{AuditEventType, NewAuditEvent, NewCompany, NewUser, RoleCode, User, UserType},
+    repositories::{
+        AuditRepository, CompanyRepository, EmailQueueRepository, RoleRepository, UserRepository,
+    },
 };
 
-fn load_db_connection() -> PgConnection {
-    let database_url = std::env::var("DATABASE_URL").expect("Unable to read database URL from env");
-    PgConnection::establish(&database_url).expect("Unable to connect to the database")
+fn load_db_connection(config: &AppConfig) -> PgConnection {
+    let database_url = &config
+        .databases
+        .postgres
+        .as_ref()
+        .expect("Unable to read database URL from config")
+        .url;
+    PgConnection::establish(database_url).expect("Unable to connect to the database")
+}
+
+async fn load_cache_connection(config: &AppConfig) -> deadpool_redis::Connection {
+    let redis_url = &config
+        .databases
+        .redis
+        .as_ref()
+        .expect("Unable to read Redis URL from config")
+        .url;
+    deadpool_redis::Config::from_url(redis_url)
+        .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+        .expect("Unable to create the Redis pool")
+        .get()
+        .await
+        .expect("Unable to connect to Redis")
+}
+
+/// Record an audit event of a command, it has no actor and is marked with the `cli` source
+fn audit(
+    connection: &mut PgConnection,
+    event_type: AuditEventType,
+    target_id: Option<i32>,
+    mut payload: Value,
+) {
+    if let Value::Object(fields) = &mut payload {
+        fields.insert("source".to_string(), Value::from("cli"));
+    }
+
+    let event = NewAuditEvent {
+        actor_id: None,
+        target_id,
+        event_type,
+        ip: None,
+        user_agent: None,
+        payload,
+    };
+
+    if let Err(e) = AuditRepository::create(connection, event) {
+        eprintln!("Unable to record the audit event {}: {}", event_type, e);
+    }
 }
 
 pub fn create_user(
+    config: &AppConfig,
     username: String,
     email: String,
     password: String,
@@ -21,7 +75,7 @@ pub fn create_user(
     user_type_code: &str,
     role_codes: Vec<String>,
 ) {
-    let mut connection = load_db_connection();
+    let mut connection = load_db_connection(config);
 
     let password_hash = auth::hash_password(password).unwrap();
     let new_user = NewUser {
@@ -46,6 +100,19 @@ pub fn create_user(
 
     let roles = RoleRepository::find_by_user(&mut connection, &user).unwrap();
 
+    audit(
+        &mut connection,
+        AuditEventType::UserCreated,
+        Some(user.id),
+        json!({
+            "username": user.username,
+            "email": user.email,
+            "user_type": user_type.to_string(),
+            "roles": roles.iter().map(|role| role.code.to_string()).collect::<Vec<String>>(),
+            "confirmed": confirmed,
+        }),
+    );
+
     println!(
         "User created: {:?}",
         User {
@@ -59,8 +126,8 @@ pub fn create_user(
     }
 }
 
-pub fn list_users() {
-    let mut connection = load_db_connection();
+pub fn list_users(config: &AppConfig) {
+    let mut connection = load_db_connection(config);
 
     let users = UserRepository::find_with_roles(&mut connection).unwrap();
 
@@ -83,26 +150,57 @@ pub fn list_users() {
     }
 }
 
-pub fn delete_user(id: i32) {
-    let mut connection = load_db_connection();
+pub fn delete_user(config: &AppConfig, id: i32) {
+    let mut connection = load_db_connection(config);
+
+    let deleted = UserRepository::delete(&mut connection, id).unwrap();
+
+    if deleted > 0 {
+        audit(
+            &mut connection,
+            AuditEventType::UserDeleted,
+            Some(id),
+            json!({}),
+        );
+    }
+}
+
+pub fn purge_unconfirmed_users(config: &AppConfig) {
+    let mut connection = load_db_connection(config);
 
-    UserRepository::delete(&mut connection, id).unwrap();
+    let users =
+        jobs::purge_unconfirmed_users(&mut connection, config.tokens.confirm_token, "cli").unwrap();
+
+    for user in users.iter() {
+        println!(
+            "Unconfirmed user purged: {} <{}>",
+            user.username, user.email
+        );
+    }
+    println!("Purged {} unconfirmed users", users.len());
 }
 
-pub fn set_user_type(id: i32, user_type_code: &str) {
-    let mut connection = load_db_connection();
+pub fn set_user_type(config: &AppConfig, id: i32, user_type_code: &str) {
+    let mut connection = load_db_connection(config);
 
     let user_type: UserType = FromStr::from_str(user_type_code).unwrap();
     let user = UserRepository::set_user_type(&mut connection, id, &user_type).unwrap();
 
+    audit(
+        &mut connection,
+        AuditEventType::UserTypeChanged,
+        Some(id),
+        json!({ "user_type": user_type.to_string() }),
+    );
+
     println!(
         "User type of user: {:?} set to: {:?}",
         user.username, user.user_type
     );
 }
 
-pub fn set_roles(id: i32, role_codes: Vec<String>, is_adding: bool) {
-    let mut connection = load_db_connection();
+pub fn set_roles(config: &AppConfig, id: i32, role_codes: Vec<String>, is_adding: bool) {
+    let mut connection = load_db_connection(config);
 
     let role_codes = role_codes
         .iter()
@@ -111,11 +209,20 @@ pub fn set_roles(id: i32, role_codes: Vec<String>, is_adding: bool) {
 
     let user = UserRepository::find(&mut connection, id).unwrap();
 
-    if is_adding {
+    let event_type = if is_adding {
         UserRepository::add_roles(&mut connection, &user, &role_codes).unwrap();
+        AuditEventType::RolesAdded
     } else {
         UserRepository::remove_roles(&mut connection, &user, &role_codes).unwrap();
-    }
+        AuditEventType::RolesRemoved
+    };
+
+    audit(
+        &mut connection,
+        event_type,
+        Some(user.id),
+        json!({ "roles": role_codes.iter().map(RoleCode::to_string).collect::<Vec<String>>() }),
+    );
 
     let roles = RoleRepository::find_by_user(&mut connection, &user).unwrap();
 
@@ -127,12 +234,13 @@ pub fn set_roles(id: i32, role_codes: Vec<String>, is_adding: bool) {
 }
 
 pub fn create_company(
+    config: &AppConfig,
     name: String,
     email: Option<String>,
     website: Option<String>,
     address: Option<String>,
 ) {
-    let mut connection = load_db_connection();
+    let mut connection = load_db_connection(config);
 
     let company = NewCompany {
         name,
@@ -143,11 +251,23 @@ pub fn create_company(
 
     let company = CompanyRepository::create(&mut connection, company).unwrap();
 
+    audit(
+        &mut connection,
+        AuditEventType::CompanyCreated,
+        None,
+        json!({ "company_id": company.id, "name": company.name }),
+    );
+
     println!("Company created: {:?}", company);
 }
 
-pub fn add_user_to_company(company_name: String, user_email: String, role_codes: Vec<String>) {
-    let mut connection = load_db_connection();
+pub fn add_user_to_company(
+    config: &AppConfig,
+    company_name: String,
+    user_email: String,
+    role_codes: Vec<String>,
+) {
+    let mut connection = load_db_connection(config);
 
     let role_codes: Vec<RoleCode> = role_codes
         .iter()
@@ -170,14 +290,24 @@ pub fn add_user_to_company(company_name: String, user_email: String, role_codes:
     )
     .unwrap();
 
+    audit(
+        &mut connection,
+        AuditEventType::CompanyMemberAdded,
+        Some(user.id),
+        json!({
+            "company_id": company.id,
+            "roles": role_codes.iter().map(RoleCode::to_string).collect::<Vec<String>>(),
+        }),
+    );
+
     println!(
         "User added: company:{}, username:{}, roles:{:?}",
         company.name, user.username, role_codes
     );
 }
 
-pub fn list_companies() {
-    let mut connection = load_db_connection();
+pub fn list_companies(config: &AppConfig) {
+    let mut connection = load_db_connection(config);
 
     let companies = CompanyRepository::list(&mut connection).unwrap();
 
@@ -186,8 +316,78 @@ pub fn list_companies() {
     }
 }
 
-pub fn delete_company(id: i32) {
-    let mut connection = load_db_connection();
+pub fn delete_company(config: &AppConfig, id: i32) {
+    let mut connection = load_db_connection(config);
+
+    let deleted = CompanyRepository::delete(&mut connection, id).unwrap();
+
+    if deleted > 0 {
+        audit(
+            &mut connection,
+            AuditEventType::CompanyDeleted,
+            None,
+            json!({ "company_id": id }),
+        );
+    }
+}
+
+pub fn list_emails(config: &AppConfig) {
+    let runtime = Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        let mut cache = load_cache_connection(config).await;
+
+        let (queued, processing, retrying, dead) =
+            EmailQueueRepository::count(&mut cache).await.unwrap();
+        println!(
+            "Emails queued: {}, being sent: {}, waiting for retry: {}, dead: {}",
+            queued, processing, retrying, dead
+        );
+
+        let retries = EmailQueueRepository::find_retries(&mut cache)
+            .await
+            .unwrap();
+        for (email, retry_at) in retries {
+            println!(
+                "Retrying email: {} to {:?}, subject: {:?}, queued at: {}, request: {}, attempts: {}, next attempt at: {}, last error: {}",
+                email.id,
+                email.to,
+                email.subject,
+                email.queued_at,
+                email.request_id.as_deref().unwrap_or("-"),
+                email.attempts,
+                DateTime::from_timestamp(retry_at, 0).unwrap_or_default(),
+                email.last_error.unwrap_or_default()
+            );
+        }
+
+        let emails = EmailQueueRepository::find_dead_letters(&mut cache)
+            .await
+            .unwrap();
+        for email in emails {
+            println!(
+                "Dead email: {} to {:?}, subject: {:?}, queued at: {}, request: {}, attempts: {}, last error: {}",
+                email.id,
+                email.to,
+                email.subject,
+                email.queued_at,
+                email.request_id.as_deref().unwrap_or("-"),
+                email.attempts,
+                email.last_error.unwrap_or_default()
+            );
+        }
+    });
+}
+
+pub fn replay_emails(config: &AppConfig, id: Option<String>) {
+    let runtime = Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        let mut cache = load_cache_connection(config).await;
 
-    CompanyRepository::delete(&mut connection, id).unwrap();
+        let replayed = EmailQueueRepository::replay_dead_letters(id.as_deref(), &mut cache)
+            .await
+            .unwrap();
+        println!("Emails queued again: {}", replayed);
+    });
 }
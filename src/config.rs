@@ -0,0 +1,355 @@
+use std::net::IpAddr;
+
+use rocket::figment::providers::Serialized;
+use rocket::figment::Figment;
+use serde::Deserialize;
+
+use crate::geoip::GeoIpConfig;
+use crate::jobs::PurgeConfig;
+use crate::jwt::JwtConfig;
+use crate::logging::LogFormat;
+use crate::mail::{EmailQueueConfig, MailConfig};
+use crate::metrics::MetricsConfig;
+use crate::oidc::OidcConfig;
+use crate::rocket_routes::cors::CorsPolicy;
+use crate::rocket_routes::health::HealthConfig;
+use crate::rocket_routes::rate_limit::RateLimits;
+use crate::webauthn::WebauthnConfig;
+
+/// Plain env vars mapped onto config keys, kept for compatibility with the deployment env
+const ENV_KEYS: [(&str, &str); 6] = [
+    ("BASE_URL", "base_url"),
+    ("SMTP_HOST", "smtp.host"),
+    ("SMTP_USERNAME", "smtp.username"),
+    ("SMTP_PASSWORD", "smtp.password"),
+    ("DATABASE_URL", "databases.postgres.url"),
+    ("REDIS_URL", "databases.redis.url"),
+];
+
+/// Links opened by the mobile app from emails
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DeepLinkConfig {
+    pub scheme: String,
+    pub host: String,
+    /// Scheme of the app itself, used to get back to it after the signup is confirmed
+    pub app_scheme: String,
+}
+
+impl Default for DeepLinkConfig {
+    fn default() -> Self {
+        DeepLinkConfig {
+            scheme: "https".to_string(),
+            host: "template.softteco.com.deep_link".to_string(),
+            app_scheme: "tmplt".to_string(),
+        }
+    }
+}
+
+impl DeepLinkConfig {
+    pub fn link(&self, path: &str) -> String {
+        format!("{}://{}/{}", self.scheme, self.host, path)
+    }
+
+    pub fn app_link(&self) -> String {
+        format!("{}://{}", self.app_scheme, self.host)
+    }
+}
+
+/// Passwordless login by emailed links
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MagicLinkConfig {
+    /// Web pages allowed to receive the tokens, matched exactly
+    pub redirect_urls: Vec<String>,
+}
+
+impl MagicLinkConfig {
+    pub fn is_redirect_allowed(&self, redirect_url: &str) -> bool {
+        self.redirect_urls.iter().any(|url| url == redirect_url)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for url in &self.redirect_urls {
+            let is_http = url.starts_with("http://") || url.starts_with("https://");
+            if !is_http || url.contains('#') {
+                return Err(format!(
+                    "magic_link.redirect_urls {:?} must be an http(s) URL without a fragment",
+                    url
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lifetimes of the issued tokens in seconds
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TokenLifetimes {
+    pub session: usize,
+    /// Refresh tokens expire after this time of inactivity
+    pub refresh_token: usize,
+    pub reset_token: usize,
+    pub magic_link_token: usize,
+    pub confirm_token: usize,
+    pub invitation_token: usize,
+    pub two_factor_challenge: usize,
+    /// Registration or login ceremony started with a passkey, until the authenticator answers
+    pub passkey_challenge: usize,
+    /// Confirmation of a new email address, sent to that address
+    pub email_change_token: usize,
+    /// Revert of an email change, sent to the previous address
+    pub email_revert_token: usize,
+    /// Authorization started with an OpenID Connect provider, until the app returns its code
+    pub oidc_authorization: usize,
+    /// Link of a provider identity to the existing user with the same email
+    pub account_link_token: usize,
+}
+
+impl Default for TokenLifetimes {
+    fn default() -> Self {
+        TokenLifetimes {
+            session: 60 * 60 * 24,
+            refresh_token: 60 * 60 * 24 * 30,
+            reset_token: 60 * 60,
+            magic_link_token: 60 * 15,
+            confirm_token: 60 * 60 * 24,
+            invitation_token: 60 * 60 * 24 * 7,
+            two_factor_challenge: 60 * 5,
+            passkey_challenge: 60 * 5,
+            email_change_token: 60 * 60 * 24,
+            email_revert_token: 60 * 60 * 24 * 7,
+            oidc_authorization: 60 * 10,
+            account_link_token: 60 * 10,
+        }
+    }
+}
+
+impl TokenLifetimes {
+    /// Lifetime of the auth tokens, a revoked JWT is kept on the denylist until it expires
+    pub fn access_token(&self) -> usize {
+        self.session
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let lifetimes = [
+            ("session", self.session),
+            ("refresh_token", self.refresh_token),
+            ("reset_token", self.reset_token),
+            ("magic_link_token", self.magic_link_token),
+            ("confirm_token", self.confirm_token),
+            ("invitation_token", self.invitation_token),
+            ("two_factor_challenge", self.two_factor_challenge),
+            ("passkey_challenge", self.passkey_challenge),
+            ("email_change_token", self.email_change_token),
+            ("email_revert_token", self.email_revert_token),
+            ("oidc_authorization", self.oidc_authorization),
+            ("account_link_token", self.account_link_token),
+        ];
+        if let Some((name, _)) = lifetimes.iter().find(|(_, lifetime)| *lifetime == 0) {
+            return Err(format!("tokens.{} must be positive", name));
+        }
+
+        if self.refresh_token < self.session {
+            return Err("tokens.refresh_token must not be shorter than tokens.session".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Network of proxies, an IP address optionally followed by `/` and the length of its prefix
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("{:?} is not an IP address or network", value);
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value.as_str(), None),
+        };
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(invalid)?,
+            None => max_prefix_len,
+        };
+
+        Ok(IpNetwork {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+/// SMTP relay used by the `smtp` mail transport
+#[derive(Deserialize, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for SmtpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpConfig")
+            .field("host", &self.host)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct DatabaseUrl {
+    pub url: String,
+}
+
+/// Connections of the `databases` table, shared with the Rocket database pools
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Databases {
+    pub postgres: Option<DatabaseUrl>,
+    pub redis: Option<DatabaseUrl>,
+}
+
+/// Application config, loaded from `Rocket.toml`, `ROCKET_*` env vars and the plain env vars
+/// above for the selected profile
+#[derive(Deserialize, Clone, Debug)]
+pub struct AppConfig {
+    /// Public URL of the server, used in links of emails and in the API doc
+    pub base_url: String,
+    #[serde(default)]
+    pub deep_links: DeepLinkConfig,
+    #[serde(default)]
+    pub tokens: TokenLifetimes,
+    #[serde(default)]
+    pub magic_link: MagicLinkConfig,
+    pub smtp: Option<SmtpConfig>,
+    #[serde(default)]
+    pub databases: Databases,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    /// Proxies in front of the server, only their client address headers are trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNetwork>,
+    #[serde(default)]
+    pub mail: MailConfig,
+    #[serde(default)]
+    pub email_queue: EmailQueueConfig,
+    #[serde(default)]
+    pub geoip: GeoIpConfig,
+    #[serde(default)]
+    pub cors: CorsPolicy,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub purge: PurgeConfig,
+    #[serde(default)]
+    pub oidc: OidcConfig,
+    #[serde(default)]
+    pub webauthn: WebauthnConfig,
+    #[serde(default)]
+    pub jwt: JwtConfig,
+}
+
+impl AppConfig {
+    /// The Rocket figment extended with the plain env vars
+    pub fn figment() -> Figment {
+        ENV_KEYS.iter().fold(
+            rocket::Config::figment(),
+            |figment, (var, key)| match std::env::var(var) {
+                Ok(value) => figment.merge(Serialized::global(key, value)),
+                Err(_) => figment,
+            },
+        )
+    }
+
+    /// Extract and validate the config of the figment
+    pub fn from_figment(figment: &Figment) -> Result<AppConfig, String> {
+        let mut config = figment.extract::<AppConfig>().map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load the config of the selected profile
+    pub fn load() -> Result<AppConfig, String> {
+        Self::from_figment(&Self::figment())
+    }
+
+    fn validate(&mut self) -> Result<(), String> {
+        self.base_url = self.base_url.trim_end_matches('/').to_string();
+        if !self.base_url.starts_with("http://") && !self.base_url.starts_with("https://") {
+            return Err(format!(
+                "base_url {:?} must be an http(s) URL",
+                self.base_url
+            ));
+        }
+
+        if self.deep_links.scheme.is_empty()
+            || self.deep_links.host.is_empty()
+            || self.deep_links.app_scheme.is_empty()
+        {
+            return Err("deep_links must have a scheme, a host and an app scheme".to_string());
+        }
+
+        self.tokens.validate()?;
+        self.magic_link.validate()?;
+
+        if self.health.timeout == 0 {
+            return Err("health.timeout must be positive".to_string());
+        }
+
+        if matches!(self.mail, MailConfig::Smtp) && self.smtp.is_none() {
+            return Err(
+                "the smtp mail transport requires SMTP_HOST, SMTP_USERNAME and SMTP_PASSWORD"
+                    .to_string(),
+            );
+        }
+
+        self.oidc.validate()?;
+        self.webauthn.validate(&self.base_url)?;
+        self.jwt.validate(&self.base_url)?;
+
+        self.cors.validate().map_err(|e| format!("cors: {}", e))
+    }
+}
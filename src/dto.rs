@@ -1,5 +1,11 @@
+use std::collections::BTreeMap;
+
 use chrono::{NaiveDate, NaiveDateTime};
 use utoipa::ToSchema;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse,
+};
 
 /// New user request body
 #[derive(serde::Serialize, ToSchema)]
@@ -41,6 +47,26 @@ pub struct ResetPasswordEmailDto {
     pub email: String,
 }
 
+/// Magic link request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct MagicLinkRequestDto {
+    /// Registered email address
+    #[schema(example = "gunrockg@gmail.com")]
+    pub email: String,
+    /// Web page receiving the tokens in the URL fragment, one of `magic_link.redirect_urls`;
+    /// the link is sent as a deep link of the app if omitted
+    #[schema(example = "https://template.softteco.com/login/magic")]
+    pub redirect_url: Option<String>,
+}
+
+/// Resend confirmation request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct ResendConfirmationDto {
+    /// Email address of the unconfirmed account
+    #[schema(example = "gunrockg@gmail.com")]
+    pub email: String,
+}
+
 /// New password request body
 #[derive(serde::Deserialize, ToSchema)]
 pub struct NewPasswordDto {
@@ -52,6 +78,17 @@ pub struct NewPasswordDto {
     pub confirmation: String,
 }
 
+/// Email change request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct NewEmailDto {
+    /// New email address, a confirmation link is sent to it
+    #[schema(example = "new.email@test.com")]
+    pub email: String,
+    /// Current password of the user
+    #[schema(example = "123456aA")]
+    pub password: String,
+}
+
 /// Login response body
 #[derive(serde::Serialize, ToSchema)]
 pub struct AuthTokenDto {
@@ -60,6 +97,23 @@ pub struct AuthTokenDto {
         example = "pJWStSthAOTYSJIwPGjJBNVkFI0sKDmd8h2oZC1aFT0n1hbtbUJUJdahMEexCdw3pRw5qbG4KiQsNluT5c4H9FamBjxPp6ZsCYK3qduafOIzusbgOnUOd8LMyIJ1R39n"
     )]
     pub token: String,
+    /// Token used to obtain a new pair of tokens once the current one expires
+    #[schema(
+        example = "u8jzPde0IgxLd6GncfBAepfJBd0Kh8oOOL8dKLzdocJ2isAjIhKtJ0RlgLKOmxgJTeKdNnFRIBXuDL7DxtpYlSXpfKtHF4vUCsMehGAkWvj7FAc9QeWJKY40uvSwMFLZ"
+    )]
+    pub refresh_token: String,
+    /// Lifetime of the access token in seconds
+    #[schema(example = 86400)]
+    pub expires_in: usize,
+}
+
+/// Refresh token request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct RefreshTokenDto {
+    #[schema(
+        example = "u8jzPde0IgxLd6GncfBAepfJBd0Kh8oOOL8dKLzdocJ2isAjIhKtJ0RlgLKOmxgJTeKdNnFRIBXuDL7DxtpYlSXpfKtHF4vUCsMehGAkWvj7FAc9QeWJKY40uvSwMFLZ"
+    )]
+    pub refresh_token: String,
 }
 
 /// User profile response body
@@ -97,3 +151,492 @@ pub struct UpdateUserDto {
     #[schema(value_type=Option<Vec<String>>,example="1970-01-01")]
     pub birth_date: Option<NaiveDate>,
 }
+
+/// Active session response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct SessionDto {
+    /// Session identifier
+    #[schema(example = "b4CX2jWl7sKHkP0aPzQ1mVN3u6yTdR8e")]
+    pub id: String,
+    /// User agent of the device the session was started from
+    #[schema(example = "okhttp/4.12.0")]
+    pub device: Option<String>,
+    /// IP address the session was started from
+    #[schema(example = "203.0.113.42")]
+    pub ip: Option<String>,
+    #[schema(value_type=String,example="2024-08-21T13:35:16")]
+    pub created_at: NaiveDateTime,
+    /// Time of the last token refresh
+    #[schema(value_type=String,example="2024-08-22T09:12:40")]
+    pub last_used_at: NaiveDateTime,
+    /// Whether the session is the one the request is made with
+    #[schema(example = true)]
+    pub current: bool,
+}
+
+/// User details visible to admins
+#[derive(serde::Serialize, ToSchema)]
+pub struct AdminUserDto {
+    #[schema(example = 42)]
+    pub id: i32,
+    #[schema(example = "falcon")]
+    pub username: String,
+    #[schema(example = "falcon@gmail.com")]
+    pub email: String,
+    #[schema(example = "Edward")]
+    pub first_name: Option<String>,
+    #[schema(example = "Falcon")]
+    pub last_name: Option<String>,
+    #[schema(example = "Great Britain")]
+    pub country: Option<String>,
+    #[schema(value_type=Option<String>,example="1970-01-01")]
+    pub birth_date: Option<NaiveDate>,
+    /// Whether the registration is confirmed by e-mail
+    #[schema(example = true)]
+    pub confirmed: bool,
+    /// Type of the user (regular or enterprise)
+    #[schema(example = "regular")]
+    pub user_type: String,
+    /// Role codes of the user (viewer, editor, admin)
+    #[schema(example = json!(["viewer", "editor"]))]
+    pub roles: Vec<String>,
+    #[schema(value_type=String,example="2023-10-12T10:00:14.930859")]
+    pub created_at: NaiveDateTime,
+    #[schema(value_type=String,example="2024-08-21T13:35:16.389450")]
+    pub updated_at: NaiveDateTime,
+}
+
+/// Page of users response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct UsersPageDto {
+    pub items: Vec<AdminUserDto>,
+    #[schema(example = 1)]
+    pub page: i64,
+    #[schema(example = 20)]
+    pub per_page: i64,
+    /// Total number of users
+    #[schema(example = 42)]
+    pub total: i64,
+}
+
+/// Audit event response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct AuditEventDto {
+    pub id: i32,
+    /// ID of the user who acted, empty for anonymous requests and for the CLI
+    #[schema(example = 1)]
+    pub actor_id: Option<i32>,
+    /// ID of the user the event is about
+    #[schema(example = 1)]
+    pub target_id: Option<i32>,
+    #[schema(example = "login")]
+    pub event_type: String,
+    #[schema(example = "203.0.113.7")]
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Details of the event, including the ID of the request it was recorded in
+    #[schema(value_type = Object, example = json!({"two_factor": false, "request_id": "a1B2c3D4e5F6g7H8"}))]
+    pub payload: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+/// Page of audit events response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct AuditEventsPageDto {
+    pub items: Vec<AuditEventDto>,
+    #[schema(example = 1)]
+    pub page: i64,
+    #[schema(example = 20)]
+    pub per_page: i64,
+    /// Total number of matching events
+    #[schema(example = 42)]
+    pub total: i64,
+}
+
+/// New user created by an admin request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct AdminNewUserDto {
+    /// Unique username (at least 3 characters, ascii alphanumeric only)
+    #[schema(example = "gunrock")]
+    pub username: String,
+    /// Unique email address
+    #[schema(example = "gunrockg@gmail.com")]
+    pub email: String,
+    /// Password (at least 6 characters, at least one uppercase)
+    #[schema(example = "123456aA")]
+    pub password: String,
+    /// Whether the registration is confirmed without e-mail verification
+    #[serde(default)]
+    #[schema(example = true)]
+    pub confirmed: bool,
+    /// Type of the user (regular or enterprise), regular by default
+    #[schema(example = "regular")]
+    pub user_type: Option<String>,
+    /// Role codes of the user (viewer, editor, admin), viewer by default
+    #[schema(example = json!(["viewer"]))]
+    pub roles: Option<Vec<String>>,
+}
+
+/// User type update request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct UserTypeDto {
+    /// Type of the user (regular or enterprise)
+    #[schema(example = "enterprise")]
+    pub user_type: String,
+}
+
+/// Role codes request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct RolesDto {
+    /// Role codes (viewer, editor, admin)
+    #[schema(example = json!(["editor"]))]
+    pub roles: Vec<String>,
+}
+
+/// Company response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct CompanyDto {
+    #[schema(example = 7)]
+    pub id: i32,
+    #[schema(example = "SoftTeco")]
+    pub name: String,
+    #[schema(example = "info@softteco.com")]
+    pub email: Option<String>,
+    #[schema(example = "https://softteco.com")]
+    pub website: Option<String>,
+    #[schema(example = "Lithuania, Vilnius")]
+    pub address: Option<String>,
+    #[schema(value_type=String,example="2024-08-21T13:35:16.389450")]
+    pub created_at: NaiveDateTime,
+    #[schema(value_type=String,example="2024-08-21T13:35:16.389450")]
+    pub updated_at: NaiveDateTime,
+}
+
+/// New or updated company request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct CompanyInfoDto {
+    /// Unique company name (1 to 64 characters)
+    #[schema(example = "SoftTeco")]
+    pub name: String,
+    #[schema(example = "info@softteco.com")]
+    pub email: Option<String>,
+    #[schema(example = "https://softteco.com")]
+    pub website: Option<String>,
+    #[schema(example = "Lithuania, Vilnius")]
+    pub address: Option<String>,
+}
+
+/// Company member response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct CompanyMemberDto {
+    #[schema(example = 42)]
+    pub id: i32,
+    #[schema(example = "falcon")]
+    pub username: String,
+    #[schema(example = "falcon@gmail.com")]
+    pub email: String,
+    #[schema(example = "Edward")]
+    pub first_name: Option<String>,
+    #[schema(example = "Falcon")]
+    pub last_name: Option<String>,
+    /// Role codes of the user within the company (viewer, editor, admin)
+    #[schema(example = json!(["editor"]))]
+    pub roles: Vec<String>,
+}
+
+/// New company member request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct NewMemberDto {
+    /// Email of a registered user
+    #[schema(example = "falcon@gmail.com")]
+    pub email: String,
+    /// Role codes of the user within the company (viewer, editor, admin), viewer by default
+    #[schema(example = json!(["editor"]))]
+    pub roles: Option<Vec<String>>,
+}
+
+/// Company invitation request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct NewInvitationDto {
+    /// Email address to invite, the invitee does not have to be registered yet
+    #[schema(example = "falcon@gmail.com")]
+    pub email: String,
+    /// Role codes granted within the company on acceptance (viewer, editor, admin), viewer by default
+    #[schema(example = json!(["editor"]))]
+    pub roles: Option<Vec<String>>,
+}
+
+/// Company invitation response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct InvitationDto {
+    #[schema(example = 7)]
+    pub company_id: i32,
+    #[schema(example = "SoftTeco")]
+    pub company_name: String,
+    /// Invited email address
+    #[schema(example = "falcon@gmail.com")]
+    pub email: String,
+    /// Role codes granted within the company on acceptance
+    #[schema(example = json!(["editor"]))]
+    pub roles: Vec<String>,
+}
+
+/// Two-factor authentication enrollment response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct TwoFactorEnrollmentDto {
+    /// Base32 encoded TOTP secret for manual entry
+    #[schema(example = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP")]
+    pub secret: String,
+    /// Provisioning URI to be rendered as a QR code for authenticator apps
+    #[schema(
+        example = "otpauth://totp/Template%20App:falcon%40gmail.com?secret=JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP&issuer=Template%20App"
+    )]
+    pub otpauth_uri: String,
+}
+
+/// Two-factor authentication code request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct TotpCodeDto {
+    /// Current code of the authenticator app or one of the recovery codes
+    #[schema(example = "492039")]
+    pub code: String,
+}
+
+/// Recovery codes response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct RecoveryCodesDto {
+    /// One-time codes to use instead of the authenticator app, shown only once
+    #[schema(example = json!(["k3xq9wz1mf", "p0a8vd2rlu"]))]
+    pub recovery_codes: Vec<String>,
+}
+
+/// Two-factor authentication challenge response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct TwoFactorChallengeDto {
+    #[schema(
+        example = "EuhbWv7V4KKhkMa7dArwYHAXOgDcn4pyB8s2KjdYh20TzBB0F4i0gVU3J5S44UXe8B9CiRpUVEjw6gqi3Uul3Zde2Xhc6hOrtM0QaPt5Wn86rTfKZqXH76KFVfx3hcaf"
+    )]
+    pub challenge_token: String,
+    /// Lifetime of the challenge token in seconds
+    #[schema(example = 300)]
+    pub expires_in: usize,
+}
+
+/// Second step of the login request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct TwoFactorLoginDto {
+    #[schema(
+        example = "EuhbWv7V4KKhkMa7dArwYHAXOgDcn4pyB8s2KjdYh20TzBB0F4i0gVU3J5S44UXe8B9CiRpUVEjw6gqi3Uul3Zde2Xhc6hOrtM0QaPt5Wn86rTfKZqXH76KFVfx3hcaf"
+    )]
+    pub challenge_token: String,
+    /// Current code of the authenticator app or one of the recovery codes
+    #[schema(example = "492039")]
+    pub code: String,
+}
+
+/// State of the service or one of its dependencies
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+/// Result of a dependency check
+#[derive(serde::Serialize, serde::Deserialize, Debug, ToSchema)]
+pub struct HealthCheckDto {
+    pub status: HealthStatus,
+    /// Duration of the check in milliseconds
+    #[schema(example = 1.7)]
+    pub duration_ms: f64,
+    /// Why the dependency is down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "timed out")]
+    pub error: Option<String>,
+}
+
+/// Health check response body, the service is up only if every checked dependency is up
+#[derive(serde::Serialize, serde::Deserialize, Debug, ToSchema)]
+pub struct HealthDto {
+    pub status: HealthStatus,
+    /// Checks by dependency: `postgres`, `redis` and, if enabled, `mail` and `geoip`
+    pub checks: BTreeMap<String, HealthCheckDto>,
+}
+
+/// Start of an authorization with an OpenID Connect provider request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct OidcAuthorizeDto {
+    /// PKCE challenge of the S256 method, the base64url encoded SHA-256 of a code verifier
+    /// generated and kept by the app
+    #[schema(example = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM")]
+    pub code_challenge: String,
+}
+
+/// Authorization with an OpenID Connect provider response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct OidcAuthorizationDto {
+    /// Authorization page of the provider to open
+    #[schema(
+        example = "https://accounts.google.com/o/oauth2/v2/auth?response_type=code&client_id=template&state=YTtrZ9aZ7N"
+    )]
+    pub authorization_url: String,
+    /// State of the authorization, returned by the provider with the code
+    #[schema(example = "YTtrZ9aZ7N")]
+    pub state: String,
+}
+
+/// Login with an OpenID Connect provider request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct OidcLoginDto {
+    /// Authorization code returned by the provider
+    #[schema(example = "4/0AeaYSHB")]
+    pub code: String,
+    /// State returned by the provider with the code
+    #[schema(example = "YTtrZ9aZ7N")]
+    pub state: String,
+    /// PKCE code verifier the challenge of the authorization was derived from
+    #[schema(example = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk")]
+    pub code_verifier: String,
+}
+
+/// Link of a provider identity to an existing user challenge response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct AccountLinkChallengeDto {
+    #[schema(
+        example = "EuhbWv7V4KKhkMa7dArwYHAXOgDcn4pyB8s2KjdYh20TzBB0F4i0gVU3J5S44UXe8B9CiRpUVEjw6gqi3Uul3Zde2Xhc6hOrtM0QaPt5Wn86rTfKZqXH76KFVfx3hcaf"
+    )]
+    pub link_token: String,
+    /// Email of the existing user
+    #[schema(example = "gunrockg@gmail.com")]
+    pub email: String,
+    /// Lifetime of the link token in seconds
+    #[schema(example = 600)]
+    pub expires_in: usize,
+}
+
+/// Link of a provider identity to an existing user request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct AccountLinkDto {
+    pub link_token: String,
+    /// Password of the existing user
+    #[schema(example = "123456aA")]
+    pub password: String,
+}
+
+/// Passkey registration challenge response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct PasskeyCreationDto {
+    /// Options of `navigator.credentials.create()` or of the platform passkey API
+    #[schema(value_type = Object)]
+    pub options: CreationChallengeResponse,
+}
+
+/// Passkey registration request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct PasskeyRegistrationDto {
+    /// Name of the passkey shown to the user (up to 64 characters)
+    #[schema(example = "Pixel 8")]
+    pub name: String,
+    /// Credential created by the authenticator
+    #[schema(value_type = Object)]
+    pub credential: RegisterPublicKeyCredential,
+}
+
+/// Passkey response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct PasskeyDto {
+    #[schema(example = 7)]
+    pub id: i32,
+    #[schema(example = "Pixel 8")]
+    pub name: String,
+    #[schema(value_type=String,example="2024-09-23T10:41:05")]
+    pub created_at: NaiveDateTime,
+    /// Time of the last login with the passkey
+    #[schema(value_type=Option<String>,example="2024-09-24T08:03:51")]
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+/// Passkey rename request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct PasskeyNameDto {
+    #[schema(example = "Work laptop")]
+    pub name: String,
+}
+
+/// Passkey login start request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct PasskeyLoginStartDto {
+    /// Email of the user, only their passkeys are allowed; any discoverable passkey is allowed if omitted
+    #[schema(example = "gunrockg@gmail.com")]
+    pub email: Option<String>,
+}
+
+/// Passkey login challenge response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct PasskeyChallengeDto {
+    #[schema(example = "Yb3nGq0ZyC8wT1xJ5rKdP7mLs2vAeH4u")]
+    pub challenge_id: String,
+    /// Options of `navigator.credentials.get()` or of the platform passkey API
+    #[schema(value_type = Object)]
+    pub options: RequestChallengeResponse,
+}
+
+/// Passkey login request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct PasskeyLoginDto {
+    pub challenge_id: String,
+    /// Assertion signed by the authenticator
+    #[schema(value_type = Object)]
+    pub credential: PublicKeyCredential,
+}
+
+/// API key creation request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct NewApiKeyDto {
+    /// Name of the key shown to the user (up to 64 characters)
+    #[schema(example = "CI deploy")]
+    pub name: String,
+    /// Roles the key may act with, capped by the roles of the user
+    #[schema(example = json!(["viewer"]))]
+    pub scopes: Vec<String>,
+    /// The key never expires if omitted
+    #[schema(value_type=Option<String>,example="2025-09-30T00:00:00")]
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// API key response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct ApiKeyDto {
+    #[schema(example = 3)]
+    pub id: i32,
+    #[schema(example = "CI deploy")]
+    pub name: String,
+    /// Public part of the key it can be recognized by
+    #[schema(example = "tmplt_Yb3nGq0ZyC8w")]
+    pub prefix: String,
+    #[schema(example = json!(["viewer"]))]
+    pub scopes: Vec<String>,
+    #[schema(value_type=Option<String>,example="2025-09-30T00:00:00")]
+    pub expires_at: Option<NaiveDateTime>,
+    /// Time of the last request with the key, recorded at most once per minute
+    #[schema(value_type=Option<String>,example="2024-10-01T08:03:51")]
+    pub last_used_at: Option<NaiveDateTime>,
+    #[schema(value_type=String,example="2024-09-30T10:41:05")]
+    pub created_at: NaiveDateTime,
+}
+
+/// Created API key response body
+#[derive(serde::Serialize, ToSchema)]
+pub struct NewApiKeyResponseDto {
+    /// The key, sent as a Bearer token; it is only returned once
+    #[schema(example = "tmplt_Yb3nGq0ZyC8w_T1xJ5rKdP7mLs2vAeH4uQ9wE3rT6yU8iO0pA1sD2fG")]
+    pub key: String,
+    pub api_key: ApiKeyDto,
+}
+
+/// API key rename request body
+#[derive(serde::Deserialize, ToSchema)]
+pub struct ApiKeyNameDto {
+    #[schema(example = "Nightly build")]
+    pub name: String,
+}
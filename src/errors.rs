@@ -1,6 +1,22 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Cursor;
+
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::serde_json;
+use rocket::{Request, Response};
+use rocket_db_pools::deadpool_redis::redis::RedisError;
+use utoipa::openapi::example::ExampleBuilder;
+use utoipa::openapi::response::ResponseBuilder;
+use utoipa::openapi::{ContentBuilder, Ref, RefOr};
 use utoipa::ToSchema;
 
-#[derive(serde::Serialize, Debug, serde::Deserialize, PartialEq)]
+use crate::rocket_routes::request_id::{RequestId, REQUEST_ID_HEADER};
+
+const PROBLEM_TYPE: &str = "about:blank";
+
+#[derive(serde::Serialize, Debug, serde::Deserialize, PartialEq, ToSchema)]
 pub struct ApiError {
     pub error_type: String,
     pub code: String,
@@ -14,13 +30,34 @@ pub enum AuthError {
     InvalidEmail,
     InvalidPassword,
     InvalidToken,
+    InvalidRedirectUrl,
     UnavailableUsername,
     EmailInUse,
     EmailNotExist,
     UnconfirmedUser,
+    AlreadyConfirmed,
+    Forbidden,
+    TooManyRequests,
 }
 
 impl AuthError {
+    pub fn status(&self) -> Status {
+        match self {
+            AuthError::WrongCredentials | AuthError::InvalidToken => Status::Unauthorized,
+            AuthError::InvalidUsername
+            | AuthError::InvalidEmail
+            | AuthError::InvalidPassword
+            | AuthError::InvalidRedirectUrl
+            | AuthError::UnavailableUsername
+            | AuthError::EmailInUse
+            | AuthError::UnconfirmedUser
+            | AuthError::AlreadyConfirmed => Status::BadRequest,
+            AuthError::EmailNotExist => Status::NotFound,
+            AuthError::Forbidden => Status::Forbidden,
+            AuthError::TooManyRequests => Status::TooManyRequests,
+        }
+    }
+
     pub fn value(&self) -> ApiError {
         const ERROR_TYPE: &str = "auth_error";
         match self {
@@ -59,6 +96,16 @@ impl AuthError {
                 code: "unconfirmed_user".to_string(),
                 message: "User has not confirmed the registration via e-mail link".to_string(),
             },
+            AuthError::InvalidRedirectUrl => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_redirect_url".to_string(),
+                message: "Redirect URL is not allowed".to_string(),
+            },
+            AuthError::AlreadyConfirmed => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "already_confirmed".to_string(),
+                message: "User has already confirmed the registration".to_string(),
+            },
             AuthError::EmailInUse => ApiError {
                 error_type: ERROR_TYPE.to_string(),
                 code: "email_in_use".to_string(),
@@ -69,6 +116,16 @@ impl AuthError {
                 code: "email_not_exist".to_string(),
                 message: "Email address is not associated with a personal user account".to_string(),
             },
+            AuthError::Forbidden => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "forbidden".to_string(),
+                message: "User does not have the role required for this action".to_string(),
+            },
+            AuthError::TooManyRequests => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "too_many_requests".to_string(),
+                message: "Too many requests, try again later".to_string(),
+            },
         }
     }
 }
@@ -79,9 +136,20 @@ pub enum ProfileError {
     InvalidLastName,
     InvalidCountry,
     InvalidBirthDate,
+    SessionNotFound,
 }
 
 impl ProfileError {
+    pub fn status(&self) -> Status {
+        match self {
+            ProfileError::InvalidFirstName
+            | ProfileError::InvalidLastName
+            | ProfileError::InvalidCountry
+            | ProfileError::InvalidBirthDate => Status::BadRequest,
+            ProfileError::SessionNotFound => Status::NotFound,
+        }
+    }
+
     pub fn value(&self) -> ApiError {
         const ERROR_TYPE: &str = "profile_error";
         match self {
@@ -105,6 +173,665 @@ impl ProfileError {
                 code: "invalid_birth_date".to_string(),
                 message: "Birth date must be in YYYY-MM-DD format".to_string(),
             },
+            ProfileError::SessionNotFound => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "session_not_found".to_string(),
+                message: "Session does not exist or has already expired".to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, PartialEq, ToSchema)]
+pub enum AdminError {
+    UserNotFound,
+    InvalidRole,
+    InvalidUserType,
+    SelfModification,
+    InvalidEventType,
+    InvalidDateTime,
+}
+
+impl AdminError {
+    pub fn status(&self) -> Status {
+        match self {
+            AdminError::UserNotFound => Status::NotFound,
+            AdminError::InvalidRole
+            | AdminError::InvalidUserType
+            | AdminError::SelfModification
+            | AdminError::InvalidEventType
+            | AdminError::InvalidDateTime => Status::BadRequest,
+        }
+    }
+
+    pub fn value(&self) -> ApiError {
+        const ERROR_TYPE: &str = "admin_error";
+        match self {
+            AdminError::UserNotFound => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "user_not_found".to_string(),
+                message: "User does not exist".to_string(),
+            },
+            AdminError::InvalidRole => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_role".to_string(),
+                message: "Role must be one of: admin, editor, viewer".to_string(),
+            },
+            AdminError::InvalidUserType => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_user_type".to_string(),
+                message: "User type must be one of: regular, enterprise".to_string(),
+            },
+            AdminError::SelfModification => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "self_modification".to_string(),
+                message: "Admins cannot delete themselves, change their own type or remove their own roles"
+                    .to_string(),
+            },
+            AdminError::InvalidEventType => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_event_type".to_string(),
+                message: "Unknown audit event type".to_string(),
+            },
+            AdminError::InvalidDateTime => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_date_time".to_string(),
+                message: "Date must be in YYYY-MM-DD or RFC 3339 format".to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, PartialEq, ToSchema)]
+pub enum CompanyError {
+    CompanyNotFound,
+    InvalidName,
+    NameInUse,
+    UserNotFound,
+    MemberNotFound,
+    AlreadyMember,
+    InvalidRole,
+    SelfModification,
+    InvitationNotFound,
+    InvitationEmailMismatch,
+}
+
+impl CompanyError {
+    pub fn status(&self) -> Status {
+        match self {
+            CompanyError::CompanyNotFound
+            | CompanyError::UserNotFound
+            | CompanyError::MemberNotFound
+            | CompanyError::InvitationNotFound => Status::NotFound,
+            CompanyError::InvalidName
+            | CompanyError::NameInUse
+            | CompanyError::AlreadyMember
+            | CompanyError::InvalidRole
+            | CompanyError::SelfModification => Status::BadRequest,
+            CompanyError::InvitationEmailMismatch => Status::Forbidden,
+        }
+    }
+
+    pub fn value(&self) -> ApiError {
+        const ERROR_TYPE: &str = "company_error";
+        match self {
+            CompanyError::CompanyNotFound => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "company_not_found".to_string(),
+                message: "Company does not exist".to_string(),
+            },
+            CompanyError::InvalidName => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_name".to_string(),
+                message: "Company name must be between 1 and 64 characters".to_string(),
+            },
+            CompanyError::NameInUse => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "name_in_use".to_string(),
+                message: "Company with this name already exists".to_string(),
+            },
+            CompanyError::UserNotFound => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "user_not_found".to_string(),
+                message: "Enterprise user with this email does not exist".to_string(),
+            },
+            CompanyError::MemberNotFound => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "member_not_found".to_string(),
+                message: "User is not a member of the company".to_string(),
+            },
+            CompanyError::AlreadyMember => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "already_member".to_string(),
+                message: "User is already a member of the company".to_string(),
+            },
+            CompanyError::InvalidRole => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_role".to_string(),
+                message: "Role must be one of: admin, editor, viewer".to_string(),
+            },
+            CompanyError::SelfModification => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "self_modification".to_string(),
+                message: "Members cannot change their own roles or remove themselves".to_string(),
+            },
+            CompanyError::InvitationNotFound => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invitation_not_found".to_string(),
+                message: "Invitation does not exist or has already expired".to_string(),
+            },
+            CompanyError::InvitationEmailMismatch => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invitation_email_mismatch".to_string(),
+                message: "Invitation was sent to another email address".to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, PartialEq, ToSchema)]
+pub enum TwoFactorError {
+    AlreadyEnabled,
+    NotEnrolled,
+    NotEnabled,
+    InvalidCode,
+}
+
+impl TwoFactorError {
+    pub fn status(&self) -> Status {
+        match self {
+            TwoFactorError::AlreadyEnabled
+            | TwoFactorError::NotEnrolled
+            | TwoFactorError::NotEnabled
+            | TwoFactorError::InvalidCode => Status::BadRequest,
+        }
+    }
+
+    pub fn value(&self) -> ApiError {
+        const ERROR_TYPE: &str = "two_factor_error";
+        match self {
+            TwoFactorError::AlreadyEnabled => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "already_enabled".to_string(),
+                message: "Two-factor authentication is already enabled".to_string(),
+            },
+            TwoFactorError::NotEnrolled => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "not_enrolled".to_string(),
+                message: "Two-factor authentication enrollment has not been started".to_string(),
+            },
+            TwoFactorError::NotEnabled => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "not_enabled".to_string(),
+                message: "Two-factor authentication is not enabled".to_string(),
+            },
+            TwoFactorError::InvalidCode => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_code".to_string(),
+                message: "Invalid authentication or recovery code".to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, PartialEq, ToSchema)]
+pub enum OidcError {
+    UnknownProvider,
+    ProviderUnavailable,
+    InvalidState,
+    InvalidCodeChallenge,
+    AuthorizationFailed,
+    MissingEmail,
+    IdentityInUse,
+}
+
+impl OidcError {
+    pub fn status(&self) -> Status {
+        match self {
+            OidcError::UnknownProvider => Status::NotFound,
+            OidcError::ProviderUnavailable => Status::BadGateway,
+            OidcError::InvalidState | OidcError::InvalidCodeChallenge | OidcError::MissingEmail => {
+                Status::BadRequest
+            }
+            OidcError::AuthorizationFailed => Status::Unauthorized,
+            OidcError::IdentityInUse => Status::Conflict,
+        }
+    }
+
+    pub fn value(&self) -> ApiError {
+        const ERROR_TYPE: &str = "oidc_error";
+        match self {
+            OidcError::UnknownProvider => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "unknown_provider".to_string(),
+                message: "Identity provider is not configured".to_string(),
+            },
+            OidcError::ProviderUnavailable => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "provider_unavailable".to_string(),
+                message: "Identity provider is not available, try again later".to_string(),
+            },
+            OidcError::InvalidState => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_state".to_string(),
+                message: "Authorization state is unknown or expired".to_string(),
+            },
+            OidcError::InvalidCodeChallenge => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_code_challenge".to_string(),
+                message: "Code challenge must be the base64url encoded SHA-256 of a code verifier"
+                    .to_string(),
+            },
+            OidcError::AuthorizationFailed => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "authorization_failed".to_string(),
+                message: "Identity provider did not authorize the user".to_string(),
+            },
+            OidcError::MissingEmail => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "missing_email".to_string(),
+                message: "Identity provider did not share the email address".to_string(),
+            },
+            OidcError::IdentityInUse => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "identity_in_use".to_string(),
+                message: "Identity is already linked to another user".to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, PartialEq, ToSchema)]
+pub enum PasskeyError {
+    InvalidName,
+    NotFound,
+    InvalidChallenge,
+    RegistrationFailed,
+    AuthenticationFailed,
+    AlreadyRegistered,
+}
+
+impl PasskeyError {
+    pub fn status(&self) -> Status {
+        match self {
+            PasskeyError::InvalidName
+            | PasskeyError::InvalidChallenge
+            | PasskeyError::RegistrationFailed => Status::BadRequest,
+            PasskeyError::NotFound => Status::NotFound,
+            PasskeyError::AuthenticationFailed => Status::Unauthorized,
+            PasskeyError::AlreadyRegistered => Status::Conflict,
+        }
+    }
+
+    pub fn value(&self) -> ApiError {
+        const ERROR_TYPE: &str = "passkey_error";
+        match self {
+            PasskeyError::InvalidName => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_passkey_name".to_string(),
+                message: "Passkey name must be 1 to 64 characters long".to_string(),
+            },
+            PasskeyError::NotFound => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "passkey_not_found".to_string(),
+                message: "Passkey does not exist".to_string(),
+            },
+            PasskeyError::InvalidChallenge => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_challenge".to_string(),
+                message: "Passkey challenge is unknown or expired".to_string(),
+            },
+            PasskeyError::RegistrationFailed => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "passkey_registration_failed".to_string(),
+                message: "Passkey credential cannot be verified".to_string(),
+            },
+            PasskeyError::AuthenticationFailed => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "passkey_authentication_failed".to_string(),
+                message: "Passkey assertion cannot be verified".to_string(),
+            },
+            PasskeyError::AlreadyRegistered => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "passkey_already_registered".to_string(),
+                message: "Passkey is already registered".to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, PartialEq, ToSchema)]
+pub enum ApiKeyError {
+    InvalidName,
+    InvalidScopes,
+    InvalidExpiration,
+    NotFound,
+}
+
+impl ApiKeyError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiKeyError::InvalidName
+            | ApiKeyError::InvalidScopes
+            | ApiKeyError::InvalidExpiration => Status::BadRequest,
+            ApiKeyError::NotFound => Status::NotFound,
+        }
+    }
+
+    pub fn value(&self) -> ApiError {
+        const ERROR_TYPE: &str = "api_key_error";
+        match self {
+            ApiKeyError::InvalidName => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_api_key_name".to_string(),
+                message: "API key name must be 1 to 64 characters long".to_string(),
+            },
+            ApiKeyError::InvalidScopes => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_api_key_scopes".to_string(),
+                message: "API key scopes must be one or more of admin, editor, viewer".to_string(),
+            },
+            ApiKeyError::InvalidExpiration => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "invalid_api_key_expiration".to_string(),
+                message: "API key expiration must be in the future".to_string(),
+            },
+            ApiKeyError::NotFound => ApiError {
+                error_type: ERROR_TYPE.to_string(),
+                code: "api_key_not_found".to_string(),
+                message: "API key does not exist".to_string(),
+            },
+        }
+    }
+}
+
+/// Error of a request handler or guard, answered with an RFC 7807 problem document
+#[derive(Debug)]
+pub enum AppError {
+    Auth(AuthError),
+    Profile(ProfileError),
+    Admin(AdminError),
+    Company(CompanyError),
+    TwoFactor(TwoFactorError),
+    Oidc(OidcError),
+    Passkey(PasskeyError),
+    ApiKey(ApiKeyError),
+    /// Malformed request, e.g. a body that cannot be parsed
+    Validation(String),
+    /// Error status without a more specific cause, e.g. of an unmatched route
+    Http(Status),
+    Database(diesel::result::Error),
+    Cache(RedisError),
+    Internal(Box<dyn Error + Send + Sync>),
+    /// Error answered with another status than its usual one
+    WithStatus(Status, Box<AppError>),
+}
+
+impl AppError {
+    /// Answer the error with the given status instead of the usual one
+    pub fn with_status(self, status: Status) -> AppError {
+        match self {
+            AppError::WithStatus(_, error) => AppError::WithStatus(status, error),
+            error => AppError::WithStatus(status, Box::new(error)),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            AppError::Auth(e) => e.status(),
+            AppError::Profile(e) => e.status(),
+            AppError::Admin(e) => e.status(),
+            AppError::Company(e) => e.status(),
+            AppError::TwoFactor(e) => e.status(),
+            AppError::Oidc(e) => e.status(),
+            AppError::Passkey(e) => e.status(),
+            AppError::ApiKey(e) => e.status(),
+            AppError::Validation(_) => Status::UnprocessableEntity,
+            AppError::Http(status) | AppError::WithStatus(status, _) => *status,
+            AppError::Database(diesel::result::Error::NotFound) => Status::NotFound,
+            AppError::Database(diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            )) => Status::Conflict,
+            AppError::Database(_) | AppError::Cache(_) | AppError::Internal(_) => {
+                Status::InternalServerError
+            }
+        }
+    }
+
+    pub fn value(&self) -> ApiError {
+        let status = self.status();
+        match self {
+            AppError::Auth(e) => e.value(),
+            AppError::Profile(e) => e.value(),
+            AppError::Admin(e) => e.value(),
+            AppError::Company(e) => e.value(),
+            AppError::TwoFactor(e) => e.value(),
+            AppError::Oidc(e) => e.value(),
+            AppError::Passkey(e) => e.value(),
+            AppError::ApiKey(e) => e.value(),
+            AppError::WithStatus(_, e) => e.value(),
+            AppError::Validation(message) => ApiError {
+                error_type: "validation_error".to_string(),
+                code: "invalid_request".to_string(),
+                message: message.clone(),
+            },
+            _ => ApiError {
+                error_type: if status.class().is_server_error() {
+                    "server_error".to_string()
+                } else {
+                    "http_error".to_string()
+                },
+                code: status
+                    .reason_lossy()
+                    .to_lowercase()
+                    .replace([' ', '-'], "_"),
+                message: status.reason_lossy().to_string(),
+            },
+        }
+    }
+
+    /// The problem document of the error, with the id of the request
+    pub fn problem(&self, instance: Option<String>, request_id: String) -> Problem {
+        let status = self.status();
+        let error = self.value();
+        Problem {
+            problem_type: PROBLEM_TYPE.to_string(),
+            title: status.reason_lossy().to_string(),
+            status: status.code,
+            detail: error.message.clone(),
+            instance,
+            request_id,
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Database(e) => write!(f, "Database error: {}", e),
+            AppError::Cache(e) => write!(f, "Cache error: {}", e),
+            AppError::Internal(e) => write!(f, "{}", e),
+            AppError::WithStatus(_, e) => write!(f, "{}", e),
+            _ => write!(f, "{}", self.value().message),
+        }
+    }
+}
+
+impl From<AuthError> for AppError {
+    fn from(e: AuthError) -> Self {
+        AppError::Auth(e)
+    }
+}
+
+impl From<ProfileError> for AppError {
+    fn from(e: ProfileError) -> Self {
+        AppError::Profile(e)
+    }
+}
+
+impl From<AdminError> for AppError {
+    fn from(e: AdminError) -> Self {
+        AppError::Admin(e)
+    }
+}
+
+impl From<CompanyError> for AppError {
+    fn from(e: CompanyError) -> Self {
+        AppError::Company(e)
+    }
+}
+
+impl From<TwoFactorError> for AppError {
+    fn from(e: TwoFactorError) -> Self {
+        AppError::TwoFactor(e)
+    }
+}
+
+impl From<OidcError> for AppError {
+    fn from(e: OidcError) -> Self {
+        AppError::Oidc(e)
+    }
+}
+
+impl From<PasskeyError> for AppError {
+    fn from(e: PasskeyError) -> Self {
+        AppError::Passkey(e)
+    }
+}
+
+impl From<ApiKeyError> for AppError {
+    fn from(e: ApiKeyError) -> Self {
+        AppError::ApiKey(e)
+    }
+}
+
+impl From<diesel::result::Error> for AppError {
+    fn from(e: diesel::result::Error) -> Self {
+        AppError::Database(e)
+    }
+}
+
+impl From<RedisError> for AppError {
+    fn from(e: RedisError) -> Self {
+        AppError::Cache(e)
+    }
+}
+
+/// RFC 7807 problem details, extended with the `ApiError` fields and the request id
+/// that is also logged with server errors
+#[derive(serde::Serialize, serde::Deserialize, Debug, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    #[schema(example = "about:blank")]
+    pub problem_type: String,
+    #[schema(example = "Unauthorized")]
+    pub title: String,
+    #[schema(example = 401)]
+    pub status: u16,
+    #[schema(example = "Wrong credentials")]
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "/login")]
+    pub instance: Option<String>,
+    #[schema(example = "d7Mf0RvXw2c9ZqLa")]
+    pub request_id: String,
+    #[serde(flatten)]
+    pub error: ApiError,
+}
+
+/// Code of the error the request has been answered with, kept for the metrics
+pub struct ErrorCode(pub Option<String>);
+
+impl<'r> Responder<'r, 'static> for AppError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let request_id = RequestId::of(request);
+        let status = self.status();
+
+        if status.class().is_server_error() {
+            log::error!(
+                request_id = request_id.as_str();
+                "Internal Server Error [{}]: {}",
+                request_id,
+                self
+            );
         }
+
+        let problem = self.problem(
+            Some(request.uri().path().to_string()),
+            request_id.to_string(),
+        );
+        request.local_cache(|| ErrorCode(Some(problem.error.code.clone())));
+        let body = serde_json::to_string(&problem).map_err(|e| {
+            log::error!("Cannot serialize the problem document: {}", e);
+            Status::InternalServerError
+        })?;
+
+        Response::build()
+            .status(status)
+            .header(ContentType::new("application", "problem+json"))
+            .raw_header(REQUEST_ID_HEADER, request_id.to_string())
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
     }
 }
+
+/// Error responses of the listed errors grouped by status, each one with the problem document
+/// of the error as an example
+pub fn problem_responses(
+    errors: Vec<AppError>,
+) -> BTreeMap<String, RefOr<utoipa::openapi::response::Response>> {
+    let mut groups: BTreeMap<u16, Vec<AppError>> = BTreeMap::new();
+    for error in errors {
+        groups.entry(error.status().code).or_default().push(error);
+    }
+
+    groups
+        .into_iter()
+        .map(|(code, errors)| {
+            let examples = errors.iter().map(|error| {
+                let problem = error.problem(None, "d7Mf0RvXw2c9ZqLa".to_string());
+                (
+                    problem.error.code.clone(),
+                    ExampleBuilder::new()
+                        .summary(problem.detail.clone())
+                        .value(serde_json::to_value(&problem).ok())
+                        .build(),
+                )
+            });
+            let content = ContentBuilder::new()
+                .schema(Ref::from_schema_name("Problem"))
+                .examples_from_iter(examples)
+                .build();
+            let response = ResponseBuilder::new()
+                .description(Status::from_code(code).map_or("", |s| s.reason_lossy()))
+                .content("application/problem+json", content)
+                .build();
+
+            (code.to_string(), RefOr::T(response))
+        })
+        .collect()
+}
+
+/// Declare a type documenting the error responses of an endpoint, used in the `responses`
+/// of `#[utoipa::path]`; an error followed by `=> status` is answered with that status
+#[macro_export]
+macro_rules! error_responses {
+    ($name:ident { $($error:expr $(=> $status:expr)?),* $(,)? }) => {
+        pub struct $name;
+
+        impl utoipa::IntoResponses for $name {
+            fn responses() -> std::collections::BTreeMap<
+                String,
+                utoipa::openapi::RefOr<utoipa::openapi::response::Response>,
+            > {
+                $crate::errors::problem_responses(vec![$({
+                    let error = $crate::errors::AppError::from($error);
+                    $(let error = error.with_status($status);)?
+                    error
+                }),*])
+            }
+        }
+    };
+}
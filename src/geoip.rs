@@ -0,0 +1,336 @@
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use reqwest::ClientBuilder;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Build, Rocket};
+use rocket_db_pools::{deadpool_redis, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::repositories::LocationRepository;
+use crate::rocket_routes::CacheConnection;
+
+const IP_GEOLOCATION_API_URI: &str = "https://freeipapi.com/api/json";
+const IP_GEOLOCATION_DURATION: u64 = 5;
+const LOOKUPS_CACHE_TTL: usize = 86400;
+const LOCATION_NAMES_LANGUAGE: &str = "en";
+
+pub type GeoIpError = Box<dyn Error + Send + Sync>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    pub city: Option<String>,
+    pub country_code: Option<String>,
+}
+
+/// Where and when a request was received from, shown in security related emails
+#[derive(Serialize, Clone, Debug)]
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    pub city: Option<String>,
+    pub country_code: Option<String>,
+    pub requested_at: DateTime<Utc>,
+}
+
+/// Source of the location of IP addresses
+#[rocket::async_trait]
+pub trait GeoIpProvider: Send + Sync {
+    /// Locate the address, `None` if the provider does not know it
+    async fn locate(&self, ip: IpAddr) -> Result<Option<Location>, GeoIpError>;
+
+    /// Check that the provider is reachable
+    async fn check(&self) -> Result<(), GeoIpError> {
+        Ok(())
+    }
+}
+
+/// Looks addresses up in a local MaxMind City database file (GeoLite2-City.mmdb or compatible)
+pub struct MaxMindProvider {
+    reader: Reader<Vec<u8>>,
+}
+
+impl MaxMindProvider {
+    pub fn open(database: &PathBuf) -> Result<MaxMindProvider, GeoIpError> {
+        Ok(MaxMindProvider {
+            reader: Reader::open_readfile(database)?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl GeoIpProvider for MaxMindProvider {
+    async fn locate(&self, ip: IpAddr) -> Result<Option<Location>, GeoIpError> {
+        let city = match self.reader.lookup::<geoip2::City>(ip) {
+            Ok(city) => city,
+            Err(MaxMindDBError::AddressNotFoundError(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(Location {
+            city: city
+                .city
+                .and_then(|city| city.names)
+                .and_then(|names| names.get(LOCATION_NAMES_LANGUAGE).map(|v| v.to_string())),
+            country_code: city
+                .country
+                .and_then(|country| country.iso_code)
+                .map(|v| v.to_string()),
+        }))
+    }
+}
+
+/// Looks addresses up through the freeipapi.com compatible HTTP API
+pub struct HttpProvider {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpProvider {
+    pub fn new(url: String, timeout: u64) -> Result<HttpProvider, GeoIpError> {
+        let client = ClientBuilder::new()
+            .timeout(Duration::from_secs(timeout))
+            .build()?;
+
+        Ok(HttpProvider { client, url })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HttpLocation {
+    city_name: Option<String>,
+    country_code: Option<String>,
+}
+
+#[rocket::async_trait]
+impl GeoIpProvider for HttpProvider {
+    async fn locate(&self, ip: IpAddr) -> Result<Option<Location>, GeoIpError> {
+        let location: HttpLocation = self
+            .client
+            .get(format!("{}/{}", self.url, ip))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // Unknown fields are returned as "-"
+        let known = |value: Option<String>| value.filter(|v| !v.is_empty() && v != "-");
+        let location = Location {
+            city: known(location.city_name),
+            country_code: known(location.country_code),
+        };
+
+        Ok(Some(location).filter(|location| *location != Location::default()))
+    }
+
+    async fn check(&self) -> Result<(), GeoIpError> {
+        self.client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Does not locate any address
+pub struct NullProvider;
+
+#[rocket::async_trait]
+impl GeoIpProvider for NullProvider {
+    async fn locate(&self, _ip: IpAddr) -> Result<Option<Location>, GeoIpError> {
+        Ok(None)
+    }
+}
+
+/// Keeps the successful lookups of the wrapped provider in Redis for `ttl` seconds, so that the
+/// instances share them and an address is not looked up again on every restart
+pub struct CachedProvider {
+    provider: Box<dyn GeoIpProvider>,
+    cache: deadpool_redis::Pool,
+    ttl: usize,
+}
+
+impl CachedProvider {
+    pub fn new(
+        provider: Box<dyn GeoIpProvider>,
+        cache: deadpool_redis::Pool,
+        ttl: usize,
+    ) -> CachedProvider {
+        CachedProvider {
+            provider,
+            cache,
+            ttl,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl GeoIpProvider for CachedProvider {
+    async fn locate(&self, ip: IpAddr) -> Result<Option<Location>, GeoIpError> {
+        let mut cache = self.cache.get().await?;
+        if let Some(location) = LocationRepository::find(ip, &mut cache).await? {
+            return Ok(location);
+        }
+
+        let location = self.provider.locate(ip).await?;
+        LocationRepository::create(ip, &location, self.ttl, &mut cache).await?;
+
+        Ok(location)
+    }
+
+    async fn check(&self) -> Result<(), GeoIpError> {
+        self.provider.check().await
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GeoIpProviderKind {
+    #[default]
+    Http,
+    Maxmind,
+    None,
+}
+
+/// IP geolocation, configured in the `geoip` table of `Rocket.toml`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    pub provider: GeoIpProviderKind,
+    /// Base URL of the HTTP API
+    pub url: String,
+    /// Seconds to wait for the HTTP API
+    pub timeout: u64,
+    /// Path of the MaxMind database file
+    pub database: Option<PathBuf>,
+    /// Seconds the lookups are cached in Redis, zero disables the cache
+    pub cache_ttl: usize,
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        GeoIpConfig {
+            provider: GeoIpProviderKind::default(),
+            url: IP_GEOLOCATION_API_URI.to_string(),
+            timeout: IP_GEOLOCATION_DURATION,
+            database: None,
+            cache_ttl: LOOKUPS_CACHE_TTL,
+        }
+    }
+}
+
+impl GeoIpConfig {
+    pub fn build(&self, cache: deadpool_redis::Pool) -> Result<Box<dyn GeoIpProvider>, GeoIpError> {
+        let provider: Box<dyn GeoIpProvider> = match self.provider {
+            GeoIpProviderKind::Http => Box::new(HttpProvider::new(self.url.clone(), self.timeout)?),
+            GeoIpProviderKind::Maxmind => {
+                let database = self
+                    .database
+                    .as_ref()
+                    .ok_or("The MaxMind provider requires a database file")?;
+                Box::new(MaxMindProvider::open(database)?)
+            }
+            GeoIpProviderKind::None => return Ok(Box::new(NullProvider)),
+        };
+
+        Ok(match self.cache_ttl {
+            0 => provider,
+            ttl => Box::new(CachedProvider::new(provider, cache, ttl)),
+        })
+    }
+}
+
+/// Builds client info with the configured provider, lookup failures only cost the location
+pub struct GeoLocator {
+    provider: Box<dyn GeoIpProvider>,
+}
+
+impl GeoLocator {
+    pub fn new(provider: Box<dyn GeoIpProvider>) -> GeoLocator {
+        GeoLocator { provider }
+    }
+
+    pub async fn check(&self) -> Result<(), GeoIpError> {
+        self.provider.check().await
+    }
+
+    pub async fn client_info(&self, ip: IpAddr) -> ClientInfo {
+        let location = if is_global(ip) {
+            self.provider.locate(ip).await.unwrap_or_else(|e| {
+                log::warn!("Cannot locate {}: {}", ip, e);
+                None
+            })
+        } else {
+            None
+        }
+        .unwrap_or_default();
+
+        ClientInfo {
+            ip,
+            city: location.city,
+            country_code: location.country_code,
+            requested_at: Utc::now(),
+        }
+    }
+}
+
+/// Whether the address may be located, that is not a loopback, private or link-local one
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => {
+            let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
+
+/// Builds the configured geolocation provider and manages the `GeoLocator` on top of it
+pub struct GeoIpFairing;
+
+#[rocket::async_trait]
+impl Fairing for GeoIpFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "IP geolocation",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let Some(config) = rocket.state::<AppConfig>() else {
+            log::error!("IP geolocation requires the app config to be managed first");
+            return Err(rocket);
+        };
+
+        let cache = match CacheConnection::fetch(&rocket) {
+            Some(cache) => deadpool_redis::Pool::clone(cache),
+            None => {
+                log::error!("IP geolocation requires the redis database to be attached first");
+                return Err(rocket);
+            }
+        };
+
+        match config.geoip.build(cache) {
+            Ok(provider) => Ok(rocket.manage(GeoLocator::new(provider))),
+            Err(e) => {
+                log::error!("Cannot set up the IP geolocation provider: {}", e);
+                Err(rocket)
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use chrono::{TimeDelta, Utc};
+use diesel::{PgConnection, QueryResult};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::serde::json::serde_json::json;
+use rocket::tokio::time::sleep;
+use rocket::{Orbit, Rocket, Shutdown};
+use rocket_sync_db_pools::ConnectionPool;
+
+use crate::config::AppConfig;
+use crate::models::{AuditEventType, NewAuditEvent, User};
+use crate::repositories::{AuditRepository, UserRepository};
+use crate::rocket_routes::DbConnection;
+
+/// Purge of the users who have not confirmed the registration in time, configured in the `purge` table of `Rocket.toml`
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct PurgeConfig {
+    /// Seconds between the purges run by the server, `0` disables them
+    pub interval: u64,
+}
+
+impl Default for PurgeConfig {
+    fn default() -> Self {
+        PurgeConfig { interval: 60 * 60 }
+    }
+}
+
+/// Delete the users who have not confirmed the registration within the lifetime of the last
+/// confirmation token sent to them, every deleted user is recorded in the audit log with the
+/// given source
+pub(crate) fn purge_unconfirmed_users(
+    connection: &mut PgConnection,
+    confirm_token_lifetime: usize,
+    source: &str,
+) -> QueryResult<Vec<User>> {
+    let sent_before =
+        Utc::now().naive_utc() - TimeDelta::try_seconds(confirm_token_lifetime as i64).unwrap();
+    let users = UserRepository::delete_unconfirmed(connection, sent_before)?;
+
+    for user in &users {
+        let event = NewAuditEvent {
+            actor_id: None,
+            target_id: Some(user.id),
+            event_type: AuditEventType::UserDeleted,
+            ip: None,
+            user_agent: None,
+            payload: json!({
+                "username": user.username,
+                "email": user.email,
+                "reason": "unconfirmed",
+                "source": source,
+            }),
+        };
+        if let Err(e) = AuditRepository::create(connection, event) {
+            log::error!(
+                "Cannot record the audit event {}: {}",
+                AuditEventType::UserDeleted,
+                e
+            );
+        }
+    }
+
+    Ok(users)
+}
+
+/// Purges the unconfirmed users every `purge.interval` seconds once the server is launched
+pub struct PurgeFairing;
+
+#[rocket::async_trait]
+impl Fairing for PurgeFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Unconfirmed users purge",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let Some(config) = rocket.state::<AppConfig>() else {
+            return;
+        };
+        if config.purge.interval == 0 {
+            return;
+        }
+
+        let Some(pool) = DbConnection::pool(rocket) else {
+            log::error!(
+                "The purge of unconfirmed users requires the postgres database to be attached"
+            );
+            return;
+        };
+
+        rocket::tokio::spawn(run_purges(
+            pool.clone(),
+            config.purge.interval,
+            config.tokens.confirm_token,
+            rocket.shutdown(),
+        ));
+    }
+}
+
+async fn run_purges(
+    pool: ConnectionPool<DbConnection, PgConnection>,
+    interval: u64,
+    confirm_token_lifetime: usize,
+    shutdown: Shutdown,
+) {
+    loop {
+        match pool.get().await {
+            Some(connection) => match connection
+                .run(move |c| purge_unconfirmed_users(c, confirm_token_lifetime, "purge"))
+                .await
+            {
+                Ok(users) if !users.is_empty() => {
+                    log::info!("Purged {} unconfirmed users", users.len())
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Cannot purge the unconfirmed users: {}", e),
+            },
+            None => log::error!("Cannot connect to the database to purge the unconfirmed users"),
+        }
+
+        rocket::tokio::select! {
+            _ = sleep(Duration::from_secs(interval)) => {}
+            _ = shutdown.clone() => break,
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+    OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Build, Rocket};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::models::{Role, User};
+
+const MIN_SECRET_LENGTH: usize = 32;
+
+/// Format of the access tokens issued by the logins
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenMode {
+    /// Random session ids, looked up in Redis on every request
+    #[default]
+    Session,
+    /// Signed JWTs, verified by their signature and revoked through a denylist in Redis
+    Jwt,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum JwtAlgorithm {
+    HS256,
+    EdDSA,
+}
+
+/// Key of the JWTs, identified by the `kid` of their header
+#[derive(Deserialize, Clone, Debug)]
+pub struct JwtKeyConfig {
+    pub kid: String,
+    pub algorithm: JwtAlgorithm,
+    /// Shared secret of an HS256 key, at least 32 bytes long
+    pub secret: Option<String>,
+    /// PKCS#8 PEM private key of an EdDSA key, only needed to sign
+    pub private_key: Option<String>,
+    /// Ed25519 public key of an EdDSA key, base64url encoded as the `x` of its JWK
+    pub public_key: Option<String>,
+}
+
+/// Access tokens, configured in the `jwt` table of `Rocket.toml`
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct JwtConfig {
+    pub mode: TokenMode,
+    /// The first key signs new tokens, the others only verify the tokens signed before a rotation
+    pub keys: Vec<JwtKeyConfig>,
+}
+
+/// Claims of a JWT access token
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccessClaims {
+    /// Id of the user
+    pub sub: String,
+    /// Id of the token family the token is issued for, the token is revoked with the family
+    pub sid: String,
+    pub user_type: String,
+    pub roles: Vec<String>,
+    pub iss: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+struct JwtKeys {
+    issuer: String,
+    signing_kid: String,
+    signing_algorithm: Algorithm,
+    signing_key: EncodingKey,
+    verifying_keys: HashMap<String, (Algorithm, DecodingKey)>,
+    jwks: JwkSet,
+}
+
+/// Issues and verifies the access tokens of the configured mode
+pub struct AccessTokens {
+    jwt: Option<JwtKeys>,
+}
+
+impl AccessTokens {
+    pub fn is_jwt(&self) -> bool {
+        self.jwt.is_some()
+    }
+
+    /// JWT of the user for the token family, valid for `lifetime` seconds
+    pub fn issue(
+        &self,
+        user: &User,
+        roles: &[Role],
+        family_id: &str,
+        lifetime: usize,
+    ) -> Result<String, String> {
+        let keys = self.jwt.as_ref().ok_or("JWTs are not enabled")?;
+        let now = Utc::now().timestamp();
+        let claims = AccessClaims {
+            sub: user.id.to_string(),
+            sid: family_id.to_string(),
+            user_type: user.user_type.to_string(),
+            roles: roles.iter().map(|role| role.code.to_string()).collect(),
+            iss: keys.issuer.clone(),
+            iat: now,
+            exp: now + lifetime as i64,
+        };
+        keys.sign(&claims)
+    }
+
+    /// Claims of a JWT signed by one of the keys that is not expired
+    pub fn verify(&self, token: &str) -> Option<AccessClaims> {
+        self.jwt.as_ref()?.verify(token)
+    }
+
+    /// Public keys the JWTs can be verified with, HS256 secrets are never published
+    pub fn jwks(&self) -> JwkSet {
+        match &self.jwt {
+            Some(keys) => keys.jwks.clone(),
+            None => JwkSet { keys: Vec::new() },
+        }
+    }
+}
+
+impl JwtKeys {
+    fn sign(&self, claims: &AccessClaims) -> Result<String, String> {
+        let mut header = Header::new(self.signing_algorithm);
+        header.kid = Some(self.signing_kid.clone());
+        jsonwebtoken::encode(&header, claims, &self.signing_key).map_err(|e| e.to_string())
+    }
+
+    fn verify(&self, token: &str) -> Option<AccessClaims> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let (algorithm, key) = self.verifying_keys.get(header.kid.as_deref()?)?;
+
+        let mut validation = Validation::new(*algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+        jsonwebtoken::decode::<AccessClaims>(token, key, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+impl JwtConfig {
+    pub fn validate(&self, issuer: &str) -> Result<(), String> {
+        self.build(issuer).map(|_| ())
+    }
+
+    /// Keys of the configured mode, the tokens are issued by `issuer`
+    pub fn build(&self, issuer: &str) -> Result<AccessTokens, String> {
+        if self.mode == TokenMode::Session {
+            return Ok(AccessTokens { jwt: None });
+        }
+        let Some(signing) = self.keys.first() else {
+            return Err("jwt.keys must not be empty in the jwt mode".to_string());
+        };
+
+        let mut verifying_keys = HashMap::new();
+        let mut jwks = Vec::new();
+        for key in &self.keys {
+            if verifying_keys.contains_key(&key.kid) {
+                return Err(format!("jwt key id {:?} is not unique", key.kid));
+            }
+            let verifying_key = match key.algorithm {
+                JwtAlgorithm::HS256 => (Algorithm::HS256, DecodingKey::from_secret(secret(key)?)),
+                JwtAlgorithm::EdDSA => {
+                    let x = key
+                        .public_key
+                        .as_deref()
+                        .ok_or_else(|| format!("jwt key {:?} must have a public_key", key.kid))?;
+                    let decoding_key = DecodingKey::from_ed_components(x).map_err(|e| {
+                        format!("jwt key {:?} has an invalid public_key: {}", key.kid, e)
+                    })?;
+                    jwks.push(public_jwk(&key.kid, x));
+                    (Algorithm::EdDSA, decoding_key)
+                }
+            };
+            verifying_keys.insert(key.kid.clone(), verifying_key);
+        }
+
+        let (signing_algorithm, signing_key) = match signing.algorithm {
+            JwtAlgorithm::HS256 => (Algorithm::HS256, EncodingKey::from_secret(secret(signing)?)),
+            JwtAlgorithm::EdDSA => {
+                let pem = signing.private_key.as_deref().ok_or_else(|| {
+                    format!("jwt signing key {:?} must have a private_key", signing.kid)
+                })?;
+                let encoding_key = EncodingKey::from_ed_pem(pem.as_bytes()).map_err(|e| {
+                    format!(
+                        "jwt key {:?} has an invalid private_key: {}",
+                        signing.kid, e
+                    )
+                })?;
+                (Algorithm::EdDSA, encoding_key)
+            }
+        };
+
+        let keys = JwtKeys {
+            issuer: issuer.to_string(),
+            signing_kid: signing.kid.clone(),
+            signing_algorithm,
+            signing_key,
+            verifying_keys,
+            jwks: JwkSet { keys: jwks },
+        };
+
+        // A private key not matching its public key would only fail on the first request
+        let now = Utc::now().timestamp();
+        let probe = keys.sign(&AccessClaims {
+            sub: String::new(),
+            sid: String::new(),
+            user_type: String::new(),
+            roles: Vec::new(),
+            iss: issuer.to_string(),
+            iat: now,
+            exp: now + 60,
+        })?;
+        if keys.verify(&probe).is_none() {
+            return Err(format!(
+                "jwt signing key {:?} cannot be verified with its public key",
+                signing.kid
+            ));
+        }
+
+        Ok(AccessTokens { jwt: Some(keys) })
+    }
+}
+
+fn secret(key: &JwtKeyConfig) -> Result<&[u8], String> {
+    key.secret
+        .as_deref()
+        .filter(|secret| secret.len() >= MIN_SECRET_LENGTH)
+        .map(str::as_bytes)
+        .ok_or_else(|| {
+            format!(
+                "jwt key {:?} must have a secret of at least {} bytes",
+                key.kid, MIN_SECRET_LENGTH
+            )
+        })
+}
+
+fn public_jwk(kid: &str, x: &str) -> Jwk {
+    Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(KeyAlgorithm::EdDSA),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: x.to_string(),
+        }),
+    }
+}
+
+/// Manages the `AccessTokens` of the configured mode
+pub struct JwtFairing;
+
+#[rocket::async_trait]
+impl Fairing for JwtFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "JWT",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let Some(config) = rocket.state::<AppConfig>() else {
+            log::error!("JWT requires the app config to be managed first");
+            return Err(rocket);
+        };
+
+        match config.jwt.build(&config.base_url) {
+            Ok(access_tokens) => Ok(rocket.manage(access_tokens)),
+            Err(e) => {
+                log::error!("Cannot set up the access tokens: {}", e);
+                Err(rocket)
+            }
+        }
+    }
+}
@@ -1,10 +1,18 @@
 mod auth;
-mod mail;
+pub mod mail;
 mod models;
 mod repositories;
 mod schema;
 
 pub mod commands;
+pub mod config;
 pub mod dto;
 pub mod errors;
+pub mod geoip;
+pub mod jobs;
+pub mod jwt;
+pub mod logging;
+pub mod metrics;
+pub mod oidc;
 pub mod rocket_routes;
+pub mod webauthn;
@@ -0,0 +1,123 @@
+use std::io::Write;
+
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, VisitSource, VisitValue};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Format of the log records, configured with `log_format` in `Rocket.toml`
+/// or the `ROCKET_LOG_FORMAT` env var
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Rocket's own human-readable logger
+    #[default]
+    Text,
+    /// A JSON object per line, with the key-values of the record as fields
+    Json,
+}
+
+/// Install the JSON logger if it is configured, Rocket installs its own logger otherwise
+pub fn init(format: LogFormat, level: LevelFilter) {
+    if format == LogFormat::Json && log::set_boxed_logger(Box::new(JsonLogger)).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Writes every log record to the standard output as a single-line JSON object
+struct JsonLogger;
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Same as Rocket, keep the noise of the connection libraries for debugging
+        let from = |path| record.module_path().is_some_and(|m| m.starts_with(path));
+        if log::max_level() < LevelFilter::Trace
+            && (from("hyper") || from("rustls") || from("r2d2"))
+        {
+            return;
+        }
+
+        let mut fields = Map::new();
+        fields.insert(
+            "timestamp".to_string(),
+            Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        );
+        fields.insert("level".to_string(), Value::from(record.level().as_str()));
+        // Rocket abuses the `_` suffix of targets for indentation
+        fields.insert(
+            "target".to_string(),
+            Value::from(record.target().trim_end_matches(['_', ':'])),
+        );
+        fields.insert(
+            "message".to_string(),
+            Value::from(record.args().to_string()),
+        );
+        let _ = record.key_values().visit(&mut Fields(&mut fields));
+
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", Value::Object(fields));
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let mut field = Field(Value::Null);
+        value.visit(&mut field)?;
+        self.0.insert(key.to_string(), field.0);
+        Ok(())
+    }
+}
+
+struct Field(Value);
+
+impl<'v> VisitValue<'v> for Field {
+    fn visit_any(&mut self, value: kv::Value) -> Result<(), kv::Error> {
+        self.0 = Value::from(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = Value::from(value);
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = Value::from(value);
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = Value::from(value);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = Value::from(value);
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.0 = Value::from(value);
+        Ok(())
+    }
+}
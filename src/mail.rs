@@ -1,120 +1,716 @@
-use std::net::IpAddr;
+use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use chrono::{Datelike, Utc};
 use lettre::message::header::ContentType;
-use lettre::transport::smtp::{authentication::Credentials, response::Response};
-use lettre::{SmtpTransport, Transport};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use rand::distributions::{Alphanumeric, DistString};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::task::spawn_blocking;
+use rocket::tokio::time::sleep;
+use rocket::{Build, Orbit, Rocket, Shutdown};
+use rocket_db_pools::{deadpool_redis, Database};
 use tera::{Context, Tera};
 
-use crate::models::User;
-use crate::rocket_routes::get_client_info;
+use crate::auth::generate_token;
+use crate::config::{AppConfig, SmtpConfig};
+use crate::geoip::ClientInfo;
+use crate::metrics::Metrics;
+use crate::models::{QueuedEmail, User};
+use crate::repositories::EmailQueueRepository;
+use crate::rocket_routes::request_id::RequestId;
+use crate::rocket_routes::CacheConnection;
 
+const EMAIL_ID_LENGTH: usize = 16;
+const WORKER_ID_LENGTH: usize = 16;
+const MAIL_SENDER: &str = "Template App <softteco.os.dev@gmail.com>";
+
+pub type MailError = Box<dyn Error + Send + Sync>;
+
+/// Transport that delivers composed emails
+pub trait Mailer: Send + Sync {
+    fn deliver(&self, message: &Message) -> Result<(), MailError>;
+
+    /// Check the connection to the remote transport, if there is one
+    fn check(&self) -> Result<(), MailError> {
+        Ok(())
+    }
+}
+
+/// Delivers emails through the configured SMTP relay
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn new(config: &SmtpConfig) -> Result<SmtpMailer, MailError> {
+        let transport = SmtpTransport::relay(&config.host)?
+            .credentials(Credentials::new(
+                config.username.clone(),
+                config.password.clone(),
+            ))
+            .build();
+
+        Ok(SmtpMailer { transport })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn deliver(&self, message: &Message) -> Result<(), MailError> {
+        self.transport.send(message)?;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), MailError> {
+        if self.transport.test_connection()? {
+            Ok(())
+        } else {
+            Err("The SMTP server is not ready".into())
+        }
+    }
+}
+
+/// Drops every email as a separate `.eml` file into a directory
+pub struct FileMailer {
+    directory: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(directory: PathBuf) -> Result<FileMailer, MailError> {
+        std::fs::create_dir_all(&directory)?;
+        Ok(FileMailer { directory })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn deliver(&self, message: &Message) -> Result<(), MailError> {
+        let file_name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%d%H%M%S%6f"),
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+        );
+        std::fs::write(self.directory.join(file_name), message.formatted())?;
+        Ok(())
+    }
+}
+
+/// Prints every email to the standard output
+pub struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn deliver(&self, message: &Message) -> Result<(), MailError> {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&message.formatted())?;
+        stdout.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// Keeps every email in memory, so that they can be inspected later
+///
+/// The fairing manages the transport of the `memory` config as `Arc<MemoryMailer>` state.
+#[derive(Default)]
+pub struct MemoryMailer {
+    messages: Mutex<Vec<Message>>,
+}
+
+impl MemoryMailer {
+    /// Emails delivered so far, the oldest first
+    pub fn sent(&self) -> Vec<Message> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn deliver(&self, message: &Message) -> Result<(), MailError> {
+        self.messages.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+}
+
+/// Discards every email
+pub struct NoopMailer;
+
+impl Mailer for NoopMailer {
+    fn deliver(&self, _message: &Message) -> Result<(), MailError> {
+        Ok(())
+    }
+}
+
+/// Mail transport, configured in the `mail` table of `Rocket.toml`
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(tag = "transport", rename_all = "snake_case")]
+pub enum MailConfig {
+    #[default]
+    Smtp,
+    File {
+        directory: PathBuf,
+    },
+    Stdout,
+    Memory,
+    Noop,
+}
+
+impl MailConfig {
+    /// Transport of the config, the memory one is set up by `MailerFairing` only, so that the
+    /// tests read the emails of the transport the server sends them with
+    pub fn build(&self, smtp: Option<&SmtpConfig>) -> Result<Arc<dyn Mailer>, MailError> {
+        Ok(match self {
+            MailConfig::Smtp => {
+                let smtp = smtp.ok_or("The SMTP transport requires the SMTP relay config")?;
+                Arc::new(SmtpMailer::new(smtp)?)
+            }
+            MailConfig::File { directory } => Arc::new(FileMailer::new(directory.clone())?),
+            MailConfig::Stdout => Arc::new(StdoutMailer),
+            MailConfig::Memory => {
+                return Err("The memory transport is only set up by the mailer fairing".into())
+            }
+            MailConfig::Noop => Arc::new(NoopMailer),
+        })
+    }
+}
+
+/// Delivery settings of the outbound email queue, configured in the `email_queue` table of `Rocket.toml`
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct EmailQueueConfig {
+    /// Attempts to deliver an email before it is moved to the dead letter list
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubled for every next one
+    pub backoff: u64,
+    /// Seconds between checks of an empty queue
+    pub poll_interval: u64,
+    /// Seconds a worker holds its emails without renewing its lease, they are sent again by the
+    /// other workers once it expires, so it has to outlast the slowest delivery
+    pub lease: usize,
+}
+
+impl Default for EmailQueueConfig {
+    fn default() -> Self {
+        EmailQueueConfig {
+            max_attempts: 5,
+            backoff: 30,
+            poll_interval: 1,
+            lease: 300,
+        }
+    }
+}
+
+impl EmailQueueConfig {
+    /// Seconds before the next attempt after the given number of failed ones
+    pub fn retry_delay(&self, attempts: u32) -> u64 {
+        self.backoff
+            .saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)))
+    }
+}
+
+/// Renders email templates and puts them into the outbound queue
 pub struct HtmlMailer {
-    pub credentials: Credentials,
-    pub smtp_host: String,
+    pub transport: Arc<dyn Mailer>,
     pub template_engine: tera::Tera,
+    pub queue_config: EmailQueueConfig,
+    cache: deadpool_redis::Pool,
 }
 
 impl HtmlMailer {
-    pub fn new() -> Result<HtmlMailer, Box<dyn std::error::Error>> {
-        let smtp_host = std::env::var("SMTP_HOST").expect("Cannot load SMTP host from env");
-        let smtp_username =
-            std::env::var("SMTP_USERNAME").expect("Cannot load SMTP username from env");
-        let smtp_password =
-            std::env::var("SMTP_PASSWORD").expect("Cannot load SMTP password from env");
-
-        let credentials =
-            lettre::transport::smtp::authentication::Credentials::new(smtp_username, smtp_password);
-        let tera = Tera::new("templates/**/*.html").unwrap_or_else(|e| {
-            panic!("Parsing error(s): {}", e);
-        });
+    pub fn new(
+        transport: Arc<dyn Mailer>,
+        queue_config: EmailQueueConfig,
+        cache: deadpool_redis::Pool,
+    ) -> Result<HtmlMailer, MailError> {
+        let tera = Tera::new("templates/**/*.html")?;
 
         Ok(HtmlMailer {
-            smtp_host,
-            credentials,
+            transport,
             template_engine: tera,
+            queue_config,
+            cache,
         })
     }
 
-    pub fn send(
-        self,
+    /// Queue the rendered template, the request id is kept with the email to trace its delivery
+    pub async fn send(
+        &self,
+        request_id: &RequestId,
         to: Vec<String>,
         subject: Option<String>,
         template_name: &str,
         context: &Context,
-    ) -> Result<Response, Box<dyn std::error::Error>> {
+    ) -> Result<(), MailError> {
         let html_body = self.template_engine.render(template_name, context)?;
         let subject = subject.unwrap_or_else(|| "(no subject)".to_string());
 
-        let mut message_builder = lettre::Message::builder()
-            .subject(subject)
-            .from("Template App <softteco.os.dev@gmail.com>".parse().unwrap())
-            .to(to[0].parse()?)
-            .header(ContentType::TEXT_HTML);
+        let email = QueuedEmail {
+            id: generate_token(EMAIL_ID_LENGTH),
+            to,
+            subject,
+            html_body,
+            attempts: 0,
+            last_error: None,
+            queued_at: Utc::now(),
+            request_id: Some(request_id.to_string()),
+        };
+        // Reject malformed addresses right away instead of retrying them
+        compose_message(&email)?;
+
+        let mut cache = self.cache.get().await?;
+        EmailQueueRepository::push(&email, &mut cache).await?;
+
+        Ok(())
+    }
+
+    pub fn worker(&self, metrics: Option<Metrics>) -> EmailWorker {
+        EmailWorker {
+            id: generate_token(WORKER_ID_LENGTH),
+            transport: self.transport.clone(),
+            config: self.queue_config,
+            cache: self.cache.clone(),
+            metrics,
+        }
+    }
+}
+
+pub fn compose_message(email: &QueuedEmail) -> Result<Message, MailError> {
+    let (to, copies) = email.to.split_first().ok_or("Email without recipients")?;
+
+    let mut message_builder = lettre::Message::builder()
+        .subject(email.subject.as_str())
+        .from(MAIL_SENDER.parse().unwrap())
+        .to(to.parse()?)
+        .header(ContentType::TEXT_HTML);
 
-        if to.len() > 1 {
-            for copy in &to[1..] {
-                message_builder = message_builder.cc(copy.parse()?);
+    for copy in copies {
+        message_builder = message_builder.cc(copy.parse()?);
+    }
+
+    Ok(message_builder.body(email.html_body.clone())?)
+}
+
+/// Background job delivering the queued emails, failed ones are retried with exponential backoff
+pub struct EmailWorker {
+    id: String,
+    transport: Arc<dyn Mailer>,
+    config: EmailQueueConfig,
+    cache: deadpool_redis::Pool,
+    metrics: Option<Metrics>,
+}
+
+impl EmailWorker {
+    pub async fn run(self, shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.heartbeat().await {
+                log::error!("Cannot renew the lease of the email worker: {}", e);
+            }
+
+            match self.deliver_next().await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => log::error!("Cannot process the email queue: {}", e),
+            }
+
+            rocket::tokio::select! {
+                _ = sleep(Duration::from_secs(self.config.poll_interval)) => {}
+                _ = shutdown.clone() => break,
             }
         }
 
-        let message = message_builder.body(html_body)?;
+        if let Err(e) = self.release().await {
+            log::error!("Cannot release the lease of the email worker: {}", e);
+        }
+    }
 
-        let mailer = SmtpTransport::relay(&self.smtp_host)?
-            .credentials(self.credentials)
-            .build();
+    /// Renew the lease of the worker and queue the emails of the workers whose lease expired
+    /// again, they may have been sent before their worker stopped
+    async fn heartbeat(&self) -> Result<(), MailError> {
+        let mut cache = self.cache.get().await?;
+        EmailQueueRepository::renew_lease(&self.id, self.config.lease, &mut cache).await?;
+
+        let recovered = EmailQueueRepository::recover_expired_workers(&mut cache).await?;
+        if recovered > 0 {
+            log::warn!("{} emails of a stopped worker are queued again", recovered);
+        }
+        Ok(())
+    }
 
-        mailer.send(&message).map_err(|e| e.into())
+    async fn release(&self) -> Result<(), MailError> {
+        let mut cache = self.cache.get().await?;
+        EmailQueueRepository::release_lease(&self.id, &mut cache).await?;
+        Ok(())
+    }
+
+    /// Deliver the next queued email, returns false if the queue is empty
+    async fn deliver_next(&self) -> Result<bool, MailError> {
+        let mut cache = self.cache.get().await?;
+        EmailQueueRepository::promote_due_retries(Utc::now().timestamp(), &mut cache).await?;
+
+        let Some((mut email, payload)) = EmailQueueRepository::pop(&self.id, &mut cache).await?
+        else {
+            return Ok(false);
+        };
+
+        let transport = self.transport.clone();
+        let result = match compose_message(&email) {
+            Ok(message) => spawn_blocking(move || transport.deliver(&message))
+                .await
+                .unwrap_or_else(|e| Err(e.into())),
+            Err(e) => Err(e),
+        };
+
+        let error = match result {
+            Ok(()) => {
+                self.record("sent");
+                log::info!(
+                    request_id = email.request_id.as_deref();
+                    "Email {} is sent to {:?}",
+                    email.id,
+                    email.to
+                );
+                EmailQueueRepository::ack(&self.id, &payload, &mut cache).await?;
+                return Ok(true);
+            }
+            Err(e) => e,
+        };
+
+        email.attempts += 1;
+        email.last_error = Some(error.to_string());
+
+        let is_settled = if email.attempts >= self.config.max_attempts {
+            log::error!(
+                request_id = email.request_id.as_deref();
+                "Email {} of request {} is moved to the dead letter list after {} attempts: {}",
+                email.id,
+                email.request_id.as_deref().unwrap_or("-"),
+                email.attempts,
+                error
+            );
+            self.record("dead_lettered");
+            EmailQueueRepository::push_dead_letter(&self.id, &email, &payload, &mut cache).await?
+        } else {
+            let delay = self.config.retry_delay(email.attempts);
+            log::warn!(
+                request_id = email.request_id.as_deref();
+                "Email {} of request {} is not sent, retrying in {} seconds: {}",
+                email.id,
+                email.request_id.as_deref().unwrap_or("-"),
+                delay,
+                error
+            );
+            self.record("failed");
+            let retry_at = Utc::now().timestamp() + delay as i64;
+            EmailQueueRepository::schedule_retry(&self.id, &email, &payload, retry_at, &mut cache)
+                .await?
+        };
+
+        // The lease expired during the delivery, the email is already queued again
+        if !is_settled {
+            log::warn!(
+                request_id = email.request_id.as_deref();
+                "Email {} has been recovered by another worker while it was sent",
+                email.id
+            );
+        }
+
+        Ok(true)
+    }
+
+    fn record(&self, outcome: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_email(outcome);
+        }
     }
 }
 
-pub async fn send_reset_password_email(user: User, deep_link: String, client_addr: IpAddr) {
-    let client_info = get_client_info(client_addr).await.unwrap();
+/// Builds the configured mail transport and manages the `HtmlMailer` on top of it,
+/// the email queue worker is started on liftoff
+pub struct MailerFairing;
+
+#[rocket::async_trait]
+impl Fairing for MailerFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Mail transport",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let Some(config) = rocket.state::<AppConfig>() else {
+            log::error!("The mail transport requires the app config to be managed first");
+            return Err(rocket);
+        };
+        let memory_mailer =
+            matches!(config.mail, MailConfig::Memory).then(|| Arc::new(MemoryMailer::default()));
+        let transport = match &memory_mailer {
+            Some(memory_mailer) => Ok(memory_mailer.clone() as Arc<dyn Mailer>),
+            None => config.mail.build(config.smtp.as_ref()),
+        };
+        let queue_config = config.email_queue;
+
+        let cache = match CacheConnection::fetch(&rocket) {
+            Some(cache) => deadpool_redis::Pool::clone(cache),
+            None => {
+                log::error!("The email queue requires the redis database to be attached first");
+                return Err(rocket);
+            }
+        };
+
+        let mailer =
+            match transport.and_then(|transport| HtmlMailer::new(transport, queue_config, cache)) {
+                Ok(mailer) => mailer,
+                Err(e) => {
+                    log::error!("Cannot set up the mail transport: {}", e);
+                    return Err(rocket);
+                }
+            };
+
+        let rocket = rocket.manage(mailer);
+        match memory_mailer {
+            Some(memory_mailer) => Ok(rocket.manage(memory_mailer)),
+            None => Ok(rocket),
+        }
+    }
 
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(mailer) = rocket.state::<HtmlMailer>() {
+            let metrics = rocket.state::<Metrics>().cloned();
+            rocket::tokio::spawn(mailer.worker(metrics).run(rocket.shutdown()));
+        }
+    }
+}
+
+pub async fn send_reset_password_email(
+    mailer: &HtmlMailer,
+    request_id: &RequestId,
+    user: User,
+    deep_link: String,
+    client_info: &ClientInfo,
+) {
     let year = Utc::now().year();
 
-    log::info!("Sending reset password email for {}", user.username);
+    log::info!(request_id = request_id.as_str(); "Sending reset password email for {}", user.username);
 
     let mut context = Context::new();
     context.insert("username", &user.username);
     context.insert("deep_link", &deep_link);
-    context.insert("client_info", &client_info);
+    context.insert("client_info", client_info);
     context.insert("year", &year);
 
-    let mailer = HtmlMailer::new().unwrap();
-
-    mailer
+    if let Err(e) = mailer
         .send(
+            request_id,
             vec![user.email],
             Some(String::from("Reset password")),
             "email/reset_password.html",
             &context,
         )
-        .unwrap();
+        .await
+    {
+        log::error!(
+            request_id = request_id.as_str();
+            "Cannot queue the email of request {}: {}",
+            request_id,
+            e
+        );
+    }
 }
 
-pub async fn send_confirmation_email(user: &User, deep_link: String, client_addr: IpAddr) {
-    let client_info = get_client_info(client_addr).await.unwrap();
+pub async fn send_magic_link_email(
+    mailer: &HtmlMailer,
+    request_id: &RequestId,
+    user: &User,
+    link: String,
+    client_info: &ClientInfo,
+) {
+    let year = Utc::now().year();
+
+    log::info!(request_id = request_id.as_str(); "Sending magic link email for {}", user.username);
+
+    let mut context = Context::new();
+    context.insert("username", &user.username);
+    context.insert("deep_link", &link);
+    context.insert("client_info", client_info);
+    context.insert("year", &year);
+
+    if let Err(e) = mailer
+        .send(
+            request_id,
+            vec![user.email.clone()],
+            Some(String::from("Log in to Template App")),
+            "email/magic_link.html",
+            &context,
+        )
+        .await
+    {
+        log::error!(
+            request_id = request_id.as_str();
+            "Cannot queue the email of request {}: {}",
+            request_id,
+            e
+        );
+    }
+}
 
+pub async fn send_confirmation_email(
+    mailer: &HtmlMailer,
+    request_id: &RequestId,
+    user: &User,
+    deep_link: String,
+    client_info: &ClientInfo,
+) {
     let year = Utc::now().year();
 
-    log::info!("Sending confirmation email for {}", user.username);
+    log::info!(request_id = request_id.as_str(); "Sending confirmation email for {}", user.username);
 
     let mut context = Context::new();
     context.insert("username", &user.username);
     context.insert("deep_link", &deep_link);
-    context.insert("client_info", &client_info);
+    context.insert("client_info", client_info);
     context.insert("year", &year);
 
-    let mailer = HtmlMailer::new().unwrap();
     let address = (user.email).to_string();
 
-    mailer
+    if let Err(e) = mailer
         .send(
+            request_id,
             vec![address],
             Some(String::from("Confirm Your Registration on Template App")),
             "email/confirmation.html",
             &context,
         )
-        .unwrap();
+        .await
+    {
+        log::error!(
+            request_id = request_id.as_str();
+            "Cannot queue the email of request {}: {}",
+            request_id,
+            e
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn send_company_invitation_email(
+    mailer: &HtmlMailer,
+    request_id: &RequestId,
+    email: String,
+    company_name: &str,
+    inviter: &str,
+    role_codes: &[String],
+    accept_link: String,
+    decline_link: String,
+) {
+    let year = Utc::now().year();
+
+    log::info!(request_id = request_id.as_str(); "Sending invitation email into company {}", company_name);
+
+    let mut context = Context::new();
+    context.insert("company_name", company_name);
+    context.insert("inviter", inviter);
+    context.insert("roles", &role_codes.join(", "));
+    context.insert("accept_link", &accept_link);
+    context.insert("decline_link", &decline_link);
+    context.insert("year", &year);
+
+    if let Err(e) = mailer
+        .send(
+            request_id,
+            vec![email],
+            Some(format!(
+                "Invitation to join {} on Template App",
+                company_name
+            )),
+            "email/company_invitation.html",
+            &context,
+        )
+        .await
+    {
+        log::error!(
+            request_id = request_id.as_str();
+            "Cannot queue the email of request {}: {}",
+            request_id,
+            e
+        );
+    }
+}
+
+/// Send the confirmation link to the new email address of the user
+pub async fn send_email_change_email(
+    mailer: &HtmlMailer,
+    request_id: &RequestId,
+    user: &User,
+    new_email: &str,
+    deep_link: String,
+    client_info: &ClientInfo,
+) {
+    let year = Utc::now().year();
+
+    log::info!(request_id = request_id.as_str(); "Sending email change confirmation for {}", user.username);
+
+    let mut context = Context::new();
+    context.insert("username", &user.username);
+    context.insert("new_email", new_email);
+    context.insert("deep_link", &deep_link);
+    context.insert("client_info", client_info);
+    context.insert("year", &year);
+
+    if let Err(e) = mailer
+        .send(
+            request_id,
+            vec![new_email.to_string()],
+            Some(String::from("Confirm Your New Email on Template App")),
+            "email/email_change.html",
+            &context,
+        )
+        .await
+    {
+        log::error!(
+            request_id = request_id.as_str();
+            "Cannot queue the email of request {}: {}",
+            request_id,
+            e
+        );
+    }
+}
+
+/// Notify the current email address of the user about the change, with the link to revert it
+pub async fn send_email_change_notice(
+    mailer: &HtmlMailer,
+    request_id: &RequestId,
+    user: &User,
+    new_email: &str,
+    deep_link: String,
+    client_info: &ClientInfo,
+) {
+    let year = Utc::now().year();
+
+    log::info!(request_id = request_id.as_str(); "Sending email change notice for {}", user.username);
+
+    let mut context = Context::new();
+    context.insert("username", &user.username);
+    context.insert("new_email", new_email);
+    context.insert("deep_link", &deep_link);
+    context.insert("client_info", client_info);
+    context.insert("year", &year);
+
+    if let Err(e) = mailer
+        .send(
+            request_id,
+            vec![user.email.clone()],
+            Some(String::from("Your Email on Template App Is Changing")),
+            "email/email_change_notice.html",
+            &context,
+        )
+        .await
+    {
+        log::error!(
+            request_id = request_id.as_str();
+            "Cannot queue the email of request {}: {}",
+            request_id,
+            e
+        );
+    }
 }
@@ -0,0 +1,228 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::{Build, Request, Response, Rocket};
+
+use crate::errors::ErrorCode;
+use crate::rocket_routes::request_id;
+
+/// Routes counted as login attempts, by the URI they are mounted at, with their login method
+const LOGIN_ROUTES: [(&str, &str); 5] = [
+    ("/login", "password"),
+    ("/login/2fa", "two_factor"),
+    ("/login/magic/<token>", "magic_link"),
+    ("/login/passkey/finish", "passkey"),
+    ("/oidc/<provider>/login", "oidc"),
+];
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Exposure of the metrics, configured in the `metrics` table of `Rocket.toml`
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Bearer token required to scrape `/metrics`, anyone can scrape them if not set
+    pub token: Option<String>,
+}
+
+/// Prometheus metrics of the service
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    logins: IntCounterVec,
+    emails: IntCounterVec,
+    pool_max_connections: IntGaugeVec,
+    pool_connections: IntGaugeVec,
+    pool_waiting: IntGaugeVec,
+    pending_migrations: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics, prometheus::Error> {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        )?;
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Latency of HTTP requests by route",
+            ),
+            &["method", "route"],
+        )?;
+        let logins = IntCounterVec::new(
+            Opts::new(
+                "logins_total",
+                "Login attempts by method, outcome and the error code of failed ones",
+            ),
+            &["method", "outcome", "code"],
+        )?;
+        let emails = IntCounterVec::new(
+            Opts::new(
+                "emails_total",
+                "Delivery attempts of queued emails by outcome",
+            ),
+            &["outcome"],
+        )?;
+        let pool_max_connections = IntGaugeVec::new(
+            Opts::new(
+                "pool_max_connections",
+                "Maximum size of the connection pools",
+            ),
+            &["pool"],
+        )?;
+        let pool_connections = IntGaugeVec::new(
+            Opts::new("pool_connections", "Connections of the pools by state"),
+            &["pool", "state"],
+        )?;
+        let pool_waiting = IntGaugeVec::new(
+            Opts::new(
+                "pool_waiting",
+                "Requests waiting for a connection of the pools",
+            ),
+            &["pool"],
+        )?;
+        let pending_migrations = IntGauge::new(
+            "db_pending_migrations",
+            "Embedded database migrations not applied yet",
+        )?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_request_duration.clone()))?;
+        registry.register(Box::new(logins.clone()))?;
+        registry.register(Box::new(emails.clone()))?;
+        registry.register(Box::new(pool_max_connections.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(pool_waiting.clone()))?;
+        registry.register(Box::new(pending_migrations.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            logins,
+            emails,
+            pool_max_connections,
+            pool_connections,
+            pool_waiting,
+            pending_migrations,
+        })
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+
+    fn record_login(&self, method: &str, status: Status, code: Option<&str>) {
+        // Magic links requested for a web page log in with a redirect
+        let outcome = if status == Status::Accepted {
+            "two_factor_required"
+        } else if status.class().is_success() || status.class().is_redirection() {
+            "success"
+        } else {
+            "failure"
+        };
+        self.logins
+            .with_label_values(&[method, outcome, code.unwrap_or_default()])
+            .inc();
+    }
+
+    /// Count a delivery attempt of a queued email: `sent`, `failed` or `dead_lettered`
+    pub fn record_email(&self, outcome: &str) {
+        self.emails.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn set_pool_size(&self, pool: &str, max_size: usize) {
+        self.pool_max_connections
+            .with_label_values(&[pool])
+            .set(max_size as i64);
+    }
+
+    pub fn set_pool_usage(&self, pool: &str, in_use: usize, idle: usize) {
+        self.pool_connections
+            .with_label_values(&[pool, "in_use"])
+            .set(in_use as i64);
+        self.pool_connections
+            .with_label_values(&[pool, "idle"])
+            .set(idle as i64);
+    }
+
+    pub fn set_pool_waiting(&self, pool: &str, waiting: usize) {
+        self.pool_waiting
+            .with_label_values(&[pool])
+            .set(waiting as i64);
+    }
+
+    pub fn set_pending_migrations(&self, pending: usize) {
+        self.pending_migrations.set(pending as i64);
+    }
+}
+
+/// Manages the `Metrics` and records every request in them
+pub struct MetricsFairing;
+
+#[rocket::async_trait]
+impl Fairing for MetricsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Prometheus metrics",
+            kind: Kind::Ignite | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let metrics = match Metrics::new() {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                log::error!("Cannot set up the metrics: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        match rocket_sync_db_pools::Config::from("postgres", &rocket) {
+            Ok(config) => metrics.set_pool_size("postgres", config.pool_size as usize),
+            Err(e) => log::warn!("Cannot read the size of the postgres pool: {}", e),
+        }
+
+        Ok(rocket.manage(metrics))
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(metrics) = req.rocket().state::<Metrics>() else {
+            return;
+        };
+        let method = req.method().as_str();
+        let route = req
+            .route()
+            .map(|route| route.uri.to_string())
+            .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+        let status = res.status();
+
+        metrics
+            .http_requests
+            .with_label_values(&[method, &route, &status.code.to_string()])
+            .inc();
+        metrics
+            .http_request_duration
+            .with_label_values(&[method, &route])
+            .observe(request_id::elapsed(req).as_secs_f64());
+
+        let login_method = LOGIN_ROUTES
+            .iter()
+            .find(|(uri, _)| *uri == route)
+            .map(|(_, method)| *method);
+        if let Some(login_method) = login_method {
+            let code = req.local_cache(|| ErrorCode(None)).0.as_deref();
+            metrics.record_login(login_method, status, code);
+        }
+    }
+}
@@ -1,17 +1,23 @@
 use std::{fmt, io::Write, str::FromStr};
 
-use crate::schema::{companies, roles, user_company_roles, user_roles, users};
-use chrono::{NaiveDate, NaiveDateTime};
+use crate::schema::{
+    api_keys, audit_events, companies, recovery_codes, roles, user_company_roles, user_identities,
+    user_roles, user_totp, users, webauthn_credentials,
+};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use diesel::{
     deserialize::{FromSql, FromSqlRow},
     expression::AsExpression,
     pg::{Pg, PgValue},
     prelude::{AsChangeset, Associations, Identifiable},
     serialize::{IsNull, Output, ToSql},
-    sql_types::Text,
-    Insertable, Queryable,
+    sql_types::{BigInt, Text},
+    Insertable, Queryable, QueryableByName,
 };
 use serde::Serialize;
+use webauthn_rs::prelude::{
+    DiscoverableAuthentication, PasskeyAuthentication, PasskeyRegistration,
+};
 
 #[derive(Queryable, Debug, Identifiable, Serialize, Clone)]
 pub struct User {
@@ -30,6 +36,9 @@ pub struct User {
     pub updated_at: NaiveDateTime,
     #[serde(skip_serializing)]
     pub user_type: UserType,
+    /// When the last confirmation email was sent, the unconfirmed user expires with its token
+    #[serde(skip_serializing)]
+    pub confirmation_sent_at: NaiveDateTime,
 }
 
 #[derive(serde::Deserialize, Insertable)]
@@ -93,7 +102,8 @@ pub struct Company {
     pub updated_at: NaiveDateTime,
 }
 
-#[derive(serde::Deserialize, Insertable)]
+#[derive(serde::Deserialize, Insertable, AsChangeset)]
+#[diesel(treat_none_as_null = true)]
 #[diesel(table_name = companies)]
 pub struct NewCompany {
     pub name: String,
@@ -123,6 +133,261 @@ pub struct NewUserCompanyRole {
     pub role_id: i32,
 }
 
+/// TOTP secret of a user, the second factor is required on login only once it is enabled
+#[derive(Queryable, Debug, Identifiable)]
+#[diesel(table_name = user_totp)]
+#[diesel(primary_key(user_id))]
+pub struct UserTotp {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    /// Time step of the last accepted code, the codes of this and earlier steps are rejected
+    pub last_used_step: Option<i64>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_totp)]
+pub struct NewUserTotp {
+    pub user_id: i32,
+    pub secret: String,
+}
+
+#[derive(Queryable, Associations, Identifiable, Debug)]
+#[diesel(table_name = recovery_codes)]
+#[diesel(belongs_to(User))]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = recovery_codes)]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+}
+
+/// Account of an external OpenID Connect provider linked to a user
+#[derive(Queryable, Associations, Identifiable, Debug)]
+#[diesel(table_name = user_identities)]
+#[diesel(belongs_to(User))]
+pub struct UserIdentity {
+    pub id: i32,
+    pub user_id: i32,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_identities)]
+pub struct NewUserIdentity {
+    pub user_id: i32,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+}
+
+/// Passkey of a user, `passkey` is the credential serialized by `webauthn-rs`, including its
+/// signature counter
+#[derive(Queryable, Associations, Identifiable, Debug)]
+#[diesel(table_name = webauthn_credentials)]
+#[diesel(belongs_to(User))]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: Vec<u8>,
+    pub user_handle: String,
+    pub name: String,
+    pub passkey: serde_json::Value,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webauthn_credentials)]
+pub struct NewWebauthnCredential {
+    pub user_id: i32,
+    pub credential_id: Vec<u8>,
+    pub user_handle: String,
+    pub name: String,
+    pub passkey: serde_json::Value,
+}
+
+/// Personal API key of a user, only the SHA-256 digest of the key is stored; `prefix` is the
+/// public part of the key it is looked up by, `scopes` cap the roles granted to requests with it
+#[derive(Queryable, Associations, Identifiable, Debug)]
+#[diesel(table_name = api_keys)]
+#[diesel(belongs_to(User))]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<RoleCode>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = api_keys)]
+pub struct NewApiKey {
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<RoleCode>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// Passkey registration started by a user, cached until it is finished
+#[derive(Serialize, serde::Deserialize)]
+pub struct PasskeyRegistrationState {
+    /// WebAuthn user handle, shared by all passkeys of the user
+    pub user_handle: String,
+    pub registration: PasskeyRegistration,
+}
+
+/// Passkey login started, cached until it is finished
+#[derive(Serialize, serde::Deserialize)]
+pub enum PasskeyLoginState {
+    /// The user is known from the email, only their passkeys are allowed
+    User {
+        user_id: i32,
+        authentication: PasskeyAuthentication,
+    },
+    /// The user is identified by the discoverable passkey itself
+    Discoverable(DiscoverableAuthentication),
+}
+
+/// Authorization request started with a provider, cached until the app returns its code
+///
+/// Only the PKCE challenge is known to the server, the app proves the login with the verifier.
+#[derive(Debug, Serialize, serde::Deserialize)]
+pub struct OidcAuthorization {
+    pub provider: String,
+    pub code_challenge: String,
+    pub nonce: String,
+}
+
+/// Login requested by email, cached until the emailed link is used or a new one is requested
+#[derive(Debug, Serialize, serde::Deserialize)]
+pub struct MagicLink {
+    pub user_id: i32,
+    /// Web page the link redirects to with the tokens, the app exchanges the link itself if not set
+    pub redirect_url: Option<String>,
+}
+
+/// Identity of a provider matching the email of an existing user, cached until the user
+/// confirms the link with their password
+#[derive(Debug, Serialize, serde::Deserialize)]
+pub struct AccountLink {
+    pub user_id: i32,
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    pub email_verified: bool,
+}
+
+/// Invitation of an email address into a company, cached until it is accepted, declined or expired
+#[derive(Debug, Serialize, serde::Deserialize)]
+pub struct CompanyInvitation {
+    pub company_id: i32,
+    pub email: String,
+    pub role_codes: Vec<String>,
+    pub invited_by: i32,
+}
+
+/// Change of the email address of a user, cached until it is confirmed from the new address
+/// or reverted from the previous one
+#[derive(Debug, Serialize, serde::Deserialize)]
+pub struct EmailChange {
+    pub user_id: i32,
+    pub old_email: String,
+    pub new_email: String,
+}
+
+/// Security-relevant event of an account, `actor_id` is empty for anonymous requests
+/// and for the CLI, `target_id` is the account the event is about
+#[derive(Queryable, Debug, Identifiable, Clone)]
+#[diesel(table_name = audit_events)]
+pub struct AuditEvent {
+    pub id: i32,
+    pub actor_id: Option<i32>,
+    pub target_id: Option<i32>,
+    pub event_type: AuditEventType,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub payload: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = audit_events)]
+pub struct NewAuditEvent {
+    pub actor_id: Option<i32>,
+    pub target_id: Option<i32>,
+    pub event_type: AuditEventType,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub payload: serde_json::Value,
+}
+
+/// Criteria of the audit events query, empty ones match every event
+#[derive(Debug, Default)]
+pub struct AuditEventFilter {
+    pub actor_id: Option<i32>,
+    pub target_id: Option<i32>,
+    pub event_type: Option<AuditEventType>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+/// Number of connections to the database in the given state
+#[derive(QueryableByName, Debug)]
+pub struct ConnectionCount {
+    #[diesel(sql_type = Text)]
+    pub state: String,
+    #[diesel(sql_type = BigInt)]
+    pub count: i64,
+}
+
+/// Rendered email waiting in the outbound queue until the mail transport delivers it
+#[derive(Debug, Clone, Serialize, serde::Deserialize)]
+pub struct QueuedEmail {
+    pub id: String,
+    pub to: Vec<String>,
+    pub subject: String,
+    pub html_body: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub queued_at: DateTime<Utc>,
+    /// Id of the request the email was sent from
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// A chain of access/refresh token pairs issued for a single login and rotated on refresh
+#[derive(Debug, Clone)]
+pub struct TokenFamily {
+    pub id: String,
+    pub user_id: i32,
+    pub session_id: String,
+    pub refresh_token: String,
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+}
+
 #[derive(AsExpression, FromSqlRow, Debug, PartialEq, Clone)]
 #[diesel(sql_type=Text)]
 pub enum RoleCode {
@@ -131,6 +396,21 @@ pub enum RoleCode {
     Viewer,
 }
 
+impl RoleCode {
+    /// Whether the role includes the permissions of the `required` one (admin > editor > viewer)
+    pub fn grants(&self, required: &RoleCode) -> bool {
+        self.level() >= required.level()
+    }
+
+    fn level(&self) -> u8 {
+        match self {
+            RoleCode::Admin => 2,
+            RoleCode::Editor => 1,
+            RoleCode::Viewer => 0,
+        }
+    }
+}
+
 impl fmt::Display for RoleCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -223,3 +503,170 @@ impl ToSql<Text, Pg> for UserType {
         Ok(IsNull::No)
     }
 }
+
+#[derive(AsExpression, FromSqlRow, Debug, PartialEq, Clone, Copy)]
+#[diesel(sql_type=Text)]
+pub enum AuditEventType {
+    Signup,
+    SignupConfirmed,
+    ConfirmationResent,
+    Login,
+    LoginFailed,
+    Logout,
+    LogoutAll,
+    RefreshTokenReused,
+    PasswordResetRequested,
+    PasswordReset,
+    PasswordChanged,
+    MagicLinkRequested,
+    EmailChangeRequested,
+    EmailChanged,
+    EmailChangeReverted,
+    ProfileUpdated,
+    UserDeleted,
+    SessionRevoked,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    PasskeyRegistered,
+    PasskeyRenamed,
+    PasskeyDeleted,
+    ApiKeyCreated,
+    ApiKeyRenamed,
+    ApiKeyDeleted,
+    IdentityLinked,
+    UserCreated,
+    UserTypeChanged,
+    RolesAdded,
+    RolesRemoved,
+    CompanyCreated,
+    CompanyDeleted,
+    CompanyMemberAdded,
+    CompanyUpdated,
+    CompanyMemberUpdated,
+    CompanyMemberRemoved,
+    CompanyInvitationSent,
+    CompanyInvitationAccepted,
+    CompanyInvitationDeclined,
+}
+
+impl AuditEventType {
+    pub const ALL: [AuditEventType; 40] = [
+        AuditEventType::Signup,
+        AuditEventType::SignupConfirmed,
+        AuditEventType::ConfirmationResent,
+        AuditEventType::Login,
+        AuditEventType::LoginFailed,
+        AuditEventType::Logout,
+        AuditEventType::LogoutAll,
+        AuditEventType::RefreshTokenReused,
+        AuditEventType::PasswordResetRequested,
+        AuditEventType::PasswordReset,
+        AuditEventType::PasswordChanged,
+        AuditEventType::MagicLinkRequested,
+        AuditEventType::EmailChangeRequested,
+        AuditEventType::EmailChanged,
+        AuditEventType::EmailChangeReverted,
+        AuditEventType::ProfileUpdated,
+        AuditEventType::UserDeleted,
+        AuditEventType::SessionRevoked,
+        AuditEventType::TwoFactorEnabled,
+        AuditEventType::TwoFactorDisabled,
+        AuditEventType::PasskeyRegistered,
+        AuditEventType::PasskeyRenamed,
+        AuditEventType::PasskeyDeleted,
+        AuditEventType::ApiKeyCreated,
+        AuditEventType::ApiKeyRenamed,
+        AuditEventType::ApiKeyDeleted,
+        AuditEventType::IdentityLinked,
+        AuditEventType::UserCreated,
+        AuditEventType::UserTypeChanged,
+        AuditEventType::RolesAdded,
+        AuditEventType::RolesRemoved,
+        AuditEventType::CompanyCreated,
+        AuditEventType::CompanyDeleted,
+        AuditEventType::CompanyMemberAdded,
+        AuditEventType::CompanyUpdated,
+        AuditEventType::CompanyMemberUpdated,
+        AuditEventType::CompanyMemberRemoved,
+        AuditEventType::CompanyInvitationSent,
+        AuditEventType::CompanyInvitationAccepted,
+        AuditEventType::CompanyInvitationDeclined,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEventType::Signup => "signup",
+            AuditEventType::SignupConfirmed => "signup_confirmed",
+            AuditEventType::ConfirmationResent => "confirmation_resent",
+            AuditEventType::Login => "login",
+            AuditEventType::LoginFailed => "login_failed",
+            AuditEventType::Logout => "logout",
+            AuditEventType::LogoutAll => "logout_all",
+            AuditEventType::RefreshTokenReused => "refresh_token_reused",
+            AuditEventType::PasswordResetRequested => "password_reset_requested",
+            AuditEventType::PasswordReset => "password_reset",
+            AuditEventType::PasswordChanged => "password_changed",
+            AuditEventType::MagicLinkRequested => "magic_link_requested",
+            AuditEventType::EmailChangeRequested => "email_change_requested",
+            AuditEventType::EmailChanged => "email_changed",
+            AuditEventType::EmailChangeReverted => "email_change_reverted",
+            AuditEventType::ProfileUpdated => "profile_updated",
+            AuditEventType::UserDeleted => "user_deleted",
+            AuditEventType::SessionRevoked => "session_revoked",
+            AuditEventType::TwoFactorEnabled => "two_factor_enabled",
+            AuditEventType::TwoFactorDisabled => "two_factor_disabled",
+            AuditEventType::PasskeyRegistered => "passkey_registered",
+            AuditEventType::PasskeyRenamed => "passkey_renamed",
+            AuditEventType::PasskeyDeleted => "passkey_deleted",
+            AuditEventType::ApiKeyCreated => "api_key_created",
+            AuditEventType::ApiKeyRenamed => "api_key_renamed",
+            AuditEventType::ApiKeyDeleted => "api_key_deleted",
+            AuditEventType::IdentityLinked => "identity_linked",
+            AuditEventType::UserCreated => "user_created",
+            AuditEventType::UserTypeChanged => "user_type_changed",
+            AuditEventType::RolesAdded => "roles_added",
+            AuditEventType::RolesRemoved => "roles_removed",
+            AuditEventType::CompanyCreated => "company_created",
+            AuditEventType::CompanyDeleted => "company_deleted",
+            AuditEventType::CompanyMemberAdded => "company_member_added",
+            AuditEventType::CompanyUpdated => "company_updated",
+            AuditEventType::CompanyMemberUpdated => "company_member_updated",
+            AuditEventType::CompanyMemberRemoved => "company_member_removed",
+            AuditEventType::CompanyInvitationSent => "company_invitation_sent",
+            AuditEventType::CompanyInvitationAccepted => "company_invitation_accepted",
+            AuditEventType::CompanyInvitationDeclined => "company_invitation_declined",
+        }
+    }
+}
+
+impl fmt::Display for AuditEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuditEventType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AuditEventType::ALL
+            .into_iter()
+            .find(|event_type| event_type.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl FromSql<Text, Pg> for AuditEventType {
+    fn from_sql(value: PgValue) -> diesel::deserialize::Result<Self> {
+        let value = std::str::from_utf8(value.as_bytes())?;
+        AuditEventType::from_str(value)
+            .map_err(|_| format!("Unknown audit event type: {}", value).into())
+    }
+}
+
+impl ToSql<Text, Pg> for AuditEventType {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use reqwest::{ClientBuilder, Url};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Build, Rocket};
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::config::AppConfig;
+
+const DISCOVERY_PATH: &str = ".well-known/openid-configuration";
+const PROVIDER_TIMEOUT: u64 = 5;
+
+pub type ProviderError = Box<dyn Error + Send + Sync>;
+
+/// Why an authorization code was not exchanged for a verified identity
+#[derive(Debug)]
+pub enum ExchangeError {
+    /// The provider cannot be reached or answers unexpectedly
+    Unavailable(ProviderError),
+    /// The provider rejected the code or issued an invalid ID token
+    Rejected(ProviderError),
+}
+
+impl std::fmt::Display for ExchangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExchangeError::Unavailable(e) => write!(f, "Provider is not available: {}", e),
+            ExchangeError::Rejected(e) => write!(f, "Authorization is rejected: {}", e),
+        }
+    }
+}
+
+fn default_scopes() -> Vec<String> {
+    ["openid", "email", "profile"]
+        .map(|scope| scope.to_string())
+        .to_vec()
+}
+
+/// OpenID Connect provider, configured under its name in the `oidc.providers` table of `Rocket.toml`
+#[derive(Deserialize, Clone)]
+pub struct OidcProviderConfig {
+    /// The provider metadata is discovered at `{issuer}/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    /// Sent to the token endpoint if set, public clients rely on PKCE only
+    pub client_secret: Option<String>,
+    /// Redirect URI registered with the provider, usually a deep link of the app
+    pub redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+}
+
+impl std::fmt::Debug for OidcProviderConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcProviderConfig")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("redirect_uri", &self.redirect_uri)
+            .field("scopes", &self.scopes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Social login, configured in the `oidc` table of `Rocket.toml`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct OidcConfig {
+    pub providers: HashMap<String, OidcProviderConfig>,
+    /// Seconds to wait for the providers
+    pub timeout: u64,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            providers: HashMap::new(),
+            timeout: PROVIDER_TIMEOUT,
+        }
+    }
+}
+
+impl OidcConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, provider) in &self.providers {
+            if Url::parse(&provider.issuer).is_err() {
+                return Err(format!("oidc.providers.{}.issuer must be a URL", name));
+            }
+            if provider.client_id.is_empty() || provider.redirect_uri.is_empty() {
+                return Err(format!(
+                    "oidc.providers.{} must have a client id and a redirect URI",
+                    name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Claims of a verified ID token
+#[derive(Deserialize, Debug)]
+pub struct IdentityClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "bool_or_string")]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+    pub nonce: Option<String>,
+}
+
+/// Some providers, e.g. Apple, send booleans as strings
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value == "true",
+    })
+}
+
+/// PKCE challenge of the code verifier with the S256 method
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Whether the challenge is a S256 one, that is a base64url encoded SHA-256 digest
+pub fn is_code_challenge_valid(code_challenge: &str) -> bool {
+    URL_SAFE_NO_PAD
+        .decode(code_challenge)
+        .is_ok_and(|digest| digest.len() == Sha256::output_size())
+}
+
+/// Whether the verifier has the length and the characters RFC 7636 allows and matches the challenge
+pub fn verify_code_verifier(code_verifier: &str, code_challenge: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-._~".contains(c);
+    (43..=128).contains(&code_verifier.len())
+        && code_verifier.chars().all(allowed)
+        && bool::from(
+            self::code_challenge(code_verifier)
+                .as_bytes()
+                .ct_eq(code_challenge.as_bytes()),
+        )
+}
+
+/// Client of the configured providers for the authorization code flow with PKCE,
+/// the metadata of a provider is discovered on its first use
+pub struct OidcClient {
+    http: reqwest::Client,
+    providers: HashMap<String, OidcProviderConfig>,
+    metadata: RwLock<HashMap<String, ProviderMetadata>>,
+}
+
+impl OidcClient {
+    pub fn new(config: &OidcConfig) -> Result<OidcClient, ProviderError> {
+        let http = ClientBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build()?;
+
+        Ok(OidcClient {
+            http,
+            providers: config.providers.clone(),
+            metadata: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn provider(&self, name: &str) -> Option<&OidcProviderConfig> {
+        self.providers.get(name)
+    }
+
+    async fn metadata(&self, name: &str) -> Result<ProviderMetadata, ProviderError> {
+        if let Some(metadata) = self.metadata.read().unwrap().get(name) {
+            return Ok(metadata.clone());
+        }
+
+        let provider = self.provider(name).ok_or("Unknown provider")?;
+        let url = format!(
+            "{}/{}",
+            provider.issuer.trim_end_matches('/'),
+            DISCOVERY_PATH
+        );
+        let metadata: ProviderMetadata = self
+            .http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if metadata.issuer.trim_end_matches('/') != provider.issuer.trim_end_matches('/') {
+            return Err(format!("Provider metadata of another issuer {}", metadata.issuer).into());
+        }
+
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(name.to_string(), metadata.clone());
+        Ok(metadata)
+    }
+
+    /// URL of the provider's authorization page for the app to open
+    pub async fn authorization_url(
+        &self,
+        name: &str,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> Result<String, ProviderError> {
+        let provider = self.provider(name).ok_or("Unknown provider")?;
+        let metadata = self.metadata(name).await?;
+
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", provider.redirect_uri.as_str()),
+                ("scope", provider.scopes.join(" ").as_str()),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(url.to_string())
+    }
+
+    /// Exchange the authorization code for an ID token and verify it, the token must have been
+    /// issued for the nonce of the authorization
+    pub async fn exchange_code(
+        &self,
+        name: &str,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdentityClaims, ExchangeError> {
+        let provider = self
+            .provider(name)
+            .ok_or_else(|| ExchangeError::Rejected("Unknown provider".into()))?;
+        let metadata = self
+            .metadata(name)
+            .await
+            .map_err(ExchangeError::Unavailable)?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &provider.client_secret {
+            form.push(("client_secret", client_secret.as_str()));
+        }
+
+        let response = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| ExchangeError::Unavailable(e.into()))?;
+        if response.status().is_client_error() {
+            return Err(ExchangeError::Rejected(
+                format!("Token endpoint answered {}", response.status()).into(),
+            ));
+        }
+        let tokens: TokenResponse = response
+            .error_for_status()
+            .map_err(|e| ExchangeError::Unavailable(e.into()))?
+            .json()
+            .await
+            .map_err(|e| ExchangeError::Unavailable(e.into()))?;
+
+        let claims = self
+            .verify_id_token(&tokens.id_token, provider, &metadata)
+            .await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(ExchangeError::Rejected(
+                "ID token is issued for another nonce".into(),
+            ));
+        }
+
+        Ok(claims)
+    }
+
+    async fn verify_id_token(
+        &self,
+        id_token: &str,
+        provider: &OidcProviderConfig,
+        metadata: &ProviderMetadata,
+    ) -> Result<IdentityClaims, ExchangeError> {
+        let rejected = |e: jsonwebtoken::errors::Error| ExchangeError::Rejected(e.into());
+
+        let header = jsonwebtoken::decode_header(id_token).map_err(rejected)?;
+        // Only keys published by the provider are trusted, not the client secret
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(ExchangeError::Rejected(
+                format!("ID token is signed with {:?}", header.alg).into(),
+            ));
+        }
+
+        let keys: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ExchangeError::Unavailable(e.into()))?
+            .json()
+            .await
+            .map_err(|e| ExchangeError::Unavailable(e.into()))?;
+
+        let key = match &header.kid {
+            Some(kid) => keys.find(kid),
+            None => keys.keys.first(),
+        }
+        .ok_or_else(|| ExchangeError::Rejected("ID token is signed with an unknown key".into()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&provider.client_id]);
+
+        let claims = jsonwebtoken::decode::<IdentityClaims>(
+            id_token,
+            &DecodingKey::from_jwk(key).map_err(rejected)?,
+            &validation,
+        )
+        .map_err(rejected)?
+        .claims;
+
+        Ok(claims)
+    }
+}
+
+/// Manages the `OidcClient` of the configured providers
+pub struct OidcFairing;
+
+#[rocket::async_trait]
+impl Fairing for OidcFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "OpenID Connect",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let Some(config) = rocket.state::<AppConfig>() else {
+            log::error!("OpenID Connect requires the app config to be managed first");
+            return Err(rocket);
+        };
+
+        match OidcClient::new(&config.oidc) {
+            Ok(client) => Ok(rocket.manage(client)),
+            Err(e) => {
+                log::error!("Cannot set up the OpenID Connect client: {}", e);
+                Err(rocket)
+            }
+        }
+    }
+}
@@ -1,13 +1,138 @@
-use crate::auth::{SESSIONS_KEY_PREFIX, SESSION_LIFE_TIME};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::auth::{
+    generate_token, ACCOUNT_LINK_TOKEN_KEY_PREFIX, CONFIRM_TOKEN_KEY_PREFIX,
+    EMAIL_CHANGE_TOKEN_KEY_PREFIX, EMAIL_REVERT_TOKEN_KEY_PREFIX, INVITATION_TOKEN_KEY_PREFIX,
+    JWT_DENYLIST_KEY_PREFIX, MAGIC_LINK_TOKEN_KEY_PREFIX, OIDC_STATE_KEY_PREFIX,
+    PASSKEY_LOGIN_KEY_PREFIX, PASSKEY_REGISTRATION_KEY_PREFIX, PENDING_EMAIL_KEY_PREFIX,
+    REFRESH_TOKENS_KEY_PREFIX, SESSIONS_KEY_PREFIX, SESSION_FAMILIES_KEY_PREFIX,
+    TOKEN_FAMILIES_KEY_PREFIX, TOKEN_FAMILY_ID_LENGTH, USER_CONFIRM_TOKEN_KEY_PREFIX,
+    USER_MAGIC_LINK_KEY_PREFIX, USER_SESSIONS_KEY_PREFIX,
+};
+use crate::config::TokenLifetimes;
+use crate::geoip::Location;
 use crate::models::{
-    Company, NewCompany, NewRole, NewUser, NewUserCompanyRole, NewUserRole, Role, RoleCode,
-    UpdatedUserInfo, User, UserCompanyRoles, UserRole, UserType,
+    AccountLink, ApiKey, AuditEvent, AuditEventFilter, Company, CompanyInvitation, ConnectionCount,
+    EmailChange, MagicLink, NewApiKey, NewAuditEvent, NewCompany, NewRecoveryCode, NewRole,
+    NewUser, NewUserCompanyRole, NewUserIdentity, NewUserRole, NewUserTotp, NewWebauthnCredential,
+    OidcAuthorization, PasskeyLoginState, PasskeyRegistrationState, QueuedEmail, Role, RoleCode,
+    TokenFamily, UpdatedUserInfo, User, UserCompanyRoles, UserIdentity, UserRole, UserTotp,
+    UserType, WebauthnCredential,
+};
+use crate::rocket_routes::{CacheConnection, MIGRATIONS};
+use crate::schema::{
+    api_keys, audit_events, companies, recovery_codes, roles, user_company_roles, user_identities,
+    user_roles, user_totp, users, webauthn_credentials,
+};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use diesel::{pg::Pg, prelude::*, Connection as DieselConnection, RunQueryDsl};
+use diesel_migrations::MigrationHarness;
+use rocket_db_pools::deadpool_redis::{
+    self,
+    redis::{self, AsyncCommands, FromRedisValue, RedisError, ToRedisArgs},
 };
-use crate::rocket_routes::CacheConnection;
-use crate::schema::{companies, roles, user_company_roles, user_roles, users};
-use diesel::{prelude::*, RunQueryDsl};
-use rocket_db_pools::deadpool_redis::redis::RedisError;
-use rocket_db_pools::{deadpool_redis::redis::AsyncCommands, Connection};
+use rocket_db_pools::Connection;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Swaps the token pair of a family only if the presented refresh token is still the current one.
+///
+/// KEYS: family, old session, new session, new refresh token, old session family, new session family,
+/// user sessions;
+/// ARGV: presented refresh token, new session id, new refresh token, user id, family id,
+/// session lifetime, refresh token lifetime, last usage timestamp.
+const ROTATE_TOKEN_FAMILY_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[1], 'refresh_token') ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[2], KEYS[5])
+redis.call('SET', KEYS[3], ARGV[4], 'EX', ARGV[6])
+redis.call('SET', KEYS[6], ARGV[5], 'EX', ARGV[6])
+redis.call('SET', KEYS[4], ARGV[5], 'EX', ARGV[7])
+redis.call('HSET', KEYS[1], 'session_id', ARGV[2], 'refresh_token', ARGV[3], 'last_used_at', ARGV[8])
+redis.call('EXPIRE', KEYS[1], ARGV[7])
+redis.call('SADD', KEYS[7], ARGV[5])
+redis.call('EXPIRE', KEYS[7], ARGV[7])
+return 1
+";
+
+/// Moves the emails whose next attempt is due from the retry set back to the queue.
+///
+/// KEYS: retry set, queue;
+/// ARGV: current unix timestamp.
+const PROMOTE_DUE_RETRIES_SCRIPT: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+for _, email in ipairs(due) do
+    redis.call('ZREM', KEYS[1], email)
+    redis.call('LPUSH', KEYS[2], email)
+end
+return #due
+";
+
+/// Moves an email to another list, only by the caller that removed it from its list, e.g. a dead
+/// letter back to the queue by the replay or a processed email to the dead letters by its worker.
+///
+/// KEYS: list of the email, list to push it to;
+/// ARGV: email to remove, email to push.
+const MOVE_EMAIL_SCRIPT: &str = r"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+    return 0
+end
+redis.call('LPUSH', KEYS[2], ARGV[2])
+return 1
+";
+
+/// Moves a processed email to the retry set, only by the worker still holding it.
+///
+/// KEYS: processing list of the worker, retry set;
+/// ARGV: processed email, email to retry, unix timestamp of the retry.
+const SCHEDULE_RETRY_SCRIPT: &str = r"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+    return 0
+end
+redis.call('ZADD', KEYS[2], ARGV[3], ARGV[2])
+return 1
+";
+
+/// Error of a value cached as JSON that cannot be (de)serialized, `what` names the value
+fn malformed(what: &'static str) -> impl Fn(serde_json::Error) -> RedisError {
+    move |e| RedisError::from((redis::ErrorKind::TypeError, what, e.to_string()))
+}
+
+pub struct DatabaseRepository;
+
+impl DatabaseRepository {
+    pub fn ping(c: &mut PgConnection) -> QueryResult<()> {
+        diesel::sql_query("SELECT 1").execute(c).map(|_| ())
+    }
+
+    /// Connections to the current database grouped by their state, e.g. `active` or `idle`
+    pub fn count_connections(c: &mut PgConnection) -> QueryResult<Vec<ConnectionCount>> {
+        diesel::sql_query(
+            "SELECT COALESCE(state, 'unknown') AS state, COUNT(*) AS count \
+             FROM pg_stat_activity WHERE datname = current_database() GROUP BY state",
+        )
+        .load(c)
+    }
+
+    /// Number of the embedded migrations not applied to the database yet
+    pub fn count_pending_migrations(
+        c: &mut PgConnection,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(c.pending_migrations(MIGRATIONS)?.len())
+    }
+}
+
+pub struct CacheRepository;
+
+impl CacheRepository {
+    pub async fn ping(cache: &mut deadpool_redis::Connection) -> Result<(), RedisError> {
+        redis::cmd("PING")
+            .query_async::<_, String>(cache)
+            .await
+            .map(|_| ())
+    }
+}
 
 pub struct UserRepository;
 
@@ -62,13 +187,38 @@ impl UserRepository {
             .map(|user_roles: Vec<(UserRole, Role)>| {
                 user_roles
                     .into_iter()
-                    .map(|(_user_role, role)| (role))
+                    .map(|(_user_role, role)| role)
                     .collect::<Vec<Role>>()
             });
 
         Ok(users.into_iter().zip(roles_by_users).collect())
     }
 
+    pub fn find_page_with_roles(
+        connection: &mut PgConnection,
+        offset: i64,
+        limit: i64,
+    ) -> QueryResult<Vec<(User, Vec<Role>)>> {
+        let users = users::table
+            .order(users::id)
+            .offset(offset)
+            .limit(limit)
+            .load(connection)?;
+
+        let roles_by_users = UserRole::belonging_to(&users)
+            .inner_join(roles::table)
+            .load::<(UserRole, Role)>(connection)?
+            .grouped_by(&users)
+            .into_iter()
+            .map(|user_roles| user_roles.into_iter().map(|(_, role)| role).collect());
+
+        Ok(users.into_iter().zip(roles_by_users).collect())
+    }
+
+    pub fn count(connection: &mut PgConnection) -> QueryResult<i64> {
+        users::table.count().get_result(connection)
+    }
+
     pub fn find_by_email(connection: &mut PgConnection, email: &str) -> QueryResult<User> {
         users::table
             .filter(users::email.eq(email))
@@ -114,6 +264,32 @@ impl UserRepository {
         diesel::delete(users::table.find(id)).execute(connection)
     }
 
+    /// Delete the users who have not confirmed the registration since before the given time
+    pub fn delete_unconfirmed(
+        connection: &mut PgConnection,
+        sent_before: NaiveDateTime,
+    ) -> QueryResult<Vec<User>> {
+        diesel::delete(
+            users::table
+                .filter(users::confirmed.eq(false))
+                .filter(users::confirmation_sent_at.lt(sent_before)),
+        )
+        .get_results(connection)
+    }
+
+    /// Restart the expiry of the unconfirmed user when a new confirmation email is sent
+    pub fn renew_confirmation(connection: &mut PgConnection, id: i32) -> QueryResult<User> {
+        diesel::update(users::table.find(id))
+            .set(users::confirmation_sent_at.eq(Utc::now().naive_utc()))
+            .get_result(connection)
+    }
+
+    pub fn update_email(connection: &mut PgConnection, id: i32, email: &str) -> QueryResult<User> {
+        diesel::update(users::table.find(id))
+            .set(users::email.eq(email))
+            .get_result(connection)
+    }
+
     pub fn confirm_signup(connection: &mut PgConnection, id: i32) -> QueryResult<User> {
         diesel::update(users::table.find(id))
             .set(users::confirmed.eq(true))
@@ -250,37 +426,629 @@ impl RoleRepository {
         let role_ids = user_roles.iter().map(|ur: &UserRole| ur.role_id).collect();
         Self::find_by_ids(connection, role_ids)
     }
+
+    pub fn find_codes_by_user_id(
+        connection: &mut PgConnection,
+        user_id: i32,
+    ) -> QueryResult<Vec<RoleCode>> {
+        user_roles::table
+            .inner_join(roles::table)
+            .filter(user_roles::user_id.eq(user_id))
+            .select(roles::code)
+            .load(connection)
+    }
 }
 
 pub struct SessionRepository;
 
 impl SessionRepository {
-    pub async fn cache_session_id(
-        session_id: &String,
+    pub async fn create_session(
         user_id: i32,
-        mut cache: Connection<CacheConnection>,
-    ) -> Result<(), RedisError> {
-        cache
-            .set_ex::<_, _, ()>(
+        session_id: &str,
+        refresh_token: &str,
+        device: Option<String>,
+        ip: Option<String>,
+        lifetimes: &TokenLifetimes,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<TokenFamily, RedisError> {
+        let now = Utc::now().naive_utc();
+        let family = TokenFamily {
+            id: generate_token(TOKEN_FAMILY_ID_LENGTH),
+            user_id,
+            session_id: session_id.to_string(),
+            refresh_token: refresh_token.to_string(),
+            device,
+            ip,
+            created_at: now,
+            last_used_at: now,
+        };
+        let family_key = format!("{}/{}", TOKEN_FAMILIES_KEY_PREFIX, family.id);
+        let user_sessions_key = format!("{}/{}", USER_SESSIONS_KEY_PREFIX, user_id);
+
+        let mut fields = vec![
+            ("user_id", user_id.to_string()),
+            ("session_id", family.session_id.clone()),
+            ("refresh_token", family.refresh_token.clone()),
+            ("created_at", now.and_utc().timestamp().to_string()),
+            ("last_used_at", now.and_utc().timestamp().to_string()),
+        ];
+        if let Some(device) = &family.device {
+            fields.push(("device", device.clone()));
+        }
+        if let Some(ip) = &family.ip {
+            fields.push(("ip", ip.clone()));
+        }
+
+        redis::pipe()
+            .atomic()
+            .set_ex(
                 format!("{}/{}", SESSIONS_KEY_PREFIX, session_id),
                 user_id,
-                SESSION_LIFE_TIME,
+                lifetimes.session,
+            )
+            .ignore()
+            .set_ex(
+                format!("{}/{}", SESSION_FAMILIES_KEY_PREFIX, session_id),
+                &family.id,
+                lifetimes.session,
+            )
+            .ignore()
+            .set_ex(
+                format!("{}/{}", REFRESH_TOKENS_KEY_PREFIX, refresh_token),
+                &family.id,
+                lifetimes.refresh_token,
+            )
+            .ignore()
+            .hset_multiple(&family_key, &fields)
+            .ignore()
+            .expire(&family_key, lifetimes.refresh_token)
+            .ignore()
+            .sadd(&user_sessions_key, &family.id)
+            .ignore()
+            .expire(&user_sessions_key, lifetimes.refresh_token)
+            .ignore()
+            .query_async::<_, ()>(&mut **cache)
+            .await?;
+
+        Ok(family)
+    }
+
+    pub async fn find_token_family(
+        refresh_token: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<TokenFamily>, RedisError> {
+        let family_id = cache
+            .get::<_, Option<String>>(format!("{}/{}", REFRESH_TOKENS_KEY_PREFIX, refresh_token))
+            .await?;
+
+        match family_id {
+            Some(family_id) => Self::find_token_family_by_id(family_id, cache).await,
+            None => Ok(None),
+        }
+    }
+
+    pub async fn find_token_family_by_session(
+        session_id: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<TokenFamily>, RedisError> {
+        let family_id = cache
+            .get::<_, Option<String>>(format!("{}/{}", SESSION_FAMILIES_KEY_PREFIX, session_id))
+            .await?;
+
+        match family_id {
+            Some(family_id) => Self::find_token_family_by_id(family_id, cache).await,
+            None => Ok(None),
+        }
+    }
+
+    pub async fn find_token_family_by_id(
+        family_id: String,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<TokenFamily>, RedisError> {
+        let fields = cache
+            .hgetall::<_, HashMap<String, String>>(format!(
+                "{}/{}",
+                TOKEN_FAMILIES_KEY_PREFIX, family_id
+            ))
+            .await?;
+
+        let timestamp = |field: &str| {
+            fields
+                .get(field)
+                .and_then(|v| v.parse::<i64>().ok())
+                .and_then(|v| DateTime::from_timestamp(v, 0))
+                .map(|v| v.naive_utc())
+        };
+
+        let family = match (
+            fields.get("user_id").and_then(|v| v.parse::<i32>().ok()),
+            fields.get("session_id"),
+            fields.get("refresh_token"),
+        ) {
+            (Some(user_id), Some(session_id), Some(refresh_token)) => Some(TokenFamily {
+                user_id,
+                session_id: session_id.clone(),
+                refresh_token: refresh_token.clone(),
+                device: fields.get("device").cloned(),
+                ip: fields.get("ip").cloned(),
+                created_at: timestamp("created_at").unwrap_or_default(),
+                last_used_at: timestamp("last_used_at").unwrap_or_default(),
+                id: family_id,
+            }),
+            _ => None,
+        };
+
+        Ok(family)
+    }
+
+    /// Loads every active token family of the user, forgetting the expired ones
+    pub async fn find_user_token_families(
+        user_id: i32,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Vec<TokenFamily>, RedisError> {
+        let user_sessions_key = format!("{}/{}", USER_SESSIONS_KEY_PREFIX, user_id);
+        let family_ids = cache.smembers::<_, Vec<String>>(&user_sessions_key).await?;
+
+        let mut families = Vec::with_capacity(family_ids.len());
+        for family_id in family_ids {
+            match Self::find_token_family_by_id(family_id.clone(), cache).await? {
+                Some(family) => families.push(family),
+                None => {
+                    cache
+                        .srem::<_, _, ()>(&user_sessions_key, family_id)
+                        .await?
+                }
+            }
+        }
+        families.sort_by_key(|family| std::cmp::Reverse(family.last_used_at));
+
+        Ok(families)
+    }
+
+    /// Returns `false` if the family has already been rotated with the presented refresh token
+    pub async fn rotate_token_family(
+        family: &TokenFamily,
+        session_id: &str,
+        refresh_token: &str,
+        lifetimes: &TokenLifetimes,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<bool, RedisError> {
+        redis::cmd("EVAL")
+            .arg(ROTATE_TOKEN_FAMILY_SCRIPT)
+            .arg(7)
+            .arg(format!("{}/{}", TOKEN_FAMILIES_KEY_PREFIX, family.id))
+            .arg(format!("{}/{}", SESSIONS_KEY_PREFIX, family.session_id))
+            .arg(format!("{}/{}", SESSIONS_KEY_PREFIX, session_id))
+            .arg(format!("{}/{}", REFRESH_TOKENS_KEY_PREFIX, refresh_token))
+            .arg(format!(
+                "{}/{}",
+                SESSION_FAMILIES_KEY_PREFIX, family.session_id
+            ))
+            .arg(format!("{}/{}", SESSION_FAMILIES_KEY_PREFIX, session_id))
+            .arg(format!("{}/{}", USER_SESSIONS_KEY_PREFIX, family.user_id))
+            .arg(&family.refresh_token)
+            .arg(session_id)
+            .arg(refresh_token)
+            .arg(family.user_id)
+            .arg(&family.id)
+            .arg(lifetimes.session)
+            .arg(lifetimes.refresh_token)
+            .arg(Utc::now().timestamp())
+            .query_async::<_, bool>(&mut **cache)
+            .await
+    }
+
+    /// The JWTs issued for the family are denied until they expire
+    pub async fn revoke_token_family(
+        family: &TokenFamily,
+        lifetimes: &TokenLifetimes,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        redis::pipe()
+            .atomic()
+            .del(&[
+                format!("{}/{}", SESSIONS_KEY_PREFIX, family.session_id),
+                format!("{}/{}", SESSION_FAMILIES_KEY_PREFIX, family.session_id),
+                format!("{}/{}", REFRESH_TOKENS_KEY_PREFIX, family.refresh_token),
+                format!("{}/{}", TOKEN_FAMILIES_KEY_PREFIX, family.id),
+            ])
+            .ignore()
+            .set_ex(
+                format!("{}/{}", JWT_DENYLIST_KEY_PREFIX, family.id),
+                family.user_id,
+                lifetimes.access_token(),
             )
+            .ignore()
+            .srem(
+                format!("{}/{}", USER_SESSIONS_KEY_PREFIX, family.user_id),
+                &family.id,
+            )
+            .ignore()
+            .query_async::<_, ()>(&mut **cache)
             .await
     }
 
-    pub async fn cache_token(
-        token: &str,
+    /// Revokes every token family of the user except the one with `except_family_id`
+    pub async fn revoke_user_token_families(
         user_id: i32,
+        except_family_id: Option<&str>,
+        lifetimes: &TokenLifetimes,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        let families = Self::find_user_token_families(user_id, cache).await?;
+
+        for family in families
+            .iter()
+            .filter(|family| Some(family.id.as_str()) != except_family_id)
+        {
+            Self::revoke_token_family(family, lifetimes, cache).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn is_token_family_denied(
+        family_id: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<bool, RedisError> {
+        cache
+            .exists(format!("{}/{}", JWT_DENYLIST_KEY_PREFIX, family_id))
+            .await
+    }
+
+    pub async fn cache_token<V: ToRedisArgs + Send + Sync>(
+        token: &str,
+        value: V,
         prefix: &str,
         lifetime: usize,
-        mut cache: Connection<CacheConnection>,
+        cache: &mut Connection<CacheConnection>,
     ) -> Result<(), RedisError> {
         cache
-            .set_ex::<_, _, ()>(format!("{}/{}", prefix, token), user_id, lifetime)
+            .set_ex::<_, _, ()>(format!("{}/{}", prefix, token), value, lifetime)
             .await
     }
 
+    /// Cache the value as JSON under the token, `what` names the value in the errors
+    async fn cache_json<T: Serialize>(
+        token: &str,
+        value: &T,
+        prefix: &str,
+        lifetime: usize,
+        what: &'static str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        let value = serde_json::to_string(value).map_err(malformed(what))?;
+        Self::cache_token(token, value, prefix, lifetime, cache).await
+    }
+
+    /// The value cached as JSON under the token
+    async fn find_json<T: DeserializeOwned>(
+        token: &str,
+        prefix: &str,
+        what: &'static str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<T>, RedisError> {
+        let value = cache
+            .get::<_, Option<String>>(format!("{}/{}", prefix, token))
+            .await?;
+        value
+            .map(|value| serde_json::from_str(&value).map_err(malformed(what)))
+            .transpose()
+    }
+
+    /// Redeem the token and return the value it was cached as JSON with
+    async fn take_json<T: DeserializeOwned>(
+        token: &str,
+        prefix: &str,
+        what: &'static str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<T>, RedisError> {
+        let value = Self::take_token::<Option<String>>(token, prefix, cache).await?;
+        value
+            .map(|value| serde_json::from_str(&value).map_err(malformed(what)))
+            .transpose()
+    }
+
+    /// Cache the signup confirmation token of the user, the previously issued one is invalidated
+    pub async fn cache_confirm_token(
+        token: &str,
+        user_id: i32,
+        lifetime: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        let user_key = format!("{}/{}", USER_CONFIRM_TOKEN_KEY_PREFIX, user_id);
+        let previous_token = cache.get::<_, Option<String>>(&user_key).await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if let Some(previous_token) = previous_token {
+            pipe.del(format!("{}/{}", CONFIRM_TOKEN_KEY_PREFIX, previous_token))
+                .ignore();
+        }
+        pipe.set_ex(
+            format!("{}/{}", CONFIRM_TOKEN_KEY_PREFIX, token),
+            user_id,
+            lifetime,
+        )
+        .ignore()
+        .set_ex(user_key, token, lifetime)
+        .ignore()
+        .query_async::<_, ()>(&mut **cache)
+        .await
+    }
+
+    pub async fn cache_invitation(
+        token: &str,
+        invitation: &CompanyInvitation,
+        lifetime: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        Self::cache_json(
+            token,
+            invitation,
+            INVITATION_TOKEN_KEY_PREFIX,
+            lifetime,
+            "Malformed company invitation",
+            cache,
+        )
+        .await
+    }
+
+    pub async fn find_invitation(
+        token: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<CompanyInvitation>, RedisError> {
+        Self::find_json(
+            token,
+            INVITATION_TOKEN_KEY_PREFIX,
+            "Malformed company invitation",
+            cache,
+        )
+        .await
+    }
+
+    pub async fn cache_oidc_authorization(
+        state: &str,
+        authorization: &OidcAuthorization,
+        lifetime: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        Self::cache_json(
+            state,
+            authorization,
+            OIDC_STATE_KEY_PREFIX,
+            lifetime,
+            "Malformed OpenID Connect authorization",
+            cache,
+        )
+        .await
+    }
+
+    /// Find the authorization started with the state and delete it, the state can only be used once
+    pub async fn take_oidc_authorization(
+        state: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<OidcAuthorization>, RedisError> {
+        Self::take_json(
+            state,
+            OIDC_STATE_KEY_PREFIX,
+            "Malformed OpenID Connect authorization",
+            cache,
+        )
+        .await
+    }
+
+    /// Cache the magic link of the user, the previously requested one is invalidated
+    pub async fn cache_magic_link(
+        token: &str,
+        link: &MagicLink,
+        lifetime: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        let user_key = format!("{}/{}", USER_MAGIC_LINK_KEY_PREFIX, link.user_id);
+        let previous_token = cache.get::<_, Option<String>>(&user_key).await?;
+        let link = serde_json::to_string(link).map_err(malformed("Malformed magic link"))?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if let Some(previous_token) = previous_token {
+            pipe.del(format!(
+                "{}/{}",
+                MAGIC_LINK_TOKEN_KEY_PREFIX, previous_token
+            ))
+            .ignore();
+        }
+        pipe.set_ex(
+            format!("{}/{}", MAGIC_LINK_TOKEN_KEY_PREFIX, token),
+            link,
+            lifetime,
+        )
+        .ignore()
+        .set_ex(user_key, token, lifetime)
+        .ignore()
+        .query_async::<_, ()>(&mut **cache)
+        .await
+    }
+
+    /// Find the magic link and delete it, the link can only be used once
+    pub async fn take_magic_link(
+        token: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<MagicLink>, RedisError> {
+        Self::take_json(
+            token,
+            MAGIC_LINK_TOKEN_KEY_PREFIX,
+            "Malformed magic link",
+            cache,
+        )
+        .await
+    }
+
+    /// Cache the passkey registration of the user, replacing the one started before
+    pub async fn cache_passkey_registration(
+        user_id: i32,
+        state: &PasskeyRegistrationState,
+        lifetime: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        Self::cache_json(
+            &user_id.to_string(),
+            state,
+            PASSKEY_REGISTRATION_KEY_PREFIX,
+            lifetime,
+            "Malformed passkey ceremony",
+            cache,
+        )
+        .await
+    }
+
+    /// Find the passkey registration of the user and delete it, the challenge can only be answered once
+    pub async fn take_passkey_registration(
+        user_id: i32,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<PasskeyRegistrationState>, RedisError> {
+        Self::take_json(
+            &user_id.to_string(),
+            PASSKEY_REGISTRATION_KEY_PREFIX,
+            "Malformed passkey ceremony",
+            cache,
+        )
+        .await
+    }
+
+    pub async fn cache_passkey_login(
+        challenge_id: &str,
+        state: &PasskeyLoginState,
+        lifetime: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        Self::cache_json(
+            challenge_id,
+            state,
+            PASSKEY_LOGIN_KEY_PREFIX,
+            lifetime,
+            "Malformed passkey ceremony",
+            cache,
+        )
+        .await
+    }
+
+    /// Find the passkey login and delete it, the challenge can only be answered once
+    pub async fn take_passkey_login(
+        challenge_id: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<PasskeyLoginState>, RedisError> {
+        Self::take_json(
+            challenge_id,
+            PASSKEY_LOGIN_KEY_PREFIX,
+            "Malformed passkey ceremony",
+            cache,
+        )
+        .await
+    }
+
+    pub async fn cache_account_link(
+        token: &str,
+        link: &AccountLink,
+        lifetime: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        Self::cache_json(
+            token,
+            link,
+            ACCOUNT_LINK_TOKEN_KEY_PREFIX,
+            lifetime,
+            "Malformed account link",
+            cache,
+        )
+        .await
+    }
+
+    pub async fn find_account_link(
+        token: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<AccountLink>, RedisError> {
+        Self::find_json(
+            token,
+            ACCOUNT_LINK_TOKEN_KEY_PREFIX,
+            "Malformed account link",
+            cache,
+        )
+        .await
+    }
+
+    /// Cache the email change under its confirmation and revert tokens,
+    /// the confirmation token replaces the pending email change of the user
+    pub async fn cache_email_change(
+        change: &EmailChange,
+        confirm_token: &str,
+        revert_token: &str,
+        lifetimes: &TokenLifetimes,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        let value = serde_json::to_string(change).map_err(malformed("Malformed email change"))?;
+
+        redis::pipe()
+            .atomic()
+            .set_ex(
+                format!("{}/{}", EMAIL_CHANGE_TOKEN_KEY_PREFIX, confirm_token),
+                &value,
+                lifetimes.email_change_token,
+            )
+            .ignore()
+            .set_ex(
+                format!("{}/{}", PENDING_EMAIL_KEY_PREFIX, change.user_id),
+                confirm_token,
+                lifetimes.email_change_token,
+            )
+            .ignore()
+            .set_ex(
+                format!("{}/{}", EMAIL_REVERT_TOKEN_KEY_PREFIX, revert_token),
+                &value,
+                lifetimes.email_revert_token,
+            )
+            .ignore()
+            .query_async::<_, ()>(&mut **cache)
+            .await
+    }
+
+    /// The email change cached under the confirmation or the revert token
+    pub async fn find_email_change(
+        token: &str,
+        prefix: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<EmailChange>, RedisError> {
+        Self::find_json(token, prefix, "Malformed email change", cache).await
+    }
+
+    /// Confirmation token of the pending email change of the user
+    pub async fn find_pending_email_change(
+        user_id: i32,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<Option<String>, RedisError> {
+        cache
+            .get(format!("{}/{}", PENDING_EMAIL_KEY_PREFIX, user_id))
+            .await
+    }
+
+    /// Count a failed attempt to use the token, the counter lives as long as the token itself
+    pub async fn count_failed_attempt(
+        token: &str,
+        prefix: &str,
+        lifetime: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<i64, RedisError> {
+        let key = format!("{}/{}", prefix, token);
+        let (attempts,): (i64,) = redis::pipe()
+            .atomic()
+            .incr(&key, 1)
+            .expire(&key, lifetime)
+            .ignore()
+            .query_async(&mut **cache)
+            .await?;
+        Ok(attempts)
+    }
+
     pub async fn redeem_token(
         token: &str,
         prefix: &str,
@@ -288,6 +1056,24 @@ impl SessionRepository {
     ) -> Result<(), RedisError> {
         cache.del(format!("{}/{}", prefix, token)).await
     }
+
+    /// Redeem the token like `redeem_token` and return the value it was cached with,
+    /// so that only one caller gets it
+    async fn take_token<V: FromRedisValue>(
+        token: &str,
+        prefix: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<V, RedisError> {
+        let key = format!("{}/{}", prefix, token);
+        let (value,) = redis::pipe()
+            .atomic()
+            .get(&key)
+            .del(&key)
+            .ignore()
+            .query_async::<_, (V,)>(&mut **cache)
+            .await?;
+        Ok(value)
+    }
 }
 
 pub struct CompanyRepository;
@@ -299,6 +1085,18 @@ impl CompanyRepository {
             .get_result::<Company>(connection)
     }
 
+    pub fn find(connection: &mut PgConnection, id: i32) -> QueryResult<Company> {
+        companies::table.find(id).get_result(connection)
+    }
+
+    /// Find the company and lock it until the end of the transaction
+    pub fn find_for_update(connection: &mut PgConnection, id: i32) -> QueryResult<Company> {
+        companies::table
+            .find(id)
+            .for_update()
+            .get_result(connection)
+    }
+
     pub fn find_by_name(connection: &mut PgConnection, name: &str) -> QueryResult<Company> {
         companies::table
             .filter(companies::name.eq(name))
@@ -316,10 +1114,102 @@ impl CompanyRepository {
             .get_results(connection)
     }
 
+    pub fn update(
+        connection: &mut PgConnection,
+        id: i32,
+        company: NewCompany,
+    ) -> QueryResult<Company> {
+        diesel::update(companies::table.find(id))
+            .set(company)
+            .get_result(connection)
+    }
+
     pub fn delete(connection: &mut PgConnection, id: i32) -> QueryResult<usize> {
         diesel::delete(companies::table.find(id)).execute(connection)
     }
 
+    /// Users having at least one role within the company, ordered by id
+    pub fn find_members(
+        connection: &mut PgConnection,
+        company_id: i32,
+    ) -> QueryResult<Vec<(User, Vec<Role>)>> {
+        let records = user_company_roles::table
+            .filter(user_company_roles::company_id.eq(company_id))
+            .inner_join(users::table)
+            .inner_join(roles::table)
+            .order((users::id, roles::id))
+            .select((users::all_columns, roles::all_columns))
+            .load::<(User, Role)>(connection)?;
+
+        let mut members: Vec<(User, Vec<Role>)> = Vec::new();
+        for (user, role) in records {
+            match members.last_mut() {
+                Some((member, roles)) if member.id == user.id => roles.push(role),
+                _ => members.push((user, vec![role])),
+            }
+        }
+        Ok(members)
+    }
+
+    pub fn find_member_roles(
+        connection: &mut PgConnection,
+        company_id: i32,
+        user_id: i32,
+    ) -> QueryResult<Vec<Role>> {
+        user_company_roles::table
+            .filter(
+                user_company_roles::company_id
+                    .eq(company_id)
+                    .and(user_company_roles::user_id.eq(user_id)),
+            )
+            .inner_join(roles::table)
+            .select(roles::all_columns)
+            .load::<Role>(connection)
+    }
+
+    /// Replace the roles of the user within the company, global roles are left untouched
+    pub fn set_member_roles(
+        connection: &mut PgConnection,
+        company_id: i32,
+        user_id: i32,
+        role_codes: &[RoleCode],
+    ) -> QueryResult<()> {
+        Self::remove_member(connection, company_id, user_id)?;
+
+        for role_code in role_codes {
+            let role = if let Ok(role) = RoleRepository::find_by_code(connection, role_code) {
+                role
+            } else {
+                RoleRepository::create_by_code(connection, role_code)?
+            };
+
+            diesel::insert_into(user_company_roles::table)
+                .values(NewUserCompanyRole {
+                    user_id,
+                    company_id,
+                    role_id: role.id,
+                })
+                .on_conflict_do_nothing()
+                .execute(connection)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_member(
+        connection: &mut PgConnection,
+        company_id: i32,
+        user_id: i32,
+    ) -> QueryResult<usize> {
+        diesel::delete(
+            user_company_roles::table.filter(
+                user_company_roles::company_id
+                    .eq(company_id)
+                    .and(user_company_roles::user_id.eq(user_id)),
+            ),
+        )
+        .execute(connection)
+    }
+
     pub fn add_user(
         connection: &mut PgConnection,
         company: Company,
@@ -378,3 +1268,737 @@ impl CompanyRepository {
         Self::find_by_ids(connection, company_ids)
     }
 }
+
+pub struct IdentityRepository;
+
+impl IdentityRepository {
+    pub fn find(
+        connection: &mut PgConnection,
+        provider: &str,
+        subject: &str,
+    ) -> QueryResult<UserIdentity> {
+        user_identities::table
+            .filter(user_identities::provider.eq(provider))
+            .filter(user_identities::subject.eq(subject))
+            .first(connection)
+    }
+
+    pub fn create(
+        connection: &mut PgConnection,
+        identity: NewUserIdentity,
+    ) -> QueryResult<UserIdentity> {
+        diesel::insert_into(user_identities::table)
+            .values(identity)
+            .get_result(connection)
+    }
+
+    /// Create a viewer with the identity linked, the user is confirmed if the provider has verified the email
+    pub fn create_user(
+        connection: &mut PgConnection,
+        new_user: NewUser,
+        identity: NewUserIdentity,
+        confirmed: bool,
+    ) -> QueryResult<User> {
+        connection.transaction(|connection| {
+            let mut user = UserRepository::create(connection, new_user, vec![RoleCode::Viewer])?;
+            if confirmed {
+                user = UserRepository::confirm_signup(connection, user.id)?;
+            }
+
+            Self::create(
+                connection,
+                NewUserIdentity {
+                    user_id: user.id,
+                    ..identity
+                },
+            )?;
+
+            Ok(user)
+        })
+    }
+}
+
+pub struct WebauthnRepository;
+
+impl WebauthnRepository {
+    pub fn find_by_user(
+        connection: &mut PgConnection,
+        user_id: i32,
+    ) -> QueryResult<Vec<WebauthnCredential>> {
+        webauthn_credentials::table
+            .filter(webauthn_credentials::user_id.eq(user_id))
+            .order((
+                webauthn_credentials::created_at.asc(),
+                webauthn_credentials::id.asc(),
+            ))
+            .load(connection)
+    }
+
+    pub fn find_by_credential_id(
+        connection: &mut PgConnection,
+        credential_id: &[u8],
+    ) -> QueryResult<WebauthnCredential> {
+        webauthn_credentials::table
+            .filter(webauthn_credentials::credential_id.eq(credential_id))
+            .first(connection)
+    }
+
+    pub fn create(
+        connection: &mut PgConnection,
+        credential: NewWebauthnCredential,
+    ) -> QueryResult<WebauthnCredential> {
+        diesel::insert_into(webauthn_credentials::table)
+            .values(credential)
+            .get_result(connection)
+    }
+
+    /// Rename a passkey of the user, other users' passkeys are not found
+    pub fn rename(
+        connection: &mut PgConnection,
+        user_id: i32,
+        id: i32,
+        name: &str,
+    ) -> QueryResult<WebauthnCredential> {
+        diesel::update(
+            webauthn_credentials::table
+                .filter(webauthn_credentials::id.eq(id))
+                .filter(webauthn_credentials::user_id.eq(user_id)),
+        )
+        .set(webauthn_credentials::name.eq(name))
+        .get_result(connection)
+    }
+
+    /// Delete a passkey of the user, other users' passkeys are not found
+    pub fn delete(
+        connection: &mut PgConnection,
+        user_id: i32,
+        id: i32,
+    ) -> QueryResult<WebauthnCredential> {
+        diesel::delete(
+            webauthn_credentials::table
+                .filter(webauthn_credentials::id.eq(id))
+                .filter(webauthn_credentials::user_id.eq(user_id)),
+        )
+        .get_result(connection)
+    }
+
+    /// Record a login with the passkey, the credential is replaced if its counter or backup state changed
+    pub fn update_usage(
+        connection: &mut PgConnection,
+        id: i32,
+        passkey: Option<serde_json::Value>,
+    ) -> QueryResult<usize> {
+        let now = Utc::now().naive_utc();
+        let credential = webauthn_credentials::table.find(id);
+        match passkey {
+            Some(passkey) => diesel::update(credential)
+                .set((
+                    webauthn_credentials::passkey.eq(passkey),
+                    webauthn_credentials::last_used_at.eq(now),
+                ))
+                .execute(connection),
+            None => diesel::update(credential)
+                .set(webauthn_credentials::last_used_at.eq(now))
+                .execute(connection),
+        }
+    }
+}
+
+/// Interval the last usage of an API key is recorded with, to not write on every request
+const API_KEY_USAGE_INTERVAL: TimeDelta = TimeDelta::minutes(1);
+
+pub struct ApiKeyRepository;
+
+impl ApiKeyRepository {
+    pub fn find_by_user(connection: &mut PgConnection, user_id: i32) -> QueryResult<Vec<ApiKey>> {
+        api_keys::table
+            .filter(api_keys::user_id.eq(user_id))
+            .order((api_keys::created_at.asc(), api_keys::id.asc()))
+            .load(connection)
+    }
+
+    pub fn find_by_prefix(connection: &mut PgConnection, prefix: &str) -> QueryResult<ApiKey> {
+        api_keys::table
+            .filter(api_keys::prefix.eq(prefix))
+            .first(connection)
+    }
+
+    pub fn create(connection: &mut PgConnection, api_key: NewApiKey) -> QueryResult<ApiKey> {
+        diesel::insert_into(api_keys::table)
+            .values(api_key)
+            .get_result(connection)
+    }
+
+    /// Rename an API key of the user, other users' keys are not found
+    pub fn rename(
+        connection: &mut PgConnection,
+        user_id: i32,
+        id: i32,
+        name: &str,
+    ) -> QueryResult<ApiKey> {
+        diesel::update(
+            api_keys::table
+                .filter(api_keys::id.eq(id))
+                .filter(api_keys::user_id.eq(user_id)),
+        )
+        .set(api_keys::name.eq(name))
+        .get_result(connection)
+    }
+
+    /// Delete an API key of the user, other users' keys are not found
+    pub fn delete(connection: &mut PgConnection, user_id: i32, id: i32) -> QueryResult<ApiKey> {
+        diesel::delete(
+            api_keys::table
+                .filter(api_keys::id.eq(id))
+                .filter(api_keys::user_id.eq(user_id)),
+        )
+        .get_result(connection)
+    }
+
+    /// Record a request with the API key, at most once per minute
+    pub fn update_usage(connection: &mut PgConnection, id: i32) -> QueryResult<usize> {
+        let now = Utc::now().naive_utc();
+        diesel::update(
+            api_keys::table.find(id).filter(
+                api_keys::last_used_at
+                    .is_null()
+                    .or(api_keys::last_used_at.lt(now - API_KEY_USAGE_INTERVAL)),
+            ),
+        )
+        .set(api_keys::last_used_at.eq(now))
+        .execute(connection)
+    }
+}
+
+pub struct TwoFactorRepository;
+
+impl TwoFactorRepository {
+    pub fn find(connection: &mut PgConnection, user_id: i32) -> QueryResult<UserTotp> {
+        user_totp::table.find(user_id).get_result(connection)
+    }
+
+    pub fn is_enabled(connection: &mut PgConnection, user_id: i32) -> QueryResult<bool> {
+        user_totp::table
+            .find(user_id)
+            .select(user_totp::enabled)
+            .first::<bool>(connection)
+            .optional()
+            .map(|enabled| enabled.unwrap_or(false))
+    }
+
+    /// Store a new pending secret, replacing a previous one that has not been verified yet
+    pub fn enroll(
+        connection: &mut PgConnection,
+        user_id: i32,
+        secret: &str,
+    ) -> QueryResult<UserTotp> {
+        diesel::insert_into(user_totp::table)
+            .values(NewUserTotp {
+                user_id,
+                secret: secret.to_string(),
+            })
+            .on_conflict(user_totp::user_id)
+            .do_update()
+            .set((
+                user_totp::secret.eq(secret),
+                user_totp::enabled.eq(false),
+                user_totp::last_used_step.eq(None::<i64>),
+            ))
+            .get_result(connection)
+    }
+
+    /// Enable the second factor and replace the recovery codes of the user
+    pub fn enable(
+        connection: &mut PgConnection,
+        user_id: i32,
+        code_hashes: Vec<String>,
+    ) -> QueryResult<()> {
+        connection.transaction(|connection| {
+            diesel::update(user_totp::table.find(user_id))
+                .set(user_totp::enabled.eq(true))
+                .execute(connection)?;
+
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(connection)?;
+
+            let new_codes = code_hashes
+                .into_iter()
+                .map(|code_hash| NewRecoveryCode { user_id, code_hash })
+                .collect::<Vec<NewRecoveryCode>>();
+
+            diesel::insert_into(recovery_codes::table)
+                .values(new_codes)
+                .execute(connection)?;
+
+            Ok(())
+        })
+    }
+
+    pub fn disable(connection: &mut PgConnection, user_id: i32) -> QueryResult<()> {
+        connection.transaction(|connection| {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(connection)?;
+            diesel::delete(user_totp::table.find(user_id)).execute(connection)?;
+            Ok(())
+        })
+    }
+
+    /// Record the time step of an accepted code, returns false if it is not after the last one
+    pub fn use_totp_step(
+        connection: &mut PgConnection,
+        user_id: i32,
+        step: i64,
+    ) -> QueryResult<bool> {
+        diesel::update(
+            user_totp::table.filter(
+                user_totp::user_id.eq(user_id).and(
+                    user_totp::last_used_step
+                        .is_null()
+                        .or(user_totp::last_used_step.lt(step)),
+                ),
+            ),
+        )
+        .set(user_totp::last_used_step.eq(step))
+        .execute(connection)
+        .map(|updated| updated > 0)
+    }
+
+    /// Mark the recovery code as used, returns false if there is no such unused code
+    pub fn use_recovery_code(
+        connection: &mut PgConnection,
+        user_id: i32,
+        code_hash: &str,
+    ) -> QueryResult<bool> {
+        diesel::update(
+            recovery_codes::table.filter(
+                recovery_codes::user_id
+                    .eq(user_id)
+                    .and(recovery_codes::code_hash.eq(code_hash))
+                    .and(recovery_codes::used_at.is_null()),
+            ),
+        )
+        .set(recovery_codes::used_at.eq(diesel::dsl::now))
+        .execute(connection)
+        .map(|updated| updated == 1)
+    }
+}
+
+pub struct AuditRepository;
+
+impl AuditRepository {
+    pub fn create(connection: &mut PgConnection, event: NewAuditEvent) -> QueryResult<AuditEvent> {
+        diesel::insert_into(audit_events::table)
+            .values(event)
+            .get_result(connection)
+    }
+
+    /// Page of the events matching the filter, the most recent first, and their total number
+    pub fn find_page(
+        connection: &mut PgConnection,
+        filter: &AuditEventFilter,
+        offset: i64,
+        limit: i64,
+    ) -> QueryResult<(Vec<AuditEvent>, i64)> {
+        let events = Self::filtered(filter)
+            .order((audit_events::created_at.desc(), audit_events::id.desc()))
+            .offset(offset)
+            .limit(limit)
+            .load(connection)?;
+        let total = Self::filtered(filter).count().get_result(connection)?;
+
+        Ok((events, total))
+    }
+
+    fn filtered(filter: &AuditEventFilter) -> audit_events::BoxedQuery<'static, Pg> {
+        let mut query = audit_events::table.into_boxed();
+
+        if let Some(actor_id) = filter.actor_id {
+            query = query.filter(audit_events::actor_id.eq(actor_id));
+        }
+        if let Some(target_id) = filter.target_id {
+            query = query.filter(audit_events::target_id.eq(target_id));
+        }
+        if let Some(event_type) = filter.event_type {
+            query = query.filter(audit_events::event_type.eq(event_type.as_str()));
+        }
+        if let Some(from) = filter.from {
+            query = query.filter(audit_events::created_at.ge(from));
+        }
+        if let Some(to) = filter.to {
+            query = query.filter(audit_events::created_at.lt(to));
+        }
+
+        query
+    }
+}
+
+pub struct RateLimitRepository;
+
+impl RateLimitRepository {
+    /// Count a hit in the fixed window started by the first hit, returns the count and the seconds left
+    pub async fn hit(
+        key: &str,
+        window: usize,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(u64, u64), RedisError> {
+        let (count, ttl): (u64, i64) = redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(key)
+            .arg(0)
+            .arg("NX")
+            .arg("EX")
+            .arg(window)
+            .ignore()
+            .incr(key, 1)
+            .ttl(key)
+            .query_async(&mut **cache)
+            .await?;
+        Ok((count, ttl.max(0) as u64))
+    }
+
+    /// The count of the current window and the seconds left, zeros if the window is not started
+    pub async fn find(
+        key: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(u64, u64), RedisError> {
+        let (count, ttl): (Option<u64>, i64) = redis::pipe()
+            .get(key)
+            .ttl(key)
+            .query_async(&mut **cache)
+            .await?;
+        Ok((count.unwrap_or(0), ttl.max(0) as u64))
+    }
+
+    pub async fn reset(
+        key: &str,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), RedisError> {
+        cache.del(key).await
+    }
+}
+
+const LOCATION_KEY_PREFIX: &str = "geoip/";
+
+/// Locations of IP addresses shared by the instances, the addresses the provider does not know
+/// are cached as well
+pub struct LocationRepository;
+
+impl LocationRepository {
+    /// The cached lookup of the address, `None` if it is not cached
+    pub async fn find(
+        ip: IpAddr,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<Option<Option<Location>>, RedisError> {
+        let location = cache
+            .get::<_, Option<String>>(format!("{}{}", LOCATION_KEY_PREFIX, ip))
+            .await?;
+        location
+            .map(|location| {
+                serde_json::from_str(&location).map_err(malformed("Malformed cached location"))
+            })
+            .transpose()
+    }
+
+    pub async fn create(
+        ip: IpAddr,
+        location: &Option<Location>,
+        ttl: usize,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<(), RedisError> {
+        let location =
+            serde_json::to_string(location).map_err(malformed("Malformed cached location"))?;
+        cache
+            .set_ex(format!("{}{}", LOCATION_KEY_PREFIX, ip), location, ttl)
+            .await
+    }
+}
+
+const EMAIL_QUEUE_KEY: &str = "email_queue";
+const EMAIL_PROCESSING_KEY_PREFIX: &str = "email_queue/processing";
+const EMAIL_WORKERS_KEY: &str = "email_queue/workers";
+const EMAIL_WORKER_LEASE_KEY_PREFIX: &str = "email_queue/lease";
+const EMAIL_RETRY_KEY: &str = "email_queue/retry";
+const EMAIL_DEAD_LETTER_KEY: &str = "email_queue/dead_letter";
+
+/// Outbound emails: a list of emails ready to be sent, a list of emails being sent, a sorted set
+/// of failed emails scored by the time of the next attempt and a dead letter list of emails that
+/// ran out of attempts
+///
+/// A popped email stays in the processing list until it is acknowledged, scheduled for a retry or
+/// dead lettered, so that the emails of a crashed worker are sent by the next one.
+pub struct EmailQueueRepository;
+
+impl EmailQueueRepository {
+    pub async fn push(
+        email: &QueuedEmail,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<(), RedisError> {
+        let email = serde_json::to_string(email).map_err(malformed("Malformed queued email"))?;
+        cache.lpush(EMAIL_QUEUE_KEY, email).await
+    }
+
+    /// Move the next email to the processing list of the worker, returns it with the payload to
+    /// settle it with
+    pub async fn pop(
+        worker_id: &str,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<Option<(QueuedEmail, String)>, RedisError> {
+        let payload = cache
+            .lmove::<_, _, Option<String>>(
+                EMAIL_QUEUE_KEY,
+                format!("{}/{}", EMAIL_PROCESSING_KEY_PREFIX, worker_id),
+                redis::Direction::Right,
+                redis::Direction::Left,
+            )
+            .await?;
+        let Some(payload) = payload else {
+            return Ok(None);
+        };
+
+        match serde_json::from_str(&payload) {
+            Ok(email) => Ok(Some((email, payload))),
+            Err(e) => {
+                // A malformed email would be recovered and fail again forever
+                Self::ack(worker_id, &payload, cache).await?;
+                Err(malformed("Malformed queued email")(e))
+            }
+        }
+    }
+
+    /// Remove the delivered email from the processing list of the worker
+    pub async fn ack(
+        worker_id: &str,
+        payload: &str,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<(), RedisError> {
+        cache
+            .lrem(
+                format!("{}/{}", EMAIL_PROCESSING_KEY_PREFIX, worker_id),
+                1,
+                payload,
+            )
+            .await
+    }
+
+    /// Register the worker and extend its lease by `lease` seconds, its processing list is
+    /// recovered by the other workers once the lease expires
+    pub async fn renew_lease(
+        worker_id: &str,
+        lease: usize,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<(), RedisError> {
+        redis::pipe()
+            .atomic()
+            .sadd(EMAIL_WORKERS_KEY, worker_id)
+            .ignore()
+            .set_ex(
+                format!("{}/{}", EMAIL_WORKER_LEASE_KEY_PREFIX, worker_id),
+                1,
+                lease,
+            )
+            .ignore()
+            .query_async(cache)
+            .await
+    }
+
+    /// Unregister the stopped worker, its processing list is empty between deliveries
+    pub async fn release_lease(
+        worker_id: &str,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<(), RedisError> {
+        redis::pipe()
+            .atomic()
+            .srem(EMAIL_WORKERS_KEY, worker_id)
+            .ignore()
+            .del(format!("{}/{}", EMAIL_WORKER_LEASE_KEY_PREFIX, worker_id))
+            .ignore()
+            .query_async(cache)
+            .await
+    }
+
+    /// Move the emails left in the processing lists of the workers whose lease expired back to
+    /// the queue, returns the number of moved emails
+    pub async fn recover_expired_workers(
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<usize, RedisError> {
+        let worker_ids = cache.smembers::<_, Vec<String>>(EMAIL_WORKERS_KEY).await?;
+
+        let mut recovered = 0;
+        for worker_id in worker_ids {
+            let lease_key = format!("{}/{}", EMAIL_WORKER_LEASE_KEY_PREFIX, worker_id);
+            if cache.exists::<_, bool>(lease_key).await? {
+                continue;
+            }
+
+            let processing_key = format!("{}/{}", EMAIL_PROCESSING_KEY_PREFIX, worker_id);
+            while cache
+                .lmove::<_, _, Option<String>>(
+                    &processing_key,
+                    EMAIL_QUEUE_KEY,
+                    redis::Direction::Right,
+                    redis::Direction::Right,
+                )
+                .await?
+                .is_some()
+            {
+                recovered += 1;
+            }
+            cache
+                .srem::<_, _, ()>(EMAIL_WORKERS_KEY, &worker_id)
+                .await?;
+        }
+
+        Ok(recovered)
+    }
+
+    /// Schedule the next attempt to send the processed email at the given unix timestamp,
+    /// nothing is scheduled if the email has been recovered from the worker meanwhile
+    pub async fn schedule_retry(
+        worker_id: &str,
+        email: &QueuedEmail,
+        payload: &str,
+        retry_at: i64,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<bool, RedisError> {
+        let email = serde_json::to_string(email).map_err(malformed("Malformed queued email"))?;
+        redis::cmd("EVAL")
+            .arg(SCHEDULE_RETRY_SCRIPT)
+            .arg(2)
+            .arg(format!("{}/{}", EMAIL_PROCESSING_KEY_PREFIX, worker_id))
+            .arg(EMAIL_RETRY_KEY)
+            .arg(payload)
+            .arg(email)
+            .arg(retry_at)
+            .query_async(cache)
+            .await
+    }
+
+    /// Move the emails whose next attempt is due back to the queue, returns the number of moved emails
+    pub async fn promote_due_retries(
+        now: i64,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<usize, RedisError> {
+        redis::cmd("EVAL")
+            .arg(PROMOTE_DUE_RETRIES_SCRIPT)
+            .arg(2)
+            .arg(EMAIL_RETRY_KEY)
+            .arg(EMAIL_QUEUE_KEY)
+            .arg(now)
+            .query_async(cache)
+            .await
+    }
+
+    /// Move the processed email to the dead letter list, nothing is moved if the email has been
+    /// recovered from the worker meanwhile
+    pub async fn push_dead_letter(
+        worker_id: &str,
+        email: &QueuedEmail,
+        payload: &str,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<bool, RedisError> {
+        let email = serde_json::to_string(email).map_err(malformed("Malformed queued email"))?;
+        redis::cmd("EVAL")
+            .arg(MOVE_EMAIL_SCRIPT)
+            .arg(2)
+            .arg(format!("{}/{}", EMAIL_PROCESSING_KEY_PREFIX, worker_id))
+            .arg(EMAIL_DEAD_LETTER_KEY)
+            .arg(payload)
+            .arg(email)
+            .query_async(cache)
+            .await
+    }
+
+    /// Emails waiting for a retry with the unix timestamp of their next attempt, the earliest first
+    pub async fn find_retries(
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<Vec<(QueuedEmail, i64)>, RedisError> {
+        let emails = cache
+            .zrange_withscores::<_, Vec<(String, i64)>>(EMAIL_RETRY_KEY, 0, -1)
+            .await?;
+
+        emails
+            .iter()
+            .map(|(email, retry_at)| {
+                serde_json::from_str(email)
+                    .map(|email| (email, *retry_at))
+                    .map_err(malformed("Malformed queued email"))
+            })
+            .collect()
+    }
+
+    /// Dead letter emails, the most recent first
+    pub async fn find_dead_letters(
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<Vec<QueuedEmail>, RedisError> {
+        let emails = cache
+            .lrange::<_, Vec<String>>(EMAIL_DEAD_LETTER_KEY, 0, -1)
+            .await?;
+
+        emails
+            .iter()
+            .map(|email| serde_json::from_str(email).map_err(malformed("Malformed queued email")))
+            .collect()
+    }
+
+    /// Move dead letter emails back to the queue with fresh attempts, all of them if no ID is given.
+    /// Returns the number of requeued emails
+    pub async fn replay_dead_letters(
+        id: Option<&str>,
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<usize, RedisError> {
+        let emails = cache
+            .lrange::<_, Vec<String>>(EMAIL_DEAD_LETTER_KEY, 0, -1)
+            .await?;
+
+        let mut replayed = 0;
+        for raw_email in emails {
+            let mut email: QueuedEmail =
+                serde_json::from_str(&raw_email).map_err(malformed("Malformed queued email"))?;
+            if id.is_some_and(|id| id != email.id) {
+                continue;
+            }
+
+            email.attempts = 0;
+            email.last_error = None;
+            let email =
+                serde_json::to_string(&email).map_err(malformed("Malformed queued email"))?;
+
+            let requeued: bool = redis::cmd("EVAL")
+                .arg(MOVE_EMAIL_SCRIPT)
+                .arg(2)
+                .arg(EMAIL_DEAD_LETTER_KEY)
+                .arg(EMAIL_QUEUE_KEY)
+                .arg(&raw_email)
+                .arg(email)
+                .query_async(cache)
+                .await?;
+            if requeued {
+                replayed += 1;
+            }
+        }
+
+        Ok(replayed)
+    }
+
+    /// Number of emails that are queued, being sent, waiting for a retry and dead
+    pub async fn count(
+        cache: &mut deadpool_redis::Connection,
+    ) -> Result<(usize, usize, usize, usize), RedisError> {
+        let worker_ids = cache.smembers::<_, Vec<String>>(EMAIL_WORKERS_KEY).await?;
+        let mut processing = 0;
+        for worker_id in worker_ids {
+            processing += cache
+                .llen::<_, usize>(format!("{}/{}", EMAIL_PROCESSING_KEY_PREFIX, worker_id))
+                .await?;
+        }
+
+        let (queued, retrying, dead) = redis::pipe()
+            .llen(EMAIL_QUEUE_KEY)
+            .zcard(EMAIL_RETRY_KEY)
+            .llen(EMAIL_DEAD_LETTER_KEY)
+            .query_async(cache)
+            .await?;
+        Ok((queued, processing, retrying, dead))
+    }
+}
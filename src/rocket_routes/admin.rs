@@ -0,0 +1,580 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use diesel::Connection as DieselConnection;
+use rocket::serde::json::{serde_json::json, Json, Value};
+use rocket::{futures::TryFutureExt, http::Status, response::status::Custom, State};
+use rocket_db_pools::Connection;
+
+use crate::dto::{AdminNewUserDto, AdminUserDto, RolesDto, UserTypeDto, UsersPageDto};
+use crate::error_responses;
+use crate::errors::{AdminError, AppError, AuthError};
+use crate::{
+    auth::{self, validate_signup_credentials},
+    config::AppConfig,
+    jwt::AccessTokens,
+    models::{AuditEventFilter, AuditEventType, NewUser, Role, RoleCode, User, UserType},
+    repositories::{AuditRepository, RoleRepository, UserRepository},
+    rocket_routes::{CacheConnection, DbConnection},
+};
+
+use super::audit::{events_page_dto, Audit};
+use super::roles::AdminUser;
+use super::{pagination, revoke_outdated_jwts, user_conflict_error};
+
+fn admin_user_dto(user: User, roles: Vec<Role>) -> AdminUserDto {
+    AdminUserDto {
+        id: user.id,
+        username: user.username,
+        email: user.email,
+        first_name: user.first_name,
+        last_name: user.last_name,
+        country: user.country,
+        birth_date: user.birth_date,
+        confirmed: user.confirmed,
+        user_type: user.user_type.to_string(),
+        roles: roles.iter().map(|role| role.code.to_string()).collect(),
+        created_at: user.created_at,
+        updated_at: user.updated_at,
+    }
+}
+
+fn parse_role_codes(codes: &[String]) -> Result<Vec<RoleCode>, AppError> {
+    codes
+        .iter()
+        .map(|code| RoleCode::from_str(code.trim()))
+        .collect::<Result<Vec<RoleCode>, ()>>()
+        .map_err(|_| AppError::from(AdminError::InvalidRole))
+}
+
+fn parse_user_type(code: &str) -> Result<UserType, AppError> {
+    UserType::from_str(code.trim()).map_err(|_| AppError::from(AdminError::InvalidUserType))
+}
+
+fn parse_event_type(code: &str) -> Result<AuditEventType, AppError> {
+    AuditEventType::from_str(code.trim()).map_err(|_| AppError::from(AdminError::InvalidEventType))
+}
+
+/// Parse a date as its midnight or an RFC 3339 date time as UTC
+fn parse_date_time(value: &str) -> Result<NaiveDateTime, AppError> {
+    let value = value.trim();
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(NaiveTime::MIN))
+        .or_else(|_| DateTime::parse_from_rfc3339(value).map(|date| date.naive_utc()))
+        .map_err(|_| AppError::from(AdminError::InvalidDateTime))
+}
+
+fn user_not_found_error(e: diesel::result::Error) -> AppError {
+    match e {
+        diesel::result::Error::NotFound => AppError::from(AdminError::UserNotFound),
+        _ => AppError::from(e),
+    }
+}
+
+fn self_modification_error() -> AppError {
+    AppError::from(AdminError::SelfModification)
+}
+
+async fn find_user_with_roles(db: &DbConnection, id: i32) -> Result<AdminUserDto, AppError> {
+    db.run(move |connection| {
+        let user = UserRepository::find(connection, id)?;
+        let roles = RoleRepository::find_by_user(connection, &user)?;
+        Ok((user, roles))
+    })
+    .map_err(user_not_found_error)
+    .map_ok(|(user, roles)| admin_user_dto(user, roles))
+    .await
+}
+
+error_responses!(ListUsersErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// List users page by page
+///
+/// Pages are numbered from 1, the page size is 20 by default and at most 100.
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    params(
+        ("page" = Option<i64>, Query, description = "Page number, starting from 1"),
+        ("per_page" = Option<i64>, Query, description = "Number of users per page"),
+    ),
+    responses(
+        (status = 200, description = "OK", body = UsersPageDto),
+        ListUsersErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[rocket::get("/admin/users?<page>&<per_page>")]
+pub async fn list_users(
+    page: Option<i64>,
+    per_page: Option<i64>,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+) -> Result<Custom<Value>, AppError> {
+    admin?;
+
+    let (page, per_page, offset) = pagination(page, per_page);
+
+    let (users, total) = db
+        .run(move |connection| {
+            let users = UserRepository::find_page_with_roles(connection, offset, per_page)?;
+            let total = UserRepository::count(connection)?;
+            Ok::<_, diesel::result::Error>((users, total))
+        })
+        .map_err(AppError::from)
+        .await?;
+
+    let items = users
+        .into_iter()
+        .map(|(user, roles)| admin_user_dto(user, roles))
+        .collect();
+
+    Ok(Custom(
+        Status::Ok,
+        json!(UsersPageDto {
+            items,
+            page,
+            per_page,
+            total,
+        }),
+    ))
+}
+
+error_responses!(GetUserErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    AdminError::UserNotFound,
+});
+
+/// Get a user by ID
+#[utoipa::path(
+    get,
+    path = "/admin/users/{id}",
+    params(("id" = i32, Path, description = "ID of the user",)),
+    responses(
+        (status = 200, description = "OK", body = AdminUserDto),
+        GetUserErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[rocket::get("/admin/users/<id>")]
+pub async fn get_user(
+    id: i32,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+) -> Result<Custom<Value>, AppError> {
+    admin?;
+
+    find_user_with_roles(&db, id)
+        .await
+        .map(|user| Custom(Status::Ok, json!(user)))
+}
+
+error_responses!(CreateUserErrors {
+    AuthError::InvalidUsername,
+    AuthError::InvalidEmail,
+    AuthError::InvalidPassword,
+    AuthError::EmailInUse,
+    AuthError::UnavailableUsername,
+    AdminError::InvalidRole,
+    AdminError::InvalidUserType,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// Create a user with the given type and roles
+///
+/// The same validation rules as in signup apply to **username**, **email** and **password**;
+///
+/// Unlike signup, no confirmation email is sent.
+#[utoipa::path(
+    post,
+    path = "/admin/users",
+    request_body = AdminNewUserDto,
+    responses(
+        (status = 201, description = "Created", body = AdminUserDto),
+        CreateUserErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[rocket::post("/admin/users", format = "json", data = "<user_dto>")]
+pub async fn create_user(
+    user_dto: Json<AdminNewUserDto>,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let admin = admin?;
+
+    let user_dto = user_dto.into_inner();
+    let mut new_user = NewUser {
+        username: user_dto.username,
+        email: user_dto.email,
+        password: user_dto.password,
+    };
+
+    if let Err(e) = validate_signup_credentials(&new_user) {
+        return Err(AppError::from(e));
+    }
+
+    let role_codes = match user_dto.roles {
+        Some(codes) => parse_role_codes(&codes)?,
+        None => vec![RoleCode::Viewer],
+    };
+    let user_type = parse_user_type(user_dto.user_type.as_deref().unwrap_or("regular"))?;
+    let confirmed = user_dto.confirmed;
+
+    new_user.password = auth::hash_password(new_user.password).unwrap();
+
+    let user = db
+        .run(move |connection| {
+            connection.transaction(|connection| {
+                let user = UserRepository::create(connection, new_user, role_codes)?;
+                if confirmed {
+                    UserRepository::confirm_signup(connection, user.id)?;
+                }
+                let user = UserRepository::set_user_type(connection, user.id, &user_type)?;
+                let roles = RoleRepository::find_by_user(connection, &user)?;
+                Ok((user, roles))
+            })
+        })
+        .map_err(user_conflict_error)
+        .map_ok(|(user, roles)| admin_user_dto(user, roles))
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::UserCreated,
+            Some(admin.id),
+            Some(user.id),
+            json!({
+                "username": user.username,
+                "email": user.email,
+                "user_type": user.user_type,
+                "roles": user.roles,
+                "confirmed": user.confirmed,
+            }),
+        )
+        .await;
+
+    Ok(Custom(Status::Created, json!(user)))
+}
+
+error_responses!(DeleteUserErrors {
+    AdminError::SelfModification,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    AdminError::UserNotFound,
+});
+
+/// Delete a user by ID
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{id}",
+    params(("id" = i32, Path, description = "ID of the user to delete",)),
+    responses(
+        (status = 204),
+        DeleteUserErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[rocket::delete("/admin/users/<id>")]
+pub async fn delete_user(
+    id: i32,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let admin = admin?;
+    if admin.id == id {
+        return Err(self_modification_error());
+    }
+
+    let deleted = db
+        .run(move |connection| UserRepository::delete(connection, id))
+        .map_err(AppError::from)
+        .await?;
+
+    if deleted == 0 {
+        return Err(AppError::from(AdminError::UserNotFound));
+    }
+
+    revoke_outdated_jwts(id, access_tokens, &config.tokens, &mut cache).await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::UserDeleted,
+            Some(admin.id),
+            Some(id),
+            json!({}),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
+
+error_responses!(SetUserTypeErrors {
+    AdminError::InvalidUserType,
+    AdminError::SelfModification,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    AdminError::UserNotFound,
+});
+
+/// Set the type of a user
+#[utoipa::path(
+    put,
+    path = "/admin/users/{id}/type",
+    params(("id" = i32, Path, description = "ID of the user",)),
+    request_body = UserTypeDto,
+    responses(
+        (status = 200, description = "OK", body = AdminUserDto),
+        SetUserTypeErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::put("/admin/users/<id>/type", format = "json", data = "<user_type_dto>")]
+pub async fn set_user_type(
+    id: i32,
+    user_type_dto: Json<UserTypeDto>,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let admin = admin?;
+    if admin.id == id {
+        return Err(self_modification_error());
+    }
+
+    let user_type = parse_user_type(&user_type_dto.user_type)?;
+
+    let user = db
+        .run(move |connection| UserRepository::set_user_type(connection, id, &user_type))
+        .map_err(user_not_found_error)
+        .await?;
+
+    revoke_outdated_jwts(id, access_tokens, &config.tokens, &mut cache).await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::UserTypeChanged,
+            Some(admin.id),
+            Some(id),
+            json!({ "user_type": user.user_type.to_string() }),
+        )
+        .await;
+
+    find_user_with_roles(&db, id)
+        .await
+        .map(|user| Custom(Status::Ok, json!(user)))
+}
+
+error_responses!(AddRolesErrors {
+    AdminError::InvalidRole,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    AdminError::UserNotFound,
+});
+
+/// Add roles to a user
+///
+/// Existing roles are kept; for enterprise users the roles are also granted in their companies.
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/roles",
+    params(("id" = i32, Path, description = "ID of the user",)),
+    request_body = RolesDto,
+    responses(
+        (status = 200, description = "OK", body = AdminUserDto),
+        AddRolesErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[rocket::post("/admin/users/<id>/roles", format = "json", data = "<roles_dto>")]
+pub async fn add_roles(
+    id: i32,
+    roles_dto: Json<RolesDto>,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let admin = admin?;
+
+    let role_codes = parse_role_codes(&roles_dto.roles)?;
+    let roles = role_codes
+        .iter()
+        .map(RoleCode::to_string)
+        .collect::<Vec<String>>();
+
+    db.run(move |connection| {
+        let user = UserRepository::find(connection, id)?;
+        UserRepository::add_roles(connection, &user, &role_codes)
+    })
+    .map_err(user_not_found_error)
+    .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::RolesAdded,
+            Some(admin.id),
+            Some(id),
+            json!({ "roles": roles }),
+        )
+        .await;
+
+    find_user_with_roles(&db, id)
+        .await
+        .map(|user| Custom(Status::Ok, json!(user)))
+}
+
+error_responses!(RemoveRolesErrors {
+    AdminError::InvalidRole,
+    AdminError::SelfModification,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    AdminError::UserNotFound,
+});
+
+/// Remove roles from a user
+///
+/// The roles are also revoked in the companies of the user.
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{id}/roles",
+    params(("id" = i32, Path, description = "ID of the user",)),
+    request_body = RolesDto,
+    responses(
+        (status = 200, description = "OK", body = AdminUserDto),
+        RemoveRolesErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::delete("/admin/users/<id>/roles", format = "json", data = "<roles_dto>")]
+pub async fn remove_roles(
+    id: i32,
+    roles_dto: Json<RolesDto>,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let admin = admin?;
+    if admin.id == id {
+        return Err(self_modification_error());
+    }
+
+    let role_codes = parse_role_codes(&roles_dto.roles)?;
+    let roles = role_codes
+        .iter()
+        .map(RoleCode::to_string)
+        .collect::<Vec<String>>();
+
+    db.run(move |connection| {
+        let user = UserRepository::find(connection, id)?;
+        UserRepository::remove_roles(connection, &user, &role_codes)
+    })
+    .map_err(user_not_found_error)
+    .await?;
+
+    revoke_outdated_jwts(id, access_tokens, &config.tokens, &mut cache).await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::RolesRemoved,
+            Some(admin.id),
+            Some(id),
+            json!({ "roles": roles }),
+        )
+        .await;
+
+    find_user_with_roles(&db, id)
+        .await
+        .map(|user| Custom(Status::Ok, json!(user)))
+}
+
+error_responses!(ListAuditEventsErrors {
+    AdminError::InvalidEventType,
+    AdminError::InvalidDateTime,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// Query the audit log of account events page by page
+///
+/// Every filter is optional; `from` is inclusive and `to` is exclusive, both are dates (`YYYY-MM-DD`)
+/// or RFC 3339 date times;
+///
+/// Events are ordered by time, the most recent first; pages are numbered from 1,
+/// the page size is 20 by default and at most 100.
+#[utoipa::path(
+    get,
+    path = "/admin/audit_events",
+    params(
+        ("actor_id" = Option<i32>, Query, description = "ID of the user who acted"),
+        ("target_id" = Option<i32>, Query, description = "ID of the user the events are about"),
+        ("event_type" = Option<String>, Query, description = "Type of the events, e.g. login_failed"),
+        ("from" = Option<String>, Query, description = "Start of the period, inclusive"),
+        ("to" = Option<String>, Query, description = "End of the period, exclusive"),
+        ("page" = Option<i64>, Query, description = "Page number, starting from 1"),
+        ("per_page" = Option<i64>, Query, description = "Number of events per page"),
+    ),
+    responses(
+        (status = 200, description = "OK", body = AuditEventsPageDto),
+        ListAuditEventsErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::get(
+    "/admin/audit_events?<actor_id>&<target_id>&<event_type>&<from>&<to>&<page>&<per_page>"
+)]
+pub async fn list_audit_events(
+    actor_id: Option<i32>,
+    target_id: Option<i32>,
+    event_type: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+) -> Result<Custom<Value>, AppError> {
+    admin?;
+
+    let filter = AuditEventFilter {
+        actor_id,
+        target_id,
+        event_type: event_type.map(parse_event_type).transpose()?,
+        from: from.map(parse_date_time).transpose()?,
+        to: to.map(parse_date_time).transpose()?,
+    };
+    let (page, per_page, offset) = pagination(page, per_page);
+
+    let (events, total) = db
+        .run(move |connection| AuditRepository::find_page(connection, &filter, offset, per_page))
+        .map_err(AppError::from)
+        .await?;
+
+    Ok(Custom(
+        Status::Ok,
+        json!(events_page_dto(events, page, per_page, total)),
+    ))
+}
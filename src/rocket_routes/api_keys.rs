@@ -0,0 +1,272 @@
+use std::str::FromStr;
+
+use chrono::Utc;
+use rocket::{
+    futures::TryFutureExt,
+    http::Status,
+    response::status::Custom,
+    serde::json::{serde_json::json, Json, Value},
+};
+
+use super::{audit::Audit, CurrentSession, DbConnection};
+use crate::error_responses;
+use crate::{
+    auth::{generate_api_key, hash_api_key, MAX_API_KEY_NAME_LENGTH},
+    dto::{ApiKeyDto, ApiKeyNameDto, NewApiKeyDto, NewApiKeyResponseDto},
+    errors::{ApiKeyError, AppError, AuthError},
+    models::{ApiKey, AuditEventType, NewApiKey, RoleCode, User},
+    repositories::ApiKeyRepository,
+};
+
+fn api_key_dto(api_key: ApiKey) -> ApiKeyDto {
+    ApiKeyDto {
+        id: api_key.id,
+        name: api_key.name,
+        prefix: api_key.prefix,
+        scopes: api_key.scopes.iter().map(RoleCode::to_string).collect(),
+        expires_at: api_key.expires_at,
+        last_used_at: api_key.last_used_at,
+        created_at: api_key.created_at,
+    }
+}
+
+fn api_key_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_API_KEY_NAME_LENGTH {
+        return Err(AppError::from(ApiKeyError::InvalidName));
+    }
+    Ok(name.to_string())
+}
+
+fn parse_scopes(codes: &[String]) -> Result<Vec<RoleCode>, AppError> {
+    let invalid_scopes = || AppError::from(ApiKeyError::InvalidScopes);
+
+    if codes.is_empty() {
+        return Err(invalid_scopes());
+    }
+
+    let mut scopes = Vec::new();
+    for code in codes {
+        let scope = RoleCode::from_str(code.trim()).map_err(|_| invalid_scopes())?;
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    Ok(scopes)
+}
+
+fn api_key_error(e: diesel::result::Error) -> AppError {
+    match e {
+        diesel::result::Error::NotFound => AppError::from(ApiKeyError::NotFound),
+        _ => AppError::from(e),
+    }
+}
+
+error_responses!(CreateApiKeyErrors {
+    AuthError::InvalidToken,
+    ApiKeyError::InvalidName,
+    ApiKeyError::InvalidScopes,
+    ApiKeyError::InvalidExpiration,
+});
+
+/// Create an API key of the current user
+///
+/// The key is sent as a Bearer token instead of an auth token, it is only returned in this response;
+///
+/// Requests with the key act with the roles of the user that are granted by its scopes,
+/// e.g. a key with the `viewer` scope cannot edit anything even if the user is an admin;
+///
+/// API keys are managed with an auth token only, not with another API key.
+#[utoipa::path(
+    post,
+    path = "/profile/api-keys",
+    request_body = NewApiKeyDto,
+    responses(
+        (status = 201, description = "Created", body = NewApiKeyResponseDto),
+        CreateApiKeyErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::post("/profile/api-keys", format = "json", data = "<new_key_dto>")]
+pub async fn create_api_key(
+    new_key_dto: Json<NewApiKeyDto>,
+    db: DbConnection,
+    user: Result<User, AppError>,
+    session: Result<CurrentSession, AppError>,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let user = user?;
+    session?;
+
+    let name = api_key_name(&new_key_dto.name)?;
+    let scopes = parse_scopes(&new_key_dto.scopes)?;
+    let expires_at = new_key_dto.expires_at;
+    if expires_at.is_some_and(|expires_at| expires_at <= Utc::now().naive_utc()) {
+        return Err(AppError::from(ApiKeyError::InvalidExpiration));
+    }
+
+    let (prefix, key) = generate_api_key();
+    let new_api_key = NewApiKey {
+        user_id: user.id,
+        name,
+        prefix,
+        key_hash: hash_api_key(&key),
+        scopes,
+        expires_at,
+    };
+    let api_key = db
+        .run(move |connection| ApiKeyRepository::create(connection, new_api_key))
+        .map_err(AppError::from)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::ApiKeyCreated,
+            Some(user.id),
+            Some(user.id),
+            json!({
+                "api_key": api_key.id,
+                "name": api_key.name,
+                "prefix": api_key.prefix,
+                "scopes": api_key.scopes.iter().map(RoleCode::to_string).collect::<Vec<_>>(),
+            }),
+        )
+        .await;
+
+    Ok(Custom(
+        Status::Created,
+        json!(NewApiKeyResponseDto {
+            key,
+            api_key: api_key_dto(api_key),
+        }),
+    ))
+}
+
+error_responses!(ApiKeysErrors {
+    AuthError::InvalidToken,
+});
+
+/// Get the API keys of the current user, expired ones included
+#[utoipa::path(
+    get,
+    path = "/profile/api-keys",
+    responses(
+        (status = 200, description = "OK", body = [ApiKeyDto]),
+        ApiKeysErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::get("/profile/api-keys")]
+pub async fn api_keys(
+    db: DbConnection,
+    user: Result<User, AppError>,
+    session: Result<CurrentSession, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    let user_id = user?.id;
+    session?;
+
+    let api_keys = db
+        .run(move |connection| ApiKeyRepository::find_by_user(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    let api_keys = api_keys.into_iter().map(api_key_dto).collect::<Vec<_>>();
+    Ok(Custom(Status::Ok, json!(api_keys)))
+}
+
+error_responses!(RenameApiKeyErrors {
+    AuthError::InvalidToken,
+    ApiKeyError::InvalidName,
+    ApiKeyError::NotFound,
+});
+
+/// Rename an API key of the current user
+#[utoipa::path(
+    put,
+    path = "/profile/api-keys/{id}",
+    params(("id" = i32, Path, description = "API key id",)),
+    request_body = ApiKeyNameDto,
+    responses(
+        (status = 200, description = "OK", body = ApiKeyDto),
+        RenameApiKeyErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::put("/profile/api-keys/<id>", format = "json", data = "<name_dto>")]
+pub async fn rename_api_key(
+    id: i32,
+    name_dto: Json<ApiKeyNameDto>,
+    db: DbConnection,
+    user: Result<User, AppError>,
+    session: Result<CurrentSession, AppError>,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let user = user?;
+    session?;
+    let name = api_key_name(&name_dto.name)?;
+
+    let user_id = user.id;
+    let api_key = db
+        .run(move |connection| ApiKeyRepository::rename(connection, user_id, id, &name))
+        .map_err(api_key_error)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::ApiKeyRenamed,
+            Some(user.id),
+            Some(user.id),
+            json!({ "api_key": api_key.id, "name": api_key.name }),
+        )
+        .await;
+
+    Ok(Custom(Status::Ok, json!(api_key_dto(api_key))))
+}
+
+error_responses!(DeleteApiKeyErrors {
+    AuthError::InvalidToken,
+    ApiKeyError::NotFound,
+});
+
+/// Delete an API key of the current user, requests with it are rejected right away
+#[utoipa::path(
+    delete,
+    path = "/profile/api-keys/{id}",
+    params(("id" = i32, Path, description = "API key id",)),
+    responses(
+        (status = 204),
+        DeleteApiKeyErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::delete("/profile/api-keys/<id>")]
+pub async fn delete_api_key(
+    id: i32,
+    db: DbConnection,
+    user: Result<User, AppError>,
+    session: Result<CurrentSession, AppError>,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let user = user?;
+    session?;
+
+    let user_id = user.id;
+    let api_key = db
+        .run(move |connection| ApiKeyRepository::delete(connection, user_id, id))
+        .map_err(api_key_error)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::ApiKeyDeleted,
+            Some(user.id),
+            Some(user.id),
+            json!({ "api_key": api_key.id, "name": api_key.name, "prefix": api_key.prefix }),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,112 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Value;
+use rocket::Request;
+
+use crate::dto::{AuditEventDto, AuditEventsPageDto};
+use crate::models::{AuditEvent, AuditEventType, NewAuditEvent};
+use crate::repositories::AuditRepository;
+
+use super::request_id::RequestId;
+use super::{ClientAddr, DbConnection, UserAgent};
+
+/// Client of the request, recorded with the audit events written while handling it
+pub struct Audit {
+    ip: Option<String>,
+    user_agent: Option<String>,
+    request_id: String,
+}
+
+impl Audit {
+    /// Record an event, the id of the request is added to the payload;
+    ///
+    /// Failures are only logged, the audited action has already succeeded or failed on its own.
+    pub async fn record(
+        &self,
+        db: &DbConnection,
+        event_type: AuditEventType,
+        actor_id: Option<i32>,
+        target_id: Option<i32>,
+        mut payload: Value,
+    ) {
+        if let Value::Object(fields) = &mut payload {
+            fields.insert(
+                "request_id".to_string(),
+                Value::from(self.request_id.as_str()),
+            );
+        }
+
+        let event = NewAuditEvent {
+            actor_id,
+            target_id,
+            event_type,
+            ip: self.ip.clone(),
+            user_agent: self.user_agent.clone(),
+            payload,
+        };
+
+        if let Err(e) = db
+            .run(move |connection| AuditRepository::create(connection, event))
+            .await
+        {
+            log::error!(
+                request_id = self.request_id.as_str();
+                "Cannot record the audit event {}: {}",
+                event_type,
+                e
+            );
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Audit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let ip = request
+            .guard::<ClientAddr>()
+            .await
+            .succeeded()
+            .map(|addr| addr.0.to_string());
+        let user_agent = request
+            .guard::<UserAgent>()
+            .await
+            .succeeded()
+            .and_then(|user_agent| user_agent.0);
+
+        Outcome::Success(Audit {
+            ip,
+            user_agent,
+            request_id: RequestId::of(request).to_string(),
+        })
+    }
+}
+
+/// Page of audit events as answered by the activity and the admin query endpoints
+pub fn events_page_dto(
+    events: Vec<AuditEvent>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+) -> AuditEventsPageDto {
+    let items = events
+        .into_iter()
+        .map(|event| AuditEventDto {
+            id: event.id,
+            actor_id: event.actor_id,
+            target_id: event.target_id,
+            event_type: event.event_type.to_string(),
+            ip: event.ip,
+            user_agent: event.user_agent,
+            payload: event.payload,
+            created_at: event.created_at,
+        })
+        .collect();
+
+    AuditEventsPageDto {
+        items,
+        page,
+        per_page,
+        total,
+    }
+}
@@ -1,31 +1,41 @@
 use super::{
-    server_error, ClientAddr, DbConnection, DEEP_LINK_APP_SCHEME, DEEP_LINK_HOST,
-    DEEP_LINK_SCHEME,
+    audit::Audit,
+    find_email_owner,
+    rate_limit::{
+        ChangePassword, Login, LoginTwoFactor, PasswordReset, RateLimit, ResendConfirmation, Signup,
+    },
+    request_id::RequestId,
+    user_conflict_error, verify_second_factor, ClientAddr, CurrentSession, DbConnection,
+    SessionUser, UserAgent,
 };
+use crate::error_responses;
 use crate::{
     auth::{
         self, generate_token, is_email_valid, is_password_valid, validate_signup_credentials,
-        CONFIRM_EMAIL_PATH, CONFIRM_TOKEN_KEY_PREFIX, CONFIRM_TOKEN_LIFE_TIME, RESET_PASSWORD_PATH,
-        RESET_TOKEN_KEY_PREFIX, RESET_TOKEN_LIFE_TIME, SESSION_ID_LENGTH,
+        CONFIRM_EMAIL_PATH, CONFIRM_TOKEN_KEY_PREFIX, MAX_TWO_FACTOR_ATTEMPTS, RESET_PASSWORD_PATH,
+        RESET_TOKEN_KEY_PREFIX, SESSION_ID_LENGTH, TWO_FACTOR_ATTEMPTS_KEY_PREFIX,
+        TWO_FACTOR_CHALLENGE_KEY_PREFIX,
     },
+    config::{AppConfig, TokenLifetimes},
     dto::{
-        AuthTokenDto, CredentialsDto, NewPasswordDto, NewUserResponseDto, ResetPasswordEmailDto,
+        AuthTokenDto, CredentialsDto, NewPasswordDto, NewUserResponseDto, RefreshTokenDto,
+        ResendConfirmationDto, ResetPasswordEmailDto, TwoFactorChallengeDto, TwoFactorLoginDto,
     },
-    errors::AuthError,
-    mail::{send_confirmation_email, send_reset_password_email},
-    models::{NewUser, RoleCode},
-    repositories::{SessionRepository, UserRepository},
+    errors::{AppError, AuthError, TwoFactorError},
+    geoip::GeoLocator,
+    jwt::AccessTokens,
+    mail::{send_confirmation_email, send_reset_password_email, HtmlMailer},
+    models::{AuditEventType, NewUser, RoleCode, TokenFamily, User},
+    repositories::{RoleRepository, SessionRepository, TwoFactorRepository, UserRepository},
     rocket_routes::CacheConnection,
 };
 
-use chrono::{TimeDelta, Utc};
-use diesel::result::DatabaseErrorKind;
-
 use rocket::{
     futures::TryFutureExt,
     http::Status,
     response::status::Custom,
     serde::json::{serde_json::json, Json, Value},
+    State,
 };
 use rocket_db_pools::{
     deadpool_redis::redis::{ErrorKind, RedisError},
@@ -33,6 +43,17 @@ use rocket_db_pools::{
 };
 use rocket_dyn_templates::{context, Template};
 
+error_responses!(SignupErrors {
+    AuthError::InvalidUsername,
+    AuthError::InvalidEmail,
+    AuthError::InvalidPassword,
+    AuthError::EmailInUse,
+    AuthError::UnavailableUsername,
+    AuthError::WrongCredentials,
+    AuthError::UnconfirmedUser,
+    AuthError::TooManyRequests,
+});
+
 /// Signup with email, username and password
 ///
 /// **Email** and *username* must be unique;
@@ -46,28 +67,28 @@ use rocket_dyn_templates::{context, Template};
     request_body = NewUserDto,
     responses(
         (status = 200, description = "OK", body = NewUserResponseDto),
-        (status = 400, description = "Bad Request", body = AuthError, examples(
-            ("InvalidUsername" = (summary = "errors::AuthError::InvalidUsername", value = json!(AuthError::InvalidUsername.value()))),
-            ("InvalidEmail" = (summary = "errors::AuthError::InvalidEmail", value = json!(AuthError::InvalidEmail.value()))),
-            ("InvalidPassword" = (summary = "errors::AuthError::InvalidPassword", value = json!(AuthError::InvalidPassword.value()))),
-            ("EmailInUse" = (summary = "errors::AuthError::EmailInUse", value = json!(AuthError::EmailInUse.value()))),
-            ("UnavailableUsername" = (summary = "errors::AuthError::UnavailableUsername", value = json!(AuthError::UnavailableUsername.value()))),
-            ("WrongCredentials" = (summary = "errors::AuthError::WrongCredentials", value = json!(AuthError::WrongCredentials.value()))),
-            ("UnconfirmedUser" = (summary = "errors::AuthError::UnconfirmedUser", value = json!(AuthError::UnconfirmedUser.value()))),
-
-        )),
+        SignupErrors,
     )
 
 )]
+#[allow(clippy::too_many_arguments)]
 #[rocket::post("/signup", format = "json", data = "<credentials>")]
 pub async fn signup(
     credentials: Json<NewUser>,
     db: DbConnection,
-    cache: Connection<CacheConnection>,
+    mut cache: Connection<CacheConnection>,
     client_addr: ClientAddr,
-) -> Result<Custom<Value>, Custom<Value>> {
+    geo_locator: &State<GeoLocator>,
+    mailer: &State<HtmlMailer>,
+    request_id: &RequestId,
+    audit: Audit,
+    config: &State<AppConfig>,
+    rate_limit: Result<RateLimit<'_, Signup>, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    rate_limit?;
+
     if let Err(e) = validate_signup_credentials(&credentials) {
-        return Err(Custom(Status::BadRequest, json!(e.value())));
+        return Err(AppError::from(e));
     }
 
     let email = credentials.email.clone();
@@ -82,46 +103,36 @@ pub async fn signup(
 
     let user = db
         .run(move |connection| {
-            UserRepository::create(connection, new_user, vec![RoleCode::Viewer]).map_err(
-                |e| match e {
-                    diesel::result::Error::DatabaseError(
-                        DatabaseErrorKind::UniqueViolation,
-                        error_info,
-                    ) => match error_info.constraint_name() {
-                        Some("users_email_key") => {
-                            Custom(Status::BadRequest, json!(AuthError::EmailInUse.value()))
-                        }
-                        Some("users_username_key") => Custom(
-                            Status::BadRequest,
-                            json!(AuthError::UnavailableUsername.value()),
-                        ),
-                        _ => Custom(
-                            Status::BadRequest,
-                            json!(AuthError::WrongCredentials.value()),
-                        ),
-                    },
-                    _ => server_error(e.into()),
-                },
-            )
+            UserRepository::create(connection, new_user, vec![RoleCode::Viewer])
+                .map_err(user_conflict_error)
         })
         .await?;
 
     let confirm_token = generate_token(SESSION_ID_LENGTH);
 
-    SessionRepository::cache_token(
+    SessionRepository::cache_confirm_token(
         &confirm_token,
         user.id,
-        CONFIRM_TOKEN_KEY_PREFIX,
-        CONFIRM_TOKEN_LIFE_TIME,
-        cache,
+        config.tokens.confirm_token,
+        &mut cache,
     )
     .await
-    .map_err(|e| server_error(e.into()))?;
+    .map_err(AppError::from)?;
 
-    let base_url = std::env::var("BASE_URL").expect("Unable to read base URL from env");
-    let link = format!("{base_url}/{CONFIRM_EMAIL_PATH}/{confirm_token}");
+    let link = format!("{}/{CONFIRM_EMAIL_PATH}/{confirm_token}", config.base_url);
 
-    send_confirmation_email(&user, link, client_addr.0).await;
+    let client_info = geo_locator.client_info(client_addr.0).await;
+    send_confirmation_email(mailer, request_id, &user, link, &client_info).await;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::Signup,
+            Some(user.id),
+            Some(user.id),
+            json!({ "username": user.username, "email": user.email }),
+        )
+        .await;
 
     Ok(Custom(
         Status::Created,
@@ -132,94 +143,592 @@ pub async fn signup(
     ))
 }
 
-async fn check_existence(email: String, db: &DbConnection) -> Result<(), Custom<Value>> {
-    let existing_user = db
-        .run(move |connection| UserRepository::find_by_email(connection, &email).map_err(|_| ()))
-        .await;
+async fn check_existence(email: String, db: &DbConnection) -> Result<(), AppError> {
+    match find_email_owner(email, db).await? {
+        Some(user) if !user.confirmed => Err(AppError::from(AuthError::UnconfirmedUser)),
+        _ => Ok(()),
+    }
+}
 
-    if let Ok(user) = existing_user {
-        if user.confirmed {
-            return Ok(());
-        }
+/// Create a new session with a pair of auth and refresh tokens
+pub async fn issue_session(
+    user: &User,
+    client_addr: ClientAddr,
+    user_agent: UserAgent,
+    db: &DbConnection,
+    access_tokens: &AccessTokens,
+    lifetimes: &TokenLifetimes,
+    cache: &mut Connection<CacheConnection>,
+) -> Result<Value, AppError> {
+    let session_id = generate_token(SESSION_ID_LENGTH);
+    let refresh_token = generate_token(SESSION_ID_LENGTH);
 
-        let current_time = Utc::now().naive_utc();
-        let expiration_time = user
-            .created_at
-            .checked_add_signed(TimeDelta::try_seconds(CONFIRM_TOKEN_LIFE_TIME as i64).unwrap())
-            .unwrap();
-
-        if current_time > expiration_time {
-            let _ = db
-                .run(move |connection| {
-                    UserRepository::delete(connection, user.id).map_err(|e| server_error(e.into()))
-                })
-                .await;
-            return Ok(());
-        } else {
-            return Err(Custom(
-                Status::BadRequest,
-                json!(AuthError::UnconfirmedUser.value()),
-            ));
-        }
+    let family = SessionRepository::create_session(
+        user.id,
+        &session_id,
+        &refresh_token,
+        user_agent.0,
+        Some(client_addr.0.to_string()),
+        lifetimes,
+        cache,
+    )
+    .await
+    .map_err(AppError::from)?;
+
+    let token = access_token(user, &family.id, session_id, db, access_tokens, lifetimes).await?;
+
+    Ok(json!(AuthTokenDto {
+        token,
+        refresh_token,
+        expires_in: lifetimes.access_token(),
+    }))
+}
+
+/// Auth token of the session, the session id itself unless JWTs are enabled
+pub async fn access_token(
+    user: &User,
+    family_id: &str,
+    session_id: String,
+    db: &DbConnection,
+    access_tokens: &AccessTokens,
+    lifetimes: &TokenLifetimes,
+) -> Result<String, AppError> {
+    if !access_tokens.is_jwt() {
+        return Ok(session_id);
     }
-    Ok(())
+
+    let role_owner = user.clone();
+    let roles = db
+        .run(move |connection| RoleRepository::find_by_user(connection, &role_owner))
+        .map_err(AppError::from)
+        .await?;
+
+    access_tokens
+        .issue(user, &roles, family_id, lifetimes.access_token())
+        .map_err(|e| AppError::Internal(e.into()))
 }
 
+/// Record a failed login attempt, `reason` is the error code answered
+pub async fn record_login_failure(
+    audit: &Audit,
+    db: &DbConnection,
+    user_id: Option<i32>,
+    email: &str,
+    reason: &str,
+) {
+    audit
+        .record(
+            db,
+            AuditEventType::LoginFailed,
+            None,
+            user_id,
+            json!({ "email": email, "reason": reason }),
+        )
+        .await;
+}
+
+error_responses!(LoginErrors {
+    AuthError::WrongCredentials,
+    AuthError::EmailNotExist => Status::Unauthorized,
+    AuthError::UnconfirmedUser,
+    AuthError::TooManyRequests,
+});
+
 /// Log in with the given credentials
 ///
-/// Returns an auth token if successful.
+/// Returns an auth token and a refresh token if successful;
+///
+/// The auth token expires after 24 hours, the refresh token after 30 days of inactivity;
+///
+/// If two-factor authentication is enabled, returns a challenge token instead,
+/// it expires after 5 minutes and is exchanged for the tokens at `/login/2fa`.
 #[utoipa::path(
     post,
     path = "/login",
     request_body = CredentialsDto,
     responses(
         (status = 200, description = "OK", body = AuthTokenDto),
-        (status = 401, description = "Unauthorized", body = AuthError, examples(
-            ("WrongCredentials" = (summary = "errors::AuthError::WrongCredentials", value = json!(AuthError::WrongCredentials.value()))),
-            ("EmailNotExist" = (summary = "errors::AuthError::EmailNotExist", value = json!(AuthError::EmailNotExist.value()))),
-            ("UnconfirmedUser" = (summary = "errors::AuthError::UnconfirmedUser", value = json!(AuthError::UnconfirmedUser.value()))),
-        )),
+        (status = 202, description = "Accepted", body = TwoFactorChallengeDto),
+        LoginErrors,
     )
 )]
+#[allow(clippy::too_many_arguments)]
 #[rocket::post("/login", format = "json", data = "<credentials>")]
 pub async fn login(
     credentials: Json<CredentialsDto>,
     db: DbConnection,
-    cache: Connection<CacheConnection>,
-) -> Result<Value, Custom<Value>> {
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    user_agent: UserAgent,
+    audit: Audit,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    rate_limit: Result<RateLimit<'_, Login>, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    let rate_limit = rate_limit?;
+
     let email = credentials.email.clone();
+    let user = match db
+        .run(move |connection| UserRepository::find_by_email(connection, &email))
+        .await
+    {
+        Ok(user) => user,
+        Err(diesel::result::Error::NotFound) => {
+            let error = AuthError::EmailNotExist;
+            record_login_failure(&audit, &db, None, &credentials.email, &error.value().code).await;
+            return Err(AppError::from(error).with_status(Status::Unauthorized));
+        }
+        Err(e) => return Err(AppError::from(e)),
+    };
+
+    if !user.confirmed {
+        let error = AuthError::UnconfirmedUser;
+        record_login_failure(&audit, &db, Some(user.id), &user.email, &error.value().code).await;
+        return Err(AppError::from(error));
+    }
+
+    rate_limit.check_account(user.id, &mut cache).await?;
+
+    if auth::authorize_user(&user, &credentials).is_err() {
+        rate_limit.fail_account(user.id, &mut cache).await?;
+        let error = AuthError::WrongCredentials;
+        record_login_failure(&audit, &db, Some(user.id), &user.email, &error.value().code).await;
+        return Err(AppError::from(error));
+    }
+
+    rate_limit.reset_account(user.id, &mut cache).await?;
+
+    complete_login(
+        &user,
+        json!({}),
+        &db,
+        &mut cache,
+        client_addr,
+        user_agent,
+        &audit,
+        config,
+        access_tokens,
+    )
+    .await
+}
+
+/// Start a session of the authenticated user, or a two-factor challenge if it is enabled;
+/// the fields of `payload` are recorded with the login event
+#[allow(clippy::too_many_arguments)]
+pub async fn complete_login(
+    user: &User,
+    mut payload: Value,
+    db: &DbConnection,
+    cache: &mut Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    user_agent: UserAgent,
+    audit: &Audit,
+    config: &AppConfig,
+    access_tokens: &AccessTokens,
+) -> Result<Custom<Value>, AppError> {
+    let user_id = user.id;
+    let is_two_factor_enabled = db
+        .run(move |connection| TwoFactorRepository::is_enabled(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    if is_two_factor_enabled {
+        let challenge_token = generate_token(SESSION_ID_LENGTH);
+
+        SessionRepository::cache_token(
+            &challenge_token,
+            user.id,
+            TWO_FACTOR_CHALLENGE_KEY_PREFIX,
+            config.tokens.two_factor_challenge,
+            cache,
+        )
+        .await
+        .map_err(AppError::from)?;
+
+        return Ok(Custom(
+            Status::Accepted,
+            json!(TwoFactorChallengeDto {
+                challenge_token,
+                expires_in: config.tokens.two_factor_challenge,
+            }),
+        ));
+    }
+
+    let tokens = issue_session(
+        user,
+        client_addr,
+        user_agent,
+        db,
+        access_tokens,
+        &config.tokens,
+        cache,
+    )
+    .await?;
+
+    if let Value::Object(fields) = &mut payload {
+        fields.insert("two_factor".to_string(), Value::from(false));
+    }
+    audit
+        .record(
+            db,
+            AuditEventType::Login,
+            Some(user.id),
+            Some(user.id),
+            payload,
+        )
+        .await;
+
+    Ok(Custom(Status::Ok, tokens))
+}
+
+error_responses!(LoginTwoFactorErrors {
+    AuthError::InvalidToken,
+    TwoFactorError::InvalidCode => Status::Unauthorized,
+    AuthError::TooManyRequests,
+});
+
+/// Complete the login with a code of the authenticator app or one of the recovery codes
+///
+/// The challenge token is revoked after 5 wrong codes.
+#[utoipa::path(
+    post,
+    path = "/login/2fa",
+    request_body = TwoFactorLoginDto,
+    responses(
+        (status = 200, description = "OK", body = AuthTokenDto),
+        LoginTwoFactorErrors,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/login/2fa", format = "json", data = "<login_dto>")]
+pub async fn login_two_factor(
+    login_dto: Json<TwoFactorLoginDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    user_agent: UserAgent,
+    audit: Audit,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    rate_limit: Result<RateLimit<'_, LoginTwoFactor>, AppError>,
+) -> Result<Value, AppError> {
+    rate_limit?;
+
+    let challenge_token = login_dto.challenge_token.as_str();
+    if challenge_token.len() != SESSION_ID_LENGTH {
+        return Err(AppError::from(AuthError::InvalidToken));
+    }
+
+    let user_id = UserRepository::find_id_by_temporary_token(
+        challenge_token,
+        TWO_FACTOR_CHALLENGE_KEY_PREFIX,
+        &mut cache,
+    )
+    .map_err(|e: RedisError| match e.kind() {
+        ErrorKind::TypeError => AppError::from(AuthError::InvalidToken),
+        _ => AppError::from(e),
+    })
+    .await?;
+
     let user = db
-        .run(move |connection| {
-            UserRepository::find_by_email(connection, &email).map_err(|e| match e {
-                diesel::result::Error::NotFound => Custom(
-                    Status::Unauthorized,
-                    json!(AuthError::EmailNotExist.value()),
-                ),
-                _ => server_error(e.into()),
-            })
+        .run(move |connection| UserRepository::find(connection, user_id))
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::from(AuthError::InvalidToken),
+            _ => AppError::from(e),
         })
         .await?;
 
-    if !user.confirmed {
-        return Err(Custom(
-            Status::BadRequest,
-            json!(AuthError::UnconfirmedUser.value()),
-        ));
+    if !verify_second_factor(&db, &user, &login_dto.code).await? {
+        let attempts = SessionRepository::count_failed_attempt(
+            challenge_token,
+            TWO_FACTOR_ATTEMPTS_KEY_PREFIX,
+            config.tokens.two_factor_challenge,
+            &mut cache,
+        )
+        .await
+        .map_err(AppError::from)?;
+
+        if attempts >= MAX_TWO_FACTOR_ATTEMPTS {
+            log::warn!(
+                "Too many wrong two-factor codes, revoking the login challenge of user {}",
+                user.id
+            );
+            SessionRepository::redeem_token(
+                challenge_token,
+                TWO_FACTOR_CHALLENGE_KEY_PREFIX,
+                &mut cache,
+            )
+            .map_err(AppError::from)
+            .await?;
+        }
+
+        let error = TwoFactorError::InvalidCode;
+        record_login_failure(&audit, &db, Some(user.id), &user.email, &error.value().code).await;
+        return Err(AppError::from(error).with_status(Status::Unauthorized));
     }
 
-    let session_id = auth::authorize_user(&user, &credentials).map_err(|_| {
-        Custom(
-            Status::Unauthorized,
-            json!(AuthError::WrongCredentials.value()),
+    SessionRepository::redeem_token(challenge_token, TWO_FACTOR_CHALLENGE_KEY_PREFIX, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+
+    let tokens = issue_session(
+        &user,
+        client_addr,
+        user_agent,
+        &db,
+        access_tokens,
+        &config.tokens,
+        &mut cache,
+    )
+    .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::Login,
+            Some(user.id),
+            Some(user.id),
+            json!({ "two_factor": true }),
+        )
+        .await;
+
+    Ok(tokens)
+}
+
+error_responses!(LogoutErrors {
+    AuthError::InvalidToken,
+});
+
+/// Log out from the current session
+///
+/// Revokes both the auth token and the refresh token of the current session.
+#[utoipa::path(
+    post,
+    path = "/logout",
+    responses(
+        (status = 204, description = "No Content"),
+        LogoutErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::post("/logout")]
+pub async fn logout(
+    session: CurrentSession,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    audit: Audit,
+    config: &State<AppConfig>,
+) -> Result<Status, AppError> {
+    SessionRepository::revoke_token_family(&session.0, &config.tokens, &mut cache)
+        .await
+        .map_err(AppError::from)?;
+
+    let user_id = session.0.user_id;
+    audit
+        .record(
+            &db,
+            AuditEventType::Logout,
+            Some(user_id),
+            Some(user_id),
+            json!({ "session": session.0.id }),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
+
+error_responses!(LogoutAllErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// Log out from every session of the current user
+#[utoipa::path(
+    post,
+    path = "/logout/all",
+    responses(
+        (status = 204, description = "No Content"),
+        LogoutAllErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::post("/logout/all")]
+pub async fn logout_all(
+    user: Result<SessionUser, AppError>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    audit: Audit,
+    config: &State<AppConfig>,
+) -> Result<Status, AppError> {
+    let user = user?;
+
+    SessionRepository::revoke_user_token_families(user.id, None, &config.tokens, &mut cache)
+        .await
+        .map_err(AppError::from)?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::LogoutAll,
+            Some(user.id),
+            Some(user.id),
+            json!({}),
         )
-    })?;
+        .await;
 
-    SessionRepository::cache_session_id(&session_id, user.id, cache)
+    Ok(Status::NoContent)
+}
+
+async fn record_refresh_token_reuse(audit: &Audit, db: &DbConnection, family: &TokenFamily) {
+    audit
+        .record(
+            db,
+            AuditEventType::RefreshTokenReused,
+            None,
+            Some(family.user_id),
+            json!({ "session": family.id }),
+        )
+        .await;
+}
+
+error_responses!(RefreshTokenErrors {
+    AuthError::InvalidToken,
+});
+
+/// Exchange a refresh token for a new pair of tokens
+///
+/// Both the auth token and the refresh token are rotated, the used refresh token cannot be exchanged again;
+///
+/// Presenting an already used refresh token revokes every token issued for the same login.
+#[utoipa::path(
+    post,
+    path = "/token/refresh",
+    request_body = RefreshTokenDto,
+    responses(
+        (status = 200, description = "OK", body = AuthTokenDto),
+        RefreshTokenErrors,
+    )
+)]
+#[rocket::post("/token/refresh", format = "json", data = "<refresh_dto>")]
+pub async fn refresh_token(
+    refresh_dto: Json<RefreshTokenDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    audit: Audit,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+) -> Result<Value, AppError> {
+    if refresh_dto.refresh_token.len() != SESSION_ID_LENGTH {
+        return Err(AppError::from(AuthError::InvalidToken));
+    }
+
+    let family = SessionRepository::find_token_family(&refresh_dto.refresh_token, &mut cache)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(|| AppError::from(AuthError::InvalidToken))?;
+
+    if family.refresh_token != refresh_dto.refresh_token {
+        log::warn!(
+            "Refresh token reuse detected, revoking the token family of user {}",
+            family.user_id
+        );
+        SessionRepository::revoke_token_family(&family, &config.tokens, &mut cache)
+            .await
+            .map_err(AppError::from)?;
+        record_refresh_token_reuse(&audit, &db, &family).await;
+        return Err(AppError::from(AuthError::InvalidToken));
+    }
+
+    let user_id = family.user_id;
+    let user = db
+        .run(move |connection| UserRepository::find(connection, user_id))
+        .await;
+
+    let user = match user {
+        Ok(user) => user,
+        Err(e) => {
+            SessionRepository::revoke_token_family(&family, &config.tokens, &mut cache)
+                .await
+                .map_err(AppError::from)?;
+            return Err(match e {
+                diesel::result::Error::NotFound => AppError::from(AuthError::InvalidToken),
+                _ => AppError::from(e),
+            });
+        }
+    };
+
+    let session_id = generate_token(SESSION_ID_LENGTH);
+    let refresh_token = generate_token(SESSION_ID_LENGTH);
+
+    let is_rotated = SessionRepository::rotate_token_family(
+        &family,
+        &session_id,
+        &refresh_token,
+        &config.tokens,
+        &mut cache,
+    )
+    .await
+    .map_err(AppError::from)?;
+
+    if !is_rotated {
+        log::warn!(
+            "Concurrent refresh token reuse detected, revoking the token family of user {}",
+            family.user_id
+        );
+        // The family has been rotated by the concurrent request, its new tokens are revoked too
+        let rotated_family =
+            SessionRepository::find_token_family_by_id(family.id.clone(), &mut cache)
+                .await
+                .map_err(AppError::from)?;
+        SessionRepository::revoke_token_family(
+            rotated_family.as_ref().unwrap_or(&family),
+            &config.tokens,
+            &mut cache,
+        )
         .await
-        .map(|_| json!(AuthTokenDto { token: session_id }))
-        .map_err(|e| server_error(e.into()))
+        .map_err(AppError::from)?;
+        record_refresh_token_reuse(&audit, &db, &family).await;
+        return Err(AppError::from(AuthError::InvalidToken));
+    }
+
+    let token = access_token(
+        &user,
+        &family.id,
+        session_id,
+        &db,
+        access_tokens,
+        &config.tokens,
+    )
+    .await?;
+
+    Ok(json!(AuthTokenDto {
+        token,
+        refresh_token,
+        expires_in: config.tokens.access_token(),
+    }))
+}
+
+/// Public keys the JWT auth tokens are verified with, as a JSON Web Key Set
+///
+/// Only EdDSA keys are listed, the set is empty unless JWTs are enabled.
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    responses(
+        (status = 200, description = "OK", body = Object),
+    )
+)]
+#[rocket::get("/.well-known/jwks.json")]
+pub fn jwks(access_tokens: &State<AccessTokens>) -> Custom<Value> {
+    Custom(Status::Ok, json!(access_tokens.jwks()))
 }
 
+error_responses!(ResetPasswordErrors {
+    AuthError::InvalidEmail,
+    AuthError::EmailNotExist,
+    AuthError::TooManyRequests,
+});
+
 /// Initiate sending a password reset email
 ///
 /// If successful, a deep link with a reset token will be sent to the provided email address;
@@ -233,36 +742,38 @@ pub async fn login(
     request_body = ResetPasswordEmailDto,
     responses(
         (status = 200, description = "OK"),
-        (status = 400, description = "Bad Request", body = AuthError, examples(
-            ("InvalidEmail" = (summary = "errors::AuthError::InvalidEmail", value = json!(AuthError::InvalidEmail.value()))),
-            ("EmailNotExist" = (summary = "errors::AuthError::EmailNotExist", value = json!(AuthError::EmailNotExist.value()))),
-        ))
+        ResetPasswordErrors,
     )
 )]
+#[allow(clippy::too_many_arguments)]
 #[rocket::post("/password_reset", format = "json", data = "<email_dto>")]
 pub async fn reset_password(
     email_dto: Json<ResetPasswordEmailDto>,
     db: DbConnection,
-    cache: Connection<CacheConnection>,
+    mut cache: Connection<CacheConnection>,
     client_addr: ClientAddr,
-) -> Result<Status, Custom<Value>> {
+    geo_locator: &State<GeoLocator>,
+    mailer: &State<HtmlMailer>,
+    request_id: &RequestId,
+    audit: Audit,
+    config: &State<AppConfig>,
+    rate_limit: Result<RateLimit<'_, PasswordReset>, AppError>,
+) -> Result<Status, AppError> {
+    let rate_limit = rate_limit?;
+
     if !is_email_valid(&email_dto.email) {
-        return Err(Custom(
-            Status::BadRequest,
-            json!(AuthError::InvalidEmail.value()),
-        ));
+        return Err(AppError::from(AuthError::InvalidEmail));
     }
 
     let user = db
         .run(move |connection| {
             UserRepository::find_by_email(connection, &email_dto.email).map_err(|e| match e {
-                diesel::result::Error::NotFound => {
-                    Custom(Status::NotFound, json!(AuthError::EmailNotExist.value()))
-                }
-                _ => server_error(e.into()),
+                diesel::result::Error::NotFound => AppError::from(AuthError::EmailNotExist),
+                _ => AppError::from(e),
             })
         })
         .await?;
+    rate_limit.hit_account(user.id, &mut cache).await?;
 
     let reset_token = generate_token(SESSION_ID_LENGTH);
 
@@ -270,21 +781,42 @@ pub async fn reset_password(
         &reset_token,
         user.id,
         RESET_TOKEN_KEY_PREFIX,
-        RESET_TOKEN_LIFE_TIME,
-        cache,
+        config.tokens.reset_token,
+        &mut cache,
     )
     .await
-    .map_err(|e| server_error(e.into()))?;
+    .map_err(AppError::from)?;
 
-    let deep_link =
-        format!("{DEEP_LINK_SCHEME}://{DEEP_LINK_HOST}/{RESET_PASSWORD_PATH}/{reset_token}");
+    let deep_link = config
+        .deep_links
+        .link(&format!("{RESET_PASSWORD_PATH}/{reset_token}"));
+
+    audit
+        .record(
+            &db,
+            AuditEventType::PasswordResetRequested,
+            None,
+            Some(user.id),
+            json!({ "email": user.email }),
+        )
+        .await;
 
-    send_reset_password_email(user, deep_link, client_addr.0).await;
+    let client_info = geo_locator.client_info(client_addr.0).await;
+    send_reset_password_email(mailer, request_id, user, deep_link, &client_info).await;
 
     Ok(Status::Ok)
 }
 
+error_responses!(ChangePasswordErrors {
+    AuthError::InvalidToken,
+    AuthError::InvalidPassword,
+    AuthError::EmailNotExist => Status::Unauthorized,
+    AuthError::TooManyRequests,
+});
+
 /// Change the password in the password reset flow
+///
+/// Every active session of the user is revoked.
 #[utoipa::path(
     put,
     path = "/password/{token}",
@@ -292,11 +824,7 @@ pub async fn reset_password(
     request_body = NewPasswordDto,
     responses(
         (status = 200, description = "OK"),
-        (status = 400, description = "Bad Request", body = AuthError, examples(
-            ("InvalidToken" = (summary = "errors::AuthError::InvalidToken", value = json!(AuthError::InvalidToken.value()))),
-            ("InvalidPassword" = (summary = "errors::AuthError::InvalidPassword", value = json!(AuthError::InvalidPassword.value()))),
-            ("EmailNotExist" = (summary = "errors::AuthError::EmailNotExist", value = json!(AuthError::EmailNotExist.value()))),
-        ))
+        ChangePasswordErrors,
     )
 )]
 #[rocket::put("/password/<token>", format = "json", data = "<password_dto>")]
@@ -305,57 +833,70 @@ pub async fn change_password(
     token: &str,
     db: DbConnection,
     mut cache: Connection<CacheConnection>,
-) -> Result<Status, Custom<Value>> {
+    audit: Audit,
+    config: &State<AppConfig>,
+    rate_limit: Result<RateLimit<'_, ChangePassword>, AppError>,
+) -> Result<Status, AppError> {
+    rate_limit?;
+
     if token.len() != SESSION_ID_LENGTH {
-        return Err(Custom(
-            Status::BadRequest,
-            json!(AuthError::InvalidToken.value()),
-        ));
+        return Err(AppError::from(AuthError::InvalidToken));
     }
 
     let is_confirmation_match = password_dto.password == password_dto.confirmation;
     if !is_confirmation_match || !is_password_valid(&password_dto.password) {
-        return Err(Custom(
-            Status::BadRequest,
-            json!(AuthError::InvalidPassword.value()),
-        ));
+        return Err(AppError::from(AuthError::InvalidPassword));
     }
 
     let user_id =
         UserRepository::find_id_by_temporary_token(token, RESET_TOKEN_KEY_PREFIX, &mut cache)
             .map_err(|e: RedisError| match e.kind() {
-                ErrorKind::TypeError => {
-                    Custom(Status::Unauthorized, json!(AuthError::InvalidToken.value()))
-                }
-                _ => server_error(e.into()),
+                ErrorKind::TypeError => AppError::from(AuthError::InvalidToken),
+                _ => AppError::from(e),
             })
             .await?;
 
     let user = db
         .run(move |connection| UserRepository::find(connection, user_id))
         .map_err(|e| match e {
-            diesel::result::Error::NotFound => Custom(
-                Status::Unauthorized,
-                json!((AuthError::EmailNotExist.value())),
-            ),
-            _ => server_error(e.into()),
+            diesel::result::Error::NotFound => {
+                AppError::from(AuthError::EmailNotExist).with_status(Status::Unauthorized)
+            }
+            _ => AppError::from(e),
         })
         .await?;
 
     let password_hash = auth::hash_password(password_dto.password.clone()).unwrap();
 
-    let _ = db
-        .run(move |connection| UserRepository::update_password(connection, user.id, &password_hash))
-        .map_err(|e| server_error(e.into()))
-        .await;
+    db.run(move |connection| UserRepository::update_password(connection, user.id, &password_hash))
+        .map_err(AppError::from)
+        .await?;
 
     SessionRepository::redeem_token(token, RESET_TOKEN_KEY_PREFIX, &mut cache)
-        .map_err(|e| server_error(e.into()))
+        .map_err(AppError::from)
         .await?;
 
+    SessionRepository::revoke_user_token_families(user_id, None, &config.tokens, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::PasswordReset,
+            Some(user_id),
+            Some(user_id),
+            json!({}),
+        )
+        .await;
+
     Ok(Status::Ok)
 }
 
+error_responses!(ConfirmSignupErrors {
+    AuthError::InvalidToken,
+});
+
 /// Change user status to confirmed;
 ///
 ///Render the confirmation page and deep link (mobile version)
@@ -365,9 +906,7 @@ pub async fn change_password(
     params(("token" = String, Path, description = "The signup confirmation token",)),
     responses(
         (status = 200, description = "OK"),
-        (status = 400, description = "Bad Request", body = AuthError, examples(
-            ("InvalidToken" = (summary = "errors::AuthError::InvalidToken", value = json!(AuthError::InvalidToken.value()))),
-        ))
+        ConfirmSignupErrors,
     )
 )]
 #[rocket::get("/confirm/<token>")]
@@ -375,45 +914,49 @@ pub async fn confirm_signup(
     token: &str,
     db: DbConnection,
     mut cache: Connection<CacheConnection>,
-) -> Result<Template, Custom<Value>> {
+    audit: Audit,
+    config: &State<AppConfig>,
+) -> Result<Template, AppError> {
     if token.len() != SESSION_ID_LENGTH {
-        return Err(Custom(
-            Status::BadRequest,
-            json!(AuthError::InvalidToken.value()),
-        ));
+        return Err(AppError::from(AuthError::InvalidToken));
     }
 
     let user_id =
         UserRepository::find_id_by_temporary_token(token, CONFIRM_TOKEN_KEY_PREFIX, &mut cache)
             .map_err(|e: RedisError| match e.kind() {
-                ErrorKind::TypeError => {
-                    Custom(Status::Unauthorized, json!(AuthError::InvalidToken.value()))
-                }
-                _ => server_error(e.into()),
+                ErrorKind::TypeError => AppError::from(AuthError::InvalidToken),
+                _ => AppError::from(e),
             })
             .await?;
 
     let user = db
         .run(move |connection| UserRepository::find(connection, user_id))
         .map_err(|e| match e {
-            diesel::result::Error::NotFound => Custom(
-                Status::Unauthorized,
-                json!((AuthError::InvalidToken.value())),
-            ),
-            _ => server_error(e.into()),
+            diesel::result::Error::NotFound => AppError::from(AuthError::InvalidToken),
+            _ => AppError::from(e),
         })
         .await?;
 
     if !user.confirmed {
-        let _ = db
+        let confirmed = db
             .run(move |connection| UserRepository::confirm_signup(connection, user.id))
-            .map_err(|e| server_error(e.into()))
             .await;
+
+        if confirmed.is_ok() {
+            audit
+                .record(
+                    &db,
+                    AuditEventType::SignupConfirmed,
+                    Some(user_id),
+                    Some(user_id),
+                    json!({}),
+                )
+                .await;
+        }
     }
 
-    let deep_link = format!("{DEEP_LINK_APP_SCHEME}://{DEEP_LINK_HOST}");
-    let base_url = std::env::var("BASE_URL").expect("Unable to read base URL from env");
-    let link = format!("{base_url}/{CONFIRM_EMAIL_PATH}");
+    let deep_link = config.deep_links.app_link();
+    let link = format!("{}/{CONFIRM_EMAIL_PATH}", config.base_url);
     let context = context! {
      deep_link: &deep_link,
      redirect_link: &link
@@ -423,3 +966,87 @@ pub async fn confirm_signup(
 
     Ok(template)
 }
+
+error_responses!(ResendConfirmationErrors {
+    AuthError::InvalidEmail,
+    AuthError::EmailNotExist,
+    AuthError::AlreadyConfirmed,
+    AuthError::TooManyRequests,
+});
+
+/// Send the signup confirmation email again
+///
+/// The previously sent confirmation link is invalidated;
+///
+/// The new link is valid for 24 hours and the unconfirmed account is kept as long, then the account is deleted and the signup has to be repeated.
+#[utoipa::path(
+    post,
+    path = "/confirm/resend",
+    request_body = ResendConfirmationDto,
+    responses(
+        (status = 200, description = "OK"),
+        ResendConfirmationErrors,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/confirm/resend", format = "json", data = "<email_dto>")]
+pub async fn resend_confirmation(
+    email_dto: Json<ResendConfirmationDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    geo_locator: &State<GeoLocator>,
+    mailer: &State<HtmlMailer>,
+    request_id: &RequestId,
+    audit: Audit,
+    config: &State<AppConfig>,
+    rate_limit: Result<RateLimit<'_, ResendConfirmation>, AppError>,
+) -> Result<Status, AppError> {
+    let rate_limit = rate_limit?;
+
+    if !is_email_valid(&email_dto.email) {
+        return Err(AppError::from(AuthError::InvalidEmail));
+    }
+
+    let user = find_email_owner(email_dto.email.clone(), &db)
+        .await?
+        .ok_or_else(|| AppError::from(AuthError::EmailNotExist))?;
+    rate_limit.hit_account(user.id, &mut cache).await?;
+    if user.confirmed {
+        return Err(AppError::from(AuthError::AlreadyConfirmed));
+    }
+
+    // The account is kept as long as the new token is valid
+    let user_id = user.id;
+    db.run(move |connection| UserRepository::renew_confirmation(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    let confirm_token = generate_token(SESSION_ID_LENGTH);
+
+    SessionRepository::cache_confirm_token(
+        &confirm_token,
+        user.id,
+        config.tokens.confirm_token,
+        &mut cache,
+    )
+    .await
+    .map_err(AppError::from)?;
+
+    let link = format!("{}/{CONFIRM_EMAIL_PATH}/{confirm_token}", config.base_url);
+
+    let client_info = geo_locator.client_info(client_addr.0).await;
+    send_confirmation_email(mailer, request_id, &user, link, &client_info).await;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::ConfirmationResent,
+            None,
+            Some(user.id),
+            json!({ "email": user.email }),
+        )
+        .await;
+
+    Ok(Status::Ok)
+}
@@ -0,0 +1,957 @@
+use std::str::FromStr;
+
+use diesel::result::DatabaseErrorKind;
+use diesel::Connection as DieselConnection;
+use rocket::serde::json::{serde_json::json, Json, Value};
+use rocket::{futures::TryFutureExt, http::Status, response::status::Custom, State};
+use rocket_db_pools::Connection;
+
+use crate::auth::{
+    generate_token, is_email_valid, INVITATION_PATH, INVITATION_TOKEN_KEY_PREFIX, SESSION_ID_LENGTH,
+};
+use crate::config::AppConfig;
+use crate::dto::{
+    CompanyInfoDto, CompanyMemberDto, InvitationDto, NewInvitationDto, NewMemberDto, RolesDto,
+};
+use crate::error_responses;
+use crate::errors::{AppError, AuthError, CompanyError};
+use crate::jwt::AccessTokens;
+use crate::mail::{send_company_invitation_email, HtmlMailer};
+use crate::{
+    models::{
+        AuditEventType, Company, CompanyInvitation, NewCompany, Role, RoleCode, User, UserType,
+    },
+    repositories::{CompanyRepository, SessionRepository, UserRepository},
+    rocket_routes::{ApiKeyScopes, CacheConnection, DbConnection, Principal},
+};
+
+use super::audit::Audit;
+use super::request_id::RequestId;
+use super::revoke_outdated_jwts;
+use super::roles::AdminUser;
+
+const MAX_COMPANY_NAME_LENGTH: usize = 64;
+
+fn company_member_dto(user: User, roles: Vec<Role>) -> CompanyMemberDto {
+    CompanyMemberDto {
+        id: user.id,
+        username: user.username,
+        email: user.email,
+        first_name: user.first_name,
+        last_name: user.last_name,
+        roles: roles.iter().map(|role| role.code.to_string()).collect(),
+    }
+}
+
+fn new_company(company_dto: CompanyInfoDto) -> Result<NewCompany, AppError> {
+    let name = company_dto.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_COMPANY_NAME_LENGTH {
+        return Err(AppError::from(CompanyError::InvalidName));
+    }
+
+    Ok(NewCompany {
+        name,
+        email: company_dto.email,
+        website: company_dto.website,
+        address: company_dto.address,
+    })
+}
+
+fn role_names(codes: &[RoleCode]) -> Vec<String> {
+    codes.iter().map(RoleCode::to_string).collect()
+}
+
+fn parse_role_codes(codes: &[String]) -> Result<Vec<RoleCode>, AppError> {
+    let invalid_role = || AppError::from(CompanyError::InvalidRole);
+
+    if codes.is_empty() {
+        return Err(invalid_role());
+    }
+
+    codes
+        .iter()
+        .map(|code| RoleCode::from_str(code.trim()))
+        .collect::<Result<Vec<RoleCode>, ()>>()
+        .map_err(|_| invalid_role())
+}
+
+fn company_error(e: diesel::result::Error) -> AppError {
+    match e {
+        diesel::result::Error::NotFound => AppError::from(CompanyError::CompanyNotFound),
+        diesel::result::Error::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            ref error_info,
+        ) if error_info.constraint_name() == Some("companies_name_key") => {
+            AppError::from(CompanyError::NameInUse)
+        }
+        _ => AppError::from(e),
+    }
+}
+
+fn self_modification_error() -> AppError {
+    AppError::from(CompanyError::SelfModification)
+}
+
+/// Ensure the user holds the required role in the company and the company exists
+///
+/// Global admins may manage every company, everyone else only acts through their roles
+/// within the company itself; requests with an API key also need a scope granting `required`.
+/// The user is authorized before the company is looked up, so that the ids of the companies
+/// the user has no access to are not revealed.
+async fn authorize_company(
+    db: &DbConnection,
+    principal: &Principal,
+    scopes: &ApiKeyScopes,
+    company_id: i32,
+    required: RoleCode,
+) -> Result<Company, AppError> {
+    let member = principal.clone();
+    let (is_global_admin, company_roles) = db
+        .run(move |connection| {
+            let is_global_admin = member.is_global_admin(connection)?;
+            let company_roles =
+                CompanyRepository::find_member_roles(connection, company_id, member.id)?;
+            Ok::<_, diesel::result::Error>((is_global_admin, company_roles))
+        })
+        .map_err(AppError::from)
+        .await?;
+
+    let has_role = is_global_admin || company_roles.iter().any(|role| role.code.grants(&required));
+    if !has_role || !scopes.allow(&required) {
+        return Err(AppError::from(AuthError::Forbidden));
+    }
+
+    db.run(move |connection| CompanyRepository::find(connection, company_id))
+        .map_err(company_error)
+        .await
+}
+
+async fn find_member(
+    db: &DbConnection,
+    company_id: i32,
+    user_id: i32,
+) -> Result<CompanyMemberDto, AppError> {
+    let (user, roles) = db
+        .run(move |connection| {
+            let roles = CompanyRepository::find_member_roles(connection, company_id, user_id)?;
+            let user = UserRepository::find(connection, user_id)?;
+            Ok::<_, diesel::result::Error>((user, roles))
+        })
+        .map_err(AppError::from)
+        .await?;
+
+    Ok(company_member_dto(user, roles))
+}
+
+error_responses!(ListCompaniesErrors {
+    AuthError::InvalidToken,
+});
+
+/// List companies
+///
+/// Global admins get every company, other users get the companies they are members of.
+#[utoipa::path(
+    get,
+    path = "/companies",
+    responses(
+        (status = 200, description = "OK", body = [CompanyDto]),
+        ListCompaniesErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::get("/companies")]
+pub async fn list_companies(
+    principal: Result<Principal, AppError>,
+    db: DbConnection,
+) -> Result<Custom<Value>, AppError> {
+    let principal = principal?;
+
+    db.run(move |connection| {
+        if principal.is_global_admin(connection)? {
+            CompanyRepository::list(connection)
+        } else {
+            UserRepository::find_companies(connection, principal.id)
+        }
+    })
+    .map_err(AppError::from)
+    .map_ok(|companies| Custom(Status::Ok, json!(companies)))
+    .await
+}
+
+error_responses!(CreateCompanyErrors {
+    CompanyError::InvalidName,
+    CompanyError::NameInUse,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// Create a company
+#[utoipa::path(
+    post,
+    path = "/companies",
+    request_body = CompanyInfoDto,
+    responses(
+        (status = 201, description = "Created", body = CompanyDto),
+        CreateCompanyErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[rocket::post("/companies", format = "json", data = "<company_dto>")]
+pub async fn create_company(
+    company_dto: Json<CompanyInfoDto>,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let admin = admin?;
+
+    let company = new_company(company_dto.into_inner())?;
+
+    let company = db
+        .run(move |connection| CompanyRepository::create(connection, company))
+        .map_err(company_error)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyCreated,
+            Some(admin.id),
+            None,
+            json!({ "company_id": company.id, "name": company.name }),
+        )
+        .await;
+
+    Ok(Custom(Status::Created, json!(company)))
+}
+
+error_responses!(GetCompanyErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    CompanyError::CompanyNotFound,
+});
+
+/// Get a company by ID
+///
+/// Available to the members of the company.
+#[utoipa::path(
+    get,
+    path = "/companies/{id}",
+    params(("id" = i32, Path, description = "ID of the company",)),
+    responses(
+        (status = 200, description = "OK", body = CompanyDto),
+        GetCompanyErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::get("/companies/<id>")]
+pub async fn get_company(
+    id: i32,
+    principal: Result<Principal, AppError>,
+    scopes: &ApiKeyScopes,
+    db: DbConnection,
+) -> Result<Custom<Value>, AppError> {
+    let principal = principal?;
+
+    authorize_company(&db, &principal, scopes, id, RoleCode::Viewer)
+        .await
+        .map(|company| Custom(Status::Ok, json!(company)))
+}
+
+error_responses!(UpdateCompanyErrors {
+    CompanyError::InvalidName,
+    CompanyError::NameInUse,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    CompanyError::CompanyNotFound,
+});
+
+/// Update a company
+///
+/// Available to the admins of the company.
+#[utoipa::path(
+    put,
+    path = "/companies/{id}",
+    params(("id" = i32, Path, description = "ID of the company",)),
+    request_body = CompanyInfoDto,
+    responses(
+        (status = 200, description = "OK", body = CompanyDto),
+        UpdateCompanyErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::put("/companies/<id>", format = "json", data = "<company_dto>")]
+pub async fn update_company(
+    id: i32,
+    company_dto: Json<CompanyInfoDto>,
+    principal: Result<Principal, AppError>,
+    scopes: &ApiKeyScopes,
+    db: DbConnection,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let principal = principal?;
+    authorize_company(&db, &principal, scopes, id, RoleCode::Admin).await?;
+
+    let company = new_company(company_dto.into_inner())?;
+
+    let company = db
+        .run(move |connection| CompanyRepository::update(connection, id, company))
+        .map_err(company_error)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyUpdated,
+            Some(principal.id),
+            None,
+            json!({ "company_id": company.id, "name": company.name }),
+        )
+        .await;
+
+    Ok(Custom(Status::Ok, json!(company)))
+}
+
+error_responses!(DeleteCompanyErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    CompanyError::CompanyNotFound,
+});
+
+/// Delete a company
+///
+/// Available to the admins of the company, memberships are deleted together with it.
+#[utoipa::path(
+    delete,
+    path = "/companies/{id}",
+    params(("id" = i32, Path, description = "ID of the company",)),
+    responses(
+        (status = 204),
+        DeleteCompanyErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::delete("/companies/<id>")]
+pub async fn delete_company(
+    id: i32,
+    principal: Result<Principal, AppError>,
+    scopes: &ApiKeyScopes,
+    db: DbConnection,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let principal = principal?;
+    let company = authorize_company(&db, &principal, scopes, id, RoleCode::Admin).await?;
+
+    db.run(move |connection| CompanyRepository::delete(connection, id))
+        .map_err(company_error)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyDeleted,
+            Some(principal.id),
+            None,
+            json!({ "company_id": company.id, "name": company.name }),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
+
+error_responses!(ListMembersErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    CompanyError::CompanyNotFound,
+});
+
+/// List members of a company
+///
+/// Available to the members of the company.
+#[utoipa::path(
+    get,
+    path = "/companies/{id}/members",
+    params(("id" = i32, Path, description = "ID of the company",)),
+    responses(
+        (status = 200, description = "OK", body = [CompanyMemberDto]),
+        ListMembersErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::get("/companies/<id>/members")]
+pub async fn list_members(
+    id: i32,
+    principal: Result<Principal, AppError>,
+    scopes: &ApiKeyScopes,
+    db: DbConnection,
+) -> Result<Custom<Value>, AppError> {
+    let principal = principal?;
+    authorize_company(&db, &principal, scopes, id, RoleCode::Viewer).await?;
+
+    let members = db
+        .run(move |connection| CompanyRepository::find_members(connection, id))
+        .map_err(AppError::from)
+        .await?;
+
+    let members = members
+        .into_iter()
+        .map(|(user, roles)| company_member_dto(user, roles))
+        .collect::<Vec<CompanyMemberDto>>();
+
+    Ok(Custom(Status::Ok, json!(members)))
+}
+
+error_responses!(AddMemberErrors {
+    CompanyError::InvalidRole,
+    CompanyError::AlreadyMember,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    CompanyError::CompanyNotFound,
+    CompanyError::UserNotFound,
+});
+
+/// Add an enterprise user to a company
+///
+/// Available to the global admins only, the admins of the company invite the users instead.
+/// Unknown emails and users that are not enterprise users get the same error.
+#[utoipa::path(
+    post,
+    path = "/companies/{id}/members",
+    params(("id" = i32, Path, description = "ID of the company",)),
+    request_body = NewMemberDto,
+    responses(
+        (status = 201, description = "Created", body = CompanyMemberDto),
+        AddMemberErrors,
+    ),
+    security(("token"=[]), ("admin_token"=[]))
+)]
+#[rocket::post("/companies/<id>/members", format = "json", data = "<member_dto>")]
+pub async fn add_member(
+    id: i32,
+    member_dto: Json<NewMemberDto>,
+    admin: Result<AdminUser, AppError>,
+    db: DbConnection,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let admin = admin?;
+    db.run(move |connection| CompanyRepository::find(connection, id))
+        .map_err(company_error)
+        .await?;
+
+    let member_dto = member_dto.into_inner();
+    let role_codes = match member_dto.roles {
+        Some(codes) => parse_role_codes(&codes)?,
+        None => vec![RoleCode::Viewer],
+    };
+
+    let member = db
+        .run(move |connection| UserRepository::find_by_email(connection, &member_dto.email))
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::from(CompanyError::UserNotFound),
+            _ => AppError::from(e),
+        })
+        .await?;
+    if member.user_type != UserType::Enterprise {
+        return Err(AppError::from(CompanyError::UserNotFound));
+    }
+    let member_id = member.id;
+    let roles = role_names(&role_codes);
+
+    let added = db
+        .run(move |connection| {
+            connection.transaction(|connection| {
+                if !CompanyRepository::find_member_roles(connection, id, member_id)?.is_empty() {
+                    return Ok(false);
+                }
+                CompanyRepository::set_member_roles(connection, id, member_id, &role_codes)?;
+                Ok(true)
+            })
+        })
+        .map_err(|e: diesel::result::Error| AppError::from(e))
+        .await?;
+
+    if !added {
+        return Err(AppError::from(CompanyError::AlreadyMember));
+    }
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyMemberAdded,
+            Some(admin.id),
+            Some(member_id),
+            json!({ "company_id": id, "roles": roles }),
+        )
+        .await;
+
+    find_member(&db, id, member_id)
+        .await
+        .map(|member| Custom(Status::Created, json!(member)))
+}
+
+error_responses!(UpdateMemberErrors {
+    CompanyError::InvalidRole,
+    CompanyError::SelfModification,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    CompanyError::CompanyNotFound,
+    CompanyError::MemberNotFound,
+});
+
+/// Replace the roles of a company member
+///
+/// Available to the admins of the company.
+#[utoipa::path(
+    put,
+    path = "/companies/{id}/members/{user_id}",
+    params(
+        ("id" = i32, Path, description = "ID of the company",),
+        ("user_id" = i32, Path, description = "ID of the member",),
+    ),
+    request_body = RolesDto,
+    responses(
+        (status = 200, description = "OK", body = CompanyMemberDto),
+        UpdateMemberErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::put(
+    "/companies/<id>/members/<user_id>",
+    format = "json",
+    data = "<roles_dto>"
+)]
+pub async fn update_member(
+    id: i32,
+    user_id: i32,
+    roles_dto: Json<RolesDto>,
+    principal: Result<Principal, AppError>,
+    scopes: &ApiKeyScopes,
+    db: DbConnection,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let principal = principal?;
+    authorize_company(&db, &principal, scopes, id, RoleCode::Admin).await?;
+
+    if principal.id == user_id {
+        return Err(self_modification_error());
+    }
+
+    let role_codes = parse_role_codes(&roles_dto.roles)?;
+    let roles = role_names(&role_codes);
+
+    let updated = db
+        .run(move |connection| {
+            connection.transaction(|connection| {
+                if CompanyRepository::find_member_roles(connection, id, user_id)?.is_empty() {
+                    return Ok(false);
+                }
+                CompanyRepository::set_member_roles(connection, id, user_id, &role_codes)?;
+                Ok(true)
+            })
+        })
+        .map_err(|e: diesel::result::Error| AppError::from(e))
+        .await?;
+
+    if !updated {
+        return Err(AppError::from(CompanyError::MemberNotFound));
+    }
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyMemberUpdated,
+            Some(principal.id),
+            Some(user_id),
+            json!({ "company_id": id, "roles": roles }),
+        )
+        .await;
+
+    find_member(&db, id, user_id)
+        .await
+        .map(|member| Custom(Status::Ok, json!(member)))
+}
+
+error_responses!(RemoveMemberErrors {
+    CompanyError::SelfModification,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    CompanyError::CompanyNotFound,
+    CompanyError::MemberNotFound,
+});
+
+/// Remove a member from a company
+///
+/// Available to the admins of the company.
+#[utoipa::path(
+    delete,
+    path = "/companies/{id}/members/{user_id}",
+    params(
+        ("id" = i32, Path, description = "ID of the company",),
+        ("user_id" = i32, Path, description = "ID of the member",),
+    ),
+    responses(
+        (status = 204),
+        RemoveMemberErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::delete("/companies/<id>/members/<user_id>")]
+pub async fn remove_member(
+    id: i32,
+    user_id: i32,
+    principal: Result<Principal, AppError>,
+    scopes: &ApiKeyScopes,
+    db: DbConnection,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let principal = principal?;
+    authorize_company(&db, &principal, scopes, id, RoleCode::Admin).await?;
+
+    if principal.id == user_id {
+        return Err(self_modification_error());
+    }
+
+    let removed = db
+        .run(move |connection| CompanyRepository::remove_member(connection, id, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    if removed == 0 {
+        return Err(AppError::from(CompanyError::MemberNotFound));
+    }
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyMemberRemoved,
+            Some(principal.id),
+            Some(user_id),
+            json!({ "company_id": id }),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
+
+async fn find_invitation(
+    token: &str,
+    cache: &mut Connection<CacheConnection>,
+) -> Result<CompanyInvitation, AppError> {
+    if token.len() != SESSION_ID_LENGTH {
+        return Err(AppError::from(AuthError::InvalidToken));
+    }
+
+    SessionRepository::find_invitation(token, cache)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(|| AppError::from(CompanyError::InvitationNotFound))
+}
+
+error_responses!(InviteMemberErrors {
+    AuthError::InvalidEmail,
+    CompanyError::InvalidRole,
+    CompanyError::AlreadyMember,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    CompanyError::CompanyNotFound,
+});
+
+/// Invite an email address into a company
+///
+/// Available to the admins of the company;
+///
+/// An email with deep links to accept or decline the invitation is sent to the invitee,
+/// the invitation expires after 7 days;
+///
+/// The deep link format: `https://template.softteco.com.deep_link/invitation/{token}/accept`
+/// (or `/decline`).
+#[utoipa::path(
+    post,
+    path = "/companies/{id}/invitations",
+    params(("id" = i32, Path, description = "ID of the company",)),
+    request_body = NewInvitationDto,
+    responses(
+        (status = 200, description = "OK"),
+        InviteMemberErrors,
+    ),
+    security(("token"=[]))
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post(
+    "/companies/<id>/invitations",
+    format = "json",
+    data = "<invitation_dto>"
+)]
+pub async fn invite_member(
+    id: i32,
+    invitation_dto: Json<NewInvitationDto>,
+    user: Result<User, AppError>,
+    scopes: &ApiKeyScopes,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    mailer: &State<HtmlMailer>,
+    request_id: &RequestId,
+    audit: Audit,
+    config: &State<AppConfig>,
+) -> Result<Status, AppError> {
+    let user = user?;
+    let company =
+        authorize_company(&db, &Principal::from(&user), scopes, id, RoleCode::Admin).await?;
+
+    let invitation_dto = invitation_dto.into_inner();
+    let email = invitation_dto.email.trim().to_string();
+    if !is_email_valid(&email) {
+        return Err(AppError::from(AuthError::InvalidEmail));
+    }
+
+    let role_codes = match invitation_dto.roles {
+        Some(codes) => parse_role_codes(&codes)?,
+        None => vec![RoleCode::Viewer],
+    };
+
+    let invitee_email = email.clone();
+    let is_member = db
+        .run(
+            move |connection| match UserRepository::find_by_email(connection, &invitee_email) {
+                Ok(invitee) => Ok(!CompanyRepository::find_member_roles(
+                    connection, id, invitee.id,
+                )?
+                .is_empty()),
+                Err(diesel::result::Error::NotFound) => Ok(false),
+                Err(e) => Err(e),
+            },
+        )
+        .map_err(|e: diesel::result::Error| AppError::from(e))
+        .await?;
+
+    if is_member {
+        return Err(AppError::from(CompanyError::AlreadyMember));
+    }
+
+    let invitation = CompanyInvitation {
+        company_id: company.id,
+        email,
+        role_codes: role_codes.iter().map(|code| code.to_string()).collect(),
+        invited_by: user.id,
+    };
+
+    let invitation_token = generate_token(SESSION_ID_LENGTH);
+
+    SessionRepository::cache_invitation(
+        &invitation_token,
+        &invitation,
+        config.tokens.invitation_token,
+        &mut cache,
+    )
+    .await
+    .map_err(AppError::from)?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyInvitationSent,
+            Some(user.id),
+            None,
+            json!({
+                "company_id": company.id,
+                "email": invitation.email,
+                "roles": invitation.role_codes,
+            }),
+        )
+        .await;
+
+    let deep_link = config
+        .deep_links
+        .link(&format!("{INVITATION_PATH}/{invitation_token}"));
+
+    send_company_invitation_email(
+        mailer,
+        request_id,
+        invitation.email,
+        &company.name,
+        &user.username,
+        &invitation.role_codes,
+        format!("{deep_link}/accept"),
+        format!("{deep_link}/decline"),
+    )
+    .await;
+
+    Ok(Status::Ok)
+}
+
+error_responses!(GetInvitationErrors {
+    AuthError::InvalidToken,
+    CompanyError::InvitationNotFound,
+    CompanyError::CompanyNotFound,
+});
+
+/// Get a company invitation by its token
+#[utoipa::path(
+    get,
+    path = "/invitations/{token}",
+    params(("token" = String, Path, description = "The invitation token",)),
+    responses(
+        (status = 200, description = "OK", body = InvitationDto),
+        GetInvitationErrors,
+    )
+)]
+#[rocket::get("/invitations/<token>")]
+pub async fn get_invitation(
+    token: &str,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+) -> Result<Custom<Value>, AppError> {
+    let invitation = find_invitation(token, &mut cache).await?;
+
+    let company_id = invitation.company_id;
+    let company = db
+        .run(move |connection| CompanyRepository::find(connection, company_id))
+        .map_err(company_error)
+        .await?;
+
+    Ok(Custom(
+        Status::Ok,
+        json!(InvitationDto {
+            company_id: company.id,
+            company_name: company.name,
+            email: invitation.email,
+            roles: invitation.role_codes,
+        }),
+    ))
+}
+
+error_responses!(AcceptInvitationErrors {
+    CompanyError::AlreadyMember,
+    AuthError::InvalidToken,
+    CompanyError::InvitationEmailMismatch,
+    CompanyError::InvitationNotFound,
+    CompanyError::CompanyNotFound,
+});
+
+/// Accept a company invitation
+///
+/// The invitation must be addressed to the email of the current user;
+///
+/// The user becomes an enterprise user with the invited roles within the company.
+#[utoipa::path(
+    post,
+    path = "/invitations/{token}/accept",
+    params(("token" = String, Path, description = "The invitation token",)),
+    responses(
+        (status = 200, description = "OK", body = CompanyDto),
+        AcceptInvitationErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::post("/invitations/<token>/accept")]
+pub async fn accept_invitation(
+    token: &str,
+    user: Result<User, AppError>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let user = user?;
+    let user_id = user.id;
+    let is_regular = user.user_type == UserType::Regular;
+    let invitation = find_invitation(token, &mut cache).await?;
+
+    if !invitation.email.eq_ignore_ascii_case(&user.email) {
+        return Err(AppError::from(CompanyError::InvitationEmailMismatch));
+    }
+
+    let role_codes = parse_role_codes(&invitation.role_codes)?;
+    let payload = json!({
+        "company_id": invitation.company_id,
+        "roles": invitation.role_codes,
+        "invited_by": invitation.invited_by,
+    });
+
+    // The company is locked, so that concurrent acceptances cannot both join the user
+    let company = db
+        .run(move |connection| {
+            connection.transaction(|connection| {
+                let company =
+                    CompanyRepository::find_for_update(connection, invitation.company_id)?;
+                if !CompanyRepository::find_member_roles(connection, company.id, user.id)?
+                    .is_empty()
+                {
+                    return Ok(None);
+                }
+                if user.user_type != UserType::Enterprise {
+                    UserRepository::set_user_type(connection, user.id, &UserType::Enterprise)?;
+                }
+                CompanyRepository::set_member_roles(connection, company.id, user.id, &role_codes)?;
+                Ok(Some(company))
+            })
+        })
+        .map_err(company_error)
+        .await?;
+
+    // Only redeemed once the membership is committed, a failed acceptance can be retried
+    SessionRepository::redeem_token(token, INVITATION_TOKEN_KEY_PREFIX, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+
+    let company = company.ok_or_else(|| AppError::from(CompanyError::AlreadyMember))?;
+
+    // The user became an enterprise one, its JWTs still carry the global roles
+    if is_regular {
+        revoke_outdated_jwts(user_id, access_tokens, &config.tokens, &mut cache).await?;
+    }
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyInvitationAccepted,
+            Some(user_id),
+            Some(user_id),
+            payload,
+        )
+        .await;
+
+    Ok(Custom(Status::Ok, json!(company)))
+}
+
+error_responses!(DeclineInvitationErrors {
+    AuthError::InvalidToken,
+    CompanyError::InvitationNotFound,
+});
+
+/// Decline a company invitation
+#[utoipa::path(
+    post,
+    path = "/invitations/{token}/decline",
+    params(("token" = String, Path, description = "The invitation token",)),
+    responses(
+        (status = 204),
+        DeclineInvitationErrors,
+    )
+)]
+#[rocket::post("/invitations/<token>/decline")]
+pub async fn decline_invitation(
+    token: &str,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let invitation = find_invitation(token, &mut cache).await?;
+
+    SessionRepository::redeem_token(token, INVITATION_TOKEN_KEY_PREFIX, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::CompanyInvitationDeclined,
+            None,
+            None,
+            json!({ "company_id": invitation.company_id, "email": invitation.email }),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
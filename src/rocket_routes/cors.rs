@@ -0,0 +1,276 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::{Request, Response};
+
+use crate::config::AppConfig;
+
+use super::health::is_health_check;
+
+const ORIGIN_HEADER: &str = "Origin";
+const REQUEST_METHOD_HEADER: &str = "Access-Control-Request-Method";
+const REQUEST_HEADERS_HEADER: &str = "Access-Control-Request-Headers";
+const ANY: &str = "*";
+
+/// CORS policy, configured in the `cors` table of `Rocket.toml` or the `ROCKET_CORS` env var.
+/// No cross-origin request is allowed by default
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CorsPolicy {
+    /// Exact origins, e.g. `https://app.example.com`, or `*` for any origin
+    pub allowed_origins: Vec<String>,
+    /// Origin patterns where `*` stands for any part of a host name or a port,
+    /// e.g. `https://*.example.com` or `http://localhost:*`
+    pub allowed_origin_patterns: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed in preflight requests, `*` for any header
+    pub allowed_headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    /// Seconds the preflight response may be cached for
+    pub max_age: Option<usize>,
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            allowed_origins: vec![],
+            allowed_origin_patterns: vec![],
+            allowed_methods: ["GET", "POST", "PUT", "DELETE", "PATCH"]
+                .map(String::from)
+                .to_vec(),
+            allowed_headers: ["Authorization", "Content-Type", "Accept"]
+                .map(String::from)
+                .to_vec(),
+            exposed_headers: [
+                "X-RateLimit-Limit",
+                "X-RateLimit-Remaining",
+                "Retry-After",
+                "X-Request-Id",
+            ]
+            .map(String::from)
+            .to_vec(),
+            max_age: Some(60 * 60),
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.allow_credentials && self.allows_any_origin() {
+            return Err("credentials cannot be allowed for any origin".to_string());
+        }
+
+        if let Some(method) = self
+            .allowed_methods
+            .iter()
+            .find(|method| method.parse::<Method>().is_err())
+        {
+            return Err(format!("unknown method {}", method));
+        }
+
+        Ok(())
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == ANY)
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allows_any_origin()
+            || self.allowed_origins.iter().any(|allowed| allowed == origin)
+            || self
+                .allowed_origin_patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern, origin))
+    }
+
+    pub fn allows_method(&self, method: Method) -> bool {
+        self.allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method.as_str()))
+    }
+
+    pub fn allows_header(&self, header: &str) -> bool {
+        self.allowed_headers
+            .iter()
+            .any(|allowed| allowed == ANY || allowed.eq_ignore_ascii_case(header))
+    }
+}
+
+/// Match the origin against a pattern where `*` stands for any run of characters other than `/`
+fn matches_pattern(pattern: &str, origin: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == origin,
+        Some((prefix, rest)) => {
+            let Some(origin) = origin.strip_prefix(prefix) else {
+                return false;
+            };
+
+            origin
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain([origin.len()])
+                .take_while(|&i| !origin[..i].contains('/'))
+                .any(|i| matches_pattern(rest, &origin[i..]))
+        }
+    }
+}
+
+/// Whether a mounted route other than the preflight one serves the method on the path
+fn is_route_mounted(request: &Request<'_>, method: Method) -> bool {
+    let path: Vec<&str> = request.uri().path().segments().collect();
+
+    request.rocket().routes().any(|route| {
+        if route.method != method {
+            return false;
+        }
+
+        let route_path: Vec<&str> = route
+            .uri
+            .path()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+
+        let mut route_segments = route_path.iter();
+        let mut path_segments = path.iter();
+        loop {
+            match (route_segments.next(), path_segments.next()) {
+                (Some(route_segment), _) if route_segment.ends_with("..>") => return true,
+                (Some(route_segment), Some(segment)) => {
+                    let dynamic = route_segment.starts_with('<');
+                    if !dynamic && route_segment != segment {
+                        return false;
+                    }
+                }
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    })
+}
+
+/// A valid preflight request: allowed origin, method and headers for a mounted route
+pub struct Preflight {
+    methods: String,
+    headers: Option<String>,
+    max_age: Option<usize>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Preflight {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let policy = &request.rocket().state::<AppConfig>().unwrap().cors;
+
+        let Some(origin) = request.headers().get_one(ORIGIN_HEADER) else {
+            return Outcome::Error((Status::BadRequest, ()));
+        };
+        let Some(method) = request
+            .headers()
+            .get_one(REQUEST_METHOD_HEADER)
+            .and_then(|method| method.parse::<Method>().ok())
+        else {
+            return Outcome::Error((Status::BadRequest, ()));
+        };
+        let headers: Vec<&str> = request
+            .headers()
+            .get(REQUEST_HEADERS_HEADER)
+            .flat_map(|headers| headers.split(','))
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .collect();
+
+        if !policy.allows_origin(origin)
+            || !policy.allows_method(method)
+            || !headers.iter().all(|header| policy.allows_header(header))
+        {
+            log::warn!("CORS preflight from {} for {} is rejected", origin, method);
+            return Outcome::Error((Status::Forbidden, ()));
+        }
+
+        if is_health_check(request) || !is_route_mounted(request, method) {
+            return Outcome::Error((Status::NotFound, ()));
+        }
+
+        Outcome::Success(Preflight {
+            methods: policy.allowed_methods.join(", "),
+            headers: (!headers.is_empty()).then(|| headers.join(", ")),
+            max_age: policy.max_age,
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Preflight {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .status(Status::NoContent)
+            .raw_header("Access-Control-Allow-Methods", self.methods);
+        if let Some(headers) = self.headers {
+            response.raw_header("Access-Control-Allow-Headers", headers);
+        }
+        if let Some(max_age) = self.max_age {
+            response.raw_header("Access-Control-Max-Age", max_age.to_string());
+        }
+        response.ok()
+    }
+}
+
+/// Answer CORS preflight requests of the mounted routes
+#[allow(clippy::let_unit_value)]
+#[rocket::options("/<_route_args..>")]
+pub fn options(_route_args: Option<std::path::PathBuf>, preflight: Preflight) -> Preflight {
+    preflight
+}
+
+/// Append CORS headers in responses to the allowed origins
+pub struct Cors;
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "Append CORS headers in responses",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if is_health_check(req) {
+            return;
+        }
+        let Some(origin) = req.headers().get_one(ORIGIN_HEADER) else {
+            return;
+        };
+        let policy = &req.rocket().state::<AppConfig>().unwrap().cors;
+
+        // The response depends on the origin unless any origin gets the same one
+        if !policy.allows_any_origin() {
+            res.adjoin_raw_header("Vary", ORIGIN_HEADER);
+        }
+
+        if !policy.allows_origin(origin) {
+            return;
+        }
+
+        if policy.allows_any_origin() {
+            res.set_raw_header("Access-Control-Allow-Origin", ANY);
+        } else {
+            res.set_raw_header("Access-Control-Allow-Origin", origin.to_string());
+        }
+        if policy.allow_credentials {
+            res.set_raw_header("Access-Control-Allow-Credentials", "true");
+        }
+        if !policy.exposed_headers.is_empty() {
+            res.set_raw_header(
+                "Access-Control-Expose-Headers",
+                policy.exposed_headers.join(", "),
+            );
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::status::Custom;
+use rocket::serde::json::{serde_json::json, Value};
+use rocket::tokio::task::spawn_blocking;
+use rocket::tokio::time::timeout;
+use rocket::{Orbit, Request, Rocket, State};
+use rocket_db_pools::Database;
+
+use crate::config::AppConfig;
+use crate::dto::{HealthCheckDto, HealthDto, HealthStatus};
+use crate::geoip::GeoLocator;
+use crate::mail::HtmlMailer;
+use crate::repositories::{CacheRepository, DatabaseRepository};
+
+use super::{CacheConnection, DbConnection};
+
+/// Path prefix of the health checks, they are left out of CORS and logged at debug level only
+pub const HEALTH_PATH: &str = "/health";
+
+type CheckError = Box<dyn Error + Send + Sync>;
+
+/// Readiness checks, configured in the `health` table of `Rocket.toml`
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Seconds a single check may take before the dependency is considered down
+    pub timeout: u64,
+    /// Also check the connection to the SMTP server
+    pub mail: bool,
+    /// Also check that the IP geolocation provider is reachable
+    pub geoip: bool,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            timeout: 2,
+            mail: false,
+            geoip: false,
+        }
+    }
+}
+
+/// The launched server, so that the checks acquire connections within their timeout
+pub struct Server<'r>(&'r Rocket<Orbit>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Server<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Server(request.rocket()))
+    }
+}
+
+/// Whether the request is one of the health checks
+pub fn is_health_check(request: &Request<'_>) -> bool {
+    request.uri().path().starts_with(HEALTH_PATH)
+}
+
+/// Run the check within the timeout and measure it, failures are logged and reported briefly
+async fn check<F>(name: &str, limit: Duration, check: F) -> HealthCheckDto
+where
+    F: Future<Output = Result<(), CheckError>>,
+{
+    let start = Instant::now();
+    let result = timeout(limit, check).await;
+    let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => {
+            log::warn!("Health check of {} failed: {}", name, e);
+            Some("unavailable".to_string())
+        }
+        Err(_) => {
+            log::warn!("Health check of {} timed out", name);
+            Some("timed out".to_string())
+        }
+    };
+
+    HealthCheckDto {
+        status: if error.is_none() {
+            HealthStatus::Up
+        } else {
+            HealthStatus::Down
+        },
+        duration_ms,
+        error,
+    }
+}
+
+/// Liveness of the server
+///
+/// Checks nothing but that the server responds.
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    responses(
+        (status = 200, description = "OK", body = HealthDto),
+    )
+)]
+#[rocket::get("/health/live")]
+pub fn live() -> Custom<Value> {
+    Custom(
+        Status::Ok,
+        json!(HealthDto {
+            status: HealthStatus::Up,
+            checks: BTreeMap::new(),
+        }),
+    )
+}
+
+/// Readiness of the server to handle requests
+///
+/// Checks Postgres and Redis and, if enabled in the `health` config, the SMTP server
+/// and the IP geolocation provider.
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    responses(
+        (status = 200, description = "OK", body = HealthDto),
+        (status = 503, description = "Service Unavailable", body = HealthDto),
+    )
+)]
+#[rocket::get("/health/ready")]
+pub async fn ready(
+    server: Server<'_>,
+    config: &State<AppConfig>,
+    mailer: &State<HtmlMailer>,
+    geo_locator: &State<GeoLocator>,
+) -> Custom<Value> {
+    let limit = Duration::from_secs(config.health.timeout);
+
+    let postgres = check("postgres", limit, async {
+        let db = DbConnection::get_one(server.0)
+            .await
+            .ok_or("Cannot get a connection")?;
+        db.run(DatabaseRepository::ping).await?;
+        Ok(())
+    });
+    let redis = check("redis", limit, async {
+        let pool = CacheConnection::fetch(server.0).ok_or("The pool is not attached")?;
+        let mut cache = pool.get().await?;
+        CacheRepository::ping(&mut cache).await?;
+        Ok(())
+    });
+    let (postgres, redis) = rocket::tokio::join!(postgres, redis);
+
+    let mut checks = BTreeMap::from([
+        ("postgres".to_string(), postgres),
+        ("redis".to_string(), redis),
+    ]);
+
+    if config.health.mail {
+        let transport = mailer.transport.clone();
+        let mail = check("mail", limit, async move {
+            spawn_blocking(move || transport.check()).await?
+        })
+        .await;
+        checks.insert("mail".to_string(), mail);
+    }
+
+    if config.health.geoip {
+        let geoip = check("geoip", limit, geo_locator.check()).await;
+        checks.insert("geoip".to_string(), geoip);
+    }
+
+    let up = checks
+        .values()
+        .all(|check| check.status == HealthStatus::Up);
+    let (status, health_status) = if up {
+        (Status::Ok, HealthStatus::Up)
+    } else {
+        (Status::ServiceUnavailable, HealthStatus::Down)
+    };
+
+    Custom(
+        status,
+        json!(HealthDto {
+            status: health_status,
+            checks,
+        }),
+    )
+}
@@ -0,0 +1,280 @@
+use rocket::{
+    futures::TryFutureExt,
+    http::{RawStr, Status},
+    response::{status::Custom, Redirect},
+    serde::json::{serde_json::json, Json, Value},
+    Either, State,
+};
+use rocket_db_pools::Connection;
+
+use super::{
+    audit::Audit,
+    authorization::complete_login,
+    rate_limit::{Login, MagicLink as MagicLinkScope, RateLimit},
+    request_id::RequestId,
+    ClientAddr, DbConnection, UserAgent,
+};
+use crate::error_responses;
+use crate::{
+    auth::{generate_token, is_email_valid, MAGIC_LINK_PATH, SESSION_ID_LENGTH},
+    config::AppConfig,
+    dto::MagicLinkRequestDto,
+    errors::{AppError, AuthError},
+    geoip::GeoLocator,
+    jwt::AccessTokens,
+    mail::{send_magic_link_email, HtmlMailer},
+    models::{AuditEventType, MagicLink, User},
+    repositories::{SessionRepository, UserRepository},
+    rocket_routes::CacheConnection,
+};
+
+error_responses!(MagicLinkErrors {
+    AuthError::InvalidEmail,
+    AuthError::InvalidRedirectUrl,
+    AuthError::EmailNotExist,
+    AuthError::UnconfirmedUser,
+    AuthError::TooManyRequests,
+});
+
+/// Send an email with a one-time login link
+///
+/// Without `redirect_url` the email contains a deep link for the app, which passes the token to `/login/magic/{token}`;
+///
+/// With `redirect_url` the email links to `/login/magic/{token}` itself, which redirects the browser to that page
+/// with the response of the login in the URL fragment;
+///
+/// The link expires after 15 minutes, can only be used once, and requesting a new link invalidates the previous one;
+///
+/// The deep link format: `https://template.softteco.com.deep_link/magic_login/{token}`.
+#[utoipa::path(
+    post,
+    path = "/login/magic",
+    request_body = MagicLinkRequestDto,
+    responses(
+        (status = 200, description = "OK"),
+        MagicLinkErrors,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/login/magic", format = "json", data = "<link_dto>")]
+pub async fn request_magic_link(
+    link_dto: Json<MagicLinkRequestDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    geo_locator: &State<GeoLocator>,
+    mailer: &State<HtmlMailer>,
+    request_id: &RequestId,
+    audit: Audit,
+    config: &State<AppConfig>,
+    rate_limit: Result<RateLimit<'_, MagicLinkScope>, AppError>,
+) -> Result<Status, AppError> {
+    let rate_limit = rate_limit?;
+
+    let MagicLinkRequestDto {
+        email,
+        redirect_url,
+    } = link_dto.into_inner();
+
+    if !is_email_valid(&email) {
+        return Err(AppError::from(AuthError::InvalidEmail));
+    }
+    if let Some(redirect_url) = &redirect_url {
+        if !config.magic_link.is_redirect_allowed(redirect_url) {
+            return Err(AppError::from(AuthError::InvalidRedirectUrl));
+        }
+    }
+
+    let user = db
+        .run(move |connection| {
+            UserRepository::find_by_email(connection, &email).map_err(|e| match e {
+                diesel::result::Error::NotFound => AppError::from(AuthError::EmailNotExist),
+                _ => AppError::from(e),
+            })
+        })
+        .await?;
+    rate_limit.hit_account(user.id, &mut cache).await?;
+
+    if !user.confirmed {
+        return Err(AppError::from(AuthError::UnconfirmedUser));
+    }
+
+    let token = generate_token(SESSION_ID_LENGTH);
+    let link = match redirect_url {
+        Some(_) => format!("{}/login/magic/{token}", config.base_url),
+        None => config
+            .deep_links
+            .link(&format!("{MAGIC_LINK_PATH}/{token}")),
+    };
+    let magic_link = MagicLink {
+        user_id: user.id,
+        redirect_url,
+    };
+
+    SessionRepository::cache_magic_link(
+        &token,
+        &magic_link,
+        config.tokens.magic_link_token,
+        &mut cache,
+    )
+    .await
+    .map_err(AppError::from)?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::MagicLinkRequested,
+            None,
+            Some(user.id),
+            json!({ "email": user.email, "redirect_url": magic_link.redirect_url }),
+        )
+        .await;
+
+    let client_info = geo_locator.client_info(client_addr.0).await;
+    send_magic_link_email(mailer, request_id, &user, link, &client_info).await;
+
+    Ok(Status::Ok)
+}
+
+error_responses!(MagicLoginErrors {
+    AuthError::InvalidToken,
+    AuthError::UnconfirmedUser,
+    AuthError::TooManyRequests,
+});
+
+/// Log in with the token of a magic link
+///
+/// Returns an auth token and a refresh token, or a two-factor challenge as `/login` does;
+///
+/// If the link was requested with `redirect_url`, redirects to that page instead, the fields of the response
+/// are passed in the URL fragment, e.g. `#token=...&refresh_token=...`.
+#[utoipa::path(
+    get,
+    path = "/login/magic/{token}",
+    params(("token" = String, Path, description = "The magic link token",)),
+    responses(
+        (status = 200, description = "OK", body = AuthTokenDto),
+        (status = 202, description = "Accepted", body = TwoFactorChallengeDto),
+        (status = 303, description = "See Other, the link was requested for a web page"),
+        MagicLoginErrors,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::get("/login/magic/<token>")]
+pub async fn magic_login(
+    token: &str,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    user_agent: UserAgent,
+    audit: Audit,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    rate_limit: Result<RateLimit<'_, Login>, AppError>,
+) -> Result<Either<Custom<Value>, Redirect>, AppError> {
+    rate_limit?;
+
+    let magic_link = match token.len() {
+        SESSION_ID_LENGTH => {
+            SessionRepository::take_magic_link(token, &mut cache)
+                .map_err(AppError::from)
+                .await?
+        }
+        _ => None,
+    };
+    let Some(magic_link) = magic_link else {
+        return Err(record_magic_login_failure(&audit, &db, None, AuthError::InvalidToken).await);
+    };
+
+    let user_id = magic_link.user_id;
+    let user = match db
+        .run(move |connection| UserRepository::find(connection, user_id))
+        .await
+    {
+        Ok(user) => user,
+        Err(diesel::result::Error::NotFound) => {
+            return Err(
+                record_magic_login_failure(&audit, &db, None, AuthError::InvalidToken).await,
+            );
+        }
+        Err(e) => return Err(AppError::from(e)),
+    };
+
+    if !user.confirmed {
+        return Err(record_magic_login_failure(
+            &audit,
+            &db,
+            Some(&user),
+            AuthError::UnconfirmedUser,
+        )
+        .await);
+    }
+
+    let response = complete_login(
+        &user,
+        json!({ "method": "magic_link" }),
+        &db,
+        &mut cache,
+        client_addr,
+        user_agent,
+        &audit,
+        config,
+        access_tokens,
+    )
+    .await?;
+
+    match magic_link.redirect_url {
+        Some(redirect_url) => Ok(Either::Right(Redirect::to(format!(
+            "{}#{}",
+            redirect_url,
+            url_fragment(&response.1)
+        )))),
+        None => Ok(Either::Left(response)),
+    }
+}
+
+/// Record a failed login with a magic link, returns the error to answer
+async fn record_magic_login_failure(
+    audit: &Audit,
+    db: &DbConnection,
+    user: Option<&User>,
+    error: AuthError,
+) -> AppError {
+    audit
+        .record(
+            db,
+            AuditEventType::LoginFailed,
+            None,
+            user.map(|user| user.id),
+            json!({
+                "email": user.map(|user| user.email.as_str()),
+                "reason": error.value().code,
+                "method": "magic_link",
+            }),
+        )
+        .await;
+    AppError::from(error)
+}
+
+/// Fields of the response encoded as the `key=value&...` fragment of a URL
+fn url_fragment(response: &Value) -> String {
+    let Value::Object(fields) = response else {
+        return String::new();
+    };
+
+    fields
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            format!(
+                "{}={}",
+                RawStr::new(key).percent_encode(),
+                RawStr::new(&value).percent_encode()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
@@ -0,0 +1,92 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, State};
+use subtle::ConstantTimeEq;
+
+use crate::config::AppConfig;
+use crate::errors::{AppError, AuthError};
+use crate::metrics::Metrics;
+use crate::repositories::DatabaseRepository;
+
+use super::{bearer_token, CacheConnection, DbConnection};
+
+const IDLE_STATE: &str = "idle";
+
+/// Scraper of the metrics, holding the `metrics.token` as a Bearer token if it is configured
+pub struct MetricsScraper;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsScraper {
+    type Error = AppError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<AppConfig>().unwrap();
+        let Some(token) = &config.metrics.token else {
+            return Outcome::Success(MetricsScraper);
+        };
+
+        let presented = bearer_token(request).unwrap_or_default();
+        if bool::from(presented.as_bytes().ct_eq(token.as_bytes())) {
+            Outcome::Success(MetricsScraper)
+        } else {
+            Outcome::Error((Status::Unauthorized, AuthError::InvalidToken.into()))
+        }
+    }
+}
+
+/// Metrics of the service in the Prometheus text format
+///
+/// Usage of the connection pools and the pending migrations are sampled on every scrape.
+#[rocket::get("/metrics")]
+pub async fn metrics(
+    scraper: Result<MetricsScraper, AppError>,
+    metrics: &State<Metrics>,
+    db: Option<DbConnection>,
+    cache: &CacheConnection,
+) -> Result<(ContentType, String), AppError> {
+    scraper?;
+
+    let status = cache.status();
+    let available = status.available.max(0) as usize;
+    metrics.set_pool_size("redis", status.max_size);
+    metrics.set_pool_usage("redis", status.size.saturating_sub(available), available);
+    metrics.set_pool_waiting("redis", (-status.available).max(0) as usize);
+
+    if let Some(db) = db {
+        let (connections, pending_migrations) = db
+            .run(|c| {
+                (
+                    DatabaseRepository::count_connections(c),
+                    DatabaseRepository::count_pending_migrations(c),
+                )
+            })
+            .await;
+
+        match connections {
+            Ok(connections) => {
+                let (mut in_use, mut idle) = (0, 0);
+                for connection in connections {
+                    if connection.state == IDLE_STATE {
+                        idle += connection.count as usize;
+                    } else {
+                        in_use += connection.count as usize;
+                    }
+                }
+                metrics.set_pool_usage("postgres", in_use, idle);
+            }
+            Err(e) => log::warn!("Cannot count the postgres connections: {}", e),
+        }
+
+        match pending_migrations {
+            Ok(pending) => metrics.set_pending_migrations(pending),
+            Err(e) => log::warn!("Cannot check the pending migrations: {}", e),
+        }
+    }
+
+    let body = metrics.render().map_err(|e| AppError::Internal(e.into()))?;
+
+    Ok((
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        body,
+    ))
+}
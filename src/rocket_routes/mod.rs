@@ -1,35 +1,53 @@
+pub mod admin;
+pub mod api_keys;
+pub mod audit;
 pub mod authorization;
+pub mod companies;
+pub mod cors;
+pub mod health;
+pub mod magic_link;
+pub mod metrics;
+pub mod oidc;
+pub mod passkeys;
 pub mod profile;
+pub mod rate_limit;
+pub mod request_id;
+pub mod roles;
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
 
 use chrono::Utc;
-use diesel::PgConnection;
-use reqwest::ClientBuilder;
-use rocket::fairing::{Fairing, Info, Kind};
+use diesel::result::DatabaseErrorKind;
+use diesel::{PgConnection, QueryResult};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
 use rocket::http::hyper::header;
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
-use rocket::response::status::Custom;
-use rocket::serde::json::{serde_json::json, Value};
 use rocket::Request;
 
 use rocket_db_pools::deadpool_redis::redis::AsyncCommands;
 use rocket_db_pools::{deadpool_redis, Connection, Database};
 
-use crate::auth::SESSIONS_KEY_PREFIX;
-use crate::errors::AuthError;
-use crate::models::User;
-use crate::repositories::UserRepository;
+use crate::auth::{self, SESSIONS_KEY_PREFIX};
+use crate::config::{AppConfig, TokenLifetimes};
+use crate::errors::{AppError, AuthError, TwoFactorError};
+use crate::jwt::{AccessClaims, AccessTokens};
+use crate::models::{RoleCode, TokenFamily, User, UserType};
+use crate::repositories::{
+    ApiKeyRepository, RoleRepository, SessionRepository, TwoFactorRepository, UserRepository,
+};
+use crate::rocket_routes::request_id::AuthenticatedUserId;
 
-pub const DEEP_LINK_HOST: &str = "template.softteco.com.deep_link";
-pub const DEEP_LINK_SCHEME: &str = "https";
-pub const DEEP_LINK_APP_SCHEME: &str = "tmplt";
 const AUTH_TYPE: &str = "Bearer";
-const IP_GEOLOCATION_API_URI: &str = "https://freeipapi.com/api/json";
-const IP_GEOLOCATION_DURATION: u64 = 5;
 const VIEWER_ADDRESS_HEADER: &str = "cloudfront-viewer-address";
+const MAX_USER_AGENT_LENGTH: usize = 256;
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+const MAX_PAGE: i64 = 1_000_000;
+
+/// Migrations of the postgres database, applied on launch
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 #[rocket_sync_db_pools::database("postgres")]
 pub struct DbConnection(PgConnection);
@@ -38,136 +56,472 @@ pub struct DbConnection(PgConnection);
 #[database("redis")]
 pub struct CacheConnection(deadpool_redis::Pool);
 
+/// IP address of the client, the address of the peer unless it is one of the `trusted_proxies`
+///
+/// `Request::client_ip` is not used, since it trusts the `X-Real-IP` header of any peer.
 pub struct ClientAddr(IpAddr);
 
-pub fn server_error(e: Box<dyn std::error::Error>) -> Custom<Value> {
-    log::error!("Internal Server Error: {}", e);
-    Custom(Status::InternalServerError, json!("Internal Server Error"))
+pub struct UserAgent(Option<String>);
+
+/// The token family the Bearer token of the request belongs to
+pub struct CurrentSession(TokenFamily);
+
+/// Identity of the authenticated user, for the routes that need no more than its id and roles
+///
+/// With a JWT it is built from the claims of the token, so that the user is not loaded.
+#[derive(Clone)]
+pub struct Principal {
+    pub id: i32,
+    pub user_type: UserType,
+    /// Global roles carried by the JWT, loaded on demand for the other tokens
+    roles: Option<Vec<RoleCode>>,
+}
+
+impl Principal {
+    /// Codes of the global roles of the user, those of a JWT are the ones it was issued with
+    pub fn role_codes(&self, connection: &mut PgConnection) -> QueryResult<Vec<RoleCode>> {
+        match &self.roles {
+            Some(roles) => Ok(roles.clone()),
+            None => RoleRepository::find_codes_by_user_id(connection, self.id),
+        }
+    }
+
+    /// Whether the user is a global admin, the roles of enterprise users are scoped to their
+    /// companies
+    pub fn is_global_admin(&self, connection: &mut PgConnection) -> QueryResult<bool> {
+        Ok(self.user_type == UserType::Regular
+            && self
+                .role_codes(connection)?
+                .iter()
+                .any(|code| code.grants(&RoleCode::Admin)))
+    }
+}
+
+impl From<&User> for Principal {
+    fn from(user: &User) -> Self {
+        Principal {
+            id: user.id,
+            user_type: user.user_type.clone(),
+            roles: None,
+        }
+    }
+}
+
+/// User authenticated with a session, for the routes managing the account itself
+///
+/// API keys are rejected with `Forbidden` whatever their scopes, so that a leaked key cannot
+/// take the account over by changing its email, credentials or second factors.
+pub struct SessionUser(pub User);
+
+impl Deref for SessionUser {
+    type Target = User;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Scopes of the API key the request is authenticated with, `None` for sessions
+pub struct ApiKeyScopes(Option<Vec<RoleCode>>);
+
+impl ApiKeyScopes {
+    /// Whether the request may act with the `required` role
+    pub fn allow(&self, required: &RoleCode) -> bool {
+        self.0
+            .as_ref()
+            .is_none_or(|scopes| scopes.iter().any(|scope| scope.grants(required)))
+    }
+}
+
+/// Map unique violations of the users table to the matching auth errors
+pub fn user_conflict_error(e: diesel::result::Error) -> AppError {
+    if let diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, error_info) = &e
+    {
+        match error_info.constraint_name() {
+            Some("users_email_key") => return AppError::from(AuthError::EmailInUse),
+            Some("users_username_key") => return AppError::from(AuthError::UnavailableUsername),
+            _ => {}
+        }
+    }
+    AppError::from(e)
+}
+
+/// The account using the email, unconfirmed accounts release their emails once purged
+pub async fn find_email_owner(email: String, db: &DbConnection) -> Result<Option<User>, AppError> {
+    match db
+        .run(move |connection| UserRepository::find_by_email(connection, &email))
+        .await
+    {
+        Ok(user) => Ok(Some(user)),
+        Err(diesel::result::Error::NotFound) => Ok(None),
+        Err(e) => Err(AppError::from(e)),
+    }
+}
+
+/// Revoke the JWTs of the user, the user type and the roles they carry are outdated
+///
+/// Session ids are not affected, the user and its roles are loaded for every request with them.
+pub async fn revoke_outdated_jwts(
+    user_id: i32,
+    access_tokens: &AccessTokens,
+    lifetimes: &TokenLifetimes,
+    cache: &mut Connection<CacheConnection>,
+) -> Result<(), AppError> {
+    if !access_tokens.is_jwt() {
+        return Ok(());
+    }
+
+    SessionRepository::revoke_user_token_families(user_id, None, lifetimes, cache)
+        .await
+        .map_err(AppError::from)
+}
+
+/// Page number starting from 1 and at most 1000000, page size, 20 by default and at most 100,
+/// and the number of rows before the page
+pub fn pagination(page: Option<i64>, per_page: Option<i64>) -> (i64, i64, i64) {
+    let page = page.unwrap_or(1).clamp(1, MAX_PAGE);
+    let per_page = per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    // Both are bounded, the offset is at most 10^8
+    let offset = (page - 1) * per_page;
+    (page, per_page, offset)
+}
+
+/// Render the request body that failed to deserialize as a validation problem
+#[rocket::catch(422)]
+pub fn unprocessable_entity() -> AppError {
+    AppError::Validation("The request body is malformed".to_string())
+}
+
+/// Render the errors raised outside of the handlers, e.g. unknown routes, as problems
+#[rocket::catch(default)]
+pub fn default_catcher(status: Status, _request: &Request) -> AppError {
+    AppError::Http(status)
+}
+
+/// Check a code of the authenticator app not used before, otherwise try to consume it as one of the
+/// recovery codes
+pub async fn verify_second_factor(
+    db: &DbConnection,
+    user: &User,
+    code: &str,
+) -> Result<bool, AppError> {
+    let user_id = user.id;
+    let account_name = user.email.clone();
+    let code = code.trim().to_string();
+
+    db.run(move |connection| {
+        let user_totp = TwoFactorRepository::find(connection, user_id)?;
+        let totp_step = auth::build_totp(&user_totp.secret, &account_name)
+            .ok()
+            .and_then(|totp| auth::find_totp_step(&totp, &code));
+
+        // A code is accepted once, so that an intercepted one cannot be replayed within its step
+        if let Some(step) = totp_step {
+            return TwoFactorRepository::use_totp_step(connection, user_id, step);
+        }
+        if !user_totp.enabled {
+            return Ok(false);
+        }
+        TwoFactorRepository::use_recovery_code(
+            connection,
+            user_id,
+            &auth::hash_recovery_code(&code),
+        )
+    })
+    .await
+    .map_err(|e| match e {
+        diesel::result::Error::NotFound => AppError::from(TwoFactorError::NotEnrolled),
+        _ => AppError::from(e),
+    })
+}
+
+/// Address of the client as reported by a trusted proxy, `None` if it is missing or malformed
+fn proxied_client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    if let Some(address) = request.headers().get_one(VIEWER_ADDRESS_HEADER) {
+        return address
+            .parse::<SocketAddr>()
+            .ok()
+            .map(|address| address.ip());
+    }
+
+    // The last element is the one appended by the proxy in front of the server
+    let forwarded = request.headers().get_one(header::FORWARDED.as_str())?;
+    let element = forwarded.rsplit(',').next()?.trim();
+    let node = match element.split_once('=') {
+        Some(_) => element.split(';').find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            name.eq_ignore_ascii_case("for").then_some(value)
+        })?,
+        None => element,
+    };
+    let node = node.trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|address| address.ip()))
+        .or_else(|| {
+            let ipv6 = node.strip_prefix('[')?.split_once(']')?.0;
+            ipv6.parse::<IpAddr>().ok()
+        })
 }
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for ClientAddr {
-    type Error = Value;
+    type Error = AppError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let header = request.headers().get_one(VIEWER_ADDRESS_HEADER);
-        match header {
-            Some(address) => match address.parse::<SocketAddr>() {
-                Ok(socket_addr) => Outcome::Success(ClientAddr(socket_addr.ip())),
-                Err(e) => Outcome::Error((
-                    Status::InternalServerError,
-                    json!(format!("Unable to extract client IP address: {}", e)),
-                )),
-            },
-            None => match request.headers().get_one(header::FORWARDED.as_str()) {
-                Some(address) => match address.parse::<IpAddr>() {
-                    Ok(ip) => Outcome::Success(ClientAddr(ip)),
-                    Err(e) => Outcome::Error((
-                        Status::InternalServerError,
-                        json!(format!("Unable to extract client IP address: {}", e)),
-                    )),
-                },
-                None => match request.client_ip() {
-                    Some(remote_addr) => Outcome::Success(ClientAddr(remote_addr)),
-                    None => Outcome::Error((
-                        Status::InternalServerError,
-                        json!("Unable to extract client IP address"),
-                    )),
-                },
-            },
+        let Some(peer_ip) = request.remote().map(|remote| remote.ip().to_canonical()) else {
+            return Outcome::Error((
+                Status::InternalServerError,
+                AppError::Internal("Unable to extract client IP address".into()),
+            ));
+        };
+
+        let is_trusted_proxy = request.rocket().state::<AppConfig>().is_some_and(|config| {
+            config
+                .trusted_proxies
+                .iter()
+                .any(|network| network.contains(peer_ip))
+        });
+        if !is_trusted_proxy {
+            return Outcome::Success(ClientAddr(peer_ip));
         }
+
+        Outcome::Success(ClientAddr(proxied_client_ip(request).unwrap_or(peer_ip)))
     }
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for User {
-    type Error = Value;
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = AppError;
+
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let auth_header = request
+        let user_agent = request
             .headers()
-            .get_one(header::AUTHORIZATION.as_str())
-            .map(|v| v.split_whitespace().collect::<Vec<_>>())
-            .filter(|v| v.len() == 2 && v[0] == AUTH_TYPE);
+            .get_one(header::USER_AGENT.as_str())
+            .map(|v| v.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        Outcome::Success(UserAgent(user_agent))
+    }
+}
 
-        if let Some(header_value) = auth_header {
+fn bearer_token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    request
+        .headers()
+        .get_one(header::AUTHORIZATION.as_str())
+        .map(|v| v.split_whitespace().collect::<Vec<_>>())
+        .filter(|v| v.len() == 2 && v[0] == AUTH_TYPE)
+        .map(|v| v[1])
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CurrentSession {
+    type Error = AppError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(session_id) = bearer_token(request) {
             let mut cache = request
                 .guard::<Connection<CacheConnection>>()
                 .await
                 .expect("Cannot connect to redis in request guard");
 
+            if auth::is_jwt(session_id) {
+                if let Some(claims) = jwt_claims(request, session_id, &mut cache).await {
+                    let result =
+                        SessionRepository::find_token_family_by_id(claims.sid, &mut cache).await;
+
+                    if let Ok(Some(family)) = result {
+                        if family.user_id.to_string() == claims.sub {
+                            return Outcome::Success(CurrentSession(family));
+                        }
+                    }
+                }
+                return Outcome::Error((Status::Unauthorized, AuthError::InvalidToken.into()));
+            }
+
+            let result =
+                SessionRepository::find_token_family_by_session(session_id, &mut cache).await;
+
+            if let Ok(Some(family)) = result {
+                if family.session_id == session_id {
+                    return Outcome::Success(CurrentSession(family));
+                }
+            }
+        }
+
+        Outcome::Error((Status::Unauthorized, AuthError::InvalidToken.into()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = AppError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(session_id) = bearer_token(request) {
+            if let Some(prefix) = auth::api_key_prefix(session_id) {
+                return authenticate_api_key(request, session_id, prefix).await;
+            }
+
+            // The claims of a JWT identify the user, the user is only loaded for the full row
+            let user_id = if auth::is_jwt(session_id) {
+                match request.guard::<Principal>().await {
+                    Outcome::Success(principal) => Some(principal.id),
+                    _ => None,
+                }
+            } else {
+                let mut cache = request
+                    .guard::<Connection<CacheConnection>>()
+                    .await
+                    .expect("Cannot connect to redis in request guard");
+
+                cache
+                    .get::<_, i32>(format!("{}/{}", SESSIONS_KEY_PREFIX, session_id))
+                    .await
+                    .ok()
+            };
+
             let db = request
                 .guard::<DbConnection>()
                 .await
                 .expect("Cannot connect to postgres in request guard");
 
-            let result = cache
-                .get::<_, i32>(format!("{}/{}", SESSIONS_KEY_PREFIX, header_value[1]))
-                .await;
-
-            if let Ok(user_id) = result {
+            if let Some(user_id) = user_id {
                 return match db.run(move |c| UserRepository::find(c, user_id)).await {
-                    Ok(user) => Outcome::Success(user),
-                    _ => Outcome::Error((
-                        Status::Unauthorized,
-                        json!(AuthError::InvalidToken.value()),
-                    )),
+                    Ok(user) => {
+                        request.local_cache(|| AuthenticatedUserId(Some(user.id)));
+                        Outcome::Success(user)
+                    }
+                    _ => Outcome::Error((Status::Unauthorized, AuthError::InvalidToken.into())),
                 };
             }
         }
 
-        Outcome::Error((Status::Unauthorized, json!(AuthError::InvalidToken.value())))
+        Outcome::Error((Status::Unauthorized, AuthError::InvalidToken.into()))
     }
 }
 
-pub async fn get_client_info(client_addr: IpAddr) -> Result<String, Box<dyn std::error::Error>> {
-    let date_time = Utc::now().format("%d %B %Y, %H:%M UTC").to_string();
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Principal {
+    type Error = AppError;
 
-    if (client_addr) == Ipv4Addr::LOCALHOST {
-        return Ok(format!("{} at {}", client_addr, date_time));
-    }
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(token) = bearer_token(request).filter(|token| auth::is_jwt(token)) else {
+            return match request.guard::<User>().await {
+                Outcome::Success(user) => Outcome::Success(Principal::from(&user)),
+                Outcome::Error(error) => Outcome::Error(error),
+                Outcome::Forward(status) => Outcome::Forward(status),
+            };
+        };
 
-    let get_location_url = format!("{}/{}", IP_GEOLOCATION_API_URI, client_addr);
+        let mut cache = request
+            .guard::<Connection<CacheConnection>>()
+            .await
+            .expect("Cannot connect to redis in request guard");
 
-    let timeout = Duration::new(IP_GEOLOCATION_DURATION, 0);
-    let client = ClientBuilder::new().timeout(timeout).build()?;
+        let principal = jwt_claims(request, token, &mut cache)
+            .await
+            .and_then(|claims| {
+                Some(Principal {
+                    id: claims.sub.parse().ok()?,
+                    user_type: claims.user_type.parse().ok()?,
+                    roles: Some(
+                        claims
+                            .roles
+                            .iter()
+                            .map(|role| role.parse())
+                            .collect::<Result<_, _>>()
+                            .ok()?,
+                    ),
+                })
+            });
 
-    let response = client.get(&get_location_url).send().await.unwrap();
-    let json: Value = response.json().await.unwrap();
+        match principal {
+            Some(principal) => {
+                request.local_cache(|| AuthenticatedUserId(Some(principal.id)));
+                Outcome::Success(principal)
+            }
+            None => Outcome::Error((Status::Unauthorized, AuthError::InvalidToken.into())),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionUser {
+    type Error = AppError;
 
-    let city = json.get("cityName").unwrap();
-    let country = json.get("countryCode").unwrap();
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = match request.guard::<User>().await {
+            Outcome::Success(user) => user,
+            Outcome::Error(error) => return Outcome::Error(error),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
 
-    Ok(format!(
-        "{}, {}, {} at {}",
-        client_addr, city, country, date_time
-    ))
+        if bearer_token(request).is_some_and(|token| auth::api_key_prefix(token).is_some()) {
+            return Outcome::Error((Status::Forbidden, AuthError::Forbidden.into()));
+        }
+
+        Outcome::Success(SessionUser(user))
+    }
 }
 
-/// Append CORS headers in responses
-#[allow(clippy::let_unit_value)]
-#[rocket::options("/<_route_args..>")]
-pub fn options(_route_args: Option<std::path::PathBuf>) -> Status {
-    Status::NoContent
+/// Claims of a JWT that is signed by a known key and whose token family is not revoked
+async fn jwt_claims(
+    request: &Request<'_>,
+    token: &str,
+    cache: &mut Connection<CacheConnection>,
+) -> Option<AccessClaims> {
+    let claims = request.rocket().state::<AccessTokens>()?.verify(token)?;
+
+    match SessionRepository::is_token_family_denied(&claims.sid, cache).await {
+        Ok(false) => Some(claims),
+        _ => None,
+    }
 }
 
-pub struct Cors;
+/// Authenticate the request with an API key that is not expired
+async fn authenticate_api_key(
+    request: &Request<'_>,
+    key: &str,
+    prefix: &str,
+) -> Outcome<User, AppError> {
+    let db = request
+        .guard::<DbConnection>()
+        .await
+        .expect("Cannot connect to postgres in request guard");
 
-#[rocket::async_trait]
-impl Fairing for Cors {
-    fn info(&self) -> Info {
-        Info {
-            name: "Append CORS headers in responses",
-            kind: Kind::Response,
+    let prefix = prefix.to_string();
+    let key_hash = auth::hash_api_key(key);
+    let result = db
+        .run(move |c| -> QueryResult<Option<(User, Vec<RoleCode>)>> {
+            let api_key = ApiKeyRepository::find_by_prefix(c, &prefix)?;
+            let is_expired = api_key
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= Utc::now().naive_utc());
+            if api_key.key_hash != key_hash || is_expired {
+                return Ok(None);
+            }
+
+            ApiKeyRepository::update_usage(c, api_key.id)?;
+            let user = UserRepository::find(c, api_key.user_id)?;
+            Ok(Some((user, api_key.scopes)))
+        })
+        .await;
+
+    match result {
+        Ok(Some((user, scopes))) => {
+            request.local_cache(|| AuthenticatedUserId(Some(user.id)));
+            request.local_cache(|| ApiKeyScopes(Some(scopes)));
+            Outcome::Success(user)
         }
+        _ => Outcome::Error((Status::Unauthorized, AuthError::InvalidToken.into())),
     }
+}
 
-    async fn on_response<'r>(&self, _req: &'r Request<'_>, res: &mut rocket::Response<'r>) {
-        res.set_raw_header("Access-Control-Allow-Origin", "*");
-        res.set_raw_header(
-            "Access-Control-Allow-Methods",
-            "GET, POST, PUT, DELETE, PATCH",
-        );
-        res.set_raw_header("Access-Control-Allow-Headers", "*");
-        res.set_raw_header("Access-Control-Allow-Credentials", "true");
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r ApiKeyScopes {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(|| ApiKeyScopes(None)))
     }
 }
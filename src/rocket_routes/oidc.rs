@@ -0,0 +1,515 @@
+use diesel::result::DatabaseErrorKind;
+use rocket::{
+    futures::TryFutureExt,
+    http::Status,
+    response::status::Custom,
+    serde::json::{serde_json::json, Json, Value},
+    State,
+};
+use rocket_db_pools::Connection;
+
+use super::{
+    audit::Audit,
+    authorization::complete_login,
+    find_email_owner,
+    rate_limit::{Login, RateLimit},
+    request_id::RequestId,
+    user_conflict_error, ClientAddr, DbConnection, UserAgent,
+};
+use crate::error_responses;
+use crate::{
+    auth::{
+        self, derive_username, generate_token, is_email_valid, ACCOUNT_LINK_TOKEN_KEY_PREFIX,
+        CONFIRM_EMAIL_PATH, SESSION_ID_LENGTH,
+    },
+    config::AppConfig,
+    dto::{
+        AccountLinkChallengeDto, AccountLinkDto, OidcAuthorizationDto, OidcAuthorizeDto,
+        OidcLoginDto,
+    },
+    errors::{AppError, AuthError, OidcError},
+    geoip::GeoLocator,
+    jwt::AccessTokens,
+    mail::{send_confirmation_email, HtmlMailer},
+    models::{AccountLink, AuditEventType, NewUser, NewUserIdentity, OidcAuthorization, User},
+    oidc::{
+        is_code_challenge_valid, verify_code_verifier, ExchangeError, IdentityClaims, OidcClient,
+    },
+    repositories::{IdentityRepository, SessionRepository, UserRepository},
+    rocket_routes::CacheConnection,
+};
+
+const USERNAME_ATTEMPTS: usize = 3;
+
+/// Map unique violations of the identities table to `IdentityInUse`, and those of the users table
+/// to the matching auth errors
+fn identity_conflict_error(e: diesel::result::Error) -> AppError {
+    if let diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, error_info) = &e
+    {
+        if error_info.constraint_name() == Some("user_identities_provider_subject_key") {
+            return AppError::from(OidcError::IdentityInUse);
+        }
+    }
+    user_conflict_error(e)
+}
+
+error_responses!(AuthorizeErrors {
+    OidcError::InvalidCodeChallenge,
+    OidcError::UnknownProvider,
+    OidcError::ProviderUnavailable,
+});
+
+/// Start a login with an OpenID Connect provider
+///
+/// Returns the authorization page of the provider for the app to open, the provider redirects to the configured
+/// redirect URI with a code and the state, which are then passed to `/oidc/{provider}/login`;
+///
+/// The app generates a PKCE code verifier for every login and sends only its S256 challenge here,
+/// the verifier itself is sent with the code to `/oidc/{provider}/login`;
+///
+/// The state expires after 10 minutes and can only be used once.
+#[utoipa::path(
+    post,
+    path = "/oidc/{provider}/authorize",
+    params(("provider" = String, Path, description = "Name of the configured provider",)),
+    request_body = OidcAuthorizeDto,
+    responses(
+        (status = 200, description = "OK", body = OidcAuthorizationDto),
+        AuthorizeErrors,
+    )
+)]
+#[rocket::post(
+    "/oidc/<provider>/authorize",
+    format = "json",
+    data = "<authorize_dto>"
+)]
+pub async fn authorize(
+    provider: &str,
+    authorize_dto: Json<OidcAuthorizeDto>,
+    mut cache: Connection<CacheConnection>,
+    oidc: &State<OidcClient>,
+    config: &State<AppConfig>,
+) -> Result<Value, AppError> {
+    if oidc.provider(provider).is_none() {
+        return Err(AppError::from(OidcError::UnknownProvider));
+    }
+    if !is_code_challenge_valid(&authorize_dto.code_challenge) {
+        return Err(AppError::from(OidcError::InvalidCodeChallenge));
+    }
+
+    let state = generate_token(SESSION_ID_LENGTH);
+    let authorization = OidcAuthorization {
+        provider: provider.to_string(),
+        code_challenge: authorize_dto.into_inner().code_challenge,
+        nonce: generate_token(SESSION_ID_LENGTH),
+    };
+
+    let authorization_url = oidc
+        .authorization_url(
+            provider,
+            &state,
+            &authorization.nonce,
+            &authorization.code_challenge,
+        )
+        .await
+        .map_err(|e| {
+            log::warn!("Cannot start the authorization with {}: {}", provider, e);
+            AppError::from(OidcError::ProviderUnavailable)
+        })?;
+
+    SessionRepository::cache_oidc_authorization(
+        &state,
+        &authorization,
+        config.tokens.oidc_authorization,
+        &mut cache,
+    )
+    .map_err(AppError::from)
+    .await?;
+
+    Ok(json!(OidcAuthorizationDto {
+        authorization_url,
+        state,
+    }))
+}
+
+error_responses!(OidcLoginErrors {
+    OidcError::UnknownProvider,
+    OidcError::InvalidState,
+    OidcError::AuthorizationFailed,
+    OidcError::MissingEmail,
+    AuthError::UnconfirmedUser,
+    AuthError::TooManyRequests,
+    OidcError::ProviderUnavailable,
+});
+
+/// Log in with the code returned by an OpenID Connect provider
+///
+/// Returns an auth token and a refresh token, or a two-factor challenge as `/login` does;
+///
+/// The code is only exchanged with the code verifier of the challenge sent to `/oidc/{provider}/authorize`;
+///
+/// The first login with an identity signs the user up, the user is confirmed if the provider has verified the email,
+/// otherwise a confirmation email is sent;
+///
+/// If the email belongs to an existing user, returns a link token instead, the identity is linked
+/// at `/oidc/link` with the password of that user. The token expires after 10 minutes.
+#[utoipa::path(
+    post,
+    path = "/oidc/{provider}/login",
+    params(("provider" = String, Path, description = "Name of the configured provider",)),
+    request_body = OidcLoginDto,
+    responses(
+        (status = 200, description = "OK", body = AuthTokenDto),
+        (status = 202, description = "Accepted, the identity has to be linked to the existing user, or the two-factor challenge of `/login` is answered", body = AccountLinkChallengeDto),
+        OidcLoginErrors,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/oidc/<provider>/login", format = "json", data = "<login_dto>")]
+pub async fn login(
+    provider: &str,
+    login_dto: Json<OidcLoginDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    user_agent: UserAgent,
+    geo_locator: &State<GeoLocator>,
+    mailer: &State<HtmlMailer>,
+    request_id: &RequestId,
+    audit: Audit,
+    oidc: &State<OidcClient>,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    rate_limit: Result<RateLimit<'_, Login>, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    rate_limit?;
+
+    if oidc.provider(provider).is_none() {
+        return Err(AppError::from(OidcError::UnknownProvider));
+    }
+
+    let authorization = SessionRepository::take_oidc_authorization(&login_dto.state, &mut cache)
+        .map_err(AppError::from)
+        .await?
+        .filter(|authorization| authorization.provider == provider)
+        .ok_or_else(|| AppError::from(OidcError::InvalidState))?;
+
+    // A code intercepted on its way to the app cannot be redeemed without the verifier
+    let claims = if verify_code_verifier(&login_dto.code_verifier, &authorization.code_challenge) {
+        oidc.exchange_code(
+            provider,
+            &login_dto.code,
+            &login_dto.code_verifier,
+            &authorization.nonce,
+        )
+        .await
+    } else {
+        Err(ExchangeError::Rejected(
+            "Code verifier does not match the challenge".into(),
+        ))
+    };
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(e) => {
+            log::warn!("Cannot log in with {}: {}", provider, e);
+            return Err(match e {
+                ExchangeError::Unavailable(_) => AppError::from(OidcError::ProviderUnavailable),
+                ExchangeError::Rejected(_) => {
+                    let error = OidcError::AuthorizationFailed;
+                    audit
+                        .record(
+                            &db,
+                            AuditEventType::LoginFailed,
+                            None,
+                            None,
+                            json!({ "reason": error.value().code, "provider": provider }),
+                        )
+                        .await;
+                    AppError::from(error)
+                }
+            });
+        }
+    };
+
+    let provider_name = provider.to_string();
+    let subject = claims.sub.clone();
+    let identity = db
+        .run(move |connection| IdentityRepository::find(connection, &provider_name, &subject))
+        .await;
+
+    let user = match identity {
+        Ok(identity) => {
+            db.run(move |connection| UserRepository::find(connection, identity.user_id))
+                .map_err(AppError::from)
+                .await?
+        }
+        Err(diesel::result::Error::NotFound) => {
+            let email = claims
+                .email
+                .clone()
+                .filter(|email| is_email_valid(email))
+                .ok_or_else(|| AppError::from(OidcError::MissingEmail))?;
+
+            let owner = find_email_owner(email.clone(), &db).await?;
+            if let Some(owner) = owner {
+                let link = AccountLink {
+                    user_id: owner.id,
+                    provider: provider.to_string(),
+                    subject: claims.sub,
+                    email: email.clone(),
+                    email_verified: claims.email_verified,
+                };
+                let link_token = generate_token(SESSION_ID_LENGTH);
+
+                SessionRepository::cache_account_link(
+                    &link_token,
+                    &link,
+                    config.tokens.account_link_token,
+                    &mut cache,
+                )
+                .map_err(AppError::from)
+                .await?;
+
+                return Ok(Custom(
+                    Status::Accepted,
+                    json!(AccountLinkChallengeDto {
+                        link_token,
+                        email: owner.email,
+                        expires_in: config.tokens.account_link_token,
+                    }),
+                ));
+            }
+
+            let user = sign_up(provider, claims, email, &db).await?;
+
+            audit
+                .record(
+                    &db,
+                    AuditEventType::Signup,
+                    Some(user.id),
+                    Some(user.id),
+                    json!({ "username": user.username, "email": user.email, "provider": provider }),
+                )
+                .await;
+
+            if !user.confirmed {
+                let confirm_token = generate_token(SESSION_ID_LENGTH);
+                SessionRepository::cache_confirm_token(
+                    &confirm_token,
+                    user.id,
+                    config.tokens.confirm_token,
+                    &mut cache,
+                )
+                .await
+                .map_err(AppError::from)?;
+
+                let link = format!("{}/{CONFIRM_EMAIL_PATH}/{confirm_token}", config.base_url);
+                let client_info = geo_locator.client_info(client_addr.0).await;
+                send_confirmation_email(mailer, request_id, &user, link, &client_info).await;
+            }
+
+            user
+        }
+        Err(e) => return Err(AppError::from(e)),
+    };
+
+    if !user.confirmed {
+        let error = AuthError::UnconfirmedUser;
+        audit
+            .record(
+                &db,
+                AuditEventType::LoginFailed,
+                None,
+                Some(user.id),
+                json!({
+                    "email": user.email,
+                    "reason": error.value().code,
+                    "provider": provider,
+                }),
+            )
+            .await;
+        return Err(AppError::from(error));
+    }
+
+    complete_login(
+        &user,
+        json!({ "provider": provider }),
+        &db,
+        &mut cache,
+        client_addr,
+        user_agent,
+        &audit,
+        config,
+        access_tokens,
+    )
+    .await
+}
+
+/// Create a viewer with the identity linked, another username is tried if the derived one is taken
+async fn sign_up(
+    provider: &str,
+    claims: IdentityClaims,
+    email: String,
+    db: &DbConnection,
+) -> Result<User, AppError> {
+    let password_hash = auth::hash_password(generate_token(SESSION_ID_LENGTH)).unwrap();
+
+    let mut attempt = 0;
+    loop {
+        let new_user = NewUser {
+            username: derive_username(claims.preferred_username.as_deref(), &email, attempt),
+            email: email.clone(),
+            password: password_hash.clone(),
+        };
+        let identity = NewUserIdentity {
+            user_id: 0,
+            provider: provider.to_string(),
+            subject: claims.sub.clone(),
+            email: Some(email.clone()),
+        };
+        let confirmed = claims.email_verified;
+
+        let result = db
+            .run(move |connection| {
+                IdentityRepository::create_user(connection, new_user, identity, confirmed)
+            })
+            .await
+            .map_err(identity_conflict_error);
+
+        attempt += 1;
+        match result {
+            Err(AppError::Auth(AuthError::UnavailableUsername)) if attempt < USERNAME_ATTEMPTS => {}
+            result => return result,
+        }
+    }
+}
+
+error_responses!(LinkErrors {
+    AuthError::InvalidToken,
+    AuthError::WrongCredentials,
+    OidcError::IdentityInUse,
+    AuthError::UnconfirmedUser,
+    AuthError::TooManyRequests,
+});
+
+/// Link a provider identity to the existing user with the same email and log in
+///
+/// Wrong passwords count towards the lockout of `/login`; the link token can only be used once;
+///
+/// Returns an auth token and a refresh token, or a two-factor challenge as `/login` does.
+#[utoipa::path(
+    post,
+    path = "/oidc/link",
+    request_body = AccountLinkDto,
+    responses(
+        (status = 200, description = "OK", body = AuthTokenDto),
+        (status = 202, description = "Accepted", body = TwoFactorChallengeDto),
+        LinkErrors,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/oidc/link", format = "json", data = "<link_dto>")]
+pub async fn link(
+    link_dto: Json<AccountLinkDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    user_agent: UserAgent,
+    audit: Audit,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    rate_limit: Result<RateLimit<'_, Login>, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    let rate_limit = rate_limit?;
+
+    let link_token = link_dto.link_token.as_str();
+    if link_token.len() != SESSION_ID_LENGTH {
+        return Err(AppError::from(AuthError::InvalidToken));
+    }
+
+    let link = SessionRepository::find_account_link(link_token, &mut cache)
+        .map_err(AppError::from)
+        .await?
+        .ok_or_else(|| AppError::from(AuthError::InvalidToken))?;
+
+    let user_id = link.user_id;
+    let mut user = db
+        .run(move |connection| UserRepository::find(connection, user_id))
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::from(AuthError::InvalidToken),
+            _ => AppError::from(e),
+        })
+        .await?;
+
+    rate_limit.check_account(user.id, &mut cache).await?;
+
+    if auth::verify_password(&user, &link_dto.password).is_err() {
+        rate_limit.fail_account(user.id, &mut cache).await?;
+        let error = AuthError::WrongCredentials;
+        audit
+            .record(
+                &db,
+                AuditEventType::LoginFailed,
+                None,
+                Some(user.id),
+                json!({
+                    "email": user.email,
+                    "reason": error.value().code,
+                    "provider": link.provider,
+                }),
+            )
+            .await;
+        return Err(AppError::from(error));
+    }
+
+    rate_limit.reset_account(user.id, &mut cache).await?;
+
+    let identity = NewUserIdentity {
+        user_id,
+        provider: link.provider.clone(),
+        subject: link.subject.clone(),
+        email: Some(link.email.clone()),
+    };
+    db.run(move |connection| IdentityRepository::create(connection, identity))
+        .map_err(identity_conflict_error)
+        .await?;
+
+    SessionRepository::redeem_token(link_token, ACCOUNT_LINK_TOKEN_KEY_PREFIX, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::IdentityLinked,
+            Some(user.id),
+            Some(user.id),
+            json!({ "provider": link.provider, "email": link.email }),
+        )
+        .await;
+
+    // The password proves the account, the provider the email
+    if !user.confirmed && link.email_verified {
+        user = db
+            .run(move |connection| UserRepository::confirm_signup(connection, user_id))
+            .map_err(AppError::from)
+            .await?;
+    }
+
+    if !user.confirmed {
+        return Err(AppError::from(AuthError::UnconfirmedUser));
+    }
+
+    complete_login(
+        &user,
+        json!({ "provider": link.provider }),
+        &db,
+        &mut cache,
+        client_addr,
+        user_agent,
+        &audit,
+        config,
+        access_tokens,
+    )
+    .await
+}
@@ -0,0 +1,581 @@
+use diesel::result::DatabaseErrorKind;
+use rocket::{
+    futures::TryFutureExt,
+    http::Status,
+    response::status::Custom,
+    serde::json::{serde_json::json, Json, Value},
+    State,
+};
+use rocket_db_pools::Connection;
+use webauthn_rs::{
+    prelude::{CredentialID, DiscoverableKey, Passkey, Uuid, WebauthnError},
+    Webauthn,
+};
+
+use super::{
+    audit::Audit,
+    authorization::{issue_session, record_login_failure},
+    rate_limit::{Login, RateLimit},
+    ClientAddr, DbConnection, Principal, SessionUser, UserAgent,
+};
+use crate::error_responses;
+use crate::{
+    auth::{generate_token, MAX_PASSKEY_NAME_LENGTH, SESSION_ID_LENGTH},
+    config::AppConfig,
+    dto::{
+        PasskeyChallengeDto, PasskeyCreationDto, PasskeyDto, PasskeyLoginDto, PasskeyLoginStartDto,
+        PasskeyNameDto, PasskeyRegistrationDto,
+    },
+    errors::{AppError, AuthError, PasskeyError},
+    jwt::AccessTokens,
+    models::{
+        AuditEventType, NewWebauthnCredential, PasskeyLoginState, PasskeyRegistrationState,
+        WebauthnCredential,
+    },
+    repositories::{SessionRepository, UserRepository, WebauthnRepository},
+    rocket_routes::CacheConnection,
+};
+
+fn passkey_dto(credential: WebauthnCredential) -> PasskeyDto {
+    PasskeyDto {
+        id: credential.id,
+        name: credential.name,
+        created_at: credential.created_at,
+        last_used_at: credential.last_used_at,
+    }
+}
+
+fn passkey_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_PASSKEY_NAME_LENGTH {
+        return Err(AppError::from(PasskeyError::InvalidName));
+    }
+    Ok(name.to_string())
+}
+
+fn stored_passkey(credential: &WebauthnCredential) -> Result<Passkey, AppError> {
+    serde_json::from_value(credential.passkey.clone()).map_err(|e| AppError::Internal(e.into()))
+}
+
+error_responses!(BeginRegistrationErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// Start the registration of a passkey for the current user
+///
+/// Returns the options to create the credential with, the registration must be finished
+/// with `/profile/passkeys/finish` within 5 minutes;
+///
+/// Passkeys registered before are excluded, so an authenticator holds one passkey of the user.
+#[utoipa::path(
+    post,
+    path = "/profile/passkeys/begin",
+    responses(
+        (status = 200, description = "OK", body = PasskeyCreationDto),
+        BeginRegistrationErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::post("/profile/passkeys/begin")]
+pub async fn begin_registration(
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    user: Result<SessionUser, AppError>,
+    webauthn: &State<Webauthn>,
+    config: &State<AppConfig>,
+) -> Result<Custom<Value>, AppError> {
+    let user = user?;
+
+    let user_id = user.id;
+    let credentials = db
+        .run(move |connection| WebauthnRepository::find_by_user(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    let user_handle = match credentials.first() {
+        Some(credential) => credential.user_handle.clone(),
+        None => Uuid::new_v4().to_string(),
+    };
+    let user_unique_id = Uuid::parse_str(&user_handle).map_err(|e| AppError::Internal(e.into()))?;
+    let exclude_credentials = credentials
+        .iter()
+        .map(|credential| CredentialID::from(credential.credential_id.clone()))
+        .collect::<Vec<_>>();
+
+    let (options, registration) = webauthn
+        .start_passkey_registration(
+            user_unique_id,
+            &user.email,
+            &user.username,
+            Some(exclude_credentials),
+        )
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    let state = PasskeyRegistrationState {
+        user_handle,
+        registration,
+    };
+    SessionRepository::cache_passkey_registration(
+        user.id,
+        &state,
+        config.tokens.passkey_challenge,
+        &mut cache,
+    )
+    .map_err(AppError::from)
+    .await?;
+
+    Ok(Custom(Status::Ok, json!(PasskeyCreationDto { options })))
+}
+
+error_responses!(FinishRegistrationErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    PasskeyError::InvalidName,
+    PasskeyError::InvalidChallenge,
+    PasskeyError::RegistrationFailed,
+    PasskeyError::AlreadyRegistered,
+});
+
+/// Finish the registration of a passkey with the credential created by the authenticator
+#[utoipa::path(
+    post,
+    path = "/profile/passkeys/finish",
+    request_body = PasskeyRegistrationDto,
+    responses(
+        (status = 201, description = "Created", body = PasskeyDto),
+        FinishRegistrationErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::post(
+    "/profile/passkeys/finish",
+    format = "json",
+    data = "<registration_dto>"
+)]
+pub async fn finish_registration(
+    registration_dto: Json<PasskeyRegistrationDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    user: Result<SessionUser, AppError>,
+    webauthn: &State<Webauthn>,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let user = user?;
+    let name = passkey_name(&registration_dto.name)?;
+
+    let state = SessionRepository::take_passkey_registration(user.id, &mut cache)
+        .map_err(AppError::from)
+        .await?
+        .ok_or_else(|| AppError::from(PasskeyError::InvalidChallenge))?;
+
+    let passkey = webauthn
+        .finish_passkey_registration(&registration_dto.credential, &state.registration)
+        .map_err(|e| {
+            log::warn!("Passkey registration of user {} failed: {}", user.id, e);
+            AppError::from(PasskeyError::RegistrationFailed)
+        })?;
+
+    let new_credential = NewWebauthnCredential {
+        user_id: user.id,
+        credential_id: passkey.cred_id().as_ref().to_vec(),
+        user_handle: state.user_handle,
+        name,
+        passkey: serde_json::to_value(&passkey).map_err(|e| AppError::Internal(e.into()))?,
+    };
+    let credential = db
+        .run(move |connection| WebauthnRepository::create(connection, new_credential))
+        .map_err(|e| match e {
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                AppError::from(PasskeyError::AlreadyRegistered)
+            }
+            _ => AppError::from(e),
+        })
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::PasskeyRegistered,
+            Some(user.id),
+            Some(user.id),
+            json!({ "passkey": credential.id, "name": credential.name }),
+        )
+        .await;
+
+    Ok(Custom(Status::Created, json!(passkey_dto(credential))))
+}
+
+error_responses!(PasskeysErrors {
+    AuthError::InvalidToken,
+});
+
+/// Get the passkeys of the current user
+#[utoipa::path(
+    get,
+    path = "/profile/passkeys",
+    responses(
+        (status = 200, description = "OK", body = [PasskeyDto]),
+        PasskeysErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::get("/profile/passkeys")]
+pub async fn passkeys(
+    db: DbConnection,
+    principal: Result<Principal, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    let user_id = principal?.id;
+    let credentials = db
+        .run(move |connection| WebauthnRepository::find_by_user(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    let passkeys = credentials.into_iter().map(passkey_dto).collect::<Vec<_>>();
+    Ok(Custom(Status::Ok, json!(passkeys)))
+}
+
+error_responses!(RenamePasskeyErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    PasskeyError::InvalidName,
+    PasskeyError::NotFound,
+});
+
+/// Rename a passkey of the current user
+#[utoipa::path(
+    put,
+    path = "/profile/passkeys/{id}",
+    params(("id" = i32, Path, description = "Passkey id",)),
+    request_body = PasskeyNameDto,
+    responses(
+        (status = 200, description = "OK", body = PasskeyDto),
+        RenamePasskeyErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::put("/profile/passkeys/<id>", format = "json", data = "<name_dto>")]
+pub async fn rename_passkey(
+    id: i32,
+    name_dto: Json<PasskeyNameDto>,
+    db: DbConnection,
+    user: Result<SessionUser, AppError>,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let user = user?;
+    let name = passkey_name(&name_dto.name)?;
+
+    let user_id = user.id;
+    let credential = db
+        .run(move |connection| WebauthnRepository::rename(connection, user_id, id, &name))
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::from(PasskeyError::NotFound),
+            _ => AppError::from(e),
+        })
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::PasskeyRenamed,
+            Some(user.id),
+            Some(user.id),
+            json!({ "passkey": credential.id, "name": credential.name }),
+        )
+        .await;
+
+    Ok(Custom(Status::Ok, json!(passkey_dto(credential))))
+}
+
+error_responses!(DeletePasskeyErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    PasskeyError::NotFound,
+});
+
+/// Delete a passkey of the current user, it can no longer be used to log in
+#[utoipa::path(
+    delete,
+    path = "/profile/passkeys/{id}",
+    params(("id" = i32, Path, description = "Passkey id",)),
+    responses(
+        (status = 204),
+        DeletePasskeyErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::delete("/profile/passkeys/<id>")]
+pub async fn delete_passkey(
+    id: i32,
+    db: DbConnection,
+    user: Result<SessionUser, AppError>,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let user = user?;
+
+    let user_id = user.id;
+    let credential = db
+        .run(move |connection| WebauthnRepository::delete(connection, user_id, id))
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::from(PasskeyError::NotFound),
+            _ => AppError::from(e),
+        })
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::PasskeyDeleted,
+            Some(user.id),
+            Some(user.id),
+            json!({ "passkey": credential.id, "name": credential.name }),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
+
+error_responses!(BeginLoginErrors {
+    AuthError::EmailNotExist => Status::Unauthorized,
+    AuthError::UnconfirmedUser,
+    PasskeyError::NotFound,
+    AuthError::TooManyRequests,
+});
+
+/// Start a login with a passkey
+///
+/// With `email` only the passkeys of that user are allowed; without it the authenticator offers
+/// any discoverable passkey of the app, which suits autofill of the login form;
+///
+/// The login must be finished with `/login/passkey/finish` within 5 minutes.
+#[utoipa::path(
+    post,
+    path = "/login/passkey/begin",
+    request_body = PasskeyLoginStartDto,
+    responses(
+        (status = 200, description = "OK", body = PasskeyChallengeDto),
+        BeginLoginErrors,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/login/passkey/begin", format = "json", data = "<login_dto>")]
+pub async fn begin_login(
+    login_dto: Json<PasskeyLoginStartDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    webauthn: &State<Webauthn>,
+    config: &State<AppConfig>,
+    rate_limit: Result<RateLimit<'_, Login>, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    rate_limit?;
+
+    let (options, state) = match login_dto.into_inner().email {
+        Some(email) => {
+            let user =
+                db.run(move |connection| UserRepository::find_by_email(connection, &email))
+                    .map_err(|e| match e {
+                        diesel::result::Error::NotFound => AppError::from(AuthError::EmailNotExist)
+                            .with_status(Status::Unauthorized),
+                        _ => AppError::from(e),
+                    })
+                    .await?;
+
+            if !user.confirmed {
+                return Err(AppError::from(AuthError::UnconfirmedUser));
+            }
+
+            let user_id = user.id;
+            let credentials = db
+                .run(move |connection| WebauthnRepository::find_by_user(connection, user_id))
+                .map_err(AppError::from)
+                .await?;
+            if credentials.is_empty() {
+                return Err(AppError::from(PasskeyError::NotFound));
+            }
+            let passkeys = credentials
+                .iter()
+                .map(stored_passkey)
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let (options, authentication) = webauthn
+                .start_passkey_authentication(&passkeys)
+                .map_err(|e| AppError::Internal(e.into()))?;
+            (
+                options,
+                PasskeyLoginState::User {
+                    user_id,
+                    authentication,
+                },
+            )
+        }
+        None => {
+            let (options, authentication) = webauthn
+                .start_discoverable_authentication()
+                .map_err(|e| AppError::Internal(e.into()))?;
+            (options, PasskeyLoginState::Discoverable(authentication))
+        }
+    };
+
+    let challenge_id = generate_token(SESSION_ID_LENGTH);
+    SessionRepository::cache_passkey_login(
+        &challenge_id,
+        &state,
+        config.tokens.passkey_challenge,
+        &mut cache,
+    )
+    .map_err(AppError::from)
+    .await?;
+
+    Ok(Custom(
+        Status::Ok,
+        json!(PasskeyChallengeDto {
+            challenge_id,
+            options,
+        }),
+    ))
+}
+
+error_responses!(FinishLoginErrors {
+    PasskeyError::InvalidChallenge,
+    PasskeyError::AuthenticationFailed,
+    AuthError::UnconfirmedUser,
+    AuthError::TooManyRequests,
+});
+
+/// Finish a login with the assertion signed by the passkey
+///
+/// Returns an auth token and a refresh token as `/login` does; a passkey verifies the user
+/// by itself, so no two-factor challenge is issued.
+#[utoipa::path(
+    post,
+    path = "/login/passkey/finish",
+    request_body = PasskeyLoginDto,
+    responses(
+        (status = 200, description = "OK", body = AuthTokenDto),
+        FinishLoginErrors,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/login/passkey/finish", format = "json", data = "<login_dto>")]
+pub async fn finish_login(
+    login_dto: Json<PasskeyLoginDto>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    user_agent: UserAgent,
+    webauthn: &State<Webauthn>,
+    audit: Audit,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    rate_limit: Result<RateLimit<'_, Login>, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    let rate_limit = rate_limit?;
+    let PasskeyLoginDto {
+        challenge_id,
+        credential,
+    } = login_dto.into_inner();
+
+    let state = SessionRepository::take_passkey_login(&challenge_id, &mut cache)
+        .map_err(AppError::from)
+        .await?
+        .ok_or_else(|| AppError::from(PasskeyError::InvalidChallenge))?;
+
+    let credential_id = credential.raw_id.as_ref().to_vec();
+    let stored = db
+        .run(move |connection| {
+            WebauthnRepository::find_by_credential_id(connection, &credential_id)
+        })
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::from(PasskeyError::AuthenticationFailed),
+            _ => AppError::from(e),
+        })
+        .await?;
+
+    let user_id = stored.user_id;
+    let user = db
+        .run(move |connection| UserRepository::find(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    if !user.confirmed {
+        let error = AuthError::UnconfirmedUser;
+        record_login_failure(&audit, &db, Some(user.id), &user.email, &error.value().code).await;
+        return Err(AppError::from(error));
+    }
+
+    rate_limit.check_account(user.id, &mut cache).await?;
+
+    let mut passkey = stored_passkey(&stored)?;
+    let result = match state {
+        PasskeyLoginState::User {
+            user_id,
+            authentication,
+        } if user_id == user.id => {
+            webauthn.finish_passkey_authentication(&credential, &authentication)
+        }
+        PasskeyLoginState::User { .. } => Err(WebauthnError::CredentialNotFound),
+        PasskeyLoginState::Discoverable(authentication) => webauthn
+            .identify_discoverable_authentication(&credential)
+            .and_then(|(user_handle, _)| {
+                if user_handle.to_string() == stored.user_handle {
+                    webauthn.finish_discoverable_authentication(
+                        &credential,
+                        authentication,
+                        &[DiscoverableKey::from(&passkey)],
+                    )
+                } else {
+                    Err(WebauthnError::UserNotVerified)
+                }
+            }),
+    };
+
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            log::warn!("Passkey login of user {} failed: {}", user.id, e);
+            rate_limit.fail_account(user.id, &mut cache).await?;
+            let error = PasskeyError::AuthenticationFailed;
+            record_login_failure(&audit, &db, Some(user.id), &user.email, &error.value().code)
+                .await;
+            return Err(AppError::from(error));
+        }
+    };
+
+    rate_limit.reset_account(user.id, &mut cache).await?;
+
+    let updated_passkey = match passkey.update_credential(&result) {
+        Some(true) => {
+            Some(serde_json::to_value(&passkey).map_err(|e| AppError::Internal(e.into()))?)
+        }
+        _ => None,
+    };
+    let stored_id = stored.id;
+    db.run(move |connection| {
+        WebauthnRepository::update_usage(connection, stored_id, updated_passkey)
+    })
+    .map_err(AppError::from)
+    .await?;
+
+    let tokens = issue_session(
+        &user,
+        client_addr,
+        user_agent,
+        &db,
+        access_tokens,
+        &config.tokens,
+        &mut cache,
+    )
+    .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::Login,
+            Some(user.id),
+            Some(user.id),
+            json!({ "method": "passkey", "passkey": stored.id, "two_factor": false }),
+        )
+        .await;
+
+    Ok(Custom(Status::Ok, tokens))
+}
@@ -1,19 +1,43 @@
 use rocket::serde::json::Error;
 use rocket::serde::json::{serde_json::json, Json, Value};
-use rocket::{futures::TryFutureExt, http::Status, response::status::Custom};
+use rocket::{futures::TryFutureExt, http::Status, response::status::Custom, State};
 
-use crate::dto::{NewPasswordDto, UpdateUserDto};
-use crate::errors::ProfileError;
-use crate::models::UpdatedUserInfo;
+use rocket_db_pools::Connection;
+
+use crate::dto::{
+    NewEmailDto, NewPasswordDto, RecoveryCodesDto, SessionDto, TotpCodeDto, TwoFactorEnrollmentDto,
+    UpdateUserDto,
+};
+use crate::error_responses;
+use crate::errors::{AppError, ProfileError, TwoFactorError};
+use crate::models::{AuditEventFilter, AuditEventType, EmailChange, UpdatedUserInfo};
 use crate::{
-    auth::{self, is_password_valid},
+    auth::{
+        self, generate_token, is_email_valid, is_password_valid, CHANGE_EMAIL_PATH,
+        EMAIL_CHANGE_TOKEN_KEY_PREFIX, EMAIL_REVERT_TOKEN_KEY_PREFIX, PENDING_EMAIL_KEY_PREFIX,
+        REVERT_EMAIL_PATH, SESSION_ID_LENGTH,
+    },
+    config::AppConfig,
     errors::AuthError,
+    geoip::GeoLocator,
+    jwt::AccessTokens,
+    mail::{send_email_change_email, send_email_change_notice, HtmlMailer},
     models::User,
-    repositories::UserRepository,
-    rocket_routes::DbConnection,
+    repositories::{AuditRepository, SessionRepository, TwoFactorRepository, UserRepository},
+    rocket_routes::{CacheConnection, DbConnection},
+};
+
+use super::audit::{events_page_dto, Audit};
+use super::rate_limit::{ChangeEmail, RateLimit};
+use super::request_id::RequestId;
+use super::{
+    find_email_owner, pagination, revoke_outdated_jwts, user_conflict_error, verify_second_factor,
+    ClientAddr, CurrentSession, Principal, SessionUser,
 };
 
-use super::server_error;
+error_responses!(MeErrors {
+    AuthError::InvalidToken,
+});
 
 /// Get the current user's profile
 #[utoipa::path(
@@ -21,30 +45,29 @@ use super::server_error;
     path = "/profile/me",
     responses(
         (status = 200, description = "OK", body = UserProfileDto),
-        (status = 401, description = "Unauthorized", body = AuthError, examples(
-            ("InvalidToken" = (summary = "errors::AuthError::InvalidToken", value = json!(AuthError::InvalidToken.value()))),
-        )),
+        MeErrors,
     ),
     security(("token"=[]))
 )]
 #[rocket::get("/profile/me")]
-pub async fn me(user: Result<User, Value>) -> Result<Custom<Value>, Custom<Value>> {
-    match user {
-        Ok(user) => Ok(Custom(Status::Ok, json!(user))),
-        Err(value) => Err(Custom(Status::Unauthorized, value)),
-    }
+pub async fn me(user: Result<User, AppError>) -> Result<Custom<Value>, AppError> {
+    Ok(Custom(Status::Ok, json!(user?)))
 }
 
+error_responses!(UpdatePasswordErrors {
+    AuthError::InvalidPassword,
+});
+
 /// Change the current user's password
+///
+/// Every session of the user except the current one is revoked.
 #[utoipa::path(
     put,
     path = "/profile/password",
     request_body = NewPasswordDto,
     responses(
         (status = 200, description = "OK"),
-        (status = 400, description = "Bad Request", body = AuthError, examples(
-            ("InvalidPassword" = (summary = "errors::AuthError::InvalidPassword", value = json!(AuthError::InvalidPassword.value()))),
-        )),
+        UpdatePasswordErrors,
     ),
     security(("token"=[]))
 )]
@@ -52,39 +75,364 @@ pub async fn me(user: Result<User, Value>) -> Result<Custom<Value>, Custom<Value
 pub async fn update_password(
     password_dto: Json<NewPasswordDto>,
     db: DbConnection,
+    mut cache: Connection<CacheConnection>,
     user: User,
-) -> Result<Status, Custom<Value>> {
+    session: CurrentSession,
+    audit: Audit,
+    config: &State<AppConfig>,
+) -> Result<Status, AppError> {
     let is_confirmation_equal = password_dto.password == password_dto.confirmation;
     if !is_confirmation_equal || !is_password_valid(&password_dto.password) {
-        return Err(Custom(
-            Status::BadRequest,
-            json!(AuthError::InvalidPassword.value()),
-        ));
+        return Err(AppError::from(AuthError::InvalidPassword));
     }
 
     let password_hash = auth::hash_password(password_dto.password.clone()).unwrap();
 
-    db.run(move |connection| UserRepository::update_password(connection, user.id, &password_hash))
-        .map_err(|e| server_error(e.into()))
-        .map_ok(|_f| Status::Ok)
-        .await
+    let user_id = user.id;
+    db.run(move |connection| UserRepository::update_password(connection, user_id, &password_hash))
+        .map_err(AppError::from)
+        .await?;
+
+    SessionRepository::revoke_user_token_families(
+        user.id,
+        Some(&session.0.id),
+        &config.tokens,
+        &mut cache,
+    )
+    .map_err(AppError::from)
+    .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::PasswordChanged,
+            Some(user.id),
+            Some(user.id),
+            json!({}),
+        )
+        .await;
+
+    Ok(Status::Ok)
 }
 
+error_responses!(ChangeEmailErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    AuthError::InvalidEmail,
+    AuthError::WrongCredentials,
+    AuthError::EmailInUse,
+    AuthError::TooManyRequests,
+});
+
+/// Request a change of the current user's email address
+///
+/// A deep link with a confirmation token is sent to the new address, the email is changed once it is confirmed;
+///
+/// The current address is notified with a deep link to revert the change;
+///
+/// The confirmation token expires after 24 hours, the revert token after 7 days. A new request replaces the pending one;
+///
+/// The deep link formats: `https://template.softteco.com.deep_link/change_email/{token}`, `https://template.softteco.com.deep_link/revert_email/{token}`.
+#[utoipa::path(
+    post,
+    path = "/profile/email",
+    request_body = NewEmailDto,
+    responses(
+        (status = 200, description = "OK"),
+        ChangeEmailErrors,
+    ),
+    security(("token"=[]))
+)]
+#[allow(clippy::too_many_arguments)]
+#[rocket::post("/profile/email", format = "json", data = "<email_dto>")]
+pub async fn change_email(
+    email_dto: Json<NewEmailDto>,
+    user: Result<SessionUser, AppError>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    client_addr: ClientAddr,
+    geo_locator: &State<GeoLocator>,
+    mailer: &State<HtmlMailer>,
+    request_id: &RequestId,
+    audit: Audit,
+    config: &State<AppConfig>,
+    rate_limit: Result<RateLimit<'_, ChangeEmail>, AppError>,
+) -> Result<Status, AppError> {
+    let rate_limit = rate_limit?;
+    let user = user?;
+    rate_limit.hit_account(user.id, &mut cache).await?;
+
+    let new_email = email_dto.email.trim().to_string();
+    if !is_email_valid(&new_email) {
+        return Err(AppError::from(AuthError::InvalidEmail));
+    }
+
+    if auth::verify_password(&user, &email_dto.password).is_err() {
+        return Err(AppError::from(AuthError::WrongCredentials));
+    }
+
+    let owner = find_email_owner(new_email.clone(), &db).await?;
+    if new_email == user.email || owner.is_some() {
+        return Err(AppError::from(AuthError::EmailInUse));
+    }
+
+    let change = EmailChange {
+        user_id: user.id,
+        old_email: user.email.clone(),
+        new_email: new_email.clone(),
+    };
+    let confirm_token = generate_token(SESSION_ID_LENGTH);
+    let revert_token = generate_token(SESSION_ID_LENGTH);
+
+    SessionRepository::cache_email_change(
+        &change,
+        &confirm_token,
+        &revert_token,
+        &config.tokens,
+        &mut cache,
+    )
+    .map_err(AppError::from)
+    .await?;
+
+    let confirm_link = config
+        .deep_links
+        .link(&format!("{CHANGE_EMAIL_PATH}/{confirm_token}"));
+    let revert_link = config
+        .deep_links
+        .link(&format!("{REVERT_EMAIL_PATH}/{revert_token}"));
+
+    let client_info = geo_locator.client_info(client_addr.0).await;
+    send_email_change_email(
+        mailer,
+        request_id,
+        &user,
+        &new_email,
+        confirm_link,
+        &client_info,
+    )
+    .await;
+    send_email_change_notice(
+        mailer,
+        request_id,
+        &user,
+        &new_email,
+        revert_link,
+        &client_info,
+    )
+    .await;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::EmailChangeRequested,
+            Some(user.id),
+            Some(user.id),
+            json!({ "old_email": change.old_email, "new_email": change.new_email }),
+        )
+        .await;
+
+    Ok(Status::Ok)
+}
+
+async fn find_email_change(
+    token: &str,
+    prefix: &str,
+    cache: &mut Connection<CacheConnection>,
+) -> Result<EmailChange, AppError> {
+    if token.len() != SESSION_ID_LENGTH {
+        return Err(AppError::from(AuthError::InvalidToken));
+    }
+
+    SessionRepository::find_email_change(token, prefix, cache)
+        .map_err(AppError::from)
+        .await?
+        .ok_or_else(|| AppError::from(AuthError::InvalidToken))
+}
+
+error_responses!(ConfirmEmailErrors {
+    AuthError::InvalidToken,
+    AuthError::EmailInUse,
+});
+
+/// Confirm the new email address of a user
+///
+/// The email of the user is changed to the new address; the token can only be used once.
+#[utoipa::path(
+    post,
+    path = "/email/confirm/{token}",
+    params(("token" = String, Path, description = "The email change token",)),
+    responses(
+        (status = 200, description = "OK"),
+        ConfirmEmailErrors,
+    )
+)]
+#[rocket::post("/email/confirm/<token>")]
+pub async fn confirm_email(
+    token: &str,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let change = find_email_change(token, EMAIL_CHANGE_TOKEN_KEY_PREFIX, &mut cache).await?;
+
+    let pending_token = SessionRepository::find_pending_email_change(change.user_id, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+    if pending_token.as_deref() != Some(token) {
+        return Err(AppError::from(AuthError::InvalidToken));
+    }
+
+    let owner = find_email_owner(change.new_email.clone(), &db).await?;
+    if owner.is_some() {
+        return Err(AppError::from(AuthError::EmailInUse));
+    }
+
+    let user_id = change.user_id;
+    let old_email = change.old_email.clone();
+    let new_email = change.new_email.clone();
+    db.run(move |connection| {
+        let user = UserRepository::find(connection, user_id)?;
+        if user.email != old_email {
+            return Err(diesel::result::Error::NotFound);
+        }
+
+        UserRepository::update_email(connection, user_id, &new_email)
+    })
+    .map_err(|e| match e {
+        diesel::result::Error::NotFound => AppError::from(AuthError::InvalidToken),
+        _ => user_conflict_error(e),
+    })
+    .await?;
+
+    SessionRepository::redeem_token(token, EMAIL_CHANGE_TOKEN_KEY_PREFIX, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+    SessionRepository::redeem_token(&user_id.to_string(), PENDING_EMAIL_KEY_PREFIX, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::EmailChanged,
+            Some(user_id),
+            Some(user_id),
+            json!({ "old_email": change.old_email, "new_email": change.new_email }),
+        )
+        .await;
+
+    Ok(Status::Ok)
+}
+
+error_responses!(RevertEmailErrors {
+    AuthError::InvalidToken,
+    AuthError::EmailInUse,
+});
+
+/// Revert an email change from the previous email address
+///
+/// A pending change is cancelled; a confirmed one is undone and every session of the user is revoked;
+///
+/// The token can only be used once.
+#[utoipa::path(
+    post,
+    path = "/email/revert/{token}",
+    params(("token" = String, Path, description = "The email revert token",)),
+    responses(
+        (status = 200, description = "OK"),
+        RevertEmailErrors,
+    )
+)]
+#[rocket::post("/email/revert/<token>")]
+pub async fn revert_email(
+    token: &str,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    audit: Audit,
+    config: &State<AppConfig>,
+) -> Result<Status, AppError> {
+    let change = find_email_change(token, EMAIL_REVERT_TOKEN_KEY_PREFIX, &mut cache).await?;
+
+    let user_id = change.user_id;
+    let user = db
+        .run(move |connection| UserRepository::find(connection, user_id))
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::from(AuthError::InvalidToken),
+            _ => AppError::from(e),
+        })
+        .await?;
+
+    let confirmed = user.email == change.new_email;
+    if confirmed {
+        let old_email = change.old_email.clone();
+        db.run(move |connection| UserRepository::update_email(connection, user_id, &old_email))
+            .map_err(user_conflict_error)
+            .await?;
+
+        SessionRepository::revoke_user_token_families(user_id, None, &config.tokens, &mut cache)
+            .map_err(AppError::from)
+            .await?;
+    } else if let Some(pending_token) =
+        SessionRepository::find_pending_email_change(user_id, &mut cache)
+            .map_err(AppError::from)
+            .await?
+    {
+        let pending_change = SessionRepository::find_email_change(
+            &pending_token,
+            EMAIL_CHANGE_TOKEN_KEY_PREFIX,
+            &mut cache,
+        )
+        .map_err(AppError::from)
+        .await?;
+
+        if pending_change.is_some_and(|pending| pending.new_email == change.new_email) {
+            SessionRepository::redeem_token(
+                &user_id.to_string(),
+                PENDING_EMAIL_KEY_PREFIX,
+                &mut cache,
+            )
+            .map_err(AppError::from)
+            .await?;
+        }
+    }
+
+    SessionRepository::redeem_token(token, EMAIL_REVERT_TOKEN_KEY_PREFIX, &mut cache)
+        .map_err(AppError::from)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::EmailChangeReverted,
+            Some(user_id),
+            Some(user_id),
+            json!({
+                "old_email": change.old_email,
+                "new_email": change.new_email,
+                "confirmed": confirmed,
+            }),
+        )
+        .await;
+
+    Ok(Status::Ok)
+}
+
+error_responses!(UpdateUserErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+    ProfileError::InvalidFirstName,
+    ProfileError::InvalidLastName,
+    ProfileError::InvalidCountry,
+    ProfileError::InvalidBirthDate,
+});
+
 #[utoipa::path(
     patch,
     path = "/profile/user",
     request_body = UpdateUserDto,
     responses(
         (status = 200, description = "OK", body = UserProfileDto),
-        (status = 401, description = "Unauthorized", body = AuthError, examples(
-            ("InvalidToken" = (summary = "errors::AuthError::InvalidToken", value = json!(AuthError::InvalidToken.value()))),
-        )),
-        (status = 400, description = "Bad Request", body = ProfileError, examples(
-            ("InvalidFirstName" = (summary = "errors::ProfileError::InvalidFirstName", value = json!(ProfileError::InvalidFirstName.value()))),
-            ("InvalidLastName" = (summary = "errors::ProfileError::InvalidLastName", value = json!(ProfileError::InvalidLastName.value()))),
-            ("InvalidCountry" = (summary = "errors::ProfileError::InvalidCountry", value = json!(ProfileError::InvalidCountry.value()))),
-            ("InvalidBirthDate" = (summary = "errors::ProfileError::InvalidBirthDate", value = json!(ProfileError::InvalidBirthDate.value()))),
-        )),
+        UpdateUserErrors,
     ),
     security(("token"=[])),
 )]
@@ -92,15 +440,10 @@ pub async fn update_password(
 pub async fn update_user(
     update_user_dto: Result<Json<UpdateUserDto>, Error<'_>>,
     db: DbConnection,
-    user: Result<User, Value>,
-) -> Result<Custom<Value>, Custom<Value>> {
-    if let Err(value) = user {
-        return Err(Custom(Status::Unauthorized, value));
-    };
-
-    let err = |e: Box<ProfileError>| -> Result<Custom<Value>, Custom<Value>> {
-        Err(Custom(Status::BadRequest, json!(e.value())))
-    };
+    user: Result<SessionUser, AppError>,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let user = user?;
 
     let is_value_invalid = |field_value: String| {
         let trimmed_value = field_value.trim();
@@ -115,19 +458,19 @@ pub async fn update_user(
         Ok(update_user_dto) => {
             if let Some(first_name) = update_user_dto.first_name.clone() {
                 if is_value_invalid(first_name) {
-                    return err(ProfileError::InvalidFirstName.into());
+                    return Err(ProfileError::InvalidFirstName.into());
                 }
             }
 
             if let Some(last_name) = update_user_dto.last_name.clone() {
                 if is_value_invalid(last_name) {
-                    return err(ProfileError::InvalidLastName.into());
+                    return Err(ProfileError::InvalidLastName.into());
                 }
             }
 
             if let Some(country) = update_user_dto.country.clone() {
                 if is_value_invalid(country) {
-                    return err(ProfileError::InvalidCountry.into());
+                    return Err(ProfileError::InvalidCountry.into());
                 }
             }
 
@@ -137,41 +480,403 @@ pub async fn update_user(
                 country: update_user_dto.0.country.map(|v| v.trim().to_string()),
                 birth_date: update_user_dto.0.birth_date,
             };
+            let changed = [
+                ("first_name", info.first_name != user.first_name),
+                ("last_name", info.last_name != user.last_name),
+                ("country", info.country != user.country),
+                ("birth_date", info.birth_date != user.birth_date),
+            ]
+            .into_iter()
+            .filter_map(|(field, is_changed)| is_changed.then_some(field))
+            .collect::<Vec<&str>>();
+
+            let user_id = user.id;
+            let updated_user = db
+                .run(move |connection| UserRepository::update_user(connection, user_id, info))
+                .map_err(AppError::from)
+                .await?;
+
+            audit
+                .record(
+                    &db,
+                    AuditEventType::ProfileUpdated,
+                    Some(user.id),
+                    Some(user.id),
+                    json!({ "changed": changed }),
+                )
+                .await;
 
-            db.run(move |connection| {
-                UserRepository::update_user(connection, user.unwrap().id, info)
-            })
-            .map_err(|e| server_error(e.into()))
-            .map_ok(|updated_user| Custom(Status::Ok, json!(updated_user)))
-            .await
+            Ok(Custom(Status::Ok, json!(updated_user)))
         }
-        Err(_) => err(ProfileError::InvalidBirthDate.into()),
+        Err(_) => Err(ProfileError::InvalidBirthDate.into()),
     }
 }
 
+error_responses!(DeleteUserErrors {
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
 /// Delete the current user's profile
 #[utoipa::path(
     delete,
     path = "/profile/user",
     responses(
         (status = 204),
-        (status = 401, description = "Unauthorized", body = AuthError, examples(
-            ("InvalidToken" = (summary = "errors::AuthError::InvalidToken", value = json!(AuthError::InvalidToken.value()))),
-        )),
+        DeleteUserErrors,
     ),
     security(("token"=[]))
 )]
 #[rocket::delete("/profile/user")]
 pub async fn delete_user(
     db: DbConnection,
-    user: Result<User, Value>,
-) -> Result<Status, Custom<Value>> {
-    if let Err(value) = user {
-        return Err(Custom(Status::Unauthorized, value));
-    };
+    mut cache: Connection<CacheConnection>,
+    config: &State<AppConfig>,
+    access_tokens: &State<AccessTokens>,
+    user: Result<SessionUser, AppError>,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let user = user?;
+
+    let user_id = user.id;
+    db.run(move |connection| UserRepository::delete(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    revoke_outdated_jwts(user_id, access_tokens, &config.tokens, &mut cache).await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::UserDeleted,
+            Some(user.id),
+            Some(user.id),
+            json!({ "username": user.username, "email": user.email }),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
+
+error_responses!(SessionsErrors {
+    AuthError::InvalidToken,
+});
+
+/// List the active sessions of the current user
+///
+/// Sessions are ordered by the time of the last token refresh, the most recent first.
+#[utoipa::path(
+    get,
+    path = "/profile/sessions",
+    responses(
+        (status = 200, description = "OK", body = Vec<SessionDto>),
+        SessionsErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::get("/profile/sessions")]
+pub async fn sessions(
+    session: Result<CurrentSession, AppError>,
+    mut cache: Connection<CacheConnection>,
+) -> Result<Custom<Value>, AppError> {
+    let session = session?;
 
-    db.run(move |connection| UserRepository::delete(connection, user.unwrap().id))
-        .map_err(|e| server_error(e.into()))
-        .map_ok(|_| Status::NoContent)
+    let families = SessionRepository::find_user_token_families(session.0.user_id, &mut cache)
         .await
+        .map_err(AppError::from)?;
+
+    let sessions = families
+        .into_iter()
+        .map(|family| SessionDto {
+            current: family.id == session.0.id,
+            id: family.id,
+            device: family.device,
+            ip: family.ip,
+            created_at: family.created_at,
+            last_used_at: family.last_used_at,
+        })
+        .collect::<Vec<SessionDto>>();
+
+    Ok(Custom(Status::Ok, json!(sessions)))
+}
+
+error_responses!(RevokeSessionErrors {
+    AuthError::InvalidToken,
+    ProfileError::SessionNotFound,
+});
+
+/// Revoke one of the current user's sessions
+#[utoipa::path(
+    delete,
+    path = "/profile/sessions/{id}",
+    params(("id" = String, Path, description = "The session identifier",)),
+    responses(
+        (status = 204),
+        RevokeSessionErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::delete("/profile/sessions/<id>")]
+pub async fn revoke_session(
+    id: &str,
+    session: Result<CurrentSession, AppError>,
+    db: DbConnection,
+    mut cache: Connection<CacheConnection>,
+    audit: Audit,
+    config: &State<AppConfig>,
+) -> Result<Status, AppError> {
+    let session = session?;
+
+    let family = SessionRepository::find_token_family_by_id(id.to_string(), &mut cache)
+        .await
+        .map_err(AppError::from)?
+        .filter(|family| family.user_id == session.0.user_id)
+        .ok_or_else(|| AppError::from(ProfileError::SessionNotFound))?;
+
+    SessionRepository::revoke_token_family(&family, &config.tokens, &mut cache)
+        .await
+        .map_err(AppError::from)?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::SessionRevoked,
+            Some(family.user_id),
+            Some(family.user_id),
+            json!({ "session": family.id }),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
+
+fn invalid_code_error() -> AppError {
+    AppError::from(TwoFactorError::InvalidCode)
+}
+
+error_responses!(EnrollTwoFactorErrors {
+    TwoFactorError::AlreadyEnabled,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// Start enrolling into two-factor authentication
+///
+/// Returns a new TOTP secret and an `otpauth://` URI for authenticator apps;
+///
+/// Two-factor authentication is enabled only after the first code is verified.
+#[utoipa::path(
+    post,
+    path = "/profile/2fa",
+    responses(
+        (status = 200, description = "OK", body = TwoFactorEnrollmentDto),
+        EnrollTwoFactorErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::post("/profile/2fa")]
+pub async fn enroll_two_factor(
+    db: DbConnection,
+    user: SessionUser,
+) -> Result<Custom<Value>, AppError> {
+    let secret = auth::generate_totp_secret();
+    let totp = auth::build_totp(&secret, &user.email).map_err(|e| AppError::Internal(e.into()))?;
+
+    let user_id = user.id;
+    let is_enrolled = db
+        .run(move |connection| {
+            if TwoFactorRepository::is_enabled(connection, user_id)? {
+                return Ok(false);
+            }
+            TwoFactorRepository::enroll(connection, user_id, &secret).map(|_| true)
+        })
+        .map_err(|e: diesel::result::Error| AppError::from(e))
+        .await?;
+
+    if !is_enrolled {
+        return Err(AppError::from(TwoFactorError::AlreadyEnabled));
+    }
+
+    Ok(Custom(
+        Status::Ok,
+        json!(TwoFactorEnrollmentDto {
+            secret: totp.get_secret_base32(),
+            otpauth_uri: totp.get_url(),
+        }),
+    ))
+}
+
+error_responses!(VerifyTwoFactorErrors {
+    TwoFactorError::AlreadyEnabled,
+    TwoFactorError::NotEnrolled,
+    TwoFactorError::InvalidCode,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// Enable two-factor authentication by verifying a code of the authenticator app
+///
+/// Returns one-time recovery codes, they are not shown again.
+#[utoipa::path(
+    post,
+    path = "/profile/2fa/verify",
+    request_body = TotpCodeDto,
+    responses(
+        (status = 200, description = "OK", body = RecoveryCodesDto),
+        VerifyTwoFactorErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::post("/profile/2fa/verify", format = "json", data = "<code_dto>")]
+pub async fn verify_two_factor(
+    code_dto: Json<TotpCodeDto>,
+    db: DbConnection,
+    user: SessionUser,
+    audit: Audit,
+) -> Result<Custom<Value>, AppError> {
+    let user_id = user.id;
+    let is_enabled = db
+        .run(move |connection| TwoFactorRepository::is_enabled(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    if is_enabled {
+        return Err(AppError::from(TwoFactorError::AlreadyEnabled));
+    }
+
+    if !verify_second_factor(&db, &user, &code_dto.code).await? {
+        return Err(invalid_code_error());
+    }
+
+    let recovery_codes = auth::generate_recovery_codes();
+    let code_hashes = recovery_codes
+        .iter()
+        .map(|code| auth::hash_recovery_code(code))
+        .collect::<Vec<String>>();
+
+    db.run(move |connection| TwoFactorRepository::enable(connection, user_id, code_hashes))
+        .map_err(AppError::from)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::TwoFactorEnabled,
+            Some(user_id),
+            Some(user_id),
+            json!({}),
+        )
+        .await;
+
+    Ok(Custom(
+        Status::Ok,
+        json!(RecoveryCodesDto { recovery_codes }),
+    ))
+}
+
+error_responses!(DisableTwoFactorErrors {
+    TwoFactorError::NotEnabled,
+    TwoFactorError::InvalidCode,
+    AuthError::InvalidToken,
+    AuthError::Forbidden,
+});
+
+/// Disable two-factor authentication
+///
+/// Requires a current code of the authenticator app or one of the recovery codes.
+#[utoipa::path(
+    delete,
+    path = "/profile/2fa",
+    request_body = TotpCodeDto,
+    responses(
+        (status = 204),
+        DisableTwoFactorErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::delete("/profile/2fa", format = "json", data = "<code_dto>")]
+pub async fn disable_two_factor(
+    code_dto: Json<TotpCodeDto>,
+    db: DbConnection,
+    user: SessionUser,
+    audit: Audit,
+) -> Result<Status, AppError> {
+    let user_id = user.id;
+    let is_enabled = db
+        .run(move |connection| TwoFactorRepository::is_enabled(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    if !is_enabled {
+        return Err(AppError::from(TwoFactorError::NotEnabled));
+    }
+
+    if !verify_second_factor(&db, &user, &code_dto.code).await? {
+        return Err(invalid_code_error());
+    }
+
+    db.run(move |connection| TwoFactorRepository::disable(connection, user_id))
+        .map_err(AppError::from)
+        .await?;
+
+    audit
+        .record(
+            &db,
+            AuditEventType::TwoFactorDisabled,
+            Some(user_id),
+            Some(user_id),
+            json!({}),
+        )
+        .await;
+
+    Ok(Status::NoContent)
+}
+
+error_responses!(ActivityErrors {
+    AuthError::InvalidToken,
+});
+
+/// List the security-relevant events of the current user's account
+///
+/// Logins, failed logins, password and profile changes, sessions, two-factor settings
+/// and changes made by admins; the most recent first;
+///
+/// Pages are numbered from 1, the page size is 20 by default and at most 100.
+#[utoipa::path(
+    get,
+    path = "/profile/activity",
+    params(
+        ("page" = Option<i64>, Query, description = "Page number, starting from 1"),
+        ("per_page" = Option<i64>, Query, description = "Number of events per page"),
+    ),
+    responses(
+        (status = 200, description = "OK", body = AuditEventsPageDto),
+        ActivityErrors,
+    ),
+    security(("token"=[]))
+)]
+#[rocket::get("/profile/activity?<page>&<per_page>")]
+pub async fn activity(
+    page: Option<i64>,
+    per_page: Option<i64>,
+    db: DbConnection,
+    principal: Result<Principal, AppError>,
+) -> Result<Custom<Value>, AppError> {
+    let principal = principal?;
+
+    let (page, per_page, offset) = pagination(page, per_page);
+    let filter = AuditEventFilter {
+        target_id: Some(principal.id),
+        ..Default::default()
+    };
+
+    let (events, total) = db
+        .run(move |connection| AuditRepository::find_page(connection, &filter, offset, per_page))
+        .map_err(AppError::from)
+        .await?;
+
+    Ok(Custom(
+        Status::Ok,
+        json!(events_page_dto(events, page, per_page, total)),
+    ))
 }
@@ -0,0 +1,346 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, Response};
+use rocket_db_pools::Connection;
+
+use crate::config::AppConfig;
+use crate::errors::{AppError, AuthError};
+use crate::repositories::RateLimitRepository;
+
+use super::{CacheConnection, ClientAddr};
+
+const RATE_LIMIT_KEY_PREFIX: &str = "rate_limit";
+const FAILED_LOGINS_KEY_PREFIX: &str = "failed_logins";
+
+/// Number of requests allowed within a window of the given number of seconds
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+pub struct Limit {
+    pub requests: u64,
+    pub window: usize,
+}
+
+/// Limits of the rate limited endpoints, configured in the `rate_limits` table of `Rocket.toml`
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RateLimits {
+    pub login: Limit,
+    pub login_two_factor: Limit,
+    pub signup: Limit,
+    pub resend_confirmation: Limit,
+    pub password_reset: Limit,
+    pub magic_link: Limit,
+    pub change_password: Limit,
+    pub change_email: Limit,
+    /// Failed logins of a single account before it is locked out until the window ends
+    pub failed_logins: Limit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            login: Limit {
+                requests: 20,
+                window: 60,
+            },
+            login_two_factor: Limit {
+                requests: 20,
+                window: 60,
+            },
+            signup: Limit {
+                requests: 10,
+                window: 60 * 60,
+            },
+            resend_confirmation: Limit {
+                requests: 5,
+                window: 60 * 60,
+            },
+            password_reset: Limit {
+                requests: 5,
+                window: 60 * 60,
+            },
+            magic_link: Limit {
+                requests: 5,
+                window: 60 * 60,
+            },
+            change_password: Limit {
+                requests: 10,
+                window: 60 * 15,
+            },
+            change_email: Limit {
+                requests: 5,
+                window: 60 * 60,
+            },
+            failed_logins: Limit {
+                requests: 5,
+                window: 60 * 15,
+            },
+        }
+    }
+}
+
+pub trait RateLimitScope: Send + Sync + 'static {
+    const NAME: &'static str;
+
+    fn limit(limits: &RateLimits) -> Limit;
+}
+
+pub struct Login;
+pub struct LoginTwoFactor;
+pub struct Signup;
+pub struct ResendConfirmation;
+pub struct PasswordReset;
+pub struct MagicLink;
+pub struct ChangePassword;
+pub struct ChangeEmail;
+
+impl RateLimitScope for Login {
+    const NAME: &'static str = "login";
+
+    fn limit(limits: &RateLimits) -> Limit {
+        limits.login
+    }
+}
+
+impl RateLimitScope for LoginTwoFactor {
+    const NAME: &'static str = "login_two_factor";
+
+    fn limit(limits: &RateLimits) -> Limit {
+        limits.login_two_factor
+    }
+}
+
+impl RateLimitScope for Signup {
+    const NAME: &'static str = "signup";
+
+    fn limit(limits: &RateLimits) -> Limit {
+        limits.signup
+    }
+}
+
+impl RateLimitScope for ResendConfirmation {
+    const NAME: &'static str = "resend_confirmation";
+
+    fn limit(limits: &RateLimits) -> Limit {
+        limits.resend_confirmation
+    }
+}
+
+impl RateLimitScope for PasswordReset {
+    const NAME: &'static str = "password_reset";
+
+    fn limit(limits: &RateLimits) -> Limit {
+        limits.password_reset
+    }
+}
+
+impl RateLimitScope for MagicLink {
+    const NAME: &'static str = "magic_link";
+
+    fn limit(limits: &RateLimits) -> Limit {
+        limits.magic_link
+    }
+}
+
+impl RateLimitScope for ChangePassword {
+    const NAME: &'static str = "change_password";
+
+    fn limit(limits: &RateLimits) -> Limit {
+        limits.change_password
+    }
+}
+
+impl RateLimitScope for ChangeEmail {
+    const NAME: &'static str = "change_email";
+
+    fn limit(limits: &RateLimits) -> Limit {
+        limits.change_email
+    }
+}
+
+/// Rate limit headers of the current request, written to the response by [`RateLimiter`]
+#[derive(Default)]
+struct RateLimitHeaders {
+    limit: AtomicU64,
+    remaining: AtomicU64,
+    retry_after: AtomicU64,
+}
+
+fn rate_limit_headers<'r>(request: &'r Request<'_>) -> &'r RateLimitHeaders {
+    request.local_cache(RateLimitHeaders::default)
+}
+
+/// Request guard counting requests of the client IP address within the window of the scope `S`
+///
+/// The guard fails with `AuthError::TooManyRequests` once the limit of the window is exceeded.
+pub struct RateLimit<'r, S: RateLimitScope> {
+    headers: &'r RateLimitHeaders,
+    limits: RateLimits,
+    scope: PhantomData<S>,
+}
+
+impl<'r, S: RateLimitScope> RateLimit<'r, S> {
+    /// Count the request against the account it is made for, within the same limit as the IP
+    ///
+    /// Keeps a client spreading its requests over many addresses from flooding a single account.
+    pub async fn hit_account(
+        &self,
+        user_id: i32,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), AppError> {
+        let key = format!("{}/{}/user/{}", RATE_LIMIT_KEY_PREFIX, S::NAME, user_id);
+        let limit = S::limit(&self.limits);
+        let (count, ttl) = RateLimitRepository::hit(&key, limit.window, cache)
+            .await
+            .map_err(AppError::from)?;
+
+        if count > limit.requests {
+            log::warn!("Rate limit of {} exceeded for user {}", S::NAME, user_id);
+            return Err(self.reject(ttl));
+        }
+        Ok(())
+    }
+
+    /// Fail if the account is locked out after too many failed logins
+    pub async fn check_account(
+        &self,
+        user_id: i32,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), AppError> {
+        let key = format!("{}/{}", FAILED_LOGINS_KEY_PREFIX, user_id);
+        let (failures, ttl) = RateLimitRepository::find(&key, cache)
+            .await
+            .map_err(AppError::from)?;
+
+        if failures >= self.limits.failed_logins.requests {
+            return Err(self.reject(ttl));
+        }
+        Ok(())
+    }
+
+    /// Count a failed login of the account, the error is returned once the account gets locked out
+    pub async fn fail_account(
+        &self,
+        user_id: i32,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), AppError> {
+        let key = format!("{}/{}", FAILED_LOGINS_KEY_PREFIX, user_id);
+        let failed_logins = self.limits.failed_logins;
+        let (failures, ttl) = RateLimitRepository::hit(&key, failed_logins.window, cache)
+            .await
+            .map_err(AppError::from)?;
+
+        if failures >= failed_logins.requests {
+            log::warn!("Too many failed logins, locking out user {}", user_id);
+            return Err(self.reject(ttl));
+        }
+        Ok(())
+    }
+
+    pub async fn reset_account(
+        &self,
+        user_id: i32,
+        cache: &mut Connection<CacheConnection>,
+    ) -> Result<(), AppError> {
+        let key = format!("{}/{}", FAILED_LOGINS_KEY_PREFIX, user_id);
+        RateLimitRepository::reset(&key, cache)
+            .await
+            .map_err(AppError::from)
+    }
+
+    fn reject(&self, retry_after: u64) -> AppError {
+        self.headers
+            .retry_after
+            .store(retry_after.max(1), Ordering::Relaxed);
+        AuthError::TooManyRequests.into()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, S: RateLimitScope> FromRequest<'r> for RateLimit<'r, S> {
+    type Error = AppError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let client_addr = match request.guard::<ClientAddr>().await {
+            Outcome::Success(client_addr) => client_addr,
+            Outcome::Error(error) => return Outcome::Error(error),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+
+        let mut cache = match request.guard::<Connection<CacheConnection>>().await {
+            Outcome::Success(cache) => cache,
+            _ => {
+                return Outcome::Error((
+                    Status::InternalServerError,
+                    AppError::Http(Status::InternalServerError),
+                ))
+            }
+        };
+
+        let limits = request
+            .rocket()
+            .state::<AppConfig>()
+            .map(|config| config.rate_limits.clone())
+            .unwrap_or_default();
+        let limit = S::limit(&limits);
+
+        let key = format!("{}/{}/{}", RATE_LIMIT_KEY_PREFIX, S::NAME, client_addr.0);
+        let (count, ttl) = match RateLimitRepository::hit(&key, limit.window, &mut cache).await {
+            Ok(hit) => hit,
+            Err(e) => return Outcome::Error((Status::InternalServerError, e.into())),
+        };
+
+        let headers = rate_limit_headers(request);
+        headers.limit.store(limit.requests, Ordering::Relaxed);
+        headers
+            .remaining
+            .store(limit.requests.saturating_sub(count), Ordering::Relaxed);
+
+        let rate_limit = RateLimit {
+            headers,
+            limits,
+            scope: PhantomData,
+        };
+
+        if count > limit.requests {
+            log::warn!("Rate limit of {} exceeded by {}", S::NAME, client_addr.0);
+            return Outcome::Error((Status::TooManyRequests, rate_limit.reject(ttl)));
+        }
+
+        Outcome::Success(rate_limit)
+    }
+}
+
+/// Append rate limit headers in responses
+pub struct RateLimiter;
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit auth endpoints",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let headers = rate_limit_headers(req);
+
+        let limit = headers.limit.load(Ordering::Relaxed);
+        if limit > 0 {
+            res.set_raw_header("X-RateLimit-Limit", limit.to_string());
+            res.set_raw_header(
+                "X-RateLimit-Remaining",
+                headers.remaining.load(Ordering::Relaxed).to_string(),
+            );
+        }
+
+        let retry_after = headers.retry_after.load(Ordering::Relaxed);
+        if retry_after > 0 {
+            res.set_raw_header("Retry-After", retry_after.to_string());
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Data, Request, Response};
+
+use crate::auth::generate_token;
+
+use super::health::is_health_check;
+use super::ClientAddr;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const REQUEST_ID_LENGTH: usize = 16;
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Id of the request, taken from the `X-Request-Id` header if the client or a proxy
+/// has set a valid one, otherwise generated
+#[derive(Clone, Debug)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// The id of the request, assigned on first access
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r RequestId {
+        request.local_cache(|| {
+            let id = request
+                .headers()
+                .get_one(REQUEST_ID_HEADER)
+                .filter(|id| is_valid(id))
+                .map(String::from)
+                .unwrap_or_else(|| generate_token(REQUEST_ID_LENGTH));
+            RequestId(id)
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(request))
+    }
+}
+
+/// Id of the user authenticated by the `User` guard, reported in the access log
+pub(crate) struct AuthenticatedUserId(pub Option<i32>);
+
+struct RequestStart(Instant);
+
+/// Time elapsed since the request has been received
+pub fn elapsed(request: &Request<'_>) -> Duration {
+    request
+        .local_cache(|| RequestStart(Instant::now()))
+        .0
+        .elapsed()
+}
+
+/// Assigns the request id, echoes it in the `X-Request-Id` response header
+/// and writes an access log record for every request
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request id and access log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+        RequestId::of(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let request_id = RequestId::of(req);
+        res.set_raw_header(REQUEST_ID_HEADER, request_id.to_string());
+
+        let latency = elapsed(req);
+        let route = req.route().map(|route| route.uri.to_string());
+        let user_id = req.local_cache(|| AuthenticatedUserId(None)).0;
+        let client_ip = req
+            .guard::<ClientAddr>()
+            .await
+            .succeeded()
+            .map(|addr| addr.0.to_string());
+
+        // Probes of the health checks would drown the other requests
+        let level = if is_health_check(req) {
+            log::Level::Debug
+        } else {
+            log::Level::Info
+        };
+        log::log!(
+            target: "access",
+            level,
+            request_id = request_id.as_str(),
+            method = req.method().as_str(),
+            path = req.uri().path().as_str(),
+            route = route.as_deref(),
+            status = res.status().code,
+            latency_ms = latency.as_secs_f64() * 1000.0,
+            user_id = user_id,
+            client_ip = client_ip.as_deref();
+            "{} {} {} {:.1}ms [{}]",
+            req.method(),
+            req.uri().path(),
+            res.status().code,
+            latency.as_secs_f64() * 1000.0,
+            request_id
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+use crate::errors::{AppError, AuthError};
+use crate::models::{RoleCode, UserType};
+
+use super::{ApiKeyScopes, DbConnection, Principal};
+
+pub trait RoleRequirement: Send + Sync + 'static {
+    const CODE: RoleCode;
+}
+
+pub struct Admin;
+pub struct Editor;
+pub struct Viewer;
+
+impl RoleRequirement for Admin {
+    const CODE: RoleCode = RoleCode::Admin;
+}
+
+impl RoleRequirement for Editor {
+    const CODE: RoleCode = RoleCode::Editor;
+}
+
+impl RoleRequirement for Viewer {
+    const CODE: RoleCode = RoleCode::Viewer;
+}
+
+/// Request guard admitting authenticated users that have the role `R` or a higher one
+///
+/// Roles of enterprise users are scoped to their companies, so they never pass this guard;
+/// requests with an API key also need a scope of the key granting `R`. The roles of a JWT are
+/// taken from its claims, they are only loaded for the other tokens.
+pub struct RequireRole<R: RoleRequirement> {
+    pub principal: Principal,
+    role: PhantomData<R>,
+}
+
+pub type AdminUser = RequireRole<Admin>;
+pub type EditorUser = RequireRole<Editor>;
+pub type ViewerUser = RequireRole<Viewer>;
+
+impl<R: RoleRequirement> Deref for RequireRole<R> {
+    type Target = Principal;
+
+    fn deref(&self) -> &Self::Target {
+        &self.principal
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, R: RoleRequirement> FromRequest<'r> for RequireRole<R> {
+    type Error = AppError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let principal = match request.guard::<Principal>().await {
+            Outcome::Success(principal) => principal,
+            Outcome::Error(error) => return Outcome::Error(error),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+
+        let forbidden =
+            || Outcome::Error((Status::Forbidden, AppError::from(AuthError::Forbidden)));
+
+        if principal.user_type != UserType::Regular {
+            return forbidden();
+        }
+
+        let db = request
+            .guard::<DbConnection>()
+            .await
+            .expect("Cannot connect to postgres in request guard");
+
+        let role_owner = principal.clone();
+        let role_codes = match db
+            .run(move |connection| role_owner.role_codes(connection))
+            .await
+        {
+            Ok(role_codes) => role_codes,
+            Err(e) => {
+                log::error!("Unable to load roles of user {}", principal.id);
+                return Outcome::Error((Status::InternalServerError, e.into()));
+            }
+        };
+
+        if !role_codes.iter().any(|code| code.grants(&R::CODE)) {
+            return forbidden();
+        }
+        if !request.local_cache(|| ApiKeyScopes(None)).allow(&R::CODE) {
+            return forbidden();
+        }
+
+        Outcome::Success(RequireRole {
+            principal,
+            role: PhantomData,
+        })
+    }
+}
@@ -1,5 +1,38 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_keys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        name -> Varchar,
+        #[max_length = 32]
+        prefix -> Varchar,
+        #[max_length = 64]
+        key_hash -> Varchar,
+        scopes -> Array<Text>,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    audit_events (id) {
+        id -> Int4,
+        actor_id -> Nullable<Int4>,
+        target_id -> Nullable<Int4>,
+        #[max_length = 64]
+        event_type -> Varchar,
+        #[max_length = 45]
+        ip -> Nullable<Varchar>,
+        #[max_length = 256]
+        user_agent -> Nullable<Varchar>,
+        payload -> Jsonb,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     companies (id) {
         id -> Int4,
@@ -16,6 +49,17 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 128]
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     roles (id) {
         id -> Int4,
@@ -37,6 +81,20 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        provider -> Varchar,
+        #[max_length = 255]
+        subject -> Varchar,
+        #[max_length = 255]
+        email -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     user_roles (id) {
         id -> Int4,
@@ -45,6 +103,18 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_totp (user_id) {
+        user_id -> Int4,
+        #[max_length = 64]
+        secret -> Varchar,
+        enabled -> Bool,
+        last_used_step -> Nullable<Int8>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
@@ -66,19 +136,46 @@ diesel::table! {
         updated_at -> Timestamp,
         #[max_length = 24]
         user_type -> Varchar,
+        confirmation_sent_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webauthn_credentials (id) {
+        id -> Int4,
+        user_id -> Int4,
+        credential_id -> Bytea,
+        #[max_length = 36]
+        user_handle -> Varchar,
+        #[max_length = 64]
+        name -> Varchar,
+        passkey -> Jsonb,
+        last_used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
     }
 }
 
+diesel::joinable!(api_keys -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
 diesel::joinable!(user_company_roles -> companies (company_id));
 diesel::joinable!(user_company_roles -> roles (role_id));
 diesel::joinable!(user_company_roles -> users (user_id));
+diesel::joinable!(user_identities -> users (user_id));
 diesel::joinable!(user_roles -> roles (role_id));
 diesel::joinable!(user_roles -> users (user_id));
+diesel::joinable!(user_totp -> users (user_id));
+diesel::joinable!(webauthn_credentials -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_keys,
+    audit_events,
     companies,
+    recovery_codes,
     roles,
     user_company_roles,
+    user_identities,
     user_roles,
+    user_totp,
     users,
+    webauthn_credentials,
 );
@@ -0,0 +1,105 @@
+use reqwest::Url;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Build, Rocket};
+use serde::Deserialize;
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+
+use crate::config::AppConfig;
+
+/// Passkeys, configured in the `webauthn` table of `Rocket.toml`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WebauthnConfig {
+    /// Relying party id, a domain of every origin; the host of the first origin if not set
+    pub rp_id: Option<String>,
+    /// Name of the relying party shown by the authenticators
+    pub rp_name: String,
+    /// Origins of the clients, `base_url` if empty; Android apps are identified
+    /// by `android:apk-key-hash:...` origins
+    pub origins: Vec<String>,
+}
+
+impl Default for WebauthnConfig {
+    fn default() -> Self {
+        WebauthnConfig {
+            rp_id: None,
+            rp_name: "Template App".to_string(),
+            origins: Vec::new(),
+        }
+    }
+}
+
+impl WebauthnConfig {
+    pub fn validate(&self, base_url: &str) -> Result<(), String> {
+        self.build(base_url).map(|_| ())
+    }
+
+    /// Relying party of the passkeys, every origin must be within the relying party id
+    pub fn build(&self, base_url: &str) -> Result<Webauthn, String> {
+        let origins = if self.origins.is_empty() {
+            vec![base_url.to_string()]
+        } else {
+            self.origins.clone()
+        };
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                Url::parse(origin)
+                    .map_err(|_| format!("webauthn origin {:?} must be a URL", origin))
+            })
+            .collect::<Result<Vec<Url>, String>>()?;
+
+        let rp_id = match &self.rp_id {
+            Some(rp_id) => rp_id.clone(),
+            None => origins[0]
+                .domain()
+                .ok_or("webauthn.rp_id must be set if the origin has no domain")?
+                .to_string(),
+        };
+
+        let builder = WebauthnBuilder::new(&rp_id, &origins[0])
+            .map_err(|e| {
+                format!(
+                    "webauthn.rp_id {:?} does not match the origin: {}",
+                    rp_id, e
+                )
+            })?
+            .rp_name(&self.rp_name);
+
+        origins[1..]
+            .iter()
+            .fold(builder, |builder, origin| {
+                builder.append_allowed_origin(origin)
+            })
+            .build()
+            .map_err(|e| format!("webauthn: {}", e))
+    }
+}
+
+/// Manages the `Webauthn` relying party of the passkeys
+pub struct WebauthnFairing;
+
+#[rocket::async_trait]
+impl Fairing for WebauthnFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "WebAuthn",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let Some(config) = rocket.state::<AppConfig>() else {
+            log::error!("WebAuthn requires the app config to be managed first");
+            return Err(rocket);
+        };
+
+        match config.webauthn.build(&config.base_url) {
+            Ok(webauthn) => Ok(rocket.manage(webauthn)),
+            Err(e) => {
+                log::error!("Cannot set up WebAuthn: {}", e);
+                Err(rocket)
+            }
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use reqwest::StatusCode;
+use rocket::serde::json::json;
+use rust_template::errors::{AdminError, ApiError, AuthError};
+use serde_json::{from_value, Value};
+
+use crate::common::{
+    delete_test_user, get_client_with_logged_in_admin, get_client_with_logged_in_editor,
+    get_client_with_logged_in_viewer, get_logged_in_client,
+};
+
+pub mod common;
+
+#[test]
+fn when_role_viewer_then_admin_users_returns_forbidden_error() {
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+
+    let response = client
+        .get(format!("{}/admin/users", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AuthError::Forbidden.value()
+    );
+}
+
+#[test]
+fn when_role_editor_then_admin_users_returns_forbidden_error() {
+    let (client, create_user_output) = get_client_with_logged_in_editor();
+
+    let response = client
+        .get(format!("{}/admin/users/1", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn when_role_admin_then_admin_users_returns_page() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+
+    let response = client
+        .get(format!(
+            "{}/admin/users?page=1&per_page=1",
+            common::APP_HOST
+        ))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert_eq!(json["page"], 1);
+    assert_eq!(json["per_page"], 1);
+    assert_eq!(json["items"].as_array().unwrap().len(), 1);
+    assert!(json["total"].as_i64().unwrap() >= 1);
+}
+
+#[test]
+fn when_page_is_out_of_range_then_admin_users_returns_empty_page() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+
+    let response = client
+        .get(format!(
+            "{}/admin/users?page={}&per_page=100",
+            common::APP_HOST,
+            i64::MAX
+        ))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert_eq!(json["page"], 1_000_000);
+    assert!(json["items"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn when_user_has_viewer_and_admin_roles_then_admin_users_returns_page() {
+    let username = format!("testAdmin{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) = get_logged_in_client(&username, &email, "viewer,admin");
+
+    let response = client
+        .get(format!("{}/admin/users", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_admin_is_enterprise_user_then_admin_users_returns_forbidden_error() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let (enterprise_client, enterprise_output) = get_client_with_logged_in_admin();
+
+    let me: Value = enterprise_client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let type_response = admin_client
+        .put(format!(
+            "{}/admin/users/{}/type",
+            common::APP_HOST,
+            me["id"]
+        ))
+        .json(&json!({ "user_type": "enterprise" }))
+        .send()
+        .unwrap();
+
+    let response = enterprise_client
+        .get(format!("{}/admin/users", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(enterprise_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(type_response.status(), StatusCode::OK);
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AuthError::Forbidden.value()
+    );
+}
+
+#[test]
+fn when_role_admin_then_create_get_and_delete_user_success() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+    let username = format!("testCreated{}", rand::random::<u32>());
+
+    let response = client
+        .post(format!("{}/admin/users", common::APP_HOST))
+        .json(&json!({
+            "username": username,
+            "email": format!("{}@gmail.com", username),
+            "password": "123456aA",
+            "confirmed": true,
+            "user_type": "enterprise",
+            "roles": ["editor"]
+        }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Value = response.json().unwrap();
+    let id = created["id"].as_i64().unwrap();
+
+    let response = client
+        .get(format!("{}/admin/users/{}", common::APP_HOST, id))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+
+    let delete_response = client
+        .delete(format!("{}/admin/users/{}", common::APP_HOST, id))
+        .send()
+        .unwrap();
+
+    let get_deleted_response = client
+        .get(format!("{}/admin/users/{}", common::APP_HOST, id))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(json["username"], username.as_str());
+    assert_eq!(json["confirmed"], true);
+    assert_eq!(json["user_type"], "enterprise");
+    assert_eq!(json["roles"], json!(["editor"]));
+    assert_eq!(delete_response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(get_deleted_response.status(), StatusCode::NOT_FOUND);
+    let json: Value = get_deleted_response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AdminError::UserNotFound.value()
+    );
+}
+
+#[test]
+fn when_role_is_unknown_then_create_user_returns_invalid_role_error() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+    let username = format!("testCreated{}", rand::random::<u32>());
+
+    let response = client
+        .post(format!("{}/admin/users", common::APP_HOST))
+        .json(&json!({
+            "username": username,
+            "email": format!("{}@gmail.com", username),
+            "password": "123456aA",
+            "roles": ["owner"]
+        }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AdminError::InvalidRole.value()
+    );
+}
+
+#[test]
+fn when_role_admin_then_set_type_and_roles_success() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let (viewer_client, viewer_output) = get_client_with_logged_in_viewer();
+
+    let me: Value = viewer_client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let id = me["id"].as_i64().unwrap();
+
+    let type_response = admin_client
+        .put(format!("{}/admin/users/{}/type", common::APP_HOST, id))
+        .json(&json!({ "user_type": "enterprise" }))
+        .send()
+        .unwrap();
+    let type_json: Value = type_response.json().unwrap();
+
+    let add_response = admin_client
+        .post(format!("{}/admin/users/{}/roles", common::APP_HOST, id))
+        .json(&json!({ "roles": ["editor"] }))
+        .send()
+        .unwrap();
+    let add_json: Value = add_response.json().unwrap();
+
+    let remove_response = admin_client
+        .delete(format!("{}/admin/users/{}/roles", common::APP_HOST, id))
+        .json(&json!({ "roles": ["viewer"] }))
+        .send()
+        .unwrap();
+    let remove_json: Value = remove_response.json().unwrap();
+
+    // Cleanup
+    delete_test_user(viewer_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(type_json["user_type"], "enterprise");
+    let mut roles: Vec<String> = from_value(add_json["roles"].clone()).unwrap();
+    roles.sort();
+    assert_eq!(roles, vec!["editor", "viewer"]);
+    assert_eq!(remove_json["roles"], json!(["editor"]));
+}
+
+#[test]
+fn when_admin_deletes_self_then_returns_self_modification_error() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+
+    let me: Value = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+
+    let response = client
+        .delete(format!(
+            "{}/admin/users/{}",
+            common::APP_HOST,
+            me["id"].as_i64().unwrap()
+        ))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AdminError::SelfModification.value()
+    );
+}
+
+#[test]
+fn when_role_admin_then_audit_events_are_filtered_by_target_and_type() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let (viewer_client, viewer_output) = get_client_with_logged_in_viewer();
+
+    let admin: Value = admin_client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let viewer: Value = viewer_client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let id = viewer["id"].as_i64().unwrap();
+
+    let add_response = admin_client
+        .post(format!("{}/admin/users/{}/roles", common::APP_HOST, id))
+        .json(&json!({ "roles": ["editor"] }))
+        .send()
+        .unwrap();
+
+    let response = admin_client
+        .get(format!(
+            "{}/admin/audit_events?target_id={}&event_type=roles_added&from=2024-01-01",
+            common::APP_HOST,
+            id
+        ))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(viewer_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(add_response.status(), StatusCode::OK);
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert_eq!(json["total"], 1);
+    let event = &json["items"][0];
+    assert_eq!(event["event_type"], "roles_added");
+    assert_eq!(event["actor_id"], admin["id"]);
+    assert_eq!(event["target_id"], id);
+    assert_eq!(event["payload"]["roles"], json!(["editor"]));
+}
+
+#[test]
+fn when_filters_are_invalid_then_audit_events_returns_admin_errors() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+
+    let type_response = client
+        .get(format!(
+            "{}/admin/audit_events?event_type=unknown",
+            common::APP_HOST
+        ))
+        .send()
+        .unwrap();
+    let date_response = client
+        .get(format!(
+            "{}/admin/audit_events?from=yesterday",
+            common::APP_HOST
+        ))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(type_response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = type_response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AdminError::InvalidEventType.value()
+    );
+    assert_eq!(date_response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = date_response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AdminError::InvalidDateTime.value()
+    );
+}
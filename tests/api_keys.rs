@@ -0,0 +1,196 @@
+use reqwest::{blocking::Client, StatusCode};
+use rocket::serde::json::json;
+use rust_template::errors::{ApiError, ApiKeyError, AuthError};
+use serde_json::{from_value, Value};
+
+use crate::common::{
+    delete_test_user, get_client_with_logged_in_admin, get_client_with_logged_in_viewer,
+};
+
+pub mod common;
+
+/// Create an API key with the logged in client, returns the response body
+fn create_api_key(client: &Client, scopes: &[&str]) -> Value {
+    let response = client
+        .post(format!("{}/profile/api-keys", common::APP_HOST))
+        .json(&json!({ "name": "Test key", "scopes": scopes }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json().unwrap()
+}
+
+fn get_with_key(path: &str, key: &str) -> reqwest::blocking::Response {
+    Client::new()
+        .get(format!("{}{}", common::APP_HOST, path))
+        .bearer_auth(key)
+        .send()
+        .unwrap()
+}
+
+#[test]
+fn when_api_key_is_created_then_it_authenticates_requests() {
+    let (client, output) = get_client_with_logged_in_viewer();
+
+    let json = create_api_key(&client, &["viewer"]);
+    let key = json["key"].as_str().unwrap();
+    let prefix = json["api_key"]["prefix"].as_str().unwrap();
+    assert!(prefix.starts_with("tmplt_"));
+    assert!(key.starts_with(&format!("{}_", prefix)));
+    assert_eq!(json["api_key"]["scopes"], json!(["viewer"]));
+
+    let response = get_with_key("/profile/me", key);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let api_keys: Vec<Value> = client
+        .get(format!("{}/profile/api-keys", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(api_keys.len(), 1);
+    assert_eq!(api_keys[0]["prefix"], prefix);
+    assert!(api_keys[0].get("key").is_none());
+    assert!(!api_keys[0]["last_used_at"].is_null());
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_api_key_scope_is_lower_than_required_role_then_returns_forbidden_error() {
+    let (client, output) = get_client_with_logged_in_admin();
+
+    let viewer_key = create_api_key(&client, &["viewer"]);
+    let response = get_with_key("/admin/users", viewer_key["key"].as_str().unwrap());
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, AuthError::Forbidden.value().code);
+
+    let admin_key = create_api_key(&client, &["admin"]);
+    let response = get_with_key("/admin/users", admin_key["key"].as_str().unwrap());
+    assert_eq!(response.status(), StatusCode::OK);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_api_key_is_used_then_api_keys_cannot_be_managed() {
+    let (client, output) = get_client_with_logged_in_viewer();
+    let json = create_api_key(&client, &["viewer"]);
+
+    let response = get_with_key("/profile/api-keys", json["key"].as_str().unwrap());
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, AuthError::InvalidToken.value().code);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_api_key_is_used_then_account_cannot_be_managed() {
+    let (client, output) = get_client_with_logged_in_viewer();
+    let json = create_api_key(&client, &["viewer"]);
+    let key = json["key"].as_str().unwrap();
+    let key_client = Client::new();
+    let url = |path: &str| format!("{}{}", common::APP_HOST, path);
+
+    let requests = [
+        key_client
+            .post(url("/profile/email"))
+            .json(&json!({ "email": "taken.over@example.com", "password": "123456aA" })),
+        key_client
+            .patch(url("/profile/user"))
+            .json(&json!({ "first_name": "Mallory" })),
+        key_client.delete(url("/profile/user")),
+        key_client.post(url("/profile/2fa")),
+        key_client
+            .post(url("/profile/2fa/verify"))
+            .json(&json!({ "code": "123456" })),
+        key_client
+            .delete(url("/profile/2fa"))
+            .json(&json!({ "code": "123456" })),
+        key_client.post(url("/profile/passkeys/begin")),
+        key_client.post(url("/logout/all")),
+    ];
+    for request in requests {
+        let response = request.bearer_auth(key).send().unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let error: ApiError = from_value(response.json().unwrap()).unwrap();
+        assert_eq!(error.code, AuthError::Forbidden.value().code);
+    }
+
+    let response = get_with_key("/profile/me", key);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_api_key_is_renamed_and_deleted_then_it_is_rejected() {
+    let (client, output) = get_client_with_logged_in_viewer();
+    let json = create_api_key(&client, &["viewer"]);
+    let key = json["key"].as_str().unwrap();
+    let url = format!(
+        "{}/profile/api-keys/{}",
+        common::APP_HOST,
+        json["api_key"]["id"]
+    );
+
+    let response = client
+        .put(&url)
+        .json(&json!({ "name": " Nightly build " }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let renamed: Value = response.json().unwrap();
+    assert_eq!(renamed["name"], "Nightly build");
+
+    let response = client.delete(&url).send().unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = get_with_key("/profile/me", key);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = client.delete(&url).send().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, ApiKeyError::NotFound.value().code);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_api_key_is_invalid_then_create_returns_error() {
+    let (client, output) = get_client_with_logged_in_viewer();
+
+    let cases = [
+        (
+            json!({ "name": "Test key", "scopes": [] }),
+            ApiKeyError::InvalidScopes,
+        ),
+        (
+            json!({ "name": "Test key", "scopes": ["owner"] }),
+            ApiKeyError::InvalidScopes,
+        ),
+        (
+            json!({ "name": "", "scopes": ["viewer"] }),
+            ApiKeyError::InvalidName,
+        ),
+        (
+            json!({ "name": "Test key", "scopes": ["viewer"], "expires_at": "2020-01-01T00:00:00" }),
+            ApiKeyError::InvalidExpiration,
+        ),
+    ];
+    for (body, expected) in cases {
+        let response = client
+            .post(format!("{}/profile/api-keys", common::APP_HOST))
+            .json(&body)
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ApiError = from_value(response.json().unwrap()).unwrap();
+        assert_eq!(error.code, expected.value().code);
+    }
+
+    delete_test_user(output);
+}
@@ -3,21 +3,28 @@ use reqwest::{
     blocking::Client,
     header::{
         ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
-        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_TYPE, ORIGIN, VARY,
     },
-    StatusCode,
+    Method, StatusCode,
 };
 use rocket::form::validate::Len;
+use rocket_db_pools::deadpool_redis::redis;
 use rust_template::{
     dto::NewUserResponseDto,
     errors::{ApiError, AuthError},
 };
 use serde_json::{from_value, json, Value};
+use std::time::Duration;
 
-use crate::common::{create_test_user, delete_test_user, generate_test_token};
+use crate::common::{
+    create_test_user, delete_test_user, generate_test_token, login_test_user, TestServer,
+};
 
 pub mod common;
 
+const ALLOWED_ORIGIN: &str = "http://localhost:3000";
+
 #[test]
 fn when_credentials_correct_then_login_success() {
     let username = format!("testViewer{}", rand::random::<u32>());
@@ -67,6 +74,273 @@ fn when_credentials_correct_then_login_returns_token() {
     assert_eq!(json["token"].as_str().len(), 128);
 }
 
+#[test]
+fn when_credentials_correct_then_login_returns_refresh_token() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "1234";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let json = login_test_user(&email, password);
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(json["refresh_token"].as_str().len(), SESSION_ID_LENGTH);
+    assert_ne!(json["refresh_token"], json["token"]);
+}
+
+#[test]
+fn when_refresh_token_valid_then_refresh_rotates_tokens() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "1234";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let tokens = login_test_user(&email, password);
+
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/token/refresh", common::APP_HOST))
+        .json(&json!({
+            "refresh_token": tokens["refresh_token"],
+        }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let rotated: Value = response.json().unwrap();
+
+    let old_token_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(tokens["token"].as_str().unwrap())
+        .send()
+        .unwrap();
+    let new_token_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(rotated["token"].as_str().unwrap())
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_ne!(rotated["refresh_token"], tokens["refresh_token"]);
+    assert_eq!(old_token_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(new_token_response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_refresh_token_reused_then_token_family_revoked() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "1234";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let tokens = login_test_user(&email, password);
+
+    let client = Client::new();
+    let refresh = |refresh_token: &Value| {
+        client
+            .post(format!("{}/token/refresh", common::APP_HOST))
+            .json(&json!({
+                "refresh_token": refresh_token,
+            }))
+            .send()
+            .unwrap()
+    };
+
+    let rotated: Value = refresh(&tokens["refresh_token"]).json().unwrap();
+    let reuse_response = refresh(&tokens["refresh_token"]);
+    let rotated_response = refresh(&rotated["refresh_token"]);
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(reuse_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(rotated_response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn when_refresh_token_used_concurrently_then_winning_tokens_revoked() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "1234";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let tokens = login_test_user(&email, password);
+
+    let refreshes = (0..8)
+        .map(|_| {
+            let refresh_token = tokens["refresh_token"].clone();
+            std::thread::spawn(move || {
+                Client::new()
+                    .post(format!("{}/token/refresh", common::APP_HOST))
+                    .json(&json!({
+                        "refresh_token": refresh_token,
+                    }))
+                    .send()
+                    .unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    let rotated = refreshes
+        .into_iter()
+        .map(|refresh| refresh.join().unwrap())
+        .filter(|response| response.status() == StatusCode::OK)
+        .map(|response| response.json::<Value>().unwrap())
+        .collect::<Vec<_>>();
+
+    let client = Client::new();
+    let me_responses = rotated
+        .iter()
+        .map(|tokens| {
+            client
+                .get(format!("{}/profile/me", common::APP_HOST))
+                .bearer_auth(tokens["token"].as_str().unwrap())
+                .send()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(rotated.len(), 1);
+    for response in me_responses {
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[test]
+fn when_logged_out_then_token_is_rejected() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "1234";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let tokens = login_test_user(&email, password);
+    let token = tokens["token"].as_str().unwrap();
+
+    let client = Client::new();
+
+    let logout_response = client
+        .post(format!("{}/logout", common::APP_HOST))
+        .bearer_auth(token)
+        .send()
+        .unwrap();
+    let me_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(token)
+        .send()
+        .unwrap();
+    let refresh_response = client
+        .post(format!("{}/token/refresh", common::APP_HOST))
+        .json(&json!({
+            "refresh_token": tokens["refresh_token"],
+        }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(logout_response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(me_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(refresh_response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn when_logged_out_everywhere_then_all_tokens_are_rejected() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "1234";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let first_tokens = login_test_user(&email, password);
+    let second_tokens = login_test_user(&email, password);
+
+    let client = Client::new();
+
+    let logout_response = client
+        .post(format!("{}/logout/all", common::APP_HOST))
+        .bearer_auth(first_tokens["token"].as_str().unwrap())
+        .send()
+        .unwrap();
+    let me_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(second_tokens["token"].as_str().unwrap())
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(logout_response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(me_response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn when_session_is_refreshed_past_first_lifetime_then_logout_everywhere_revokes_it() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "1234";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let server = TestServer::start(
+        8110,
+        &[("ROCKET_TOKENS", "{session=4,refresh_token=6}".to_string())],
+    );
+    let client = Client::new();
+    let refresh = |refresh_token: &Value| -> Value {
+        let response = client
+            .post(format!("{}/token/refresh", server.host))
+            .json(&json!({
+                "refresh_token": refresh_token,
+            }))
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.json().unwrap()
+    };
+
+    let tokens: Value = client
+        .post(format!("{}/login", server.host))
+        .json(&json!({
+            "email": email,
+            "password": password,
+        }))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+
+    // Each refresh happens before the refresh token expires, the last one after the first
+    // refresh token would have expired
+    std::thread::sleep(Duration::from_millis(3500));
+    let tokens = refresh(&tokens["refresh_token"]);
+    std::thread::sleep(Duration::from_millis(3500));
+    let tokens = refresh(&tokens["refresh_token"]);
+    let token = tokens["token"].as_str().unwrap();
+
+    let logout_response = client
+        .post(format!("{}/logout/all", server.host))
+        .bearer_auth(token)
+        .send()
+        .unwrap();
+    let me_response = client
+        .get(format!("{}/profile/me", server.host))
+        .bearer_auth(token)
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(logout_response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(me_response.status(), StatusCode::UNAUTHORIZED);
+}
+
 #[test]
 fn when_password_is_wrong_then_login_failed() {
     let username = format!("testViewer{}", rand::random::<u32>());
@@ -114,6 +388,80 @@ fn when_email_is_wrong_then_login_failed() {
     assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 }
 
+#[test]
+fn when_login_failed_then_error_is_problem_with_request_id() {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/login", common::APP_HOST))
+        .header("X-Request-Id", "testRequest42")
+        .json(&json!({
+            "email":"wrong_email",
+            "password":"1234"
+        }))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+    assert_eq!(response.headers()["X-Request-Id"], "testRequest42");
+
+    let problem: Value = response.json().unwrap();
+    assert_eq!(problem["status"], 401);
+    assert_eq!(problem["instance"], "/login");
+    assert_eq!(problem["request_id"], "testRequest42");
+
+    let error: ApiError = from_value(problem).unwrap();
+    assert_eq!(error.code, AuthError::EmailNotExist.value().code);
+}
+
+#[test]
+fn when_request_id_missing_or_invalid_then_it_is_generated() {
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap();
+    let generated_id = response.headers()["X-Request-Id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(!generated_id.is_empty());
+
+    let problem: Value = response.json().unwrap();
+    assert_eq!(problem["request_id"], generated_id);
+
+    let response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .header("X-Request-Id", "not a valid id")
+        .send()
+        .unwrap();
+    assert_ne!(response.headers()["X-Request-Id"], "not a valid id");
+}
+
+#[test]
+fn when_body_malformed_then_returns_validation_problem() {
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/login", common::APP_HOST))
+        .json(&json!({
+            "email":"wrong_email"
+        }))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+    assert!(response.headers().contains_key("X-Request-Id"));
+
+    let problem: Value = response.json().unwrap();
+    assert_eq!(problem["status"], 422);
+    assert_eq!(problem["error_type"], "validation_error");
+    assert_eq!(problem["code"], "invalid_request");
+}
+
 #[test]
 fn when_user_exist_and_unconfirmed_then_login_return_unconfirmed_error() {
     let username = format!("testViewer{}", rand::random::<u32>());
@@ -186,6 +534,193 @@ fn when_credentials_ok_then_signup_returns_username_and_email() {
     assert_eq!(user, NewUserResponseDto { username, email });
 }
 
+#[test]
+fn when_signup_confirmed_by_mailed_link_then_login_success() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "123456aA";
+
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/signup", common::APP_HOST))
+        .json(&json!({
+            "username":username,
+            "email": email,
+            "password":password
+        }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let token = common::find_mailed_token(&email, "confirm").unwrap();
+    assert_eq!(token.len(), common::SESSION_ID_LENGTH);
+
+    let response = client
+        .get(format!("{}/confirm/{}", common::APP_HOST, token))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post(format!("{}/login", common::APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": password
+        }))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_confirmation_is_resent_then_only_the_new_link_confirms_signup() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "123456aA";
+
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/signup", common::APP_HOST))
+        .json(&json!({
+            "username":username,
+            "email": email,
+            "password":password
+        }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let old_token = common::find_mailed_token(&email, "confirm").unwrap();
+
+    let response = client
+        .post(format!("{}/confirm/resend", common::APP_HOST))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Wait for the queue to deliver the second confirmation email
+    let new_token = (0..50)
+        .find_map(|_| {
+            let token = common::find_mailed_token(&email, "confirm").unwrap();
+            if token == old_token {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                return None;
+            }
+            Some(token)
+        })
+        .unwrap();
+
+    let old_response = client
+        .get(format!("{}/confirm/{}", common::APP_HOST, old_token))
+        .send()
+        .unwrap();
+    let new_response = client
+        .get(format!("{}/confirm/{}", common::APP_HOST, new_token))
+        .send()
+        .unwrap();
+    let login_response = client
+        .post(format!("{}/login", common::APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": password
+        }))
+        .send()
+        .unwrap();
+
+    assert_eq!(old_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(new_response.status(), StatusCode::OK);
+    assert_eq!(login_response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_confirmation_is_resent_then_new_token_gets_full_lifetime() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "123456aA";
+
+    let server = TestServer::start(
+        8117,
+        &[
+            ("ROCKET_TOKENS", "{confirm_token=4}".to_string()),
+            ("ROCKET_PURGE", "{interval=0}".to_string()),
+        ],
+    );
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/signup", server.host))
+        .json(&json!({
+            "username": username,
+            "email": email,
+            "password": password
+        }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let old_token = common::find_mailed_token(&email, "confirm").unwrap();
+
+    std::thread::sleep(Duration::from_millis(2500));
+    let response = client
+        .post(format!("{}/confirm/resend", server.host))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let new_token = (0..50)
+        .find_map(|_| {
+            let token = common::find_mailed_token(&email, "confirm").unwrap();
+            if token == old_token {
+                std::thread::sleep(Duration::from_millis(100));
+                return None;
+            }
+            Some(token)
+        })
+        .unwrap();
+
+    // The lifetime has passed since the signup, but not since the resend
+    std::thread::sleep(Duration::from_millis(2500));
+    let confirm_response = client
+        .get(format!("{}/confirm/{}", server.host, new_token))
+        .send()
+        .unwrap();
+    let login_response = client
+        .post(format!("{}/login", server.host))
+        .json(&json!({
+            "email": email,
+            "password": password
+        }))
+        .send()
+        .unwrap();
+
+    assert_eq!(confirm_response.status(), StatusCode::OK);
+    assert_eq!(login_response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_signup_is_confirmed_then_resend_confirmation_returns_already_confirmed_error() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "123456aA", "viewer", &true.to_string());
+
+    let response = Client::new()
+        .post(format!("{}/confirm/resend", common::APP_HOST))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json().unwrap();
+    let error: ApiError = from_value(json).unwrap();
+    assert_eq!(error, AuthError::AlreadyConfirmed.value());
+}
+
 #[test]
 fn when_user_exist_then_signup_failed() {
     let username = format!("testViewer{}", rand::random::<u32>());
@@ -462,6 +997,93 @@ fn when_email_correct_and_exists_password_reset_success() {
     assert_eq!(response.status(), StatusCode::OK);
 }
 
+#[test]
+fn when_password_reset_then_email_contains_client_address() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "123456aA", "viewer", &true.to_string());
+    let client_ip = format!(
+        "10.{}.{}.{}",
+        rand::random::<u8>(),
+        rand::random::<u8>(),
+        rand::random::<u8>()
+    );
+
+    // The client address is only taken from the header of a trusted proxy
+    let server = TestServer::start(
+        8115,
+        &[("ROCKET_TRUSTED_PROXIES", r#"["127.0.0.1"]"#.to_string())],
+    );
+
+    let response = Client::new()
+        .post(format!("{}/password_reset", server.host))
+        .header("cloudfront-viewer-address", format!("{}:443", client_ip))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+    let mailed_email = common::find_mailed_email(&email);
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(response.status(), StatusCode::OK);
+    // Private addresses are not located
+    assert!(mailed_email
+        .unwrap()
+        .contains(&format!("this request was received from {} at ", client_ip)));
+}
+
+#[test]
+fn when_location_is_cached_then_email_contains_it_without_lookup() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "123456aA", "viewer", &true.to_string());
+    let client_ip = format!(
+        "8.{}.{}.{}",
+        rand::random::<u8>(),
+        rand::random::<u8>(),
+        rand::random::<u8>()
+    );
+
+    let mut cache = redis::Client::open(std::env::var("REDIS_URL").unwrap())
+        .unwrap()
+        .get_connection()
+        .unwrap();
+    redis::cmd("SET")
+        .arg(format!("geoip/{}", client_ip))
+        .arg(json!({ "city": "Vilnius", "country_code": "LT" }).to_string())
+        .arg("EX")
+        .arg(60)
+        .query::<()>(&mut cache)
+        .unwrap();
+
+    // The provider is unreachable, the location can only come from the cache
+    let server = TestServer::start(
+        8116,
+        &[
+            ("ROCKET_TRUSTED_PROXIES", r#"["127.0.0.1"]"#.to_string()),
+            ("ROCKET_GEOIP", r#"{url="http://127.0.0.1:9"}"#.to_string()),
+        ],
+    );
+
+    let response = Client::new()
+        .post(format!("{}/password_reset", server.host))
+        .header("cloudfront-viewer-address", format!("{}:443", client_ip))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+    let mailed_email = common::find_mailed_email(&email);
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(mailed_email.unwrap().contains(&format!(
+        "this request was received from {}, Vilnius, LT at ",
+        client_ip
+    )));
+}
+
 #[test]
 fn when_email_is_wrong_then_password_reset_returns_invalid_email_error() {
     let client = Client::new();
@@ -610,23 +1232,104 @@ fn when_token_missed_then_password_returns_not_found_status() {
 }
 
 #[test]
-fn when_any_request_is_received_then_response_contains_cors_headers() {
+fn when_origin_allowed_then_response_contains_cors_headers() {
     let client = Client::new();
 
-    let response = client.get(common::APP_HOST.to_string()).send().unwrap();
+    let response = client
+        .get(common::APP_HOST.to_string())
+        .header(ORIGIN, ALLOWED_ORIGIN)
+        .send()
+        .unwrap();
 
     let headers = response.headers();
 
-    assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
-    assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "*");
     assert_eq!(
-        headers.get(ACCESS_CONTROL_ALLOW_METHODS).unwrap(),
-        "GET, POST, PUT, DELETE, PATCH"
+        headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+        ALLOWED_ORIGIN
     );
     assert_eq!(
         headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(),
         "true"
     );
+    assert!(headers
+        .get(ACCESS_CONTROL_EXPOSE_HEADERS)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("Retry-After"));
+    assert_eq!(headers.get(VARY).unwrap(), "Origin");
+}
+
+#[test]
+fn when_origin_not_allowed_then_response_does_not_contain_cors_headers() {
+    let client = Client::new();
+
+    let response = client
+        .get(common::APP_HOST.to_string())
+        .header(ORIGIN, "https://evil.example.com")
+        .send()
+        .unwrap();
+
+    let headers = response.headers();
+
+    assert!(headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    assert!(headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+}
+
+#[test]
+fn when_preflight_for_mounted_route_then_response_allows_request() {
+    let client = Client::new();
+
+    let response = client
+        .request(Method::OPTIONS, format!("{}/login", common::APP_HOST))
+        .header(ORIGIN, ALLOWED_ORIGIN)
+        .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+        .send()
+        .unwrap();
+
+    let headers = response.headers();
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+        ALLOWED_ORIGIN
+    );
+    assert!(headers
+        .get(ACCESS_CONTROL_ALLOW_METHODS)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("POST"));
+    assert_eq!(
+        headers.get(ACCESS_CONTROL_ALLOW_HEADERS).unwrap(),
+        "content-type"
+    );
+}
+
+#[test]
+fn when_preflight_invalid_then_request_is_not_allowed() {
+    let client = Client::new();
+    let preflight = |path: &str, origin: &str, method: &str| {
+        client
+            .request(Method::OPTIONS, format!("{}{}", common::APP_HOST, path))
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .send()
+            .unwrap()
+    };
+
+    let unknown_path_response = preflight("/unknown", ALLOWED_ORIGIN, "POST");
+    let unknown_method_response = preflight("/login", ALLOWED_ORIGIN, "DELETE");
+    let not_allowed_origin_response = preflight("/login", "https://evil.example.com", "POST");
+
+    assert_eq!(unknown_path_response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(unknown_method_response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(not_allowed_origin_response.status(), StatusCode::FORBIDDEN);
+    assert!(not_allowed_origin_response
+        .headers()
+        .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+        .is_none());
 }
 
 #[test]
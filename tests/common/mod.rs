@@ -4,10 +4,56 @@ use reqwest::blocking::{Client, ClientBuilder};
 use reqwest::header::{self, HeaderMap, HeaderValue};
 use reqwest::StatusCode;
 use serde_json::{json, Value};
-use std::process::{Command, Output};
+use std::process::{Child, Command, Output, Stdio};
+use std::time::Duration;
 
-pub const APP_HOST: &'static str = "http://127.0.0.1:8000";
+pub const APP_HOST: &str = "http://127.0.0.1:8000";
 pub const SESSION_ID_LENGTH: usize = 128;
+pub const MAIL_DIRECTORY: &str = "target/mail";
+const MAIL_DELIVERY_CHECKS: usize = 50;
+const MAIL_DELIVERY_CHECK_INTERVAL: u64 = 200;
+const SERVER_START_CHECKS: usize = 100;
+const SERVER_START_CHECK_INTERVAL: u64 = 200;
+
+/// Server started next to the one of the tests with config overridden by `ROCKET_*` env vars,
+/// killed when dropped
+pub struct TestServer {
+    pub host: String,
+    process: Child,
+}
+
+impl TestServer {
+    pub fn start(port: u16, env: &[(&str, String)]) -> TestServer {
+        let process = Command::new(env!("CARGO_BIN_EXE_server"))
+            .env("ROCKET_PORT", port.to_string())
+            .envs(env.iter().map(|(key, value)| (key, value)))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = TestServer {
+            host: format!("http://127.0.0.1:{}", port),
+            process,
+        };
+
+        let client = Client::new();
+        for _ in 0..SERVER_START_CHECKS {
+            let response = client.get(format!("{}/health/live", server.host)).send();
+            if response.is_ok_and(|response| response.status() == StatusCode::OK) {
+                return server;
+            }
+            std::thread::sleep(Duration::from_millis(SERVER_START_CHECK_INTERVAL));
+        }
+        panic!("Test server on port {} did not start", port);
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
 
 pub fn create_test_user(
     username: &str,
@@ -57,6 +103,19 @@ pub fn delete_test_user(create_output: Output) {
         .status();
 }
 
+pub fn login_test_user(email: &str, password: &str) -> Value {
+    let response = Client::new()
+        .post(format!("{}/login", APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": password
+        }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().unwrap()
+}
+
 pub fn get_logged_in_client(username: &str, email: &str, role: &str) -> (Client, Output) {
     let password = "123456aA";
     let output = create_test_user(username, email, password, role, &true.to_string());
@@ -87,7 +146,7 @@ pub fn get_logged_in_client(username: &str, email: &str, role: &str) -> (Client,
         .build()
         .unwrap();
 
-    return (client, output);
+    (client, output)
 }
 
 pub fn get_client_with_logged_in_viewer() -> (Client, Output) {
@@ -102,6 +161,12 @@ pub fn get_client_with_logged_in_editor() -> (Client, Output) {
     get_logged_in_client(username.as_str(), email.as_str(), "editor")
 }
 
+pub fn get_client_with_logged_in_admin() -> (Client, Output) {
+    let username = format!("testAdmin{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    get_logged_in_client(username.as_str(), email.as_str(), "admin")
+}
+
 pub fn generate_test_token(length: usize) -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -109,3 +174,52 @@ pub fn generate_test_token(length: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+/// Finds the latest email dropped for the recipient by the file mail transport, waiting for
+/// the email queue to deliver it. The HTML body is decoded to plain text
+pub fn find_mailed_email(recipient: &str) -> Option<String> {
+    for _ in 0..MAIL_DELIVERY_CHECKS {
+        if let Some(email) = find_dropped_email(recipient) {
+            return Some(email);
+        }
+        std::thread::sleep(Duration::from_millis(MAIL_DELIVERY_CHECK_INTERVAL));
+    }
+    None
+}
+
+/// Finds the token of the `/<path>/<token>` link in the latest email mailed to the recipient
+pub fn find_mailed_token(recipient: &str, path: &str) -> Option<String> {
+    let email = find_mailed_email(recipient)?;
+
+    let link_prefix = format!("/{}/", path);
+    let start = email.find(&link_prefix)? + link_prefix.len();
+    let token: String = email[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+    Some(token)
+}
+
+fn find_dropped_email(recipient: &str) -> Option<String> {
+    let mut emails: Vec<_> = std::fs::read_dir(MAIL_DIRECTORY)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "eml"))
+        .collect();
+    emails.sort();
+
+    let recipient_header = format!("To: {}", recipient);
+
+    emails.iter().rev().find_map(|email| {
+        // Undo the quoted-printable soft line breaks and the escaping of the HTML body
+        let content = std::fs::read_to_string(email)
+            .ok()?
+            .replace("=\r\n", "")
+            .replace("=3D", "=")
+            .replace("&#x2F;", "/");
+        content
+            .lines()
+            .any(|line| line == recipient_header)
+            .then_some(content)
+    })
+}
@@ -0,0 +1,658 @@
+use reqwest::{blocking::Client, StatusCode};
+use rocket::serde::json::json;
+use rust_template::errors::{ApiError, AuthError, CompanyError};
+use serde_json::{from_value, Value};
+
+use crate::common::{
+    delete_test_user, find_mailed_token, generate_test_token, get_client_with_logged_in_admin,
+    get_client_with_logged_in_viewer, get_logged_in_client,
+};
+
+pub mod common;
+
+fn create_test_company(client: &Client) -> Value {
+    let response = client
+        .post(format!("{}/companies", common::APP_HOST))
+        .json(&json!({
+            "name": format!("testCompany{}", rand::random::<u32>()),
+            "website": "https://softteco.com"
+        }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json().unwrap()
+}
+
+fn delete_test_company(client: &Client, company: &Value) {
+    let _ = client
+        .delete(format!("{}/companies/{}", common::APP_HOST, company["id"]))
+        .send();
+}
+
+/// Turn the user logged in with `client` into an enterprise user, who can be added to companies
+fn set_enterprise_type(admin_client: &Client, client: &Client) {
+    let profile: Value = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let response = admin_client
+        .put(format!(
+            "{}/admin/users/{}/type",
+            common::APP_HOST,
+            profile["id"]
+        ))
+        .json(&json!({ "user_type": "enterprise" }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_role_viewer_then_create_company_returns_forbidden_error() {
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+
+    let response = client
+        .post(format!("{}/companies", common::APP_HOST))
+        .json(&json!({ "name": format!("testCompany{}", rand::random::<u32>()) }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AuthError::Forbidden.value()
+    );
+}
+
+#[test]
+fn when_company_name_taken_then_create_company_returns_name_in_use_error() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+    let company = create_test_company(&client);
+
+    let response = client
+        .post(format!("{}/companies", common::APP_HOST))
+        .json(&json!({ "name": company["name"] }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&client, &company);
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        CompanyError::NameInUse.value()
+    );
+}
+
+#[test]
+fn when_user_is_not_member_then_get_company_returns_forbidden_error() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+    let company = create_test_company(&admin_client);
+
+    let response = client
+        .get(format!("{}/companies/{}", common::APP_HOST, company["id"]))
+        .send()
+        .unwrap();
+
+    let list: Value = client
+        .get(format!("{}/companies", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&admin_client, &company);
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(list, json!([]));
+}
+
+#[test]
+fn when_user_is_not_member_then_unknown_company_returns_forbidden_error() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+    let unknown_company_url = format!("{}/companies/{}", common::APP_HOST, i32::MAX);
+
+    let response = client.get(&unknown_company_url).send().unwrap();
+    let admin_response = admin_client.get(&unknown_company_url).send().unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(admin_response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn when_member_is_company_admin_then_manages_only_own_company() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    set_enterprise_type(&admin_client, &client);
+    let own_company = create_test_company(&admin_client);
+    let other_company = create_test_company(&admin_client);
+
+    let add_response = admin_client
+        .post(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            own_company["id"]
+        ))
+        .json(&json!({ "email": email, "roles": ["admin"] }))
+        .send()
+        .unwrap();
+    let member: Value = add_response.json().unwrap();
+
+    let own_update_response = client
+        .put(format!(
+            "{}/companies/{}",
+            common::APP_HOST,
+            own_company["id"]
+        ))
+        .json(&json!({
+            "name": own_company["name"],
+            "address": "Lithuania, Vilnius"
+        }))
+        .send()
+        .unwrap();
+    let own_update_json: Value = own_update_response.json().unwrap();
+
+    let other_update_response = client
+        .put(format!(
+            "{}/companies/{}",
+            common::APP_HOST,
+            other_company["id"]
+        ))
+        .json(&json!({ "name": other_company["name"] }))
+        .send()
+        .unwrap();
+
+    let members: Value = client
+        .get(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            own_company["id"]
+        ))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+
+    let self_removal_response = client
+        .delete(format!(
+            "{}/companies/{}/members/{}",
+            common::APP_HOST,
+            own_company["id"],
+            member["id"]
+        ))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&admin_client, &own_company);
+    delete_test_company(&admin_client, &other_company);
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(member["email"], email.as_str());
+    assert_eq!(member["roles"], json!(["admin"]));
+    assert_eq!(own_update_json["address"], "Lithuania, Vilnius");
+    assert_eq!(other_update_response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(members.as_array().unwrap().len(), 1);
+    assert_eq!(self_removal_response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn when_company_admin_then_update_and_remove_member_success() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    set_enterprise_type(&admin_client, &client);
+    let company = create_test_company(&admin_client);
+
+    let member: Value = admin_client
+        .post(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let member_url = format!(
+        "{}/companies/{}/members/{}",
+        common::APP_HOST,
+        company["id"],
+        member["id"]
+    );
+
+    let forbidden_response = client
+        .put(format!("{}/companies/{}", common::APP_HOST, company["id"]))
+        .json(&json!({ "name": company["name"] }))
+        .send()
+        .unwrap();
+
+    let update_json: Value = admin_client
+        .put(&member_url)
+        .json(&json!({ "roles": ["editor"] }))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+
+    let remove_response = admin_client.delete(&member_url).send().unwrap();
+    let remove_again_response = admin_client.delete(&member_url).send().unwrap();
+
+    // Cleanup
+    delete_test_company(&admin_client, &company);
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(member["roles"], json!(["viewer"]));
+    assert_eq!(forbidden_response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(update_json["roles"], json!(["editor"]));
+    assert_eq!(remove_response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(remove_again_response.status(), StatusCode::NOT_FOUND);
+    let json: Value = remove_again_response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        CompanyError::MemberNotFound.value()
+    );
+}
+
+/// Events of the type recorded for the target user, as listed by `/admin/audit_events`
+fn find_audit_events(admin_client: &Client, target_id: &Value, event_type: &str) -> Value {
+    let response = admin_client
+        .get(format!(
+            "{}/admin/audit_events?target_id={}&event_type={}",
+            common::APP_HOST,
+            target_id,
+            event_type
+        ))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().unwrap()
+}
+
+#[test]
+fn when_member_roles_changed_and_removed_then_audit_events_are_recorded() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    set_enterprise_type(&admin_client, &client);
+    let company = create_test_company(&admin_client);
+
+    let member: Value = admin_client
+        .post(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let member_url = format!(
+        "{}/companies/{}/members/{}",
+        common::APP_HOST,
+        company["id"],
+        member["id"]
+    );
+    admin_client
+        .put(&member_url)
+        .json(&json!({ "roles": ["editor"] }))
+        .send()
+        .unwrap();
+    admin_client.delete(&member_url).send().unwrap();
+
+    let added = find_audit_events(&admin_client, &member["id"], "company_member_added");
+    let updated = find_audit_events(&admin_client, &member["id"], "company_member_updated");
+    let removed = find_audit_events(&admin_client, &member["id"], "company_member_removed");
+
+    // Cleanup
+    delete_test_company(&admin_client, &company);
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(added["total"], 1);
+    assert_eq!(added["items"][0]["payload"]["roles"], json!(["viewer"]));
+    assert_eq!(updated["total"], 1);
+    assert_eq!(updated["items"][0]["payload"]["company_id"], company["id"]);
+    assert_eq!(updated["items"][0]["payload"]["roles"], json!(["editor"]));
+    assert_eq!(removed["total"], 1);
+    assert_eq!(removed["items"][0]["payload"]["company_id"], company["id"]);
+}
+
+#[test]
+fn when_email_unknown_then_add_member_returns_user_not_found_error() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+    let company = create_test_company(&client);
+
+    let response = client
+        .post(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": format!("unknown{}@gmail.com", rand::random::<u32>()) }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&client, &company);
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        CompanyError::UserNotFound.value()
+    );
+}
+
+#[test]
+fn when_user_is_not_enterprise_then_add_member_returns_user_not_found_error() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (_, create_user_output) = get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    let company = create_test_company(&admin_client);
+
+    let response = admin_client
+        .post(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&admin_client, &company);
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        CompanyError::UserNotFound.value()
+    );
+}
+
+#[test]
+fn when_company_admin_is_not_global_admin_then_add_member_returns_forbidden_error() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    set_enterprise_type(&admin_client, &client);
+    let company = create_test_company(&admin_client);
+
+    let _ = admin_client
+        .post(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": email, "roles": ["admin"] }))
+        .send()
+        .unwrap();
+
+    let (other_client, other_output) = get_client_with_logged_in_viewer();
+    set_enterprise_type(&admin_client, &other_client);
+    let other: Value = other_client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+
+    let response = client
+        .post(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": other["email"] }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&admin_client, &company);
+    delete_test_user(create_user_output);
+    delete_test_user(other_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn when_member_is_not_company_admin_then_invite_returns_forbidden_error() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    set_enterprise_type(&admin_client, &client);
+    let company = create_test_company(&admin_client);
+
+    let _ = admin_client
+        .post(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": email, "roles": ["editor"] }))
+        .send()
+        .unwrap();
+
+    let response = client
+        .post(format!(
+            "{}/companies/{}/invitations",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": format!("invitee{}@gmail.com", rand::random::<u32>()) }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&admin_client, &company);
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn when_email_invalid_then_invite_returns_invalid_email_error() {
+    let (client, create_user_output) = get_client_with_logged_in_admin();
+    let company = create_test_company(&client);
+
+    let response = client
+        .post(format!(
+            "{}/companies/{}/invitations",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": "invitee" }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&client, &company);
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AuthError::InvalidEmail.value()
+    );
+}
+
+#[test]
+fn when_invitation_accepted_then_invitee_becomes_member() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    let company = create_test_company(&admin_client);
+
+    let invite_response = admin_client
+        .post(format!(
+            "{}/companies/{}/invitations",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .json(&json!({ "email": email, "roles": ["editor"] }))
+        .send()
+        .unwrap();
+    let token = find_mailed_token(&email, "invitation").unwrap();
+
+    let accept_response = client
+        .post(format!("{}/invitations/{}/accept", common::APP_HOST, token))
+        .send()
+        .unwrap();
+    let members: Value = admin_client
+        .get(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&admin_client, &company);
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_eq!(invite_response.status(), StatusCode::OK);
+    assert_eq!(accept_response.status(), StatusCode::OK);
+    let member = members
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|member| member["email"] == email.as_str())
+        .unwrap();
+    assert_eq!(member["roles"], json!(["editor"]));
+}
+
+#[test]
+fn when_invitee_is_already_member_then_accept_returns_already_member_error() {
+    let (admin_client, admin_output) = get_client_with_logged_in_admin();
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    let company = create_test_company(&admin_client);
+    let invite = |roles: Value| {
+        admin_client
+            .post(format!(
+                "{}/companies/{}/invitations",
+                common::APP_HOST,
+                company["id"]
+            ))
+            .json(&json!({ "email": email, "roles": roles }))
+            .send()
+            .unwrap()
+    };
+
+    invite(json!(["viewer"]));
+    let first_token = find_mailed_token(&email, "invitation").unwrap();
+    invite(json!(["admin"]));
+    let mut second_token = first_token.clone();
+    for _ in 0..50 {
+        second_token = find_mailed_token(&email, "invitation").unwrap();
+        if second_token != first_token {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    let accept = |token: &str| {
+        client
+            .post(format!("{}/invitations/{}/accept", common::APP_HOST, token))
+            .send()
+            .unwrap()
+    };
+    let first_response = accept(&first_token);
+    let second_response = accept(&second_token);
+    let members: Value = admin_client
+        .get(format!(
+            "{}/companies/{}/members",
+            common::APP_HOST,
+            company["id"]
+        ))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+
+    // Cleanup
+    delete_test_company(&admin_client, &company);
+    delete_test_user(create_user_output);
+    delete_test_user(admin_output);
+
+    assert_ne!(first_token, second_token);
+    assert_eq!(first_response.status(), StatusCode::OK);
+    assert_eq!(second_response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = second_response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        CompanyError::AlreadyMember.value()
+    );
+    assert_eq!(members[0]["roles"], json!(["viewer"]));
+}
+
+#[test]
+fn when_invitation_token_unknown_then_decline_returns_invitation_not_found_error() {
+    let token = generate_test_token(common::SESSION_ID_LENGTH);
+
+    let response = Client::new()
+        .post(format!(
+            "{}/invitations/{}/decline",
+            common::APP_HOST,
+            token
+        ))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        CompanyError::InvitationNotFound.value()
+    );
+}
@@ -0,0 +1,59 @@
+use reqwest::{
+    blocking::Client,
+    header::{ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN},
+    StatusCode,
+};
+use rust_template::dto::{HealthDto, HealthStatus};
+
+pub mod common;
+
+#[test]
+fn when_server_is_running_then_it_is_live() {
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/health/live", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let health: HealthDto = response.json().unwrap();
+    assert_eq!(health.status, HealthStatus::Up);
+    assert!(health.checks.is_empty());
+}
+
+#[test]
+fn when_dependencies_are_up_then_server_is_ready() {
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/health/ready", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let health: HealthDto = response.json().unwrap();
+    assert_eq!(health.status, HealthStatus::Up);
+    for dependency in ["postgres", "redis"] {
+        let check = &health.checks[dependency];
+        assert_eq!(check.status, HealthStatus::Up);
+        assert!(check.duration_ms >= 0.0);
+        assert!(check.error.is_none());
+    }
+}
+
+#[test]
+fn when_health_checked_cross_origin_then_no_cors_headers() {
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/health/ready", common::APP_HOST))
+        .header(ORIGIN, "http://localhost:3000")
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+}
@@ -0,0 +1,287 @@
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{blocking::Client, StatusCode};
+use rust_template::errors::{ApiError, AuthError};
+use serde_json::{from_value, json, Value};
+
+use crate::common::{create_test_user, delete_test_user};
+
+pub mod common;
+
+const SIGNING_KID: &str = "ed-2024";
+const RETIRED_KID: &str = "hs-2023";
+const RETIRED_SECRET: &str = "retired secret of the integration tests";
+const PASSWORD: &str = "123456aA";
+
+/// Server started in the JWT mode next to the one of the other tests, killed when dropped
+struct JwtServer {
+    host: String,
+    process: Child,
+}
+
+impl JwtServer {
+    fn start(port: u16) -> JwtServer {
+        let jwt_config = format!(
+            r#"{{mode="jwt",keys=[{{kid="{}",algorithm="EdDSA",private_key="{}",public_key="{}"}},{{kid="{}",algorithm="HS256",secret="{}"}}]}}"#,
+            SIGNING_KID,
+            include_str!("fixtures/jwt/private_key.pem").replace('\n', "\\n"),
+            include_str!("fixtures/jwt/public_key.txt").trim(),
+            RETIRED_KID,
+            RETIRED_SECRET,
+        );
+        let process = Command::new(env!("CARGO_BIN_EXE_server"))
+            .env("ROCKET_PORT", port.to_string())
+            .env("ROCKET_JWT", jwt_config)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = JwtServer {
+            host: format!("http://127.0.0.1:{}", port),
+            process,
+        };
+
+        let client = Client::new();
+        for _ in 0..100 {
+            let response = client.get(format!("{}/health/live", server.host)).send();
+            if response.is_ok_and(|response| response.status() == StatusCode::OK) {
+                return server;
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        panic!("JWT server on port {} did not start", port);
+    }
+
+    /// Log in a new viewer, returns the tokens and the output of its creation
+    fn login_viewer(&self) -> (Value, std::process::Output) {
+        self.login("testViewer", "viewer")
+    }
+
+    /// Log in a new user with the roles, returns the tokens and the output of its creation
+    fn login(&self, name: &str, roles: &str) -> (Value, std::process::Output) {
+        let username = format!("{}{}", name, rand::random::<u32>());
+        let email = format!("{}@gmail.com", username);
+        let output = create_test_user(&username, &email, PASSWORD, roles, "true");
+
+        let response = Client::new()
+            .post(format!("{}/login", self.host))
+            .json(&json!({ "email": email, "password": PASSWORD }))
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        (response.json().unwrap(), output)
+    }
+
+    fn get_profile(&self, token: &str) -> reqwest::blocking::Response {
+        self.get(token, "/profile/me")
+    }
+
+    fn get(&self, token: &str, path: &str) -> reqwest::blocking::Response {
+        Client::new()
+            .get(format!("{}{}", self.host, path))
+            .bearer_auth(token)
+            .send()
+            .unwrap()
+    }
+}
+
+impl Drop for JwtServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn decode_part(token: &str, index: usize) -> Value {
+    let part = token.split('.').nth(index).unwrap();
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+}
+
+fn sign_retired(claims: &Value, secret: &str) -> String {
+    let mut header = Header::new(Algorithm::HS256);
+    header.kid = Some(RETIRED_KID.to_string());
+    jsonwebtoken::encode(
+        &header,
+        claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap()
+}
+
+#[test]
+fn when_jwt_mode_is_enabled_then_login_returns_signed_jwt() {
+    let server = JwtServer::start(8101);
+    let (tokens, output) = server.login_viewer();
+
+    let token = tokens["token"].as_str().unwrap();
+    assert_eq!(token.split('.').count(), 3);
+    let header = decode_part(token, 0);
+    assert_eq!(header["alg"], "EdDSA");
+    assert_eq!(header["kid"], SIGNING_KID);
+    let claims = decode_part(token, 1);
+    assert_eq!(claims["roles"], json!(["viewer"]));
+    assert_eq!(claims["user_type"], "regular");
+
+    let response = server.get_profile(token);
+    assert_eq!(response.status(), StatusCode::OK);
+    let profile: Value = response.json().unwrap();
+    assert_eq!(profile["id"].to_string(), claims["sub"].as_str().unwrap());
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_session_is_logged_out_then_jwt_is_rejected() {
+    let server = JwtServer::start(8102);
+    let (tokens, output) = server.login_viewer();
+    let token = tokens["token"].as_str().unwrap();
+
+    let response = Client::new()
+        .post(format!("{}/logout", server.host))
+        .bearer_auth(token)
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = server.get_profile(token);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, AuthError::InvalidToken.value().code);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_token_is_refreshed_then_new_jwt_is_issued() {
+    let server = JwtServer::start(8103);
+    let (tokens, output) = server.login_viewer();
+
+    let response = Client::new()
+        .post(format!("{}/token/refresh", server.host))
+        .json(&json!({ "refresh_token": tokens["refresh_token"] }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let refreshed: Value = response.json().unwrap();
+    let token = refreshed["token"].as_str().unwrap();
+    assert_eq!(token.split('.').count(), 3);
+    assert_eq!(
+        decode_part(token, 1)["sid"],
+        decode_part(tokens["token"].as_str().unwrap(), 1)["sid"]
+    );
+
+    let response = server.get_profile(token);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_jwks_is_requested_then_only_public_keys_are_listed() {
+    let server = JwtServer::start(8104);
+
+    let response = Client::new()
+        .get(format!("{}/.well-known/jwks.json", server.host))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let jwks: Value = response.json().unwrap();
+    let keys = jwks["keys"].as_array().unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0]["kid"], SIGNING_KID);
+    assert_eq!(keys[0]["kty"], "OKP");
+    assert_eq!(keys[0]["crv"], "Ed25519");
+    assert_eq!(
+        keys[0]["x"],
+        include_str!("fixtures/jwt/public_key.txt").trim()
+    );
+
+    let response = Client::new()
+        .get(format!("{}/.well-known/jwks.json", common::APP_HOST))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let jwks: Value = response.json().unwrap();
+    assert!(jwks["keys"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn when_jwt_is_signed_with_retired_key_then_only_jwt_server_accepts_it() {
+    let server = JwtServer::start(8105);
+    let (tokens, output) = server.login_viewer();
+    let claims = decode_part(tokens["token"].as_str().unwrap(), 1);
+
+    let retired_token = sign_retired(&claims, RETIRED_SECRET);
+    let response = server.get_profile(&retired_token);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let forged_token = sign_retired(&claims, "forged secret of the integration tests");
+    let response = server.get_profile(&forged_token);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = Client::new()
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(&retired_token)
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_jwt_carries_roles_then_they_are_not_loaded() {
+    let server = JwtServer::start(8106);
+    let (tokens, output) = server.login_viewer();
+    let token = tokens["token"].as_str().unwrap();
+
+    let response = server.get(token, "/admin/users");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let mut claims = decode_part(token, 1);
+    claims["roles"] = json!(["admin"]);
+    let admin_token = sign_retired(&claims, RETIRED_SECRET);
+    let response = server.get(&admin_token, "/admin/users");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_roles_of_user_are_removed_then_its_jwts_are_revoked() {
+    let server = JwtServer::start(8107);
+    let (admin_tokens, admin_output) = server.login("testAdmin", "admin");
+    let (tokens, output) = server.login("testAdmin", "admin");
+    let token = tokens["token"].as_str().unwrap();
+    let user_id = decode_part(token, 1)["sub"].as_str().unwrap().to_string();
+
+    let response = server.get(token, "/admin/users");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = Client::new()
+        .delete(format!("{}/admin/users/{}/roles", server.host, user_id))
+        .bearer_auth(admin_tokens["token"].as_str().unwrap())
+        .json(&json!({ "roles": ["admin"] }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = server.get(token, "/admin/users");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, AuthError::InvalidToken.value().code);
+
+    let response = Client::new()
+        .post(format!("{}/token/refresh", server.host))
+        .json(&json!({ "refresh_token": tokens["refresh_token"] }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    delete_test_user(output);
+    delete_test_user(admin_output);
+}
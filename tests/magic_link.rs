@@ -0,0 +1,147 @@
+use reqwest::{blocking::Client, header::LOCATION, redirect::Policy, StatusCode};
+use rust_template::errors::{ApiError, AuthError};
+use serde_json::{from_value, json, Value};
+
+use crate::common::{create_test_user, delete_test_user};
+
+pub mod common;
+
+const REDIRECT_URL: &str = "http://localhost:3000/login/magic";
+
+fn request_magic_link(email: &str, redirect_url: Option<&str>) -> StatusCode {
+    Client::new()
+        .post(format!("{}/login/magic", common::APP_HOST))
+        .json(&json!({ "email": email, "redirect_url": redirect_url }))
+        .send()
+        .unwrap()
+        .status()
+}
+
+/// Waits for the magic link emailed after `previous_token` and returns its token
+fn find_new_magic_token(email: &str, path: &str, previous_token: Option<&str>) -> String {
+    for _ in 0..50 {
+        if let Some(token) = common::find_mailed_token(email, path) {
+            if Some(token.as_str()) != previous_token {
+                return token;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    }
+    panic!("Magic link is not mailed to {}", email);
+}
+
+#[test]
+fn when_magic_link_is_used_then_login_success_only_once() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "123456aA", "viewer", &true.to_string());
+
+    assert_eq!(request_magic_link(&email, None), StatusCode::OK);
+    let token = find_new_magic_token(&email, "magic_login", None);
+    assert_eq!(token.len(), common::SESSION_ID_LENGTH);
+
+    let client = Client::new();
+    let response = client
+        .get(format!("{}/login/magic/{}", common::APP_HOST, token))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert!(json.get("token").is_some());
+    assert!(json.get("refresh_token").is_some());
+
+    let response = client
+        .get(format!("{}/login/magic/{}", common::APP_HOST, token))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, AuthError::InvalidToken.value().code);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_magic_link_is_requested_for_web_page_then_login_redirects_with_tokens() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "123456aA", "viewer", &true.to_string());
+
+    assert_eq!(
+        request_magic_link(&email, Some(REDIRECT_URL)),
+        StatusCode::OK
+    );
+    let token = find_new_magic_token(&email, "login/magic", None);
+
+    let client = Client::builder().redirect(Policy::none()).build().unwrap();
+    let response = client
+        .get(format!("{}/login/magic/{}", common::APP_HOST, token))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    let location = response.headers()[LOCATION].to_str().unwrap();
+    let fragment = location
+        .strip_prefix(&format!("{}#", REDIRECT_URL))
+        .unwrap();
+    assert!(fragment.starts_with("token=") || fragment.contains("&token="));
+    assert!(fragment.contains("refresh_token="));
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_new_magic_link_is_requested_then_previous_link_is_invalid() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "123456aA", "viewer", &true.to_string());
+
+    assert_eq!(request_magic_link(&email, None), StatusCode::OK);
+    let previous_token = find_new_magic_token(&email, "magic_login", None);
+
+    assert_eq!(request_magic_link(&email, None), StatusCode::OK);
+    let token = find_new_magic_token(&email, "magic_login", Some(&previous_token));
+
+    let client = Client::new();
+    let response = client
+        .get(format!(
+            "{}/login/magic/{}",
+            common::APP_HOST,
+            previous_token
+        ))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = client
+        .get(format!("{}/login/magic/{}", common::APP_HOST, token))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_redirect_url_is_not_allowed_then_magic_link_returns_invalid_redirect_url_error() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "123456aA", "viewer", &true.to_string());
+
+    let response = Client::new()
+        .post(format!("{}/login/magic", common::APP_HOST))
+        .json(&json!({ "email": email, "redirect_url": "https://evil.example.com/login" }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, AuthError::InvalidRedirectUrl.value().code);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_email_not_exist_then_magic_link_returns_email_not_exist_error() {
+    let email = format!("testViewer{}@gmail.com", rand::random::<u32>());
+    assert_eq!(request_magic_link(&email, None), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,242 @@
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use lettre::Message;
+use reqwest::{blocking::Client, StatusCode};
+use rocket::figment::providers::Serialized;
+use rocket_db_pools::deadpool_redis::redis;
+use rocket_db_pools::Database;
+use rust_template::config::AppConfig;
+use rust_template::mail::{HtmlMailer, MailerFairing, MemoryMailer};
+use rust_template::rocket_routes::CacheConnection;
+use serde_json::json;
+
+use crate::common::{create_test_user, delete_test_user, TestServer};
+
+pub mod common;
+
+const QUEUE_CHECKS: usize = 60;
+const QUEUE_CHECK_INTERVAL: u64 = 500;
+
+/// Server with its own email queue in the Redis database `redis_db`, sending the emails
+/// through an SMTP relay that refuses connections
+fn start_failing_mail_server(
+    port: u16,
+    redis_db: u8,
+    max_attempts: u32,
+    backoff: u64,
+) -> (TestServer, String) {
+    let redis_url = format!(
+        "{}/{}",
+        std::env::var("REDIS_URL").unwrap().trim_end_matches('/'),
+        redis_db
+    );
+    let server = TestServer::start(
+        port,
+        &[
+            ("REDIS_URL", redis_url.clone()),
+            ("ROCKET_MAIL", r#"{transport="smtp"}"#.to_string()),
+            ("SMTP_HOST", "127.0.0.1".to_string()),
+            ("SMTP_USERNAME", "test".to_string()),
+            ("SMTP_PASSWORD", "test".to_string()),
+            (
+                "ROCKET_EMAIL_QUEUE",
+                format!(
+                    "{{max_attempts={},backoff={},poll_interval=1}}",
+                    max_attempts, backoff
+                ),
+            ),
+        ],
+    );
+    (server, redis_url)
+}
+
+fn run_emails_command(redis_url: &str, args: &[&str]) -> String {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cli", "emails"])
+        .args(args)
+        .env("REDIS_URL", redis_url)
+        .output()
+        .unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Wait until the email of the request is listed with the given prefix, returns its line
+fn wait_for_email(redis_url: &str, prefix: &str, request_id: &str, attempts: u32) -> String {
+    let request = format!("request: {}, attempts: {},", request_id, attempts);
+    for _ in 0..QUEUE_CHECKS {
+        let output = run_emails_command(redis_url, &["list"]);
+        let line = output
+            .lines()
+            .find(|line| line.starts_with(prefix) && line.contains(&request));
+        if let Some(line) = line {
+            return line.to_string();
+        }
+        std::thread::sleep(Duration::from_millis(QUEUE_CHECK_INTERVAL));
+    }
+    panic!(
+        "Email of request {} is not listed as {:?}",
+        request_id, prefix
+    );
+}
+
+/// Request a password reset of a new user, returns the id of the request and the user output
+fn request_password_reset(server: &TestServer) -> (String, std::process::Output) {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "123456aA", "viewer", "true");
+    let request_id = format!("testMail{}", rand::random::<u32>());
+
+    let response = Client::new()
+        .post(format!("{}/password_reset", server.host))
+        .header("X-Request-Id", &request_id)
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    (request_id, output)
+}
+
+#[rocket::async_test]
+async fn when_memory_transport_is_configured_then_delivered_emails_are_kept() {
+    let figment = AppConfig::figment().merge(Serialized::global("mail.transport", "memory"));
+    let config = AppConfig::from_figment(&figment).unwrap();
+    let rocket = rocket::custom(figment)
+        .manage(config)
+        .attach(CacheConnection::init())
+        .attach(MailerFairing)
+        .ignite()
+        .await
+        .unwrap();
+
+    let message = Message::builder()
+        .from("sender@gmail.com".parse().unwrap())
+        .to("recipient@gmail.com".parse().unwrap())
+        .subject("Memory transport")
+        .body("Kept in memory".to_string())
+        .unwrap();
+    let mailer = rocket.state::<HtmlMailer>().unwrap();
+    mailer.transport.deliver(&message).unwrap();
+
+    let sent = rocket.state::<Arc<MemoryMailer>>().unwrap().sent();
+    assert_eq!(sent.len(), 1);
+    let formatted = String::from_utf8(sent[0].formatted()).unwrap();
+    assert!(formatted.contains("To: recipient@gmail.com"));
+    assert!(formatted.contains("Subject: Memory transport"));
+}
+
+#[test]
+fn when_delivery_fails_then_email_is_retried_after_backoff() {
+    let (server, redis_url) = start_failing_mail_server(8113, 1, 3, 3600);
+    let (request_id, output) = request_password_reset(&server);
+
+    let line = wait_for_email(&redis_url, "Retrying email:", &request_id, 1);
+
+    // Cleanup
+    delete_test_user(output);
+
+    let next_attempt_at = line
+        .split("next attempt at: ")
+        .nth(1)
+        .and_then(|rest| rest.split(", last error").next())
+        .unwrap();
+    let next_attempt_at: DateTime<Utc> = next_attempt_at.parse().unwrap();
+    let delay = (next_attempt_at - Utc::now()).num_seconds();
+    assert!((3500..=3600).contains(&delay), "{}", line);
+}
+
+#[test]
+fn when_attempts_run_out_then_email_is_dead_lettered_until_replayed() {
+    let (server, redis_url) = start_failing_mail_server(8114, 2, 2, 1);
+    let (request_id, output) = request_password_reset(&server);
+
+    let line = wait_for_email(&redis_url, "Dead email:", &request_id, 2);
+    let email_id = line
+        .trim_start_matches("Dead email: ")
+        .split(' ')
+        .next()
+        .unwrap();
+
+    let replay_output = run_emails_command(&redis_url, &["replay", email_id]);
+    let dead_again_line = wait_for_email(&redis_url, "Dead email:", &request_id, 2);
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert!(!line.ends_with("last error: "));
+    assert!(replay_output.contains("Emails queued again: 1"));
+    assert!(dead_again_line.starts_with(&format!("Dead email: {} ", email_id)));
+}
+
+#[test]
+fn when_worker_lease_expired_then_only_its_emails_are_recovered() {
+    let redis_url = format!(
+        "{}/3",
+        std::env::var("REDIS_URL").unwrap().trim_end_matches('/')
+    );
+    let mut cache = redis::Client::open(redis_url.as_str())
+        .unwrap()
+        .get_connection()
+        .unwrap();
+    let queued_email = |request_id: &str| {
+        json!({
+            "id": format!("{}Email", request_id),
+            "to": ["recipient@gmail.com"],
+            "subject": "Recovery",
+            "html_body": "<p>Recovery</p>",
+            "attempts": 0,
+            "last_error": null,
+            "queued_at": Utc::now(),
+            "request_id": request_id,
+        })
+        .to_string()
+    };
+    let stopped_request_id = format!("testStopped{}", rand::random::<u32>());
+    let alive_request_id = format!("testAlive{}", rand::random::<u32>());
+    redis::pipe()
+        .sadd("email_queue/workers", &["stopped", "alive"])
+        .rpush(
+            "email_queue/processing/stopped",
+            queued_email(&stopped_request_id),
+        )
+        .rpush(
+            "email_queue/processing/alive",
+            queued_email(&alive_request_id),
+        )
+        .set_ex("email_queue/lease/alive", 1, 3600)
+        .query::<()>(&mut cache)
+        .unwrap();
+
+    let (_server, redis_url) = start_failing_mail_server(8118, 3, 3, 3600);
+    wait_for_email(&redis_url, "Retrying email:", &stopped_request_id, 1);
+
+    let alive_processing: Vec<String> = redis::cmd("LRANGE")
+        .arg("email_queue/processing/alive")
+        .arg(0)
+        .arg(-1)
+        .query(&mut cache)
+        .unwrap();
+    let workers: Vec<String> = redis::cmd("SMEMBERS")
+        .arg("email_queue/workers")
+        .query(&mut cache)
+        .unwrap();
+
+    // Cleanup
+    redis::cmd("DEL")
+        .arg("email_queue/processing/alive")
+        .arg("email_queue/lease/alive")
+        .query::<()>(&mut cache)
+        .unwrap();
+    redis::cmd("SREM")
+        .arg("email_queue/workers")
+        .arg("alive")
+        .query::<()>(&mut cache)
+        .unwrap();
+
+    assert_eq!(alive_processing.len(), 1);
+    assert!(alive_processing[0].contains(&alive_request_id));
+    assert!(workers.contains(&"alive".to_string()));
+    assert!(!workers.contains(&"stopped".to_string()));
+}
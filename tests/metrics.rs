@@ -0,0 +1,84 @@
+use reqwest::{blocking::Client, StatusCode};
+use rocket::serde::json::json;
+
+use crate::common::{create_test_user, delete_test_user};
+
+pub mod common;
+
+fn scrape(client: &Client) -> String {
+    let response = client
+        .get(format!("{}/metrics", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+
+    response.text().unwrap()
+}
+
+#[test]
+fn when_login_failed_then_metrics_count_it_by_error_code() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let output = create_test_user(&username, &email, "1234", "viewer", "true");
+
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/login", common::APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": "wrong_password"
+        }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let metrics = scrape(&client);
+    assert!(metrics
+        .contains(r#"logins_total{code="wrong_credentials",method="password",outcome="failure"}"#));
+    assert!(metrics.contains(r#"http_requests_total{method="POST",route="/login",status="401"}"#));
+}
+
+#[test]
+fn when_magic_link_is_invalid_then_metrics_count_it_as_magic_link_login() {
+    let client = Client::new();
+
+    let response = client
+        .get(format!(
+            "{}/login/magic/{}",
+            common::APP_HOST,
+            "x".repeat(common::SESSION_ID_LENGTH)
+        ))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let metrics = scrape(&client);
+    assert!(metrics
+        .contains(r#"logins_total{code="invalid_token",method="magic_link",outcome="failure"}"#));
+}
+
+#[test]
+fn when_scraped_then_metrics_expose_pools_and_migrations() {
+    let client = Client::new();
+
+    // The latency of a scrape is recorded after its response
+    scrape(&client);
+    let metrics = scrape(&client);
+
+    assert!(metrics.contains(r#"pool_max_connections{pool="postgres"}"#));
+    assert!(metrics.contains(r#"pool_connections{pool="redis",state="idle"}"#));
+    assert!(metrics.contains("db_pending_migrations 0"));
+    assert!(
+        metrics.contains(r#"http_request_duration_seconds_bucket{method="GET",route="/metrics""#)
+    );
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{blocking::Client, redirect::Policy, StatusCode, Url};
+use rocket::{
+    form::{Form, FromForm},
+    http::Status,
+    response::{content::RawJson, status::Custom, Redirect},
+    serde::json::{json, Value},
+    State,
+};
+use rust_template::errors::{ApiError, AuthError, OidcError};
+use serde_json::from_value;
+use sha2::{Digest, Sha256};
+
+use crate::common::{create_test_user, delete_test_user};
+
+pub mod common;
+
+const PROVIDER_HOST: &str = "http://127.0.0.1:8090";
+const PROVIDER_PORT: u16 = 8090;
+const CLIENT_ID: &str = "template";
+const CLIENT_SECRET: &str = "secret";
+
+/// Authorization granted by the mock provider, the claims are taken from the `mock_*` parameters
+/// appended to the authorization URL
+struct Grant {
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+    claims: Value,
+}
+
+#[derive(Default)]
+struct Grants(Mutex<HashMap<String, Grant>>);
+
+#[derive(FromForm)]
+struct AuthorizeParams {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: String,
+    code_challenge: String,
+    code_challenge_method: String,
+    mock_sub: String,
+    mock_email: Option<String>,
+    mock_email_verified: Option<bool>,
+    mock_username: Option<String>,
+}
+
+#[derive(FromForm)]
+struct TokenRequest {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    client_secret: Option<String>,
+    code_verifier: String,
+}
+
+#[rocket::get("/.well-known/openid-configuration")]
+fn discovery() -> Value {
+    json!({
+        "issuer": PROVIDER_HOST,
+        "authorization_endpoint": format!("{}/authorize", PROVIDER_HOST),
+        "token_endpoint": format!("{}/token", PROVIDER_HOST),
+        "jwks_uri": format!("{}/jwks", PROVIDER_HOST),
+    })
+}
+
+#[rocket::get("/jwks")]
+fn jwks() -> RawJson<&'static str> {
+    RawJson(include_str!("fixtures/oidc/jwks.json"))
+}
+
+#[rocket::get("/authorize?<params..>")]
+fn authorize(params: AuthorizeParams, grants: &State<Grants>) -> Result<Redirect, Status> {
+    if params.code_challenge_method != "S256" {
+        return Err(Status::BadRequest);
+    }
+
+    let code = common::generate_test_token(32);
+    let grant = Grant {
+        client_id: params.client_id,
+        redirect_uri: params.redirect_uri.clone(),
+        code_challenge: params.code_challenge,
+        claims: json!({
+            "iss": PROVIDER_HOST,
+            "aud": CLIENT_ID,
+            "sub": params.mock_sub,
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "nonce": params.nonce,
+            "email": params.mock_email,
+            "email_verified": params.mock_email_verified.unwrap_or(false),
+            "preferred_username": params.mock_username,
+        }),
+    };
+    grants.0.lock().unwrap().insert(code.clone(), grant);
+
+    let redirect_uri = Url::parse_with_params(
+        &params.redirect_uri,
+        &[("code", code.as_str()), ("state", params.state.as_str())],
+    )
+    .unwrap();
+    Ok(Redirect::to(redirect_uri.to_string()))
+}
+
+#[rocket::post("/token", data = "<request>")]
+fn token(request: Form<TokenRequest>, grants: &State<Grants>) -> Custom<Value> {
+    let invalid_grant = Custom(Status::BadRequest, json!({ "error": "invalid_grant" }));
+
+    let Some(grant) = grants.0.lock().unwrap().remove(&request.code) else {
+        return invalid_grant;
+    };
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(request.code_verifier.as_bytes()));
+    if request.grant_type != "authorization_code"
+        || request.client_id != grant.client_id
+        || request.client_secret.as_deref() != Some(CLIENT_SECRET)
+        || request.redirect_uri != grant.redirect_uri
+        || code_challenge != grant.code_challenge
+    {
+        return invalid_grant;
+    }
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("mock".to_string());
+    let key = EncodingKey::from_rsa_pem(include_bytes!("fixtures/oidc/private_key.pem")).unwrap();
+    let id_token = jsonwebtoken::encode(&header, &grant.claims, &key).unwrap();
+
+    Custom(
+        Status::Ok,
+        json!({ "access_token": "mock", "token_type": "Bearer", "id_token": id_token }),
+    )
+}
+
+/// Start the mock provider configured as `mock` for the debug profile once per test run
+fn start_mock_provider() {
+    static STARTED: OnceLock<()> = OnceLock::new();
+
+    STARTED.get_or_init(|| {
+        std::thread::spawn(|| {
+            let config = rocket::Config {
+                address: Ipv4Addr::LOCALHOST.into(),
+                port: PROVIDER_PORT,
+                log_level: rocket::config::LogLevel::Off,
+                ..rocket::Config::debug_default()
+            };
+            let rocket = rocket::custom(config)
+                .manage(Grants::default())
+                .mount("/", rocket::routes![discovery, jwks, authorize, token]);
+            let _ = rocket::execute(rocket.launch());
+        });
+
+        let client = Client::new();
+        for _ in 0..50 {
+            let discovered = client
+                .get(format!(
+                    "{}/.well-known/openid-configuration",
+                    PROVIDER_HOST
+                ))
+                .send()
+                .is_ok_and(|response| response.status().is_success());
+            if discovered {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        panic!("Mock OpenID Connect provider is not started");
+    });
+}
+
+/// Authorization code returned to the app with the state, and the code verifier the app kept
+struct Authorization {
+    code: String,
+    state: String,
+    code_verifier: String,
+}
+
+fn start_authorization(code_challenge: &str) -> reqwest::blocking::Response {
+    Client::new()
+        .post(format!("{}/oidc/mock/authorize", common::APP_HOST))
+        .json(&json!({ "code_challenge": code_challenge }))
+        .send()
+        .unwrap()
+}
+
+/// Authorize with the mock provider as the app would, with a new code verifier
+fn authorize_with_mock(claims: &[(&str, &str)]) -> Authorization {
+    start_mock_provider();
+
+    let code_verifier = common::generate_test_token(64);
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+    let response = start_authorization(&code_challenge);
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    let state = json["state"].as_str().unwrap().to_string();
+    assert_eq!(state.len(), common::SESSION_ID_LENGTH);
+
+    let mut authorization_url = Url::parse(json["authorization_url"].as_str().unwrap()).unwrap();
+    authorization_url.query_pairs_mut().extend_pairs(claims);
+
+    let client = Client::builder().redirect(Policy::none()).build().unwrap();
+    let response = client.get(authorization_url).send().unwrap();
+    assert!(response.status().is_redirection());
+
+    let location = response.headers()["location"].to_str().unwrap();
+    let redirect_uri = Url::parse(location).unwrap();
+    assert_eq!(redirect_uri.scheme(), "tmplt");
+
+    let params: HashMap<_, _> = redirect_uri.query_pairs().into_owned().collect();
+    assert_eq!(params["state"], state);
+    Authorization {
+        code: params["code"].clone(),
+        state,
+        code_verifier,
+    }
+}
+
+fn login_with_mock(authorization: &Authorization) -> reqwest::blocking::Response {
+    Client::new()
+        .post(format!("{}/oidc/mock/login", common::APP_HOST))
+        .json(&json!({
+            "code": authorization.code,
+            "state": authorization.state,
+            "code_verifier": authorization.code_verifier,
+        }))
+        .send()
+        .unwrap()
+}
+
+#[test]
+fn when_identity_is_new_and_email_verified_then_oidc_login_signs_up_user() {
+    let subject = common::generate_test_token(16);
+    let email = format!("testOidc{}@gmail.com", rand::random::<u32>());
+
+    let authorization = authorize_with_mock(&[
+        ("mock_sub", &subject),
+        ("mock_email", &email),
+        ("mock_email_verified", "true"),
+        ("mock_username", "test oidc"),
+    ]);
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert!(json.get("token").is_some());
+
+    let response = Client::new()
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(json["token"].as_str().unwrap())
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let profile: Value = response.json().unwrap();
+    assert_eq!(profile["email"], email);
+
+    // The identity is linked, the email of the provider no longer matters
+    let authorization =
+        authorize_with_mock(&[("mock_sub", &subject), ("mock_email", "changed@gmail.com")]);
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_email_is_not_verified_then_oidc_signup_requires_confirmation() {
+    let subject = common::generate_test_token(16);
+    let email = format!("testOidc{}@gmail.com", rand::random::<u32>());
+    let claims = [("mock_sub", subject.as_str()), ("mock_email", &email)];
+
+    let authorization = authorize_with_mock(&claims);
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, AuthError::UnconfirmedUser.value().code);
+
+    let token = common::find_mailed_token(&email, "confirm").unwrap();
+    let response = Client::new()
+        .get(format!("{}/confirm/{}", common::APP_HOST, token))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let authorization = authorize_with_mock(&claims);
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_email_belongs_to_user_then_identity_is_linked_with_password() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "123456aA";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let subject = common::generate_test_token(16);
+    let claims = [
+        ("mock_sub", subject.as_str()),
+        ("mock_email", &email),
+        ("mock_email_verified", "true"),
+    ];
+
+    let authorization = authorize_with_mock(&claims);
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let json: Value = response.json().unwrap();
+    assert_eq!(json["email"], email);
+    let link_token = json["link_token"].as_str().unwrap();
+
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/oidc/link", common::APP_HOST))
+        .json(&json!({ "link_token": link_token, "password": "wrong123A" }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = client
+        .post(format!("{}/oidc/link", common::APP_HOST))
+        .json(&json!({ "link_token": link_token, "password": password }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert!(json.get("token").is_some());
+
+    // The link token is redeemed
+    let response = client
+        .post(format!("{}/oidc/link", common::APP_HOST))
+        .json(&json!({ "link_token": link_token, "password": password }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let authorization = authorize_with_mock(&claims);
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_state_is_unknown_or_used_then_oidc_login_returns_invalid_state_error() {
+    let subject = common::generate_test_token(16);
+    let email = format!("testOidc{}@gmail.com", rand::random::<u32>());
+
+    let authorization = authorize_with_mock(&[
+        ("mock_sub", &subject),
+        ("mock_email", &email),
+        ("mock_email_verified", "true"),
+    ]);
+
+    let unknown_state = Authorization {
+        state: common::generate_test_token(authorization.state.len()),
+        code: authorization.code.clone(),
+        code_verifier: authorization.code_verifier.clone(),
+    };
+    let response = login_with_mock(&unknown_state);
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, OidcError::InvalidState.value().code);
+
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, OidcError::InvalidState.value().code);
+}
+
+#[test]
+fn when_code_verifier_does_not_match_then_oidc_login_returns_authorization_failed_error() {
+    let subject = common::generate_test_token(16);
+    let email = format!("testOidc{}@gmail.com", rand::random::<u32>());
+
+    let authorization = authorize_with_mock(&[
+        ("mock_sub", &subject),
+        ("mock_email", &email),
+        ("mock_email_verified", "true"),
+    ]);
+
+    // The code and the state intercepted without the verifier kept by the app
+    let intercepted = Authorization {
+        code: authorization.code.clone(),
+        state: authorization.state.clone(),
+        code_verifier: common::generate_test_token(64),
+    };
+    let response = login_with_mock(&intercepted);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, OidcError::AuthorizationFailed.value().code);
+
+    // The state is used up by the failed attempt
+    let response = login_with_mock(&authorization);
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, OidcError::InvalidState.value().code);
+}
+
+#[test]
+fn when_code_challenge_is_not_s256_then_oidc_authorize_returns_invalid_code_challenge_error() {
+    start_mock_provider();
+
+    let response = start_authorization("plain-verifier");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, OidcError::InvalidCodeChallenge.value().code);
+}
+
+#[test]
+fn when_provider_is_not_configured_then_oidc_authorize_returns_unknown_provider_error() {
+    let response = Client::new()
+        .post(format!("{}/oidc/unknown/authorize", common::APP_HOST))
+        .json(&json!({ "code_challenge": URL_SAFE_NO_PAD.encode(Sha256::digest("verifier")) }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, OidcError::UnknownProvider.value().code);
+}
@@ -0,0 +1,213 @@
+use reqwest::{blocking::Client, StatusCode, Url};
+use rust_template::errors::{ApiError, PasskeyError};
+use serde_json::{from_value, json, Value};
+use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+use crate::common::{delete_test_user, get_logged_in_client};
+
+pub mod common;
+
+const ORIGIN: &str = "http://localhost:3000";
+
+fn authenticator() -> WebauthnAuthenticator<SoftPasskey> {
+    WebauthnAuthenticator::new(SoftPasskey::new(true))
+}
+
+/// Register a passkey of the logged in user with the authenticator, returns the response of the finish
+fn register_passkey(
+    client: &Client,
+    authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+    name: &str,
+) -> Value {
+    let response = client
+        .post(format!("{}/profile/passkeys/begin", common::APP_HOST))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+
+    let credential = authenticator
+        .do_registration(
+            Url::parse(ORIGIN).unwrap(),
+            from_value(json["options"].clone()).unwrap(),
+        )
+        .unwrap();
+
+    let response = client
+        .post(format!("{}/profile/passkeys/finish", common::APP_HOST))
+        .json(&json!({ "name": name, "credential": credential }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json().unwrap()
+}
+
+/// Start a login with the passkeys of the user, returns the challenge
+fn begin_login(email: &str) -> Value {
+    let response = Client::new()
+        .post(format!("{}/login/passkey/begin", common::APP_HOST))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().unwrap()
+}
+
+#[test]
+fn when_passkey_is_registered_then_login_with_passkey_success() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, output) = get_logged_in_client(&username, &email, "viewer");
+    let mut authenticator = authenticator();
+
+    let passkey = register_passkey(&client, &mut authenticator, "Test key");
+    assert_eq!(passkey["name"], "Test key");
+    assert!(passkey["last_used_at"].is_null());
+
+    let challenge = begin_login(&email);
+    let credential = authenticator
+        .do_authentication(
+            Url::parse(ORIGIN).unwrap(),
+            from_value(challenge["options"].clone()).unwrap(),
+        )
+        .unwrap();
+
+    let response = Client::new()
+        .post(format!("{}/login/passkey/finish", common::APP_HOST))
+        .json(&json!({ "challenge_id": challenge["challenge_id"], "credential": credential }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        json["token"].as_str().unwrap().len(),
+        common::SESSION_ID_LENGTH
+    );
+    assert!(json.get("refresh_token").is_some());
+
+    let passkeys: Vec<Value> = client
+        .get(format!("{}/profile/passkeys", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(passkeys.len(), 1);
+    assert_eq!(passkeys[0]["id"], passkey["id"]);
+    assert!(!passkeys[0]["last_used_at"].is_null());
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_login_challenge_is_reused_then_returns_invalid_challenge_error() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, output) = get_logged_in_client(&username, &email, "viewer");
+    let mut authenticator = authenticator();
+    register_passkey(&client, &mut authenticator, "Test key");
+
+    let challenge = begin_login(&email);
+    let credential = authenticator
+        .do_authentication(
+            Url::parse(ORIGIN).unwrap(),
+            from_value(challenge["options"].clone()).unwrap(),
+        )
+        .unwrap();
+    let body = json!({ "challenge_id": challenge["challenge_id"], "credential": credential });
+
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/login/passkey/finish", common::APP_HOST))
+        .json(&body)
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post(format!("{}/login/passkey/finish", common::APP_HOST))
+        .json(&body)
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, PasskeyError::InvalidChallenge.value().code);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_passkey_is_renamed_and_deleted_then_list_is_updated() {
+    let (client, output) = common::get_client_with_logged_in_viewer();
+    let mut authenticator = authenticator();
+    let passkey = register_passkey(&client, &mut authenticator, "Test key");
+    let url = format!("{}/profile/passkeys/{}", common::APP_HOST, passkey["id"]);
+
+    let response = client
+        .put(&url)
+        .json(&json!({ "name": "  Work laptop " }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert_eq!(json["name"], "Work laptop");
+
+    let response = client.delete(&url).send().unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let passkeys: Vec<Value> = client
+        .get(format!("{}/profile/passkeys", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert!(passkeys.is_empty());
+
+    let response = client.delete(&url).send().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, PasskeyError::NotFound.value().code);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_passkey_name_is_empty_then_returns_invalid_name_error() {
+    let (client, output) = common::get_client_with_logged_in_viewer();
+
+    let response = client
+        .post(format!("{}/profile/passkeys/finish", common::APP_HOST))
+        .json(&json!({
+            "name": " ",
+            "credential": {
+                "id": "",
+                "rawId": "",
+                "response": { "attestationObject": "", "clientDataJSON": "" },
+                "type": "public-key"
+            }
+        }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, PasskeyError::InvalidName.value().code);
+
+    delete_test_user(output);
+}
+
+#[test]
+fn when_user_has_no_passkeys_then_login_returns_passkey_not_found_error() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (_, output) = get_logged_in_client(&username, &email, "viewer");
+
+    let response = Client::new()
+        .post(format!("{}/login/passkey/begin", common::APP_HOST))
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let error: ApiError = from_value(response.json().unwrap()).unwrap();
+    assert_eq!(error.code, PasskeyError::NotFound.value().code);
+
+    delete_test_user(output);
+}
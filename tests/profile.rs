@@ -5,7 +5,8 @@ use rust_template::errors::{ApiError, AuthError, ProfileError};
 use serde_json::{from_value, Value};
 
 use crate::common::{
-    delete_test_user, get_client_with_logged_in_editor, get_client_with_logged_in_viewer,
+    create_test_user, delete_test_user, find_mailed_token, get_client_with_logged_in_editor,
+    get_client_with_logged_in_viewer, login_test_user,
 };
 
 pub mod common;
@@ -483,3 +484,322 @@ fn when_token_wrong_or_expired_then_delete_user_returns_invalid_token_error() {
 
     assert_eq!(error, AuthError::InvalidToken.value());
 }
+
+#[test]
+fn when_session_is_active_then_sessions_returns_current_session() {
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+
+    let response = client
+        .get(format!("{}/profile/sessions", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    let sessions = json.as_array().unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0]["current"], json!(true));
+    assert!(sessions[0]["ip"].is_string());
+}
+
+#[test]
+fn when_session_is_revoked_then_its_token_is_rejected() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "123456aA";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let current_token = login_test_user(&email, password)["token"].clone();
+    let other_token = login_test_user(&email, password)["token"].clone();
+
+    let client = Client::new();
+
+    let sessions: Value = client
+        .get(format!("{}/profile/sessions", common::APP_HOST))
+        .bearer_auth(current_token.as_str().unwrap())
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let other_session = sessions
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|session| session["current"] == json!(false))
+        .unwrap();
+
+    let revoke_response = client
+        .delete(format!(
+            "{}/profile/sessions/{}",
+            common::APP_HOST,
+            other_session["id"].as_str().unwrap()
+        ))
+        .bearer_auth(current_token.as_str().unwrap())
+        .send()
+        .unwrap();
+    let other_me_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(other_token.as_str().unwrap())
+        .send()
+        .unwrap();
+    let current_me_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(current_token.as_str().unwrap())
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(revoke_response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(other_me_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(current_me_response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_session_is_unknown_then_revoke_session_returns_session_not_found_error() {
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+
+    let response = client
+        .delete(format!("{}/profile/sessions/unknown", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let json: Value = response.json().unwrap();
+    let error: ApiError = from_value(json).unwrap();
+    assert_eq!(error, ProfileError::SessionNotFound.value());
+}
+
+#[test]
+fn when_password_is_updated_then_other_sessions_are_revoked() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "123456aA";
+    let output = create_test_user(&username, &email, password, "viewer", &true.to_string());
+
+    let current_token = login_test_user(&email, password)["token"].clone();
+    let other_token = login_test_user(&email, password)["token"].clone();
+
+    let client = Client::new();
+
+    let password_response = client
+        .put(format!("{}/profile/password", common::APP_HOST))
+        .bearer_auth(current_token.as_str().unwrap())
+        .json(&json!({
+            "password": "654321aA",
+            "confirmation": "654321aA"
+        }))
+        .send()
+        .unwrap();
+    let other_me_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(other_token.as_str().unwrap())
+        .send()
+        .unwrap();
+    let current_me_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .bearer_auth(current_token.as_str().unwrap())
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(output);
+
+    assert_eq!(password_response.status(), StatusCode::OK);
+    assert_eq!(other_me_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(current_me_response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_logged_in_and_login_failed_then_activity_lists_account_events() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) = get_logged_in_client(&username, &email, "viewer");
+
+    let failed_response = Client::new()
+        .post(format!("{}/login", common::APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": "wrong_password"
+        }))
+        .send()
+        .unwrap();
+
+    let response = client
+        .get(format!("{}/profile/activity?per_page=10", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(failed_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert_eq!(json["total"], 3);
+
+    let event_types = json["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["event_type"].as_str().unwrap())
+        .collect::<Vec<&str>>();
+    assert_eq!(event_types, vec!["login_failed", "login", "user_created"]);
+
+    let failed_login = &json["items"][0];
+    assert_eq!(failed_login["actor_id"], Value::Null);
+    assert_eq!(failed_login["payload"]["reason"], "wrong_credentials");
+    assert!(failed_login["ip"].is_string());
+    assert_eq!(json["items"][2]["payload"]["source"], "cli");
+}
+
+#[test]
+fn when_activity_page_is_out_of_range_then_activity_returns_empty_page() {
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+
+    let response = client
+        .get(format!(
+            "{}/profile/activity?page={}&per_page=100",
+            common::APP_HOST,
+            i64::MAX
+        ))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+    assert_eq!(json["page"], 1_000_000);
+    assert!(json["items"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn when_email_change_is_confirmed_and_reverted_then_previous_email_is_restored() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let new_email = format!("{}.new@gmail.com", username);
+    let (client, create_user_output) = get_logged_in_client(&username, &email, "viewer");
+
+    let change_response = client
+        .post(format!("{}/profile/email", common::APP_HOST))
+        .json(&json!({
+            "email": new_email,
+            "password": "123456aA"
+        }))
+        .send()
+        .unwrap();
+    let confirm_token = find_mailed_token(&new_email, "change_email").unwrap();
+    let revert_token = find_mailed_token(&email, "revert_email").unwrap();
+
+    let confirm_response = Client::new()
+        .post(format!(
+            "{}/email/confirm/{}",
+            common::APP_HOST,
+            confirm_token
+        ))
+        .send()
+        .unwrap();
+    let me_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap();
+    let me: Value = me_response.json().unwrap();
+    let reused_confirm_response = Client::new()
+        .post(format!(
+            "{}/email/confirm/{}",
+            common::APP_HOST,
+            confirm_token
+        ))
+        .send()
+        .unwrap();
+
+    let revert_response = Client::new()
+        .post(format!(
+            "{}/email/revert/{}",
+            common::APP_HOST,
+            revert_token
+        ))
+        .send()
+        .unwrap();
+    let reverted_me_response = client
+        .get(format!("{}/profile/me", common::APP_HOST))
+        .send()
+        .unwrap();
+    let login_response = Client::new()
+        .post(format!("{}/login", common::APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": "123456aA"
+        }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(change_response.status(), StatusCode::OK);
+    assert_eq!(confirm_response.status(), StatusCode::OK);
+    assert_eq!(me["email"], new_email);
+    assert_eq!(reused_confirm_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(revert_response.status(), StatusCode::OK);
+    assert_eq!(reverted_me_response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(login_response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_email_is_in_use_then_change_email_returns_email_in_use_error() {
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+
+    let username = format!("testEditor{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let other_output = create_test_user(&username, &email, "123456aA", "editor", &true.to_string());
+
+    let response = client
+        .post(format!("{}/profile/email", common::APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": "123456aA"
+        }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+    delete_test_user(other_output);
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json().unwrap();
+    let error: ApiError = from_value(json).unwrap();
+    assert_eq!(error, AuthError::EmailInUse.value());
+}
+
+#[test]
+fn when_password_is_wrong_then_change_email_returns_wrong_credentials_error() {
+    let (client, create_user_output) = get_client_with_logged_in_viewer();
+
+    let response = client
+        .post(format!("{}/profile/email", common::APP_HOST))
+        .json(&json!({
+            "email": format!("testViewer{}@gmail.com", rand::random::<u32>()),
+            "password": "wrong_password"
+        }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let json: Value = response.json().unwrap();
+    let error: ApiError = from_value(json).unwrap();
+    assert_eq!(error, AuthError::WrongCredentials.value());
+}
@@ -0,0 +1,173 @@
+use reqwest::{
+    blocking::{Client, Response},
+    StatusCode,
+};
+use rocket::serde::json::json;
+use rust_template::errors::{ApiError, AuthError};
+use serde_json::{from_value, Value};
+
+use crate::common::{create_test_user, delete_test_user, TestServer};
+
+pub mod common;
+
+const VIEWER_ADDRESS_HEADER: &str = "cloudfront-viewer-address";
+const PASSWORD_RESET_LIMIT: u64 = 3;
+
+#[test]
+fn when_login_fails_too_many_times_then_account_is_locked_out() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let password = "123456aA";
+    let create_user_output = create_test_user(&username, &email, password, "viewer", "true");
+
+    let client = Client::new();
+    let mut statuses = vec![];
+    for _ in 0..5 {
+        let response = client
+            .post(format!("{}/login", common::APP_HOST))
+            .json(&json!({
+                "email": email,
+                "password": "wrongPassword1"
+            }))
+            .send()
+            .unwrap();
+        statuses.push(response.status());
+    }
+
+    let response = client
+        .post(format!("{}/login", common::APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": password
+        }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(statuses[..4], [StatusCode::UNAUTHORIZED; 4]);
+    assert_eq!(statuses[4], StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AuthError::TooManyRequests.value()
+    );
+}
+
+/// Server trusting the client addresses reported by the tests, with a low password reset limit
+fn start_proxied_server(port: u16) -> TestServer {
+    TestServer::start(
+        port,
+        &[
+            ("ROCKET_TRUSTED_PROXIES", r#"["127.0.0.1"]"#.to_string()),
+            (
+                "ROCKET_RATE_LIMITS",
+                format!(
+                    "{{password_reset={{requests={},window=3600}}}}",
+                    PASSWORD_RESET_LIMIT
+                ),
+            ),
+        ],
+    )
+}
+
+fn random_viewer_address() -> String {
+    format!(
+        "10.{}.{}.{}:443",
+        rand::random::<u8>(),
+        rand::random::<u8>(),
+        rand::random::<u8>()
+    )
+}
+
+fn request_password_reset(host: &str, viewer_address: &str, email: &str) -> Response {
+    Client::new()
+        .post(format!("{}/password_reset", host))
+        .header(VIEWER_ADDRESS_HEADER, viewer_address)
+        .json(&json!({ "email": email }))
+        .send()
+        .unwrap()
+}
+
+#[test]
+fn when_ip_exceeds_limit_then_password_reset_returns_too_many_requests_error() {
+    let server = start_proxied_server(8111);
+    let viewer_address = random_viewer_address();
+
+    let mut statuses = vec![];
+    for _ in 0..PASSWORD_RESET_LIMIT {
+        statuses.push(request_password_reset(&server.host, &viewer_address, "invalid").status());
+    }
+    let response = request_password_reset(&server.host, &viewer_address, "invalid");
+    let other_client_response =
+        request_password_reset(&server.host, &random_viewer_address(), "invalid");
+
+    assert_eq!(
+        statuses,
+        [StatusCode::BAD_REQUEST; PASSWORD_RESET_LIMIT as usize]
+    );
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("Retry-After"));
+    assert_eq!(response.headers()["X-RateLimit-Remaining"], "0");
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AuthError::TooManyRequests.value()
+    );
+    assert_eq!(other_client_response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn when_account_exceeds_limit_then_password_reset_from_other_ip_returns_too_many_requests_error() {
+    let server = start_proxied_server(8112);
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let create_user_output = create_test_user(&username, &email, "123456aA", "viewer", "true");
+
+    let mut statuses = vec![];
+    for _ in 0..=PASSWORD_RESET_LIMIT {
+        let response = request_password_reset(&server.host, &random_viewer_address(), &email);
+        statuses.push(response.status());
+    }
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(
+        statuses[..PASSWORD_RESET_LIMIT as usize],
+        [StatusCode::OK; PASSWORD_RESET_LIMIT as usize]
+    );
+    assert_eq!(
+        statuses[PASSWORD_RESET_LIMIT as usize],
+        StatusCode::TOO_MANY_REQUESTS
+    );
+}
+
+#[test]
+fn when_peer_is_not_trusted_proxy_then_client_address_header_is_ignored() {
+    let remaining = |response: &Response| -> u64 {
+        response.headers()["X-RateLimit-Remaining"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+
+    let first_response =
+        request_password_reset(common::APP_HOST, &random_viewer_address(), "invalid");
+    let second_response =
+        request_password_reset(common::APP_HOST, &random_viewer_address(), "invalid");
+    let malformed_response = request_password_reset(common::APP_HOST, "malformed", "invalid");
+
+    assert_eq!(first_response.status(), StatusCode::BAD_REQUEST);
+    assert!(remaining(&second_response) < remaining(&first_response));
+    assert_eq!(malformed_response.status(), StatusCode::BAD_REQUEST);
+}
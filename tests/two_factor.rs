@@ -0,0 +1,261 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use reqwest::{
+    blocking::{Client, Response},
+    StatusCode,
+};
+use rocket::serde::json::json;
+use rust_template::errors::{ApiError, AuthError, TwoFactorError};
+use serde_json::{from_value, Value};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::common::{delete_test_user, generate_test_token, get_logged_in_client};
+
+pub mod common;
+
+const TEST_PASSWORD: &str = "123456aA";
+
+/// Code of the authenticator app for the time step `steps_ahead` steps after the current one
+fn generate_code(secret: &str, steps_ahead: u64) -> String {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().unwrap();
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    TOTP::new_unchecked(Algorithm::SHA1, 6, 1, 30, secret, None, String::new())
+        .generate(time + steps_ahead * 30)
+}
+
+fn login(email: &str) -> Response {
+    Client::new()
+        .post(format!("{}/login", common::APP_HOST))
+        .json(&json!({
+            "email": email,
+            "password": TEST_PASSWORD
+        }))
+        .send()
+        .unwrap()
+}
+
+/// Enroll the user and enable two-factor authentication, returns the secret and recovery codes
+fn enable_two_factor(client: &Client) -> (String, Vec<String>) {
+    let enrollment: Value = client
+        .post(format!("{}/profile/2fa", common::APP_HOST))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    let secret = enrollment["secret"].as_str().unwrap().to_string();
+    assert!(enrollment["otpauth_uri"]
+        .as_str()
+        .unwrap()
+        .starts_with("otpauth://totp/"));
+
+    let response = client
+        .post(format!("{}/profile/2fa/verify", common::APP_HOST))
+        .json(&json!({ "code": generate_code(&secret, 0) }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json().unwrap();
+
+    (secret, from_value(json["recovery_codes"].clone()).unwrap())
+}
+
+#[test]
+fn when_two_factor_enabled_then_login_requires_code() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    let (secret, _) = enable_two_factor(&client);
+
+    let login_response = login(&email);
+    let login_status = login_response.status();
+    let challenge: Value = login_response.json().unwrap();
+
+    let wrong_code_response = Client::new()
+        .post(format!("{}/login/2fa", common::APP_HOST))
+        .json(&json!({
+            "challenge_token": challenge["challenge_token"],
+            "code": "000000x"
+        }))
+        .send()
+        .unwrap();
+
+    let response = Client::new()
+        .post(format!("{}/login/2fa", common::APP_HOST))
+        .json(&json!({
+            "challenge_token": challenge["challenge_token"],
+            "code": generate_code(&secret, 1)
+        }))
+        .send()
+        .unwrap();
+    let status = response.status();
+    let tokens: Value = response.json().unwrap();
+
+    let reused_challenge_response = Client::new()
+        .post(format!("{}/login/2fa", common::APP_HOST))
+        .json(&json!({
+            "challenge_token": challenge["challenge_token"],
+            "code": generate_code(&secret, 1)
+        }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(login_status, StatusCode::ACCEPTED);
+    assert!(challenge.get("token").is_none());
+    assert_eq!(wrong_code_response.status(), StatusCode::UNAUTHORIZED);
+    let json: Value = wrong_code_response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        TwoFactorError::InvalidCode.value()
+    );
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        tokens["token"].as_str().unwrap().len(),
+        common::SESSION_ID_LENGTH
+    );
+    assert_eq!(reused_challenge_response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn when_code_already_used_then_login_two_factor_rejects_it() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    let (secret, _) = enable_two_factor(&client);
+    let enable_code = generate_code(&secret, 0);
+    let code = generate_code(&secret, 1);
+
+    let mut statuses = vec![];
+    for code in [&code, &code, &enable_code] {
+        let challenge: Value = login(&email).json().unwrap();
+        let response = Client::new()
+            .post(format!("{}/login/2fa", common::APP_HOST))
+            .json(&json!({
+                "challenge_token": challenge["challenge_token"],
+                "code": code
+            }))
+            .send()
+            .unwrap();
+        statuses.push(response.status());
+    }
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(
+        statuses,
+        vec![
+            StatusCode::OK,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::UNAUTHORIZED
+        ]
+    );
+}
+
+#[test]
+fn when_recovery_code_used_then_it_cannot_be_used_again() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    let (_, recovery_codes) = enable_two_factor(&client);
+
+    let mut statuses = vec![];
+    for _ in 0..2 {
+        let challenge: Value = login(&email).json().unwrap();
+        let response = Client::new()
+            .post(format!("{}/login/2fa", common::APP_HOST))
+            .json(&json!({
+                "challenge_token": challenge["challenge_token"],
+                "code": recovery_codes[0]
+            }))
+            .send()
+            .unwrap();
+        statuses.push(response.status());
+    }
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(recovery_codes.len(), 10);
+    assert_eq!(statuses, vec![StatusCode::OK, StatusCode::UNAUTHORIZED]);
+}
+
+#[test]
+fn when_code_invalid_then_verify_returns_invalid_code_error() {
+    let (client, create_user_output) = get_logged_in_client(
+        format!("testViewer{}", rand::random::<u32>()).as_str(),
+        format!("testViewer{}@gmail.com", rand::random::<u32>()).as_str(),
+        "viewer",
+    );
+
+    let _ = client
+        .post(format!("{}/profile/2fa", common::APP_HOST))
+        .send()
+        .unwrap();
+
+    let response = client
+        .post(format!("{}/profile/2fa/verify", common::APP_HOST))
+        .json(&json!({ "code": "12345" }))
+        .send()
+        .unwrap();
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        TwoFactorError::InvalidCode.value()
+    );
+}
+
+#[test]
+fn when_two_factor_disabled_then_login_returns_tokens() {
+    let username = format!("testViewer{}", rand::random::<u32>());
+    let email = format!("{}@gmail.com", username);
+    let (client, create_user_output) =
+        get_logged_in_client(username.as_str(), email.as_str(), "viewer");
+    let (secret, _) = enable_two_factor(&client);
+
+    let disable_response = client
+        .delete(format!("{}/profile/2fa", common::APP_HOST))
+        .json(&json!({ "code": generate_code(&secret, 1) }))
+        .send()
+        .unwrap();
+
+    let login_response = login(&email);
+
+    // Cleanup
+    delete_test_user(create_user_output);
+
+    assert_eq!(disable_response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(login_response.status(), StatusCode::OK);
+}
+
+#[test]
+fn when_challenge_token_unknown_then_login_two_factor_returns_invalid_token_error() {
+    let response = Client::new()
+        .post(format!("{}/login/2fa", common::APP_HOST))
+        .json(&json!({
+            "challenge_token": generate_test_token(common::SESSION_ID_LENGTH),
+            "code": "123456"
+        }))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let json: Value = response.json().unwrap();
+    assert_eq!(
+        from_value::<ApiError>(json).unwrap(),
+        AuthError::InvalidToken.value()
+    );
+}